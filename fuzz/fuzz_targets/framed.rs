@@ -0,0 +1,28 @@
+#![no_main]
+
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+
+use editr::config::ServerConfig;
+use editr::state::{MemoryStream, SharedState, Transport};
+use editr::text_server;
+
+static SERVER: OnceLock<SharedState<MemoryStream>> = OnceLock::new();
+
+// Sends data as a client would, however it is framed, and reads every
+// response until the server hangs up
+fuzz_target!(|data: &[u8]| {
+	let server = SERVER.get_or_init(|| {
+		let home = std::env::temp_dir().join("editr-fuzz-framed");
+		std::fs::create_dir_all(&home).unwrap();
+		text_server::start_in_memory(&home, ServerConfig::default()).unwrap()
+	});
+	let mut client = text_server::connect_in_memory(server).unwrap();
+	client.write_all(data).ok();
+	client.shutdown(Shutdown::Write).ok();
+	let mut responses = Vec::new();
+	client.read_to_end(&mut responses).ok();
+});
@@ -0,0 +1,30 @@
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+
+use editr::config::ServerConfig;
+use editr::message::Message;
+use editr::state::{LocalState, MemoryStream, SharedState};
+use editr::text_server;
+
+static SERVER: OnceLock<SharedState<MemoryStream>> = OnceLock::new();
+
+// Processes each line of data as a message from one connection, in order
+fuzz_target!(|data: &[u8]| {
+	let server = SERVER.get_or_init(|| {
+		let home = std::env::temp_dir().join("editr-fuzz-local-state");
+		std::fs::create_dir_all(&home).unwrap();
+		text_server::start_in_memory(&home, ServerConfig::default()).unwrap()
+	});
+	let (mut local, _client) = LocalState::in_memory(server.clone()).unwrap();
+	for line in data.split(|&byte| byte == b'\n') {
+		if let Ok(msg) = Message::from_slice(line) {
+			let (_, exit) = msg.process(&mut local);
+			if exit.is_some() {
+				break;
+			}
+		}
+	}
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use editr::message::Message;
+
+// Parsing a single message, limits and all
+fuzz_target!(|data: &[u8]| {
+	Message::from_slice(data).ok();
+});
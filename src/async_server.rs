@@ -0,0 +1,197 @@
+use std::net::{self, ToSocketAddrs};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
+
+use tokio::net::TcpListener;
+use tokio::runtime::{Builder, Runtime};
+use tokio::task::block_in_place;
+use tokio::time::sleep;
+
+use crate::config::ServerConfig;
+use crate::error::{EditrError, EditrResult};
+use crate::state::{ConnectionSlot, FileStates, LocalState, SharedState, TaskStream};
+use crate::text_server::{
+	accept_failed, admit, bind, close, configure_stream, connection_ended, handle_message,
+	install_signal_handlers, permitted, prepare, shutting_down, ServerHandle, Thread, Threads,
+	ACCEPT_INTERVAL, WIND_DOWN,
+};
+
+// Same as text_server::start, but serves connections as tokio tasks rather
+// than threads of their own. A connection waiting on its client holds no
+// thread, while each message is handled by the same blocking code as
+// text_server's, on a thread of the runtime's. The files themselves are
+// shared with the rest of the server as they are. With workers set, at most
+// that many connections are served at once, any more being sent ServerBusy
+pub fn start<A: ToSocketAddrs>(path: &Path, address: A) -> EditrResult<()> {
+	start_with_config(path, address, ServerConfig::default())
+}
+
+pub fn start_with_config<A: ToSocketAddrs>(
+	path: &Path,
+	address: A,
+	config: ServerConfig,
+) -> EditrResult<()> {
+	install_signal_handlers();
+	spawn(path, address, config)?.join()
+}
+
+// As text_server::spawn, returning once the server is listening on every
+// address given
+pub fn spawn<A: ToSocketAddrs>(
+	path: &Path,
+	address: A,
+	config: ServerConfig,
+) -> EditrResult<ServerHandle<TaskStream>> {
+	let listeners = address
+		.to_socket_addrs()?
+		.map(|address| bind(address, &config))
+		.collect::<Result<Vec<_>, _>>()?;
+	serve_files(path, listeners, config, FileStates::new())
+}
+
+// As text_server::serve_files, accepting from listeners on a runtime run by
+// a thread of its own
+pub fn serve_files(
+	path: &Path,
+	listeners: Vec<net::TcpListener>,
+	config: ServerConfig,
+	files: FileStates,
+) -> EditrResult<ServerHandle<TaskStream>> {
+	if listeners.is_empty() {
+		return Err(EditrError::Config("No address to listen on".to_string()));
+	}
+	let runtime = Builder::new_multi_thread().enable_all().build()?;
+	let mut local_addrs = Vec::new();
+	let listeners = {
+		let _runtime = runtime.enter();
+		listeners
+			.into_iter()
+			.map(|listener| {
+				listener.set_nonblocking(true)?;
+				local_addrs.push(listener.local_addr()?);
+				TcpListener::from_std(listener)
+			})
+			.collect::<Result<Vec<_>, _>>()?
+	};
+	let shared = prepare(path, config, files)?;
+
+	let thread = {
+		let shared = shared.clone();
+		thread::spawn(move || run(runtime, listeners, shared).map_err(|e| e.to_string()))
+	};
+
+	Ok(ServerHandle {
+		local_addrs,
+		shared,
+		thread,
+	})
+}
+
+// Accepts connections from every listener until shut down
+fn run(
+	runtime: Runtime,
+	listeners: Vec<TcpListener>,
+	shared: SharedState<TaskStream>,
+) -> EditrResult<()> {
+	let workers = shared.config.workers.map(Threads::new);
+	runtime.block_on(async {
+		let accepting = listeners
+			.into_iter()
+			.map(|listener| tokio::spawn(accept(listener, shared.clone(), workers.clone())))
+			.collect::<Vec<_>>();
+		// Signals are noticed here too, as they set what this checks
+		while !shutting_down(&shared) {
+			sleep(ACCEPT_INTERVAL).await;
+		}
+		for task in accepting {
+			task.abort();
+		}
+	});
+
+	// The runtime carries on serving the connections as they are wound down
+	let closed = close(&shared);
+
+	// Writer tasks finish off what was queued for their clients before the
+	// runtime is dropped, which would cut them short
+	let started = Instant::now();
+	while runtime.metrics().num_alive_tasks() > 0 && started.elapsed() < WIND_DOWN {
+		thread::sleep(ACCEPT_INTERVAL);
+	}
+	runtime.shutdown_background();
+	closed
+}
+
+// Accepts connections from listener, serving each as a task of its own,
+// until the task is aborted
+async fn accept(listener: TcpListener, shared: SharedState<TaskStream>, workers: Option<Threads>) {
+	loop {
+		let (stream, peer) = match listener.accept().await {
+			Ok(accepted) => accepted,
+			Err(e) => {
+				block_in_place(|| accept_failed(&e));
+				continue;
+			}
+		};
+		let mut stream = TaskStream::new(stream, peer);
+		if !permitted(&shared, &mut stream, peer) {
+			continue;
+		}
+		configure_stream(&shared, stream.socket(), peer);
+
+		let slot = match admit(&shared, &mut stream) {
+			Some(slot) => slot,
+			None => continue,
+		};
+		let worker = match &workers {
+			Some(workers) => match workers.take(&mut stream) {
+				Some(worker) => Some(worker),
+				None => continue,
+			},
+			None => None,
+		};
+
+		tokio::spawn(serve(shared.clone(), stream, slot, worker));
+	}
+}
+
+// Runs a connection from start to finish, as text_server::serve_connection
+// does, but only taking a thread while there is a message to handle. The
+// slot and worker are held until the connection is done with
+async fn serve(
+	shared: SharedState<TaskStream>,
+	stream: TaskStream,
+	_slot: ConnectionSlot,
+	_worker: Option<Thread>,
+) {
+	let mut thread_local = match block_in_place(|| LocalState::new(shared, stream)) {
+		Ok(thread_local) => thread_local,
+		Err(e) => {
+			error!("Failed to set up connection: {}", e);
+			return;
+		}
+	};
+
+	let mut invalid_messages = 0;
+	let result = loop {
+		let msg = thread_local.next_message().await;
+		let handled = block_in_place(|| {
+			catch_unwind(AssertUnwindSafe(|| {
+				handle_message(&mut thread_local, msg, &mut invalid_messages)
+			}))
+		});
+		match handled {
+			Ok(Ok(true)) => continue,
+			Ok(Ok(false)) => break Ok(Ok(())),
+			Ok(Err(e)) => break Ok(Err(e)),
+			Err(payload) => break Err(payload),
+		}
+	};
+
+	// Whatever the session held may be broken after a panic, so it is dropped
+	let panicked = connection_ended(&thread_local, result);
+	if let Err(e) = block_in_place(|| thread_local.disconnect(!panicked)) {
+		error!("Failed to clean up after connection: {}", e);
+	}
+}
@@ -0,0 +1,603 @@
+use std::io::{self, Stdout, Write};
+use std::net::SocketAddr;
+use std::panic;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+
+use editr::error::{EditrError, EditrResult};
+use editr::message::{Message, UpdateData};
+use editr::text_client::Client;
+
+// Length asked for when reading the whole file
+const WHOLE_FILE: usize = isize::MAX as usize;
+// Columns a tab is drawn across
+const TAB_WIDTH: usize = 4;
+// Longest to wait for a key before looking for others' edits
+const TICK: Duration = Duration::from_millis(100);
+
+/// Terminal editor for files on an editr server
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+	/// Server to connect to
+	address: SocketAddr,
+
+	/// File to open, chosen from a list if not given
+	file: Option<String>,
+
+	/// Name shown to others editing the file
+	#[arg(long)]
+	name: Option<String>,
+}
+
+fn main() {
+	let args = Args::parse();
+	let client = match Client::connect(args.address) {
+		Ok(client) => client,
+		Err(e) => {
+			println!("Failed to connect: {}", e);
+			std::process::exit(1);
+		}
+	};
+	install_panic_hook();
+	let result = Terminal::enter().and_then(|mut terminal| run(&mut terminal, &client, &args));
+	if let Err(e) = result {
+		println!("{}", e);
+		std::process::exit(1);
+	}
+}
+
+fn run(terminal: &mut Terminal, client: &Client, args: &Args) -> EditrResult<()> {
+	let file = match &args.file {
+		Some(file) => file.clone(),
+		None => match terminal.pick(&client.list_files()?)? {
+			Some(file) => file,
+			None => return Ok(()),
+		},
+	};
+	let path = client.open(&file, args.name.as_deref())?;
+	let mut editor = Editor::new(path, client.read(0, WHOLE_FILE)?, args.name.clone());
+	editor.sync(client)?;
+	let mut redraw = true;
+	loop {
+		if redraw {
+			terminal.draw(&mut editor)?;
+		}
+		redraw = false;
+		if event::poll(TICK)? {
+			match event::read()? {
+				Event::Key(key) if key.kind != KeyEventKind::Release => {
+					if !editor.key(client, key)? {
+						break;
+					}
+					redraw = true;
+				}
+				Event::Resize(..) => redraw = true,
+				_ => (),
+			}
+		}
+		// Others' cursors move without telling anyone, so are asked after
+		redraw |= editor.sync(client)?;
+	}
+	client.close()
+}
+
+// The terminal, in raw mode on the alternate screen until dropped
+struct Terminal {
+	out: Stdout,
+}
+
+impl Terminal {
+	fn enter() -> EditrResult<Terminal> {
+		terminal::enable_raw_mode()?;
+		let mut out = io::stdout();
+		execute!(out, EnterAlternateScreen, Hide)?;
+		Ok(Terminal { out })
+	}
+
+	// Lets the user choose from files, returning None if they give up
+	fn pick(&mut self, files: &[String]) -> EditrResult<Option<String>> {
+		let mut selected = 0;
+		loop {
+			let (_, height) = terminal::size()?;
+			let rows = (height as usize).saturating_sub(1).max(1);
+			let top = selected - selected % rows;
+			queue!(self.out, Clear(ClearType::All))?;
+			for (row, file) in files.iter().enumerate().skip(top).take(rows) {
+				queue!(self.out, MoveTo(0, (row - top) as u16))?;
+				if row == selected {
+					queue!(self.out, SetAttribute(Attribute::Reverse), Print(file))?;
+					queue!(self.out, SetAttribute(Attribute::Reset))?;
+				}
+				else {
+					queue!(self.out, Print(file))?;
+				}
+			}
+			queue!(
+				self.out,
+				MoveTo(0, height.saturating_sub(1)),
+				Print("Enter opens, Esc quits")
+			)?;
+			self.out.flush()?;
+
+			if let Event::Key(key) = event::read()? {
+				if key.kind == KeyEventKind::Release {
+					continue;
+				}
+				match key.code {
+					KeyCode::Up => selected = selected.saturating_sub(1),
+					KeyCode::Down if selected + 1 < files.len() => selected += 1,
+					KeyCode::Enter if !files.is_empty() => {
+						return Ok(Some(files[selected].clone()))
+					}
+					KeyCode::Esc | KeyCode::Char('q') => return Ok(None),
+					_ => (),
+				}
+			}
+		}
+	}
+
+	fn draw(&mut self, editor: &mut Editor) -> EditrResult<()> {
+		let (width, height) = terminal::size()?;
+		let (width, rows) = (width as usize, (height as usize).saturating_sub(1));
+		let starts = line_starts(&editor.text);
+		let cursor = editor.cursor.min(editor.text.len());
+		let cursor_line = line_of(&starts, cursor);
+		// Scrolls just far enough to keep the cursor in view
+		if cursor_line < editor.top {
+			editor.top = cursor_line;
+		}
+		else if rows > 0 && cursor_line >= editor.top + rows {
+			editor.top = cursor_line + 1 - rows;
+		}
+
+		queue!(self.out, Hide, Clear(ClearType::All))?;
+		for (row, line) in (editor.top..starts.len()).take(rows).enumerate() {
+			queue!(self.out, MoveTo(0, row as u16))?;
+			let end = line_end(&editor.text, &starts, line);
+			let mut column = 0;
+			for (offset, c) in chars(&editor.text, starts[line], end) {
+				let shown = if c == '\t' {
+					" ".repeat(TAB_WIDTH - column % TAB_WIDTH)
+				}
+				else {
+					c.to_string()
+				};
+				column += shown.chars().count();
+				if column > width {
+					break;
+				}
+				self.print(&shown, editor.others_at(offset))?;
+			}
+			// Cursors past the last character of the line
+			if column < width && editor.others_at(end) {
+				self.print(" ", true)?;
+			}
+		}
+
+		let status = format!(
+			" {} | {} others | {}",
+			editor.path.display(),
+			editor.others.len(),
+			editor.status
+		);
+		let status: String = status.chars().take(width).collect();
+		queue!(
+			self.out,
+			MoveTo(0, rows as u16),
+			SetAttribute(Attribute::Reverse),
+			Print(format!("{:width$}", status, width = width)),
+			SetAttribute(Attribute::Reset)
+		)?;
+
+		let column = columns(&editor.text, starts[cursor_line], cursor);
+		queue!(
+			self.out,
+			MoveTo(
+				column.min(width.saturating_sub(1)) as u16,
+				(cursor_line - editor.top) as u16
+			),
+			Show
+		)?;
+		self.out.flush()?;
+		Ok(())
+	}
+
+	// Prints text, highlighted where another client's cursor is
+	fn print(&mut self, text: &str, highlight: bool) -> EditrResult<()> {
+		if highlight {
+			queue!(
+				self.out,
+				SetAttribute(Attribute::Reverse),
+				Print(text),
+				SetAttribute(Attribute::Reset)
+			)?;
+		}
+		else {
+			queue!(self.out, Print(text))?;
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Terminal {
+	fn drop(&mut self) { restore(); }
+}
+
+// Puts the terminal back as it was, which must work even mid panic
+fn restore() {
+	execute!(io::stdout(), Show, LeaveAlternateScreen).ok();
+	terminal::disable_raw_mode().ok();
+}
+
+// Restores the terminal before a panic is reported, so the report can be read
+fn install_panic_hook() {
+	let hook = panic::take_hook();
+	panic::set_hook(Box::new(move |info| {
+		restore();
+		hook(info);
+	}));
+}
+
+// The open file as last heard from the server
+struct Editor {
+	path: PathBuf,
+	text: Vec<u8>,
+	// This client's cursor, as the server has it
+	cursor: usize,
+	// Every other client's cursor in the file, with their names
+	others: Vec<(usize, Option<String>)>,
+	name: Option<String>,
+	// First line shown
+	top: usize,
+	status: String,
+}
+
+impl Editor {
+	fn new(path: PathBuf, text: Vec<u8>, name: Option<String>) -> Editor {
+		Editor {
+			path,
+			text,
+			cursor: 0,
+			others: Vec::new(),
+			name,
+			top: 0,
+			status: "Ctrl-S saves, Ctrl-Q quits".to_string(),
+		}
+	}
+
+	// Catches up with the server's cursors and others' edits, returning
+	// whether anything changed.
+	// Edits broadcast before the cursors were sent have arrived by the time
+	// they have, so applying every broadcast after leaves both in step
+	fn sync(&mut self, client: &Client) -> EditrResult<bool> {
+		let (cursor, mut others) = client.cursors()?;
+		// The list includes this client, which can only be told by its cursor
+		if let Some(own) = others
+			.iter()
+			.position(|(offset, name)| *offset == cursor && *name == self.name)
+		{
+			others.remove(own);
+		}
+		let mut changed = cursor != self.cursor || others != self.others;
+		self.cursor = cursor;
+		self.others = others;
+		for msg in client.take_broadcasts() {
+			self.apply(msg)?;
+			changed = true;
+		}
+		Ok(changed)
+	}
+
+	fn apply(&mut self, msg: Message) -> EditrResult<()> {
+		match msg {
+			Message::UpdateMessage(UpdateData::Add(add)) => {
+				let offset = add.offset.min(self.text.len());
+				self.text.splice(offset..offset, add.data);
+			}
+			Message::UpdateMessage(UpdateData::Remove(remove)) => {
+				let from = remove.offset.min(self.text.len());
+				let to = (remove.offset + remove.len).min(self.text.len());
+				self.text.drain(from..to);
+			}
+			Message::UpdateMessage(UpdateData::Replace(replace)) => {
+				let from = replace.offset.min(self.text.len());
+				let to = (replace.offset + replace.len).min(self.text.len());
+				self.text.splice(from..to, replace.data);
+			}
+			Message::PeerLeft(peer) => {
+				self.status = format!("{} left", peer.name.as_deref().unwrap_or("Someone"));
+			}
+			Message::SaveFailed(failed) => {
+				self.status = format!("Saving failed: {}", failed.detail);
+			}
+			Message::Disconnecting(disconnecting) => {
+				return Err(EditrError::Protocol(format!(
+					"Disconnected by the server: {:?}",
+					disconnecting.reason
+				)))
+			}
+			Message::ServerClosing => {
+				return Err(EditrError::Protocol("Server is shutting down".to_string()))
+			}
+			_ => (),
+		}
+		Ok(())
+	}
+
+	// Acts on a key, returning false once the user has asked to quit
+	fn key(&mut self, client: &Client, key: KeyEvent) -> EditrResult<bool> {
+		let control = key.modifiers.contains(KeyModifiers::CONTROL);
+		match key.code {
+			KeyCode::Char('q') if control => return Ok(false),
+			KeyCode::Esc => return Ok(false),
+			KeyCode::Char('s') if control => {
+				self.status = match client.save() {
+					Ok(_) => "Saved".to_string(),
+					Err(e) => e.to_string(),
+				};
+			}
+			KeyCode::Char(c) if !control => {
+				self.insert(client, c.encode_utf8(&mut [0; 4]).as_bytes())?
+			}
+			KeyCode::Enter => self.insert(client, b"\n")?,
+			KeyCode::Tab => self.insert(client, b"\t")?,
+			KeyCode::Backspace if self.cursor > 0 => {
+				let len = self.cursor - prev_char(&self.text, self.cursor);
+				self.move_to(client, self.cursor - len)?;
+				self.remove(client, len)?;
+			}
+			KeyCode::Delete if self.cursor < self.text.len() => {
+				let len = next_char(&self.text, self.cursor) - self.cursor;
+				self.remove(client, len)?;
+			}
+			KeyCode::Left if self.cursor > 0 => {
+				self.move_to(client, prev_char(&self.text, self.cursor))?
+			}
+			KeyCode::Right if self.cursor < self.text.len() => {
+				self.move_to(client, next_char(&self.text, self.cursor))?
+			}
+			KeyCode::Up => self.move_lines(client, -1)?,
+			KeyCode::Down => self.move_lines(client, 1)?,
+			KeyCode::Home => {
+				let starts = line_starts(&self.text);
+				self.move_to(client, starts[line_of(&starts, self.cursor)])?
+			}
+			KeyCode::End => {
+				let starts = line_starts(&self.text);
+				let end = line_end(&self.text, &starts, line_of(&starts, self.cursor));
+				self.move_to(client, end)?
+			}
+			_ => (),
+		}
+		Ok(true)
+	}
+
+	// Writes data at the cursor, showing it straight away rather than
+	// waiting for the next sync, as the server sends no update to the writer
+	fn insert(&mut self, client: &Client, data: &[u8]) -> EditrResult<()> {
+		self.report(client.write_at_cursor(data))?;
+		let cursor = self.cursor.min(self.text.len());
+		self.text.splice(cursor..cursor, data.iter().cloned());
+		self.cursor = cursor + data.len();
+		Ok(())
+	}
+
+	fn remove(&mut self, client: &Client, len: usize) -> EditrResult<()> {
+		self.report(client.remove_at_cursor(len))?;
+		let to = (self.cursor + len).min(self.text.len());
+		self.text.drain(self.cursor.min(to)..to);
+		Ok(())
+	}
+
+	fn move_to(&mut self, client: &Client, offset: usize) -> EditrResult<()> {
+		self.report(client.move_cursor(offset as isize - self.cursor as isize))?;
+		self.cursor = offset;
+		Ok(())
+	}
+
+	// Moves the cursor up or down by lines, staying in the same column
+	// where the line is long enough
+	fn move_lines(&mut self, client: &Client, lines: isize) -> EditrResult<()> {
+		let starts = line_starts(&self.text);
+		let line = line_of(&starts, self.cursor);
+		let target = line as isize + lines;
+		if target < 0 || target as usize >= starts.len() {
+			return Ok(());
+		}
+		let target = target as usize;
+		let column = columns(&self.text, starts[line], self.cursor);
+		let end = line_end(&self.text, &starts, target);
+		let offset = chars(&self.text, starts[target], end)
+			.find(|(offset, _)| columns(&self.text, starts[target], *offset) >= column)
+			.map_or(end, |(offset, _)| offset);
+		self.move_to(client, offset)
+	}
+
+	// Shows a request the server turned down in the status line, leaving
+	// only failures of the connection itself to end the session
+	fn report(&mut self, result: EditrResult<()>) -> EditrResult<()> {
+		match result {
+			Err(EditrError::Rejected(e)) => {
+				self.status = e;
+				Ok(())
+			}
+			result => result,
+		}
+	}
+
+	// Whether another client's cursor is at offset
+	fn others_at(&self, offset: usize) -> bool {
+		self.others.iter().any(|(cursor, _)| *cursor == offset)
+	}
+}
+
+// Offsets at which each line of text starts
+fn line_starts(text: &[u8]) -> Vec<usize> {
+	let mut starts = vec![0];
+	starts.extend(
+		text.iter()
+			.enumerate()
+			.filter(|(_, byte)| **byte == b'\n')
+			.map(|(offset, _)| offset + 1),
+	);
+	starts
+}
+
+// The line offset falls on
+fn line_of(starts: &[usize], offset: usize) -> usize {
+	match starts.binary_search(&offset) {
+		Ok(line) => line,
+		Err(next) => next - 1,
+	}
+}
+
+// Offset of the end of line, before its newline
+fn line_end(text: &[u8], starts: &[usize], line: usize) -> usize {
+	starts.get(line + 1).map_or(text.len(), |next| next - 1)
+}
+
+// Each character in text from 'from' to 'to', with its offset. Bytes which
+// aren't UTF-8 are shown as replacement characters
+fn chars(text: &[u8], from: usize, to: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+	let mut offset = from;
+	std::iter::from_fn(move || {
+		if offset >= to {
+			return None;
+		}
+		let next = next_char(text, offset).min(to);
+		let c = std::str::from_utf8(&text[offset..next])
+			.ok()
+			.and_then(|c| c.chars().next())
+			.unwrap_or(char::REPLACEMENT_CHARACTER);
+		let found = (offset, c);
+		offset = next;
+		Some(found)
+	})
+}
+
+// Columns taken up by text from 'from' to 'to', on a line starting at from
+fn columns(text: &[u8], from: usize, to: usize) -> usize {
+	chars(text, from, to).fold(0, |column, (_, c)| {
+		if c == '\t' {
+			column + TAB_WIDTH - column % TAB_WIDTH
+		}
+		else {
+			column + 1
+		}
+	})
+}
+
+// Offset of the character after the one at offset
+fn next_char(text: &[u8], offset: usize) -> usize {
+	let len = match text[offset] {
+		0xC0..=0xDF => 2,
+		0xE0..=0xEF => 3,
+		0xF0..=0xF7 => 4,
+		_ => 1,
+	};
+	(offset + len).min(text.len())
+}
+
+// Offset of the character before the one at offset
+fn prev_char(text: &[u8], offset: usize) -> usize {
+	let mut prev = offset - 1;
+	while prev > 0 && text[prev] & 0xC0 == 0x80 {
+		prev -= 1;
+	}
+	prev
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::path::PathBuf;
+
+	use editr::config::ServerConfig;
+	use editr::message::{Message, UpdateAdd, UpdateData, UpdateRemove};
+	use editr::text_client::Client;
+	use editr::text_server::spawn;
+
+	use super::{columns, line_end, line_of, line_starts, next_char, prev_char, Editor};
+
+	#[test]
+	fn lines_are_found_by_offset() {
+		let text = b"one\ntwo\n\nfour";
+		let starts = line_starts(text);
+		assert_eq!(starts, [0, 4, 8, 9]);
+		assert_eq!(line_of(&starts, 0), 0);
+		assert_eq!(line_of(&starts, 3), 0);
+		assert_eq!(line_of(&starts, 4), 1);
+		assert_eq!(line_of(&starts, 13), 3);
+		assert_eq!(line_end(text, &starts, 0), 3);
+		assert_eq!(line_end(text, &starts, 2), 8);
+		assert_eq!(line_end(text, &starts, 3), text.len());
+	}
+
+	#[test]
+	fn tabs_and_wide_characters_take_their_columns() {
+		let text = "a\tb\u{e9}c".as_bytes();
+		assert_eq!(columns(text, 0, 2), 4);
+		assert_eq!(columns(text, 0, text.len()), 7);
+		// The two bytes of the accented character are one step
+		assert_eq!(next_char(text, 2), 3);
+		assert_eq!(next_char(text, 3), 5);
+		assert_eq!(prev_char(text, 5), 3);
+	}
+
+	#[test]
+	fn updates_are_applied_to_the_text() {
+		let mut editor = Editor::new(PathBuf::from("file.txt"), b"hello".to_vec(), None);
+		let updates = [
+			UpdateData::Add(UpdateAdd {
+				offset: 5,
+				data: b" world".to_vec(),
+			}),
+			UpdateData::Remove(UpdateRemove { offset: 0, len: 1 }),
+			// Past the end, as can happen before catching up
+			UpdateData::Remove(UpdateRemove {
+				offset: 8,
+				len: 100,
+			}),
+		];
+		for update in updates {
+			editor.apply(Message::UpdateMessage(update)).unwrap();
+		}
+		assert_eq!(editor.text, b"ello wor");
+		assert!(editor.apply(Message::ServerClosing).is_err());
+	}
+
+	#[test]
+	fn syncing_picks_up_others_edits_and_cursors() {
+		let home = std::env::temp_dir().join(format!("editr-tui-{}", std::process::id()));
+		fs::create_dir_all(&home).unwrap();
+		fs::write(home.join("file.txt"), "hello").unwrap();
+		let server = spawn(&home, "127.0.0.1:0", ServerConfig::default()).unwrap();
+		let ours = Client::connect(server.local_addr()).unwrap();
+		let path = ours.open("file.txt", Some("ours")).unwrap();
+		let theirs = Client::connect(server.local_addr()).unwrap();
+		theirs.open("file.txt", Some("theirs")).unwrap();
+
+		let mut editor = Editor::new(path, ours.read(0, 5).unwrap(), Some("ours".to_string()));
+		theirs.move_cursor(5).unwrap();
+		theirs.write_at_cursor(b" world").unwrap();
+		// Broadcasts may still be on their way after the first sync
+		while editor.text != b"hello world" {
+			editor.sync(&ours).unwrap();
+		}
+		editor.sync(&ours).unwrap();
+		assert_eq!(editor.cursor, 0);
+		assert_eq!(editor.others, [(11, Some("theirs".to_string()))]);
+		assert!(editor.others_at(11));
+
+		server.stop().unwrap();
+		fs::remove_dir_all(&home).ok();
+	}
+}
@@ -0,0 +1,275 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+
+use editr::error::{Context, EditrError, EditrResult};
+use editr::text_client::Client;
+
+// Length asked for when reading the whole file
+const WHOLE_FILE: usize = isize::MAX as usize;
+
+// Exit statuses, so scripts can tell failures apart without parsing output.
+// Usage errors exit with 2, as clap does
+const EXIT_FAILED: i32 = 1;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_ALREADY_EXISTS: i32 = 4;
+const EXIT_PERMISSION_DENIED: i32 = 5;
+const EXIT_BUSY: i32 = 6;
+const EXIT_INVALID: i32 = 7;
+const EXIT_QUOTA_EXCEEDED: i32 = 8;
+const EXIT_RATE_LIMITED: i32 = 9;
+const EXIT_UNAVAILABLE: i32 = 69;
+
+/// Runs a single operation against an editr server, for scripts
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+	/// Server to connect to
+	address: SocketAddr,
+
+	/// Log in as this user before anything else
+	#[arg(long, value_name = "NAME", requires = "token")]
+	user: Option<String>,
+
+	/// Token the server has for --user
+	#[arg(long, value_name = "TOKEN", requires = "user")]
+	token: Option<String>,
+
+	/// Print results as JSON rather than as they are
+	#[arg(long)]
+	json: bool,
+
+	#[command(subcommand)]
+	command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// List the files which may be opened
+	List,
+
+	/// Print the contents of a file, including edits not yet saved
+	Read {
+		path: String,
+
+		/// Byte to start reading from
+		#[arg(long, default_value_t = 0)]
+		offset: usize,
+
+		/// Most bytes to read, to the end of the file if not given
+		#[arg(long)]
+		len: Option<usize>,
+	},
+
+	/// Insert the contents of a file at an offset, then save
+	Write {
+		path: String,
+
+		/// Byte to insert at
+		#[arg(long)]
+		offset: usize,
+
+		/// File holding the bytes to insert, or - for stdin
+		#[arg(long, value_name = "PATH")]
+		data_file: PathBuf,
+
+		/// Leave the edit unsaved, for those editing the file to save
+		#[arg(long)]
+		no_save: bool,
+	},
+
+	/// Add the contents of a file to the end, then save
+	Append {
+		path: String,
+
+		/// File holding the bytes to add, or - for stdin
+		#[arg(long, value_name = "PATH")]
+		data_file: PathBuf,
+
+		/// Leave the edit unsaved, for those editing the file to save
+		#[arg(long)]
+		no_save: bool,
+	},
+
+	/// Save a file, along with every unsaved edit made to it
+	Save { path: String },
+
+	/// Delete a file
+	Delete { path: String },
+
+	/// Rename a file
+	Rename { from: String, to: String },
+}
+
+fn main() {
+	let args = Args::parse();
+	let result = Client::connect(args.address).and_then(|client| {
+		if let (Some(user), Some(token)) = (&args.user, &args.token) {
+			client.login(user, token)?;
+		}
+		let output = run(&client, &args.command)?;
+		client.disconnect()?;
+		Ok(output)
+	});
+	let status = match result.and_then(|output| print(&args, output)) {
+		Ok(()) => 0,
+		Err(e) => {
+			if args.json {
+				println!("{}", json!({ "error": e.to_string() }));
+			}
+			else {
+				eprintln!("{}", e);
+			}
+			exit_status(&e)
+		}
+	};
+	std::process::exit(status);
+}
+
+// What a command produced, to be printed once the connection is closed
+enum Output {
+	// Bytes read from a file, printed raw unless asked for JSON
+	Data(Vec<u8>),
+	Value(Value),
+	Nothing,
+}
+
+fn run(client: &Client, command: &Command) -> EditrResult<Output> {
+	match command {
+		Command::List => Ok(Output::Value(json!(client.list_files()?))),
+		Command::Read { path, offset, len } => {
+			client.open(path, None)?;
+			let data = client.read(*offset, len.unwrap_or(WHOLE_FILE))?;
+			Ok(Output::Data(data))
+		}
+		Command::Write {
+			path,
+			offset,
+			data_file,
+			no_save,
+		} => {
+			let data = read_data(data_file)?;
+			let opened = client.open(path, None)?;
+			client.write_at(*offset, &data)?;
+			finish_write(client, opened, *offset, data.len(), *no_save)
+		}
+		Command::Append {
+			path,
+			data_file,
+			no_save,
+		} => {
+			let data = read_data(data_file)?;
+			let opened = client.open(path, None)?;
+			// Others may be editing too, so the end is only known once opened
+			let offset = client.read(0, WHOLE_FILE)?.len();
+			client.write_at(offset, &data)?;
+			finish_write(client, opened, offset, data.len(), *no_save)
+		}
+		Command::Save { path } => {
+			let opened = client.open(path, None)?;
+			client.save()?;
+			Ok(Output::Value(json!({ "path": opened })))
+		}
+		Command::Delete { path } => {
+			client.delete(path)?;
+			Ok(Output::Nothing)
+		}
+		Command::Rename { from, to } => {
+			client.rename(from, to)?;
+			Ok(Output::Nothing)
+		}
+	}
+}
+
+// Saves after a write unless asked not to, describing where it landed
+fn finish_write(
+	client: &Client,
+	opened: PathBuf,
+	offset: usize,
+	len: usize,
+	no_save: bool,
+) -> EditrResult<Output> {
+	if !no_save {
+		client.save()?;
+	}
+	Ok(Output::Value(json!({
+		"path": opened,
+		"offset": offset,
+		"len": len,
+		"saved": !no_save,
+	})))
+}
+
+// Reads the bytes to send from path, or from stdin for -
+fn read_data(path: &PathBuf) -> EditrResult<Vec<u8>> {
+	if path.as_os_str() == "-" {
+		let mut data = Vec::new();
+		io::stdin()
+			.lock()
+			.read_to_end(&mut data)
+			.context("read", None)?;
+		Ok(data)
+	}
+	else {
+		fs::read(path).context("read", Some(path))
+	}
+}
+
+fn print(args: &Args, output: Output) -> EditrResult<()> {
+	let mut stdout = io::stdout().lock();
+	let written = match output {
+		Output::Data(data) if args.json => match String::from_utf8(data) {
+			Ok(text) => writeln!(stdout, "{}", json!({ "data": text })),
+			// Bytes which aren't text are given as numbers, so nothing is lost
+			Err(e) => writeln!(stdout, "{}", json!({ "bytes": e.into_bytes() })),
+		},
+		Output::Data(data) => stdout.write_all(&data),
+		Output::Value(Value::Array(entries)) if !args.json => entries
+			.iter()
+			.try_for_each(|entry| writeln!(stdout, "{}", entry.as_str().unwrap_or_default())),
+		Output::Value(value) if args.json => writeln!(stdout, "{}", value),
+		Output::Value(_) => Ok(()),
+		Output::Nothing if args.json => writeln!(stdout, "{}", json!({})),
+		Output::Nothing => Ok(()),
+	};
+	match written.and_then(|_| stdout.flush()) {
+		// Whoever was reading has seen all they wanted, as with head
+		Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+		result => Ok(result?),
+	}
+}
+
+// Chooses the exit status for e. Responses only carry the server's reason,
+// so requests it turned down are told apart by how each kind of error reads
+fn exit_status(e: &EditrError) -> i32 {
+	// Local files failing to read are wrapped in context, unlike the server
+	// failing to answer
+	let reason = match (e, e.root()) {
+		(EditrError::Io(_) | EditrError::Disconnected, _) => return EXIT_UNAVAILABLE,
+		(_, EditrError::Rejected(reason)) => reason,
+		_ => return EXIT_FAILED,
+	};
+	let classes = [
+		("No such", EXIT_NOT_FOUND),
+		("Already exists", EXIT_ALREADY_EXISTS),
+		("File exists", EXIT_ALREADY_EXISTS),
+		("Permission denied", EXIT_PERMISSION_DENIED),
+		("Login required", EXIT_PERMISSION_DENIED),
+		("File is busy", EXIT_BUSY),
+		("Invalid path", EXIT_INVALID),
+		("Is a directory", EXIT_INVALID),
+		("Not a regular file", EXIT_INVALID),
+		("out of bounds", EXIT_INVALID),
+		("ends before it starts", EXIT_INVALID),
+		("Quota exceeded", EXIT_QUOTA_EXCEEDED),
+		("Rate limited", EXIT_RATE_LIMITED),
+	];
+	classes
+		.iter()
+		.find(|(phrase, _)| reason.contains(phrase))
+		.map_or(EXIT_FAILED, |(_, status)| *status)
+}
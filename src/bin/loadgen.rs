@@ -0,0 +1,323 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+
+use editr::config::parse_duration;
+use editr::error::{EditrError, EditrResult};
+use editr::message::{Message, UpdateData};
+use editr::text_client::Client;
+
+// Length asked for when reading the whole file
+const WHOLE_FILE: usize = isize::MAX as usize;
+// Bytes read by each periodic read
+const READ_LEN: usize = 256;
+
+/// Drives many simultaneous editors against an editr server, then checks
+/// that every one of them sees the same files
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+	/// Server to connect to
+	address: SocketAddr,
+
+	/// Editors to connect
+	#[arg(long, default_value_t = 100)]
+	connections: usize,
+
+	/// Files to edit, created if missing. Editors are shared out between them
+	#[arg(long, value_name = "PATH", default_value = "loadgen.txt")]
+	file: Vec<String>,
+
+	/// How long to edit for, such as 30s or 5m
+	#[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "10s")]
+	duration: Duration,
+
+	/// Characters typed in each burst of cursor writes
+	#[arg(long, value_name = "COUNT", default_value_t = 8)]
+	burst: usize,
+
+	/// Relative weight of typing bursts in the operation mix
+	#[arg(long, value_name = "WEIGHT", default_value_t = 80)]
+	writes: u32,
+
+	/// Relative weight of reads in the operation mix
+	#[arg(long, value_name = "WEIGHT", default_value_t = 18)]
+	reads: u32,
+
+	/// Relative weight of saves in the operation mix
+	#[arg(long, value_name = "WEIGHT", default_value_t = 2)]
+	saves: u32,
+
+	/// Time each editor waits between operations
+	#[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "10ms")]
+	pause: Duration,
+}
+
+// What a single editor did, and what it saw at the end
+#[derive(Default)]
+struct Stats {
+	// Time taken by every request, answered or not
+	latencies: Vec<Duration>,
+	// Edits the server accepted
+	edits: u64,
+	// Update broadcasts received from other editors
+	updates: u64,
+	errors: BTreeMap<String, u64>,
+	// Hash of the file as read at the end
+	hash: Option<u64>,
+}
+
+fn main() {
+	let args = Args::parse();
+	if args.writes + args.reads + args.saves == 0 {
+		println!("At least one of --writes, --reads and --saves must be above zero");
+		std::process::exit(1);
+	}
+	for file in &args.file {
+		if let Err(e) = create(args.address, file) {
+			println!("Failed to create {}: {}", file, e);
+			std::process::exit(1);
+		}
+	}
+
+	let args = Arc::new(args);
+	// Editors start together, and only read the files back once all have stopped
+	let start = Arc::new(Barrier::new(args.connections));
+	let stop = Arc::new(Barrier::new(args.connections));
+	let began = Instant::now();
+	let workers = (0..args.connections)
+		.map(|index| {
+			let (args, start, stop) = (args.clone(), start.clone(), stop.clone());
+			thread::spawn(move || edit(index, &args, &start, &stop))
+		})
+		.collect::<Vec<_>>();
+	let results = workers
+		.into_iter()
+		.map(|worker| {
+			worker
+				.join()
+				.unwrap_or_else(|_| Err(EditrError::Internal("Editor panicked".to_string())))
+		})
+		.collect::<Vec<_>>();
+	let elapsed = began.elapsed();
+
+	if !report(&args, &results, elapsed) {
+		std::process::exit(1);
+	}
+}
+
+// Creates file unless it is already there
+fn create(address: SocketAddr, file: &str) -> EditrResult<()> {
+	let client = Client::connect(address)?;
+	match client.create(file, true, None, false) {
+		Err(EditrError::Rejected(_)) if client.open(file, None).is_ok() => client.close(),
+		result => result.map(|_| ()),
+	}
+}
+
+// Connects as the index'th editor and edits its file until the time is up
+fn edit(index: usize, args: &Args, start: &Barrier, stop: &Barrier) -> EditrResult<Stats> {
+	let file = &args.file[index % args.file.len()];
+	// Waits at both barriers even if connecting fails, so the others carry on
+	let connected = Client::connect(args.address).and_then(|client| {
+		client.open(file, Some(&format!("loadgen-{}", index)))?;
+		Ok(client)
+	});
+	start.wait();
+	let client = match connected {
+		Ok(client) => client,
+		Err(e) => {
+			stop.wait();
+			return Err(e);
+		}
+	};
+
+	let mut stats = Stats::default();
+	let mut rng = Rng::new(index);
+	let deadline = Instant::now() + args.duration;
+	while Instant::now() < deadline {
+		let pick = rng.below(args.writes + args.reads + args.saves);
+		if pick < args.writes {
+			for _ in 0..args.burst {
+				let c = b'a' + rng.below(26) as u8;
+				if stats.time(|| client.write_at_cursor(&[c])) {
+					stats.edits += 1;
+				}
+			}
+		}
+		else if pick < args.writes + args.reads {
+			stats.time(|| client.read(0, READ_LEN));
+		}
+		else {
+			stats.time(|| client.save());
+		}
+		stats.count_updates(&client);
+		thread::sleep(args.pause);
+	}
+
+	stop.wait();
+	// Every edit was answered before anyone got here, so the updates for it
+	// were sent ahead of the response to this read
+	let contents = client.read(0, WHOLE_FILE)?;
+	let mut hasher = DefaultHasher::new();
+	contents.hash(&mut hasher);
+	stats.hash = Some(hasher.finish());
+	stats.count_updates(&client);
+	client.close()?;
+	Ok(stats)
+}
+
+impl Stats {
+	// Times a request, returning whether it succeeded
+	fn time<T, F: FnOnce() -> EditrResult<T>>(&mut self, request: F) -> bool {
+		let sent = Instant::now();
+		let result = request();
+		self.latencies.push(sent.elapsed());
+		match result {
+			Ok(_) => true,
+			Err(e) => {
+				*self.errors.entry(e.to_string()).or_insert(0) += 1;
+				false
+			}
+		}
+	}
+
+	fn count_updates(&mut self, client: &Client) {
+		self.updates += client
+			.take_broadcasts()
+			.iter()
+			.filter(|msg| matches!(msg, Message::UpdateMessage(UpdateData::Add(_))))
+			.count() as u64;
+	}
+}
+
+// Prints what happened, returning false if any editor failed or the
+// editors disagree about the contents of any file
+fn report(args: &Args, results: &[EditrResult<Stats>], elapsed: Duration) -> bool {
+	let mut latencies = Vec::new();
+	let mut errors = BTreeMap::new();
+	let mut requests = 0;
+	let mut updates = 0;
+	let mut expected_updates = 0;
+	let mut converged = true;
+	let mut failed = 0;
+	for (index, file) in args.file.iter().enumerate() {
+		let results = results
+			.iter()
+			.skip(index)
+			.step_by(args.file.len())
+			.collect::<Vec<_>>();
+		// An editor which never read the file back can't vouch for it
+		let unchecked = results
+			.iter()
+			.filter(|result| !matches!(result, Ok(Stats { hash: Some(_), .. })))
+			.count();
+		if unchecked > 0 {
+			failed += unchecked;
+			println!("{} was not read back by {} editors", file, unchecked);
+		}
+		let editors = results
+			.iter()
+			.filter_map(|result| result.as_ref().ok())
+			.collect::<Vec<_>>();
+		let edits = editors.iter().map(|stats| stats.edits).sum::<u64>();
+		// Each edit is sent to every other editor of the file
+		for stats in &editors {
+			expected_updates += edits - stats.edits;
+		}
+		let hashes = editors.iter().filter_map(|stats| stats.hash).fold(
+			HashMap::new(),
+			|mut hashes, hash| {
+				*hashes.entry(hash).or_insert(0) += 1;
+				hashes
+			},
+		);
+		if hashes.len() > 1 {
+			converged = false;
+			println!(
+				"{} diverged: {} different contents seen",
+				file,
+				hashes.len()
+			);
+		}
+	}
+	for result in results {
+		match result {
+			Ok(stats) => {
+				requests += stats.latencies.len();
+				latencies.extend(&stats.latencies);
+				updates += stats.updates;
+				for (error, count) in &stats.errors {
+					*errors.entry(error.clone()).or_insert(0) += count;
+				}
+			}
+			Err(e) => {
+				*errors.entry(e.to_string()).or_insert(0) += 1;
+			}
+		}
+	}
+	latencies.sort();
+
+	println!(
+		"{} editors on {} files for {:.1}s, {} failed",
+		args.connections,
+		args.file.len(),
+		elapsed.as_secs_f64(),
+		failed
+	);
+	println!(
+		"Requests: {} ({:.0} per second)",
+		requests,
+		requests as f64 / elapsed.as_secs_f64()
+	);
+	println!(
+		"Latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+		percentile(&latencies, 50.0),
+		percentile(&latencies, 90.0),
+		percentile(&latencies, 99.0),
+		latencies.last().copied().unwrap_or_default()
+	);
+	println!("Updates delivered: {} of {}", updates, expected_updates);
+	println!("Errors: {}", errors.values().sum::<u64>());
+	for (error, count) in &errors {
+		println!("  {}: {}", error, count);
+	}
+	println!("Converged: {}", if converged { "yes" } else { "no" });
+	converged && failed == 0
+}
+
+// The latency below which percent of requests were answered
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+	if sorted.is_empty() {
+		return Duration::ZERO;
+	}
+	let index = ((sorted.len() - 1) as f64 * percent / 100.0).round() as usize;
+	sorted[index]
+}
+
+// Xorshift, which is plenty for picking operations and letters
+struct Rng(u64);
+
+impl Rng {
+	fn new(index: usize) -> Rng {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |time| time.as_nanos() as u64);
+		// Never zero, which xorshift would be stuck at
+		Rng((now ^ (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1)
+	}
+
+	// A number from 0 up to but not including below
+	fn below(&mut self, below: u32) -> u32 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		(self.0 % u64::from(below)) as u32
+	}
+}
@@ -1,53 +1,491 @@
-use std::env;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use editr::text_server;
+use clap::Parser;
+
+use editr::config::{parse_duration, AccessLogConfig, Cidr, Durability, JournalConfig, ServerConfig, SymlinkPolicy};
+use editr::error::EditrResult;
+use editr::log::{self, Level};
+use editr::text_server::{self, ServerHandle};
+#[cfg(all(unix, feature = "daemon"))]
+use editr::daemon::{self, Pidfile};
+
+// Size past which the access log is moved aside
+const ACCESS_LOG_MAX_SIZE: u64 = 64 << 20;
+
+/// Collaborative text editing server
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+	/// Directory served to clients
+	#[arg(value_parser = parse_home, required_unless_present = "self_test")]
+	home: Option<PathBuf>,
+
+	/// Address to listen on
+	#[arg(required_unless_present_any = ["listen", "socket_activation", "stdio", "unix", "self_test"])]
+	address: Option<SocketAddr>,
+
+	/// Further addresses to listen on
+	#[arg(long, value_name = "ADDRESS")]
+	listen: Vec<SocketAddr>,
+
+	/// Accept on the sockets passed down by a supervisor, through LISTEN_FDS
+	#[cfg(unix)]
+	#[arg(long, conflicts_with_all = ["address", "listen"])]
+	socket_activation: bool,
+
+	/// Serve the one client on stdin and stdout, exiting once it has gone
+	#[cfg(unix)]
+	#[arg(long, conflicts_with_all = ["address", "listen", "socket_activation"])]
+	stdio: bool,
+
+	/// Accept clients on this machine through a Unix socket made here
+	#[cfg(unix)]
+	#[arg(long, value_name = "PATH", conflicts_with_all = ["address", "listen", "socket_activation", "stdio"])]
+	unix: Option<PathBuf>,
+
+	/// Where output goes with --stdio, rather than stderr
+	#[cfg(unix)]
+	#[arg(long, value_name = "PATH", requires = "stdio")]
+	stdio_log: Option<PathBuf>,
+
+	/// Read settings from this JSON file, which the options given here override
+	#[arg(long, value_name = "PATH", value_parser = parse_config)]
+	config: Option<ServerConfig>,
+
+	/// Log no more than this, one of error, warn, info or debug
+	#[arg(long, value_name = "LEVEL", default_value = "debug")]
+	log_level: Level,
+
+	/// Refuse every change to files
+	#[arg(long)]
+	read_only: bool,
+
+	/// Refuse paths through symlinks, rather than following those which stay within home
+	#[arg(long)]
+	refuse_symlinks: bool,
+
+	/// Confine each logged in user to a directory of their own under home
+	#[arg(long)]
+	user_homes: bool,
+
+	/// Directory under home which every user may access
+	#[arg(long, value_name = "NAME", requires = "user_homes")]
+	shared_dir: Option<String>,
+
+	/// File of `name token` lines, one for each user who may log in
+	#[arg(long, value_name = "PATH", value_parser = parse_users)]
+	users: Option<HashMap<String, String>>,
+
+	/// Most bytes on disk per home
+	#[arg(long, value_name = "BYTES")]
+	quota: Option<u64>,
+
+	/// Most connections served at once
+	#[arg(long, value_name = "COUNT")]
+	max_connections: Option<usize>,
+
+	/// Serve connections on this many threads rather than one each, turning away any more
+	#[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u64).range(1..))]
+	workers: Option<u64>,
+
+	/// Largest message a client may send
+	#[arg(long, value_name = "BYTES")]
+	max_message_size: Option<usize>,
+
+	/// Disconnect clients silent for this long, such as 90s or 5m
+	#[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+	idle_timeout: Option<Duration>,
+
+	/// Only accept clients from these addresses, such as 10.0.0.0/8
+	#[arg(long, value_name = "CIDR")]
+	allow: Vec<Cidr>,
+
+	/// Turn away clients from these addresses
+	#[arg(long, value_name = "CIDR")]
+	deny: Vec<Cidr>,
+
+	/// Log every operation to this file
+	#[arg(long, value_name = "PATH")]
+	access_log: Option<PathBuf>,
+
+	/// Sync every save to disk before reporting it done
+	#[arg(long)]
+	fsync: bool,
+
+	/// Record unsaved edits to this file, replaying them on startup
+	#[arg(long, value_name = "PATH")]
+	journal: Option<PathBuf>,
+
+	/// How often the journal is synced to disk
+	#[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1s", requires = "journal")]
+	journal_sync: Duration,
+
+	/// Report metrics this often
+	#[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+	metrics_interval: Option<Duration>,
+
+	/// Append metrics reports to this file as JSON
+	#[arg(long, value_name = "PATH", requires = "metrics_interval")]
+	metrics_file: Option<PathBuf>,
+
+	/// Log messages which take longer than this to handle, such as 50ms
+	#[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+	slow_op: Option<Duration>,
+
+	/// Save files with unsaved edits this often, such as 30s
+	#[arg(long, value_name = "DURATION", value_parser = parse_interval)]
+	autosave: Option<Duration>,
+
+	/// Check that concurrent clients converge on a temporary server, then exit
+	#[arg(long, conflicts_with_all = ["home", "address", "listen", "unix"])]
+	self_test: bool,
+
+	/// Clients editing at once in the self test
+	#[arg(long, value_name = "COUNT", default_value_t = 8, requires = "self_test")]
+	self_test_clients: usize,
+
+	/// Requests each client makes in the self test
+	#[arg(long, value_name = "COUNT", default_value_t = 500, requires = "self_test")]
+	self_test_operations: usize,
+
+	/// Seed for the self test's edits, to repeat a failed run
+	#[arg(long, value_name = "SEED", requires = "self_test")]
+	seed: Option<u64>,
+
+	/// Carry on in the background, detached from the terminal
+	#[cfg(all(unix, feature = "daemon"))]
+	#[arg(long, conflicts_with = "stdio")]
+	daemon: bool,
+
+	/// Where output goes once in the background
+	#[cfg(all(unix, feature = "daemon"))]
+	#[arg(long, value_name = "PATH", default_value = "editr.log", requires = "daemon")]
+	log: PathBuf,
+
+	/// Write the server's pid here, refusing to start if another server has
+	#[cfg(all(unix, feature = "daemon"))]
+	#[arg(long, value_name = "PATH")]
+	pidfile: Option<PathBuf>,
+}
+
+impl Args {
+	fn home(&self) -> &Path {
+		self.home.as_deref().expect("Home is required without --self-test")
+	}
+
+	fn addresses(&self) -> Vec<SocketAddr> {
+		self.address.iter()
+			.chain(&self.listen)
+			.cloned()
+			.collect()
+	}
+
+	// The config file's settings, overridden by any options given
+	fn server_config(&self) -> ServerConfig {
+		let base = self.config.clone().unwrap_or_default();
+		ServerConfig {
+			read_only: self.read_only || base.read_only,
+			symlinks: if self.refuse_symlinks { SymlinkPolicy::Refuse } else { base.symlinks },
+			user_homes: self.user_homes || base.user_homes,
+			shared_dir: self.shared_dir.clone().or(base.shared_dir),
+			user_tokens: self.users.clone().unwrap_or(base.user_tokens),
+			quota: self.quota.or(base.quota),
+			max_connections: self.max_connections.or(base.max_connections),
+			workers: self.workers.map(|workers| workers as usize).or(base.workers),
+			max_message_size: self.max_message_size.unwrap_or(base.max_message_size),
+			idle_timeout: self.idle_timeout.or(base.idle_timeout),
+			allow: if self.allow.is_empty() { base.allow } else { self.allow.clone() },
+			deny: if self.deny.is_empty() { base.deny } else { self.deny.clone() },
+			access_log: self.access_log.clone().map(|path| AccessLogConfig {
+				path,
+				max_size: ACCESS_LOG_MAX_SIZE,
+			}).or(base.access_log),
+			journal: self.journal.clone().map(|path| JournalConfig {
+				path,
+				sync_interval: self.journal_sync,
+			}).or(base.journal),
+			metrics_log: self.metrics_interval.unwrap_or(base.metrics_log),
+			metrics_file: self.metrics_file.clone().or(base.metrics_file),
+			slow_op: self.slow_op.or(base.slow_op),
+			durability: if self.fsync { Durability::Fsync } else { base.durability },
+			autosave: self.autosave.or(base.autosave),
+			..base
+		}
+	}
+}
 
 fn main() {
-	let args: Vec<String> = env::args().collect();
-	match Config::new(args) {
-		Ok(config) => {
-			text_server::start(&config.home, config.address).unwrap();
+	let args = Args::parse();
+	log::set_level(args.log_level);
+	if args.self_test {
+		std::process::exit(self_test(&args));
+	}
+	#[cfg(unix)]
+	if args.stdio {
+		if let Err(e) = text_server::serve_stdio(args.home(), args.stdio_log.as_deref(), args.server_config()) {
+			eprintln!("{}", e);
+			std::process::exit(1);
 		}
+		return;
+	}
+	// Removes the pidfile once the server has shut down
+	#[cfg(all(unix, feature = "daemon"))]
+	let _pidfile = match detach(&args) {
+		Ok(pidfile) => pidfile,
 		Err(e) => {
-			println!("Error parsing arguments...");
-			println!("\t{}", e.to_string());
-			print_help();
+			println!("Failed to start: {}", e);
+			std::process::exit(1);
 		}
+	};
+	text_server::install_signal_handlers();
+	#[cfg(unix)]
+	if let Some(socket) = &args.unix {
+		let server = text_server::serve_unix(args.home(), socket, args.server_config()).unwrap();
+		println!("LISTENING {}", socket.display());
+		server.join().unwrap();
+		return;
+	}
+	let server = start(&args).unwrap();
+	// Supervisors binding port 0 read the real addresses from here
+	for address in server.local_addrs() {
+		println!("LISTENING {}", address);
+	}
+	server.join().unwrap();
+}
+
+// Listens where asked, or where the supervisor has already set up
+fn start(args: &Args) -> EditrResult<ServerHandle> {
+	#[cfg(unix)]
+	if args.socket_activation {
+		let listeners = text_server::activated_listeners()?;
+		return text_server::serve_listeners(args.home(), listeners, args.server_config());
 	}
+	text_server::spawn(args.home(), &args.addresses()[..], args.server_config())
 }
 
-fn print_help() {
-	println!("usage: server <home> <address>")
+// Runs the self test with the server configured as asked, returning the
+// exit status
+#[cfg(feature = "client")]
+fn self_test(args: &Args) -> i32 {
+	let config = text_server::SelfTestConfig {
+		clients: args.self_test_clients,
+		operations: args.self_test_operations,
+		seed: args.seed,
+		server: args.server_config(),
+	};
+	match text_server::self_test(config) {
+		Ok(report) => {
+			println!(
+				"Self test passed: {} clients made {} edits ({} rejected) in {:.1}s, ending with {} bytes",
+				report.clients,
+				report.edits,
+				report.rejected,
+				report.elapsed.as_secs_f64(),
+				report.final_len
+			);
+			println!(
+				"Latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+				report.p50, report.p90, report.p99, report.max
+			);
+			println!("Seed: {}", report.seed);
+			0
+		}
+		Err(e) => {
+			println!("{}", e);
+			1
+		}
+	}
 }
 
-struct Config {
-	home: PathBuf,
-	address: SocketAddr,
+#[cfg(not(feature = "client"))]
+fn self_test(_: &Args) -> i32 {
+	println!("Built without the client feature, which the self test needs");
+	1
 }
 
-impl Config {
-	fn new(args: Vec<String>) -> Result<Config, &'static str>{
-		const NUM_ARGS: usize = 2;
-		if args.len() == NUM_ARGS + 1 {
-			let home = PathBuf::from(&args[1]);
-			if !home.exists() {
-				return Err("Path does not exist")
-			}
-			else if !home.is_dir() {
-				return Err("Path is not a directory")
-			}
+// Claims the pidfile and goes into the background, as asked. The pidfile
+// is claimed first so a running server is reported to the terminal
+#[cfg(all(unix, feature = "daemon"))]
+fn detach(args: &Args) -> EditrResult<Option<Pidfile>> {
+	let pidfile = match &args.pidfile {
+		Some(path) => Some(Pidfile::claim(path)?),
+		None => None,
+	};
+	if args.daemon {
+		daemon::daemonize(&args.log)?;
+		if let Some(pidfile) = &pidfile {
+			pidfile.update()?;
+		}
+	}
+	Ok(pidfile)
+}
+
+fn parse_home(home: &str) -> Result<PathBuf, &'static str> {
+	let home = PathBuf::from(home);
+	if !home.exists() {
+		return Err("Path does not exist")
+	}
+	else if !home.is_dir() {
+		return Err("Path is not a directory")
+	}
+	Ok(home)
+}
 
-			let address = args[2].parse::<SocketAddr>()
-							.map_err(|_|
-								"Address is invalid"
-							)?;
+// Reads settings from a config file, see ServerConfig
+fn parse_config(path: &str) -> Result<ServerConfig, String> {
+	ServerConfig::load(Path::new(path)).map_err(|e| e.to_string())
+}
+
+// Reads a duration which must be more than nothing
+fn parse_interval(interval: &str) -> Result<Duration, &'static str> {
+	match parse_duration(interval)? {
+		interval if interval.is_zero() => Err("Duration must be more than zero"),
+		interval => Ok(interval),
+	}
+}
 
-			Ok(Config {home, address})
+// Reads the users who may log in, each line a name and its token. Blank
+// lines and those starting with # are skipped
+fn parse_users(path: &str) -> Result<HashMap<String, String>, String> {
+	let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+	let mut users = HashMap::new();
+	for (index, line) in contents.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
 		}
-		else {
-			Err("Wrong number of arguments given")
+		match line.split_whitespace().collect::<Vec<_>>()[..] {
+			[name, token] => {
+				users.insert(name.to_string(), token.to_string());
+			}
+			_ => return Err(format!("Line {} is not a name and a token", index + 1)),
 		}
 	}
+	Ok(users)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::time::Duration;
+
+	use clap::error::ErrorKind;
+	use clap::Parser;
+	use editr::log::Level;
+
+	use super::Args;
+
+	fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+		let home = std::env::temp_dir();
+		let home = home.to_str().unwrap();
+		Args::try_parse_from(["server"].iter().chain(args).map(|arg| arg.replace("HOME", home)))
+	}
+
+	fn error(args: &[&str]) -> ErrorKind {
+		parse(args).expect_err("Arguments were accepted").kind()
+	}
+
+	#[test]
+	fn home_and_address_are_positional() {
+		let args = parse(&["HOME", "127.0.0.1:3000"]).unwrap();
+		assert_eq!(args.home(), std::env::temp_dir());
+		assert_eq!(args.addresses(), ["127.0.0.1:3000".parse().unwrap()]);
+
+		let args = parse(&["HOME", "127.0.0.1:0", "--listen", "[::1]:0"]).unwrap();
+		assert_eq!(args.addresses().len(), 2);
+		let args = parse(&["HOME", "--listen", "127.0.0.1:0"]).unwrap();
+		assert_eq!(args.addresses().len(), 1);
+	}
+
+	#[test]
+	fn values_are_checked() {
+		assert_eq!(error(&["HOME"]), ErrorKind::MissingRequiredArgument);
+		assert_eq!(error(&["/no/such/home", "127.0.0.1:0"]), ErrorKind::ValueValidation);
+		assert_eq!(error(&["HOME", "localhost"]), ErrorKind::ValueValidation);
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--idle-timeout", "soon"]), ErrorKind::ValueValidation);
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--workers", "0"]), ErrorKind::ValueValidation);
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--allow", "10.0.0.0/33"]), ErrorKind::ValueValidation);
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--autosave", "0s"]), ErrorKind::ValueValidation);
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--log-level", "loud"]), ErrorKind::ValueValidation);
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--config", "/no/such/config.json"]), ErrorKind::ValueValidation);
+	}
+
+	#[test]
+	fn conflicting_options_are_refused() {
+		#[cfg(unix)]
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--stdio"]), ErrorKind::ArgumentConflict);
+		#[cfg(unix)]
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--socket-activation"]), ErrorKind::ArgumentConflict);
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--self-test"]), ErrorKind::ArgumentConflict);
+		#[cfg(unix)]
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--unix", "editr.sock"]), ErrorKind::ArgumentConflict);
+		#[cfg(unix)]
+		assert_eq!(error(&["HOME", "--listen", "127.0.0.1:0", "--unix", "editr.sock"]), ErrorKind::ArgumentConflict);
+		#[cfg(unix)]
+		assert_eq!(error(&["HOME", "--stdio", "--unix", "editr.sock"]), ErrorKind::ArgumentConflict);
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--shared-dir", "team"]), ErrorKind::MissingRequiredArgument);
+		assert_eq!(error(&["HOME", "127.0.0.1:0", "--journal-sync", "2s"]), ErrorKind::MissingRequiredArgument);
+		assert!(parse(&["--self-test"]).is_ok());
+	}
+
+	#[test]
+	fn options_reach_the_server_config() {
+		let config = parse(&[
+			"HOME", "127.0.0.1:0",
+			"--read-only",
+			"--idle-timeout", "90s",
+			"--max-connections", "4",
+			"--workers", "2",
+			"--deny", "10.0.0.0/8",
+			"--fsync",
+		]).unwrap().server_config();
+		assert!(config.read_only);
+		assert_eq!(config.idle_timeout, Some(Duration::from_secs(90)));
+		assert_eq!(config.max_connections, Some(4));
+		assert_eq!(config.workers, Some(2));
+		assert_eq!(config.deny, ["10.0.0.0/8".parse().unwrap()]);
+		assert_eq!(config.durability, editr::config::Durability::Fsync);
+
+		let config = parse(&["HOME", "127.0.0.1:0"]).unwrap().server_config();
+		assert!(!config.read_only);
+		assert_eq!(config.workers, None);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn unix_sockets_stand_in_for_an_address() {
+		let args = parse(&["HOME", "--unix", "editr.sock"]).unwrap();
+		assert_eq!(args.unix.as_deref(), Some(std::path::Path::new("editr.sock")));
+		assert!(args.addresses().is_empty());
+	}
+
+	#[test]
+	fn logging_is_as_verbose_as_asked() {
+		assert_eq!(parse(&["HOME", "127.0.0.1:0"]).unwrap().log_level, Level::Debug);
+		let args = parse(&["HOME", "127.0.0.1:0", "--log-level", "warn"]).unwrap();
+		assert_eq!(args.log_level, Level::Warn);
+	}
+
+	#[test]
+	fn options_override_the_config_file() {
+		let path = std::env::temp_dir().join(format!("editr-server-config-{}.json", std::process::id()));
+		fs::write(&path, r#"{ "idle_timeout": "30s", "quota": 100, "read_only": true, "autosave": "1m" }"#).unwrap();
+		let args = parse(&[
+			"HOME", "127.0.0.1:0",
+			"--config", path.to_str().unwrap(),
+			"--idle-timeout", "90s",
+			"--autosave", "10s",
+		]);
+		fs::remove_file(&path).ok();
+		let config = args.unwrap().server_config();
+		assert_eq!(config.idle_timeout, Some(Duration::from_secs(90)));
+		assert_eq!(config.autosave, Some(Duration::from_secs(10)));
+		assert_eq!(config.quota, Some(100));
+		assert!(config.read_only);
+		assert_eq!(config.durability, editr::config::Durability::Fast);
+	}
 }
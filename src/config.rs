@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{EditrError, EditrResult};
+
+// What a connection is allowed to do
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permissions {
+	// May list, open and read, but never change anything
+	Viewer,
+	Editor,
+}
+
+impl Permissions {
+	pub fn can_write(self) -> bool { self == Permissions::Editor }
+}
+
+// What happens to a message sent faster than the rate limit allows
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RateLimitAction {
+	// Hold the message back until it fits within the limit
+	Delay,
+	// Answer the message with a RateLimited error
+	Reject,
+}
+
+// Most a single connection may send per second
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+	pub messages_per_sec: u32,
+	pub bytes_per_sec: u64,
+	pub action: RateLimitAction,
+}
+
+// How the server checks that quiet clients are still there
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Heartbeat {
+	// Time between ServerPings sent to every connection
+	#[serde(deserialize_with = "duration")]
+	pub interval: Duration,
+	// Connections which have sent nothing, not even a ServerPong, for this
+	// long are disconnected
+	#[serde(deserialize_with = "duration")]
+	pub timeout: Duration,
+}
+
+// How binding a listening address is retried
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BindRetry {
+	// Attempts after the first, each waiting twice as long as the last
+	pub attempts: u32,
+	#[serde(deserialize_with = "duration")]
+	pub delay: Duration,
+}
+
+// How hard a save tries to survive the machine losing power
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Durability {
+	// Hand the new contents to the OS and carry on
+	Fast,
+	// Sync the new contents to disk before putting them in place, then
+	// sync the directory so the rename itself is on disk
+	Fsync,
+}
+
+// Which symlinks within a home clients may reach files through
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SymlinkPolicy {
+	// Paths through any symlink are invalid
+	Refuse,
+	// Symlinks are followed so long as they lead somewhere else under the
+	// same home. Those leading out of it, or nowhere, are refused
+	WithinHome,
+}
+
+// Where operations are logged for auditing
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+	pub path: PathBuf,
+	// Size past which the log is moved aside to <path>.1
+	pub max_size: u64,
+}
+
+// Where edits are recorded until saved, so they survive a crash
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct JournalConfig {
+	pub path: PathBuf,
+	// How often recorded edits are synced to disk
+	#[serde(deserialize_with = "duration")]
+	pub sync_interval: Duration,
+}
+
+// A block of addresses, written as address/prefix, or a lone address
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+	address: IpAddr,
+	prefix: u8,
+}
+
+impl Cidr {
+	pub fn contains(&self, address: IpAddr) -> bool {
+		// Clients on dual stack listeners show up as IPv4 mapped addresses
+		let address = match address {
+			IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+			v4 => v4,
+		};
+		match (self.address, address) {
+			(IpAddr::V4(block), IpAddr::V4(address)) => prefix_matches(
+				u32::from(block).into(),
+				u32::from(address).into(),
+				self.prefix,
+				32,
+			),
+			(IpAddr::V6(block), IpAddr::V6(address)) => {
+				prefix_matches(block.into(), address.into(), self.prefix, 128)
+			}
+			_ => false,
+		}
+	}
+}
+
+// Whether two addresses, bits wide, agree in their first prefix bits
+fn prefix_matches(block: u128, address: u128, prefix: u8, bits: u8) -> bool {
+	let ignored = u32::from(bits - prefix);
+	block.checked_shr(ignored).unwrap_or(0) == address.checked_shr(ignored).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+	type Err = EditrError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || EditrError::InvalidAddressRange(s.into());
+		let (address, prefix) = match s.split_once('/') {
+			Some((address, prefix)) => (address, Some(prefix)),
+			None => (s, None),
+		};
+		let address = address.parse::<IpAddr>().map_err(|_| invalid())?;
+		let bits = if address.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+			None => bits,
+		};
+		if prefix > bits {
+			return Err(invalid());
+		}
+		Ok(Cidr { address, prefix })
+	}
+}
+
+impl TryFrom<String> for Cidr {
+	type Error = EditrError;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> { s.parse() }
+}
+
+// Server wide settings shared by every connection. Config files give
+// them as a JSON object, with durations written as parse_duration reads
+// them, leaving out any to be left as they are by default
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+	// Confine each logged in user to home/<user>/
+	pub user_homes: bool,
+	// Name of a directory under home which every user may access
+	pub shared_dir: Option<String>,
+	// Maximum bytes on disk per home (per user home with user_homes)
+	pub quota: Option<u64>,
+	// How long a dropped session may be resumed for, zero disables resumption
+	#[serde(deserialize_with = "duration")]
+	pub session_grace: Duration,
+	// Connections silent for longer than this are disconnected
+	#[serde(deserialize_with = "optional_duration")]
+	pub idle_timeout: Option<Duration>,
+	// Connections which have not sent a whole message this long after
+	// connecting are closed
+	#[serde(deserialize_with = "optional_duration")]
+	pub handshake_timeout: Option<Duration>,
+	pub heartbeat: Option<Heartbeat>,
+	// Permissions of connections which have not logged in as a listed user
+	pub default_permissions: Permissions,
+	pub user_permissions: HashMap<String, Permissions>,
+	// Secret each user gives to log in as them. Users not listed here can't
+	// log in at all
+	pub user_tokens: HashMap<String, String>,
+	// Number of threads serving connections. Connections beyond this are
+	// sent ServerBusy and disconnected. None serves each connection on its
+	// own thread
+	pub workers: Option<usize>,
+	// Most connections served or waiting at once. Clients beyond this are
+	// sent ServerBusy and disconnected
+	pub max_connections: Option<usize>,
+	// Bind addresses with SO_REUSEADDR, so a restarted server needn't wait
+	// for the last one's connections to time out
+	pub reuse_address: bool,
+	pub bind_retry: Option<BindRetry>,
+	// Send small writes straight away rather than batching them up
+	pub nodelay: bool,
+	// Probe quiet connections after this long, so dead peers are noticed
+	#[serde(deserialize_with = "optional_duration")]
+	pub keepalive: Option<Duration>,
+	// Report a summary of the metrics this often, or never if zero
+	#[serde(deserialize_with = "duration")]
+	pub metrics_log: Duration,
+	// Also append each report to this file as a line of JSON
+	pub metrics_file: Option<PathBuf>,
+	// Largest message a client may send, in bytes
+	pub max_message_size: usize,
+	pub rate_limit: Option<RateLimit>,
+	// Secret required by administrative messages, which are refused if unset
+	pub admin_token: Option<String>,
+	pub access_log: Option<AccessLogConfig>,
+	// Refuse every change to files, whatever the connection's permissions
+	pub read_only: bool,
+	pub symlinks: SymlinkPolicy,
+	// Least durability of every save. Clients may ask for more, never less
+	pub durability: Durability,
+	// Clients must come from one of these, unless it is empty
+	pub allow: Vec<Cidr>,
+	// Clients from any of these are turned away, even if also allowed
+	pub deny: Vec<Cidr>,
+	// Tell turned away clients why, rather than just disconnecting them
+	pub refusal_notice: bool,
+	// Let clients ask for their connection to be compressed
+	pub compression: bool,
+	// Open files left unedited this long have their ropes compacted
+	#[serde(deserialize_with = "optional_duration")]
+	pub compact_after: Option<Duration>,
+	// Unsaved edits left in the journal are replayed on startup
+	pub journal: Option<JournalConfig>,
+	// Messages taking longer than this to handle are logged, along with
+	// where the time went
+	#[serde(deserialize_with = "optional_duration")]
+	pub slow_op: Option<Duration>,
+	// Files with unsaved edits are saved this often
+	#[serde(deserialize_with = "optional_duration")]
+	pub autosave: Option<Duration>,
+}
+
+impl ServerConfig {
+	// Reads settings from a config file, as described above
+	pub fn load(path: &Path) -> EditrResult<ServerConfig> {
+		let contents = fs::read(path)?;
+		serde_json::from_slice(&contents)
+			.map_err(|e| EditrError::Config(format!("{}: {}", path.display(), e)))
+	}
+}
+
+impl Default for ServerConfig {
+	fn default() -> Self {
+		ServerConfig {
+			user_homes: false,
+			shared_dir: None,
+			quota: None,
+			session_grace: Duration::from_secs(30),
+			idle_timeout: None,
+			handshake_timeout: Some(Duration::from_secs(10)),
+			heartbeat: None,
+			default_permissions: Permissions::Editor,
+			user_permissions: HashMap::new(),
+			user_tokens: HashMap::new(),
+			workers: None,
+			max_connections: None,
+			reuse_address: true,
+			bind_retry: None,
+			nodelay: true,
+			keepalive: Some(Duration::from_secs(60)),
+			metrics_log: Duration::ZERO,
+			metrics_file: None,
+			max_message_size: 16 << 20,
+			rate_limit: None,
+			admin_token: None,
+			access_log: None,
+			read_only: false,
+			symlinks: SymlinkPolicy::WithinHome,
+			durability: Durability::Fast,
+			allow: Vec::new(),
+			deny: Vec::new(),
+			refusal_notice: false,
+			compression: true,
+			compact_after: Some(Duration::from_secs(30)),
+			journal: None,
+			slow_op: None,
+			autosave: None,
+		}
+	}
+}
+
+// Reads durations such as 500ms, 30s, 5m or 2h. Bare numbers are seconds
+pub fn parse_duration(duration: &str) -> Result<Duration, &'static str> {
+	let split = duration
+		.find(|c: char| !c.is_ascii_digit())
+		.unwrap_or(duration.len());
+	let (count, unit) = duration.split_at(split);
+	let count = count.parse::<u64>().map_err(|_| "Duration is invalid")?;
+	match unit {
+		"ms" => Ok(Duration::from_millis(count)),
+		"" | "s" => Ok(Duration::from_secs(count)),
+		"m" => Ok(Duration::from_secs(count * 60)),
+		"h" => Ok(Duration::from_secs(count * 60 * 60)),
+		_ => Err("Duration unit must be one of ms, s, m or h"),
+	}
+}
+
+// Reads a duration from a config file, as parse_duration does
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+	let duration = String::deserialize(deserializer)?;
+	parse_duration(&duration).map_err(de::Error::custom)
+}
+
+// As duration, with null leaving it unset
+fn optional_duration<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+	match Option::<String>::deserialize(deserializer)? {
+		Some(duration) => parse_duration(&duration)
+			.map(Some)
+			.map_err(de::Error::custom),
+		None => Ok(None),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::net::IpAddr;
+	use std::time::Duration;
+
+	use super::{Cidr, Durability, Permissions, ServerConfig};
+
+	fn ip(address: &str) -> IpAddr { address.parse().unwrap() }
+
+	#[test]
+	fn ranges_parse_with_or_without_a_prefix() {
+		assert!("10.0.0.0/8".parse::<Cidr>().is_ok());
+		assert!("192.168.1.7".parse::<Cidr>().is_ok());
+		assert!("fd00::/8".parse::<Cidr>().is_ok());
+		assert!("::1".parse::<Cidr>().is_ok());
+		assert!("0.0.0.0/0".parse::<Cidr>().is_ok());
+
+		assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+		assert!("fd00::/129".parse::<Cidr>().is_err());
+		assert!("10.0.0/8".parse::<Cidr>().is_err());
+		assert!("10.0.0.0/".parse::<Cidr>().is_err());
+		assert!("localhost".parse::<Cidr>().is_err());
+	}
+
+	#[test]
+	fn ranges_contain_addresses_sharing_their_prefix() {
+		let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+		assert!(lan.contains(ip("192.168.1.0")));
+		assert!(lan.contains(ip("192.168.1.255")));
+		assert!(!lan.contains(ip("192.168.2.1")));
+
+		let host: Cidr = "10.1.2.3".parse().unwrap();
+		assert!(host.contains(ip("10.1.2.3")));
+		assert!(!host.contains(ip("10.1.2.4")));
+
+		let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+		assert!(everything.contains(ip("8.8.8.8")));
+
+		let v6: Cidr = "fd00::/8".parse().unwrap();
+		assert!(v6.contains(ip("fd12:3456::1")));
+		assert!(!v6.contains(ip("fe80::1")));
+		// Families never match each other
+		assert!(!v6.contains(ip("10.0.0.1")));
+		assert!(!everything.contains(ip("fe80::1")));
+	}
+
+	#[test]
+	fn mapped_addresses_match_ipv4_ranges() {
+		let loopback: Cidr = "127.0.0.0/8".parse().unwrap();
+		assert!(loopback.contains(ip("::ffff:127.0.0.1")));
+		assert!(!loopback.contains(ip("::ffff:10.0.0.1")));
+	}
+
+	#[test]
+	fn config_files_set_only_what_they_give() {
+		let path = std::env::temp_dir().join(format!("editr-config-{}.json", std::process::id()));
+		fs::write(
+			&path,
+			r#"{
+				"read_only": true,
+				"idle_timeout": "90s",
+				"session_grace": "2m",
+				"heartbeat": { "interval": "5s", "timeout": "15s" },
+				"deny": ["10.0.0.0/8"],
+				"default_permissions": "Viewer",
+				"durability": "Fsync",
+				"autosave": "500ms",
+				"keepalive": null
+			}"#,
+		)
+		.unwrap();
+		let config = ServerConfig::load(&path);
+		fs::remove_file(&path).ok();
+		let config = config.unwrap();
+
+		assert!(config.read_only);
+		assert_eq!(config.idle_timeout, Some(Duration::from_secs(90)));
+		assert_eq!(config.session_grace, Duration::from_secs(120));
+		assert_eq!(config.heartbeat.unwrap().timeout, Duration::from_secs(15));
+		assert_eq!(config.deny, ["10.0.0.0/8".parse().unwrap()]);
+		assert_eq!(config.default_permissions, Permissions::Viewer);
+		assert_eq!(config.durability, Durability::Fsync);
+		assert_eq!(config.autosave, Some(Duration::from_millis(500)));
+		assert_eq!(config.keepalive, None);
+		// Everything else is as it is by default
+		let defaults = ServerConfig::default();
+		assert_eq!(config.max_message_size, defaults.max_message_size);
+		assert_eq!(config.handshake_timeout, defaults.handshake_timeout);
+		assert!(config.compression);
+	}
+
+	#[test]
+	fn config_files_are_checked() {
+		let parse = |json: &str| serde_json::from_str::<ServerConfig>(json);
+		assert!(parse("{}").is_ok());
+		assert!(parse(r#"{ "idle_timeout": "soon" }"#).is_err());
+		assert!(parse(r#"{ "idle_timeout": 90 }"#).is_err());
+		assert!(parse(r#"{ "allow": ["10.0.0.0/33"] }"#).is_err());
+		assert!(parse(r#"{ "read_ony": true }"#).is_err());
+		assert!(parse(r#"{ "heartbeat": { "interval": "5s" } }"#).is_err());
+
+		let missing = std::env::temp_dir().join("editr-no-such-config.json");
+		assert!(ServerConfig::load(&missing).is_err());
+	}
+}
@@ -0,0 +1,165 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::error::{EditrError, EditrResult};
+
+// A file holding the pid of the running server, so only one server runs
+// against it at a time. Removed again once dropped
+pub struct Pidfile {
+	path: PathBuf,
+}
+
+impl Pidfile {
+	// Takes the pidfile at path for this process. A pidfile naming a process
+	// which is no longer running is left over from a crash, and replaced
+	pub fn claim(path: &Path) -> EditrResult<Pidfile> {
+		loop {
+			match OpenOptions::new().write(true).create_new(true).open(path) {
+				Ok(mut file) => {
+					writeln!(file, "{}", std::process::id())?;
+					return Ok(Pidfile {
+						path: path.to_path_buf(),
+					});
+				}
+				Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+				Err(e) => return Err(e.into()),
+			}
+
+			match read_pid(path) {
+				Some(pid) if running(pid) => {
+					return Err(EditrError::Config(format!(
+						"Already running as pid {}",
+						pid
+					)))
+				}
+				_ => {
+					warn!("Removing stale pidfile {}", path.display());
+					fs::remove_file(path).or_else(|e| match e.kind() {
+						io::ErrorKind::NotFound => Ok(()),
+						_ => Err(e),
+					})?;
+				}
+			}
+		}
+	}
+
+	// Rewrites the pidfile after the process has changed, as it does when
+	// daemonizing
+	pub fn update(&self) -> EditrResult<()> {
+		fs::write(&self.path, format!("{}\n", std::process::id()))?;
+		Ok(())
+	}
+}
+
+impl Drop for Pidfile {
+	fn drop(&mut self) {
+		// Leave the file alone if another process has since taken it over
+		if read_pid(&self.path) == Some(std::process::id() as libc::pid_t) {
+			fs::remove_file(&self.path).ok();
+		}
+	}
+}
+
+fn read_pid(path: &Path) -> Option<libc::pid_t> {
+	fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn running(pid: libc::pid_t) -> bool {
+	if pid <= 0 {
+		return false;
+	}
+	// Signal 0 only checks the process exists. Being refused still means it
+	// does, just as someone else
+	let signalled = unsafe { libc::kill(pid, 0) };
+	signalled == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// Detaches the process from its terminal, carrying on in the background with
+// output appended to log. Must be called before any threads are started, as
+// only the calling thread survives.
+// The working directory is kept, so relative paths stay valid
+pub fn daemonize(log: &Path) -> EditrResult<()> {
+	// Opened first, so a bad path is reported to the terminal
+	let log = OpenOptions::new().create(true).append(true).open(log)?;
+	let null = File::open("/dev/null")?;
+	io::stdout().flush()?;
+
+	unsafe {
+		// The first child starts a new session away from the terminal. The
+		// second is not its leader, so can never gain a terminal again
+		fork()?;
+		if libc::setsid() == -1 {
+			return Err(io::Error::last_os_error().into());
+		}
+		fork()?;
+
+		for (from, to) in [
+			(null.as_raw_fd(), libc::STDIN_FILENO),
+			(log.as_raw_fd(), libc::STDOUT_FILENO),
+			(log.as_raw_fd(), libc::STDERR_FILENO),
+		] {
+			if libc::dup2(from, to) == -1 {
+				return Err(io::Error::last_os_error().into());
+			}
+		}
+	}
+	Ok(())
+}
+
+// Forks, carrying on in the child while the parent exits
+unsafe fn fork() -> io::Result<()> {
+	match libc::fork() {
+		-1 => Err(io::Error::last_os_error()),
+		0 => Ok(()),
+		_ => libc::_exit(0),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::path::PathBuf;
+	use std::process::Command;
+
+	use super::Pidfile;
+
+	fn pidfile_path(name: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("editr-{}-{}.pid", name, std::process::id()));
+		fs::remove_file(&path).ok();
+		path
+	}
+
+	#[test]
+	fn claiming_writes_the_pid_and_dropping_removes_it() {
+		let path = pidfile_path("claim");
+		let pidfile = Pidfile::claim(&path).unwrap();
+		let written = fs::read_to_string(&path).unwrap();
+		assert_eq!(written.trim(), std::process::id().to_string());
+
+		// Held by a running process, so not to be taken
+		assert!(Pidfile::claim(&path).is_err());
+		drop(pidfile);
+		assert!(!path.exists());
+	}
+
+	#[test]
+	fn stale_pidfiles_are_replaced() {
+		let path = pidfile_path("stale");
+		// A process which has since exited and been reaped
+		let mut exited = Command::new("true").spawn().unwrap();
+		exited.wait().unwrap();
+		fs::write(&path, format!("{}\n", exited.id())).unwrap();
+
+		let pidfile = Pidfile::claim(&path).unwrap();
+		let written = fs::read_to_string(&path).unwrap();
+		assert_eq!(written.trim(), std::process::id().to_string());
+		drop(pidfile);
+
+		// As is one which doesn't hold a pid at all
+		fs::write(&path, "garbage").unwrap();
+		drop(Pidfile::claim(&path).unwrap());
+		assert!(!path.exists());
+	}
+}
@@ -1,3 +1,331 @@
 use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-pub type EditrResult<T> = Result<T, Box<dyn Error>>;
+#[cfg(feature = "server")]
+use serde::{Deserialize, Serialize};
+
+pub type EditrResult<T> = Result<T, EditrError>;
+
+#[derive(Debug)]
+pub enum EditrError {
+	Io(io::Error),
+	AlreadyExists(PathBuf),
+	PermissionDenied(Option<PathBuf>),
+	InvalidPath(PathBuf),
+	QuotaExceeded {
+		used: u64,
+		limit: u64,
+	},
+	// Lists some of the directory's entries, so clients can offer to browse
+	IsADirectory {
+		path: PathBuf,
+		entries: Vec<String>,
+	},
+	NotARegularFile(PathBuf),
+	// No file is open to act on
+	NotOpen,
+	// The file is open, so may not be deleted or replaced
+	Busy(PathBuf),
+	// The file on disk is not the one edits were made to, so they can't be
+	// replayed onto it
+	ChangedOnDisk(PathBuf),
+	// An offset into the rope past its len
+	OffsetOutOfBounds {
+		offset: usize,
+		len: usize,
+	},
+	// A range of the rope, from and to, which doesn't lie within its len
+	RangeOutOfBounds {
+		from: usize,
+		to: usize,
+		len: usize,
+	},
+	// A regular expression which could not be compiled, and why
+	InvalidPattern(String),
+	// An offset into the rope in the middle of a UTF-8 character
+	NotCharBoundary(usize),
+	// A line of the rope past the lines it has
+	LineOutOfBounds {
+		line: usize,
+		lines: usize,
+	},
+	// A single incoming message ran past the limit in bytes
+	MessageTooLarge(usize),
+	// An incoming message could not be understood, but the stream is intact
+	InvalidMessage(String),
+	// A field of an incoming message ran past its limit in bytes, though the
+	// message as a whole fitted
+	FieldTooLong {
+		field: &'static str,
+		len: usize,
+		limit: usize,
+	},
+	// The connection is sending faster than allowed, and may retry after this long
+	RateLimited(Duration),
+	// Not an address, or address/prefix
+	InvalidAddressRange(String),
+	// The other end closed the connection
+	Disconnected,
+	// Scratch buffers have nowhere to be saved to but a new file
+	ScratchBuffer(PathBuf),
+	// A message which makes no sense in the connection's current state
+	Protocol(String),
+	// The server was set up with settings which cannot work
+	Config(String),
+	// The server turned a request down, saying why
+	Rejected(String),
+	// Something which should not be possible, such as missing bookkeeping
+	Internal(String),
+	// What was being done, and to which path, when source occurred
+	Context {
+		op: String,
+		path: Option<PathBuf>,
+		source: Box<EditrError>,
+	},
+}
+
+impl EditrError {
+	// Notes that self occurred while doing op, to path if given
+	pub fn context(self, op: &str, path: Option<&Path>) -> EditrError {
+		EditrError::Context {
+			op: op.to_string(),
+			path: path.map(Path::to_path_buf),
+			source: Box::new(self),
+		}
+	}
+
+	// The error underneath any context
+	pub fn root(&self) -> &EditrError {
+		match self {
+			EditrError::Context { source, .. } => source.root(),
+			e => e,
+		}
+	}
+}
+
+// Adds context to the error of a result, as EditrError::context
+pub trait Context<T> {
+	fn context(self, op: &str, path: Option<&Path>) -> EditrResult<T>;
+}
+
+impl<T, E: Into<EditrError>> Context<T> for Result<T, E> {
+	fn context(self, op: &str, path: Option<&Path>) -> EditrResult<T> {
+		self.map_err(|e| e.into().context(op, path))
+	}
+}
+
+// Kinds of error as sent to clients, so they can act on them without
+// parsing messages
+#[cfg_attr(feature = "server", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorCode {
+	NotFound,
+	AlreadyExists,
+	PermissionDenied,
+	StorageFull,
+	InvalidPath,
+	QuotaExceeded,
+	IsADirectory,
+	NotARegularFile,
+	NotOpen,
+	Busy,
+	OutOfBounds,
+	NotCharBoundary,
+	MessageTooLarge,
+	InvalidMessage,
+	RateLimited,
+	ScratchBuffer,
+	Protocol,
+	Io,
+	Internal,
+}
+
+impl fmt::Display for EditrError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			EditrError::Io(e) => e.fmt(f),
+			EditrError::AlreadyExists(path) => write!(f, "Already exists: {}", path.display()),
+			EditrError::PermissionDenied(Some(path)) => {
+				write!(f, "Permission denied: {}", path.display())
+			}
+			EditrError::PermissionDenied(None) => write!(f, "Permission denied"),
+			EditrError::InvalidPath(path) => write!(f, "Invalid path: {}", path.display()),
+			EditrError::IsADirectory { path, entries } => write!(
+				f,
+				"Is a directory: {} (entries: {})",
+				path.display(),
+				entries.join(", ")
+			),
+			EditrError::NotARegularFile(path) => {
+				write!(f, "Not a regular file: {}", path.display())
+			}
+			EditrError::MessageTooLarge(limit) => {
+				write!(f, "Message larger than {} bytes", limit)
+			}
+			EditrError::InvalidMessage(reason) => write!(f, "Invalid message: {}", reason),
+			EditrError::InvalidPattern(reason) => write!(f, "Invalid pattern: {}", reason),
+			EditrError::FieldTooLong { field, len, limit } => write!(
+				f,
+				"Invalid message: {} is {} bytes, over the limit of {}",
+				field, len, limit
+			),
+			EditrError::RateLimited(wait) => {
+				write!(f, "Rate limited, retry in {} ms", wait.as_millis())
+			}
+			EditrError::InvalidAddressRange(range) => {
+				write!(f, "Invalid address range: {}", range)
+			}
+			EditrError::QuotaExceeded { used, limit } => {
+				write!(f, "Quota exceeded: {} of {} bytes", used, limit)
+			}
+			EditrError::NotOpen => write!(f, "File not open"),
+			EditrError::Busy(_) => write!(f, "File is busy"),
+			EditrError::ChangedOnDisk(path) => {
+				write!(f, "File changed on disk: {}", path.display())
+			}
+			EditrError::OffsetOutOfBounds { offset, len } => write!(
+				f,
+				"Offset {} out of bounds for rope of length {}",
+				offset, len
+			),
+			EditrError::RangeOutOfBounds { from, to, .. } if from > to => {
+				write!(f, "Range {}..{} ends before it starts", from, to)
+			}
+			EditrError::RangeOutOfBounds { from, to, len } => write!(
+				f,
+				"Range {}..{} out of bounds for rope of length {}",
+				from, to, len
+			),
+			EditrError::LineOutOfBounds { line, lines } => {
+				write!(f, "Line {} out of bounds for rope of {} lines", line, lines)
+			}
+			EditrError::NotCharBoundary(offset) => {
+				write!(f, "Offset {} is not on a character boundary", offset)
+			}
+			EditrError::Disconnected => write!(f, "Could not get message"),
+			EditrError::ScratchBuffer(path) => {
+				write!(
+					f,
+					"Scratch buffer must be saved with SaveAs: {}",
+					path.display()
+				)
+			}
+			EditrError::Protocol(reason)
+			| EditrError::Config(reason)
+			| EditrError::Rejected(reason)
+			| EditrError::Internal(reason) => {
+				write!(f, "{}", reason)
+			}
+			EditrError::Context {
+				op,
+				path: Some(path),
+				source,
+			} => write!(f, "Failed to {} {}: {}", op, path.display(), source),
+			EditrError::Context {
+				op,
+				path: None,
+				source,
+			} => write!(f, "Failed to {}: {}", op, source),
+		}
+	}
+}
+
+impl Error for EditrError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			EditrError::Io(e) => Some(e),
+			EditrError::Context { source, .. } => Some(source.as_ref()),
+			_ => None,
+		}
+	}
+}
+
+impl From<&EditrError> for ErrorCode {
+	fn from(e: &EditrError) -> Self {
+		match e {
+			EditrError::Io(e) => match e.kind() {
+				io::ErrorKind::NotFound => ErrorCode::NotFound,
+				io::ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+				io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+					ErrorCode::PermissionDenied
+				}
+				io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => ErrorCode::StorageFull,
+				_ => ErrorCode::Io,
+			},
+			EditrError::AlreadyExists(_) => ErrorCode::AlreadyExists,
+			EditrError::PermissionDenied(_) => ErrorCode::PermissionDenied,
+			EditrError::InvalidPath(_) => ErrorCode::InvalidPath,
+			EditrError::QuotaExceeded { .. } => ErrorCode::QuotaExceeded,
+			EditrError::IsADirectory { .. } => ErrorCode::IsADirectory,
+			EditrError::NotARegularFile(_) => ErrorCode::NotARegularFile,
+			EditrError::NotOpen => ErrorCode::NotOpen,
+			EditrError::Busy(_) => ErrorCode::Busy,
+			EditrError::OffsetOutOfBounds { .. }
+			| EditrError::RangeOutOfBounds { .. }
+			| EditrError::LineOutOfBounds { .. } => ErrorCode::OutOfBounds,
+			EditrError::NotCharBoundary(_) => ErrorCode::NotCharBoundary,
+			EditrError::MessageTooLarge(_) => ErrorCode::MessageTooLarge,
+			EditrError::InvalidMessage(_)
+			| EditrError::FieldTooLong { .. }
+			| EditrError::InvalidPattern(_) => ErrorCode::InvalidMessage,
+			EditrError::RateLimited(_) => ErrorCode::RateLimited,
+			EditrError::ScratchBuffer(_) => ErrorCode::ScratchBuffer,
+			EditrError::Protocol(_) | EditrError::Disconnected | EditrError::Rejected(_) => {
+				ErrorCode::Protocol
+			}
+			EditrError::InvalidAddressRange(_)
+			| EditrError::ChangedOnDisk(_)
+			| EditrError::Config(_)
+			| EditrError::Internal(_) => ErrorCode::Internal,
+			EditrError::Context { source, .. } => ErrorCode::from(source.as_ref()),
+		}
+	}
+}
+
+impl From<io::Error> for EditrError {
+	fn from(e: io::Error) -> Self { EditrError::Io(e) }
+}
+
+// Messages which fail to serialise or parse were not understood, unless
+// the stream itself failed
+#[cfg(feature = "server")]
+impl From<serde_json::Error> for EditrError {
+	fn from(e: serde_json::Error) -> Self {
+		if e.is_io() {
+			EditrError::Io(e.into())
+		}
+		else {
+			EditrError::Protocol(e.to_string())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io;
+	use std::path::Path;
+
+	use super::{Context, EditrError, ErrorCode};
+
+	#[test]
+	fn context_is_shown_but_keeps_the_source() {
+		let result: Result<(), io::Error> = Err(io::ErrorKind::NotFound.into());
+		let e = result
+			.context("read", Some(Path::new("a/b.txt")))
+			.unwrap_err()
+			.context("open", None);
+
+		assert_eq!(
+			e.to_string(),
+			format!(
+				"Failed to open: Failed to read a/b.txt: {}",
+				io::Error::from(io::ErrorKind::NotFound)
+			)
+		);
+		assert!(matches!(e.root(), EditrError::Io(e) if e.kind() == io::ErrorKind::NotFound));
+		assert_eq!(ErrorCode::from(&e), ErrorCode::NotFound);
+	}
+}
@@ -1,5 +1,22 @@
+// Declared ahead of the rest so its macros can be used in all of them
+#[cfg(feature = "server")]
+#[macro_use]
+pub mod log;
+#[cfg(feature = "async-net")]
+pub mod async_server;
+#[cfg(feature = "server")]
+pub mod config;
+#[cfg(all(unix, feature = "daemon"))]
+pub mod daemon;
 pub mod error;
+#[cfg(feature = "server")]
 pub mod message;
 pub mod rope;
+#[cfg(feature = "client")]
+mod self_test;
+#[cfg(feature = "server")]
 pub mod state;
+#[cfg(feature = "client")]
+pub mod text_client;
+#[cfg(feature = "server")]
 pub mod text_server;
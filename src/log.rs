@@ -0,0 +1,83 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// How much the server logs, each level logging what those before it do too
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+	// Failures the server carries on from
+	Error,
+	// Things going slowly, or clients being turned away
+	Warn,
+	// Connections coming and going, and the server starting and stopping
+	Info,
+	// Every message received and sent
+	Debug,
+}
+
+// Everything is logged unless the server is told otherwise
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+
+// Sets the level for every server in the process
+pub fn set_level(level: Level) { LEVEL.store(level as u8, Ordering::Relaxed); }
+
+pub fn enabled(level: Level) -> bool { level as u8 <= LEVEL.load(Ordering::Relaxed) }
+
+impl FromStr for Level {
+	type Err = &'static str;
+
+	fn from_str(level: &str) -> Result<Self, Self::Err> {
+		match level {
+			"error" => Ok(Level::Error),
+			"warn" => Ok(Level::Warn),
+			"info" => Ok(Level::Info),
+			"debug" => Ok(Level::Debug),
+			_ => Err("Log level must be one of error, warn, info or debug"),
+		}
+	}
+}
+
+// Prints to the log if the server logs at level
+macro_rules! log_at {
+	($level:expr, $($arg:tt)*) => {
+		if $crate::log::enabled($level) {
+			println!($($arg)*);
+		}
+	};
+}
+
+macro_rules! error {
+	($($arg:tt)*) => { log_at!($crate::log::Level::Error, $($arg)*) };
+}
+
+// Warnings stand out from the rest of the log
+macro_rules! warn {
+	($($arg:tt)*) => { log_at!($crate::log::Level::Warn, "WARN {}", format_args!($($arg)*)) };
+}
+
+macro_rules! info {
+	($($arg:tt)*) => { log_at!($crate::log::Level::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+	($($arg:tt)*) => { log_at!($crate::log::Level::Debug, $($arg)*) };
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Level;
+
+	#[test]
+	fn levels_parse_in_order() {
+		let levels = ["error", "warn", "info", "debug"]
+			.iter()
+			.map(|level| level.parse::<Level>().unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(
+			levels,
+			[Level::Error, Level::Warn, Level::Info, Level::Debug]
+		);
+		assert!(levels.windows(2).all(|pair| pair[0] < pair[1]));
+		assert!("verbose".parse::<Level>().is_err());
+		assert!("WARN".parse::<Level>().is_err());
+	}
+}
@@ -1,4 +1,3 @@
-use std::error::Error;
 use std::io::Read;
 use std::path::PathBuf;
 
@@ -6,11 +5,90 @@ use serde::{Deserialize, Serialize};
 
 use serde_json;
 
+use crate::config::{Durability, Permissions};
+use crate::error::{EditrError, EditrResult, ErrorCode};
 use crate::state::*;
 
+// Longest path a request may name, in bytes
+pub const MAX_PATH_LEN: usize = 4096;
+// Longest name, token or reason a request may carry, in bytes
+pub const MAX_NAME_LEN: usize = 256;
+// Most data a single request may carry, in bytes
+pub const MAX_DATA_LEN: usize = 4 << 20;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InvalidData {
+	pub reason: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HelloData {
+	pub session: String,
+	pub permissions: Permissions,
+	pub read_only: bool,
+	pub workers: Option<usize>,
+	pub max_message_size: usize,
+	pub compression: bool,
+}
+
+// Leaves the session token out, as anyone holding it can resume the session
+impl std::fmt::Debug for HelloData {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("HelloData")
+			.field("permissions", &self.permissions)
+			.field("read_only", &self.read_only)
+			.field("workers", &self.workers)
+			.field("max_message_size", &self.max_message_size)
+			.field("compression", &self.compression)
+			.finish_non_exhaustive()
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HelloResult {
+	Ok(HelloData),
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ResumeResult {
+	Ok(Option<PathBuf>),
+	Err(String),
+}
+
+// Logs in as user, proven by the token configured for them
+#[derive(Serialize, Deserialize)]
+pub struct LoginReqData {
+	pub user: String,
+	pub token: String,
+}
+
+// Leaves the token out, as messages are written to the log
+impl std::fmt::Debug for LoginReqData {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("LoginReqData")
+			.field("user", &self.user)
+			.finish_non_exhaustive()
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum LoginResult {
+	Ok(Permissions),
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreateReqData {
+	pub path: String,
+	pub parents: bool,
+	pub contents: Option<Vec<u8>>,
+	pub open: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CreateResult {
-	Ok,
+	Ok(Option<PathBuf>),
 	Err(String),
 }
 
@@ -20,10 +98,22 @@ pub enum DeleteResult {
 	Err(String),
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MkdirReqData {
+	pub path: String,
+	pub recursive: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MkdirResult {
+	Ok,
+	Err(String),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RenameReqData {
-	from: String,
-	to: String,
+	pub from: String,
+	pub to: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -34,8 +124,8 @@ pub enum RenameResult {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OpenReqData {
-	file: String,
-	name: Option<String>,
+	pub file: String,
+	pub name: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,6 +134,23 @@ pub enum OpenResult {
 	Err(String),
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenScratchReqData {
+	pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecentFile {
+	pub path: String,
+	pub exists: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RecentFilesResult {
+	Ok(Vec<RecentFile>),
+	Err(String),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CloseResult {
 	Ok,
@@ -52,8 +159,8 @@ pub enum CloseResult {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WriteReqData {
-	offset: usize,
-	data: Vec<u8>,
+	pub offset: usize,
+	pub data: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -64,38 +171,71 @@ pub enum WriteResult {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateAdd {
-	offset: usize,
-	data: Vec<u8>,
+	pub offset: usize,
+	pub data: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateRemove {
-	offset: usize,
-	len: usize,
+	pub offset: usize,
+	pub len: usize,
+}
+
+// len bytes from offset were replaced with data
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateReplace {
+	pub offset: usize,
+	pub len: usize,
+	pub data: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum UpdateData {
 	Add(UpdateAdd),
 	Remove(UpdateRemove),
+	Replace(UpdateReplace),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerData {
+	pub client: ClientId,
+	pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SaveFailedData {
+	pub path: PathBuf,
+	pub error_code: ErrorCode,
+	pub detail: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ReadReqData {
-	offset: usize,
-	len: usize,
+	pub offset: usize,
+	pub len: usize,
+}
+
+// What was read, which is cut short where the file ends
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadData {
+	// Where the bytes start, which is the end of the file if the read
+	// started past it
+	pub offset: usize,
+	pub data: Vec<u8>,
+	// Length of the file when it was read
+	pub file_len: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ReadResult {
-	Ok(Vec<u8>),
+	Ok(ReadData),
 	Err(String),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RemoveReqData {
-	offset: usize,
-	len: usize,
+	pub offset: usize,
+	pub len: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -104,12 +244,157 @@ pub enum RemoveResult {
 	Err(String),
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplaceReqData {
+	pub offset: usize,
+	pub len: usize,
+	pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ReplaceResult {
+	Ok,
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SaveReqData {
+	// Durability wanted beyond the server's own, if any
+	pub durability: Option<Durability>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SaveData {
+	// Durability the save was made with
+	pub durability: Durability,
+	// Time spent syncing to disk, in microseconds
+	pub sync_us: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SaveResult {
+	Ok(SaveData),
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UsageData {
+	pub used: u64,
+	pub limit: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum UsageResult {
+	Ok(UsageData),
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AdminListResult {
+	Ok(Vec<ConnectionInfo>),
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminKickReqData {
+	pub token: String,
+	pub client_id: ClientId,
+	pub reason: String,
+}
+
+// Leaves the admin token out, as messages are written to the log
+impl std::fmt::Debug for AdminKickReqData {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("AdminKickReqData")
+			.field("client_id", &self.client_id)
+			.field("reason", &self.reason)
+			.finish_non_exhaustive()
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AdminKickResult {
 	Ok,
 	Err(String),
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DisconnectingData {
+	pub reason: DisconnectReason,
+	pub detail: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminTraceReqData {
+	pub token: String,
+	pub client_id: ClientId,
+}
+
+// As for kicks, the token is left out
+impl std::fmt::Debug for AdminTraceReqData {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("AdminTraceReqData")
+			.field("client_id", &self.client_id)
+			.finish_non_exhaustive()
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AdminTraceResult {
+	Ok(Vec<TraceEntry>),
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CompressResult {
+	Ok,
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StatusResult {
+	Ok(ServerSnapshot),
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MetricsResult {
+	Ok(MetricsSnapshot),
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SaveAsReqData {
+	pub path: String,
+	pub overwrite: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SaveAsResult {
+	Ok(PathBuf),
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GrepReqData {
+	pub pattern: Vec<u8>,
+	pub max_matches: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GrepData {
+	pub matches: Vec<FileMatch>,
+	pub truncated: bool,
+	// Files and directories which couldn't be read, so weren't searched
+	pub skipped: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum GrepResult {
+	Ok(GrepData),
+	Err(String),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum FilesListResult {
 	Ok(Vec<String>),
@@ -124,7 +409,7 @@ pub enum MoveCursorResult {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WriteAtCursorReqData {
-	data: Vec<u8>,
+	pub data: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -135,7 +420,7 @@ pub enum WriteAtCursorResult {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RemoveAtCursorReqData {
-	len: usize,
+	pub len: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -144,60 +429,195 @@ pub enum RemoveAtCursorResult {
 	Err(String),
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct YankReqData {
+	pub offset: usize,
+	pub len: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum YankResult {
+	Ok,
+	Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum PasteAtCursorResult {
+	Ok,
+	Err(String),
+}
+
+// A client's own cursor, then the cursor and name of each client in its file
+pub type Cursors = (usize, Vec<(usize, Option<String>)>);
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum GetCursorsResult {
-	Ok((usize, Vec<(usize, Option<String>)>)),
+	Ok(Cursors),
 	Err(String),
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
 	Invalid,
+	InvalidResp(InvalidData),
 	Echo(Vec<u8>),
-	CreateReq(String),
+	Ping,
+	Pong,
+	HelloReq,
+	HelloResp(HelloResult),
+	ResumeReq(String),
+	ResumeResp(ResumeResult),
+	LoginReq(LoginReqData),
+	LoginResp(LoginResult),
+	CreateReq(CreateReqData),
 	CreateResp(CreateResult),
 	DeleteReq(String),
 	DeleteResp(DeleteResult),
+	MkdirReq(MkdirReqData),
+	MkdirResp(MkdirResult),
 	RenameReq(RenameReqData),
 	RenameResp(RenameResult),
 	OpenReq(OpenReqData),
 	OpenResp(OpenResult),
+	// Opens a new scratch buffer, answered with the path others can open it by
+	OpenScratchReq(OpenScratchReqData),
+	OpenScratchResp(OpenResult),
+	RecentFilesReq,
+	RecentFilesResp(RecentFilesResult),
 	CloseReq,
 	CloseResp(CloseResult),
 	WriteReq(WriteReqData),
 	WriteResp(WriteResult),
 	UpdateMessage(UpdateData),
+	PeerLeft(PeerData),
+	// Sent to every client of a file which could not be saved
+	SaveFailed(SaveFailedData),
+	ServerClosing,
+	// Sent to every connection now and then, to be answered with ServerPong
+	ServerPing,
+	ServerPong,
+	ServerBusy,
+	Refused,
 	ReadReq(ReadReqData),
 	ReadResp(ReadResult),
 	RemoveReq(RemoveReqData),
 	RemoveResp(RemoveResult),
-	SaveReq,
+	// Removes and inserts as one edit, so neighbours never see only half
+	ReplaceReq(ReplaceReqData),
+	ReplaceResp(ReplaceResult),
+	SaveReq(SaveReqData),
 	SaveResp(SaveResult),
+	SaveAsReq(SaveAsReqData),
+	SaveAsResp(SaveAsResult),
 	FilesListReq,
 	FilesListResp(FilesListResult),
+	GrepReq(GrepReqData),
+	GrepResp(GrepResult),
+	UsageReq,
+	UsageResp(UsageResult),
+	MetricsReq,
+	MetricsResp(MetricsResult),
+	StatusReq,
+	StatusResp(StatusResult),
+	// Deflates the connection both ways. The response is the last message
+	// sent uncompressed, and the client compresses everything after its request
+	CompressReq,
+	CompressResp(CompressResult),
+	AdminListReq(String),
+	AdminListResp(AdminListResult),
+	AdminKickReq(AdminKickReqData),
+	AdminKickResp(AdminKickResult),
+	AdminTraceReq(AdminTraceReqData),
+	AdminTraceResp(AdminTraceResult),
+	// The last message sent before the server ends a connection
+	Disconnecting(DisconnectingData),
 	MoveCursor(isize),
 	MoveCursorResp(MoveCursorResult),
 	WriteAtCursorReq(WriteAtCursorReqData),
 	WriteAtCursorResp(WriteAtCursorResult),
 	RemoveAtCursorReq(RemoveAtCursorReqData),
 	RemoveAtCursorResp(RemoveAtCursorResult),
+	YankReq(YankReqData),
+	YankResp(YankResult),
+	PasteAtCursorReq,
+	PasteAtCursorResp(PasteAtCursorResult),
 	GetCursorsReq,
 	GetCursorsResp(GetCursorsResult),
 }
 
 impl Message {
-	pub fn from_reader<R: Read>(reader: R) -> Result<Message, Box<dyn Error>> {
+	pub fn from_reader<R: Read>(reader: R) -> EditrResult<Message> {
 		// let mut buffer = [0u8; 4096 * 10];
 
 		// let counter = reader.read(&mut buffer)?;
 		// let deserialised = serde_json::from_slice(&buffer[..counter])?;
 
-		let deserialised = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+		let deserialised = serde_json::from_reader(reader)?;
 
 		println!("{:?}", deserialised);
 
 		Ok(deserialised)
 	}
 
+	// Reads a single message from data, which must hold nothing else
+	pub fn from_slice(data: &[u8]) -> EditrResult<Message> {
+		let msg: Message =
+			serde_json::from_slice(data).map_err(|e| EditrError::InvalidMessage(e.to_string()))?;
+		msg.check_limits()?;
+		Ok(msg)
+	}
+
+	// Refuses requests with a field longer than its limit, which the limit
+	// on the size of the whole message would otherwise let through
+	pub fn check_limits(&self) -> EditrResult<()> {
+		let fields = match self {
+			Message::Echo(data) => vec![("data", data.len(), MAX_DATA_LEN)],
+			Message::ResumeReq(session) => vec![("session", session.len(), MAX_NAME_LEN)],
+			Message::LoginReq(inner) => vec![
+				("user", inner.user.len(), MAX_NAME_LEN),
+				("token", inner.token.len(), MAX_NAME_LEN),
+			],
+			Message::CreateReq(inner) => vec![
+				("path", inner.path.len(), MAX_PATH_LEN),
+				(
+					"contents",
+					inner.contents.as_ref().map_or(0, Vec::len),
+					MAX_DATA_LEN,
+				),
+			],
+			Message::DeleteReq(path) => vec![("path", path.len(), MAX_PATH_LEN)],
+			Message::MkdirReq(inner) => vec![("path", inner.path.len(), MAX_PATH_LEN)],
+			Message::RenameReq(inner) => vec![
+				("from", inner.from.len(), MAX_PATH_LEN),
+				("to", inner.to.len(), MAX_PATH_LEN),
+			],
+			Message::OpenReq(inner) => vec![
+				("file", inner.file.len(), MAX_PATH_LEN),
+				(
+					"name",
+					inner.name.as_ref().map_or(0, String::len),
+					MAX_NAME_LEN,
+				),
+			],
+			Message::OpenScratchReq(inner) => vec![("name", inner.name.len(), MAX_NAME_LEN)],
+			Message::WriteReq(inner) => vec![("data", inner.data.len(), MAX_DATA_LEN)],
+			Message::ReplaceReq(inner) => vec![("data", inner.data.len(), MAX_DATA_LEN)],
+			Message::SaveAsReq(inner) => vec![("path", inner.path.len(), MAX_PATH_LEN)],
+			Message::GrepReq(inner) => vec![("pattern", inner.pattern.len(), MAX_PATH_LEN)],
+			Message::AdminListReq(token) => vec![("token", token.len(), MAX_NAME_LEN)],
+			Message::AdminKickReq(inner) => vec![
+				("token", inner.token.len(), MAX_NAME_LEN),
+				("reason", inner.reason.len(), MAX_NAME_LEN),
+			],
+			Message::AdminTraceReq(inner) => vec![("token", inner.token.len(), MAX_NAME_LEN)],
+			Message::WriteAtCursorReq(inner) => vec![("data", inner.data.len(), MAX_DATA_LEN)],
+			_ => Vec::new(),
+		};
+		match fields.into_iter().find(|(_, len, limit)| len > limit) {
+			Some((field, len, limit)) => Err(EditrError::FieldTooLong { field, len, limit }),
+			None => Ok(()),
+		}
+	}
+
 	pub fn make_add_broadcast(offset: usize, data: &[u8]) -> Message {
 		Message::UpdateMessage(UpdateData::Add(UpdateAdd {
 			offset,
@@ -209,92 +629,636 @@ impl Message {
 		Message::UpdateMessage(UpdateData::Remove(UpdateRemove { offset, len }))
 	}
 
-	pub fn process(self, thread_local: &mut LocalState) -> (Message, bool) {
+	pub fn make_replace_broadcast(offset: usize, len: usize, data: &[u8]) -> Message {
+		Message::UpdateMessage(UpdateData::Replace(UpdateReplace {
+			offset,
+			len,
+			data: Vec::from(data),
+		}))
+	}
+
+	pub fn make_invalid(reason: String) -> Message { Message::InvalidResp(InvalidData { reason }) }
+
+	pub fn make_disconnecting(reason: DisconnectReason, detail: Option<String>) -> Message {
+		Message::Disconnecting(DisconnectingData { reason, detail })
+	}
+
+	pub fn make_peer_left(client: ClientId, name: Option<String>) -> Message {
+		Message::PeerLeft(PeerData { client, name })
+	}
+
+	pub fn make_save_failed(path: PathBuf, e: &EditrError) -> Message {
+		Message::SaveFailed(SaveFailedData {
+			path,
+			error_code: e.into(),
+			detail: e.to_string(),
+		})
+	}
+
+	// Whether processing the message may change files or their contents
+	fn is_mutating(&self) -> bool {
+		matches!(
+			self,
+			Message::CreateReq(_)
+				| Message::DeleteReq(_)
+				| Message::MkdirReq(_)
+				| Message::RenameReq(_)
+				| Message::WriteReq(_)
+				| Message::RemoveReq(_)
+				| Message::ReplaceReq(_)
+				| Message::SaveReq(_)
+				| Message::SaveAsReq(_)
+				| Message::WriteAtCursorReq(_)
+				| Message::RemoveAtCursorReq(_)
+				| Message::PasteAtCursorReq
+		)
+	}
+
+	// Builds the error response to a request without processing it
+	fn error_response(&self, e: String) -> Message {
+		match self {
+			Message::HelloReq => Message::HelloResp(HelloResult::Err(e)),
+			Message::ResumeReq(_) => Message::ResumeResp(ResumeResult::Err(e)),
+			Message::LoginReq(_) => Message::LoginResp(LoginResult::Err(e)),
+			Message::CreateReq(_) => Message::CreateResp(CreateResult::Err(e)),
+			Message::DeleteReq(_) => Message::DeleteResp(DeleteResult::Err(e)),
+			Message::MkdirReq(_) => Message::MkdirResp(MkdirResult::Err(e)),
+			Message::RenameReq(_) => Message::RenameResp(RenameResult::Err(e)),
+			Message::OpenReq(_) => Message::OpenResp(OpenResult::Err(e)),
+			Message::OpenScratchReq(_) => Message::OpenScratchResp(OpenResult::Err(e)),
+			Message::RecentFilesReq => Message::RecentFilesResp(RecentFilesResult::Err(e)),
+			Message::CloseReq => Message::CloseResp(CloseResult::Err(e)),
+			Message::WriteReq(_) => Message::WriteResp(WriteResult::Err(e)),
+			Message::ReadReq(_) => Message::ReadResp(ReadResult::Err(e)),
+			Message::RemoveReq(_) => Message::RemoveResp(RemoveResult::Err(e)),
+			Message::ReplaceReq(_) => Message::ReplaceResp(ReplaceResult::Err(e)),
+			Message::SaveReq(_) => Message::SaveResp(SaveResult::Err(e)),
+			Message::SaveAsReq(_) => Message::SaveAsResp(SaveAsResult::Err(e)),
+			Message::FilesListReq => Message::FilesListResp(FilesListResult::Err(e)),
+			Message::GrepReq(_) => Message::GrepResp(GrepResult::Err(e)),
+			Message::UsageReq => Message::UsageResp(UsageResult::Err(e)),
+			Message::MetricsReq => Message::MetricsResp(MetricsResult::Err(e)),
+			Message::StatusReq => Message::StatusResp(StatusResult::Err(e)),
+			Message::CompressReq => Message::CompressResp(CompressResult::Err(e)),
+			Message::AdminListReq(_) => Message::AdminListResp(AdminListResult::Err(e)),
+			Message::AdminKickReq(_) => Message::AdminKickResp(AdminKickResult::Err(e)),
+			Message::AdminTraceReq(_) => Message::AdminTraceResp(AdminTraceResult::Err(e)),
+			Message::MoveCursor(_) => Message::MoveCursorResp(MoveCursorResult::Err(e)),
+			Message::WriteAtCursorReq(_) => Message::WriteAtCursorResp(WriteAtCursorResult::Err(e)),
+			Message::RemoveAtCursorReq(_) => {
+				Message::RemoveAtCursorResp(RemoveAtCursorResult::Err(e))
+			}
+			Message::YankReq(_) => Message::YankResp(YankResult::Err(e)),
+			Message::PasteAtCursorReq => Message::PasteAtCursorResp(PasteAtCursorResult::Err(e)),
+			Message::GetCursorsReq => Message::GetCursorsResp(GetCursorsResult::Err(e)),
+			_ => Message::Invalid,
+		}
+	}
+
+	// Names the kind of request, for metrics
+	fn kind(&self) -> &'static str {
+		match self {
+			Message::Echo(_) => "Echo",
+			Message::Ping => "Ping",
+			Message::HelloReq => "HelloReq",
+			Message::ResumeReq(_) => "ResumeReq",
+			Message::LoginReq(_) => "LoginReq",
+			Message::CreateReq(_) => "CreateReq",
+			Message::DeleteReq(_) => "DeleteReq",
+			Message::MkdirReq(_) => "MkdirReq",
+			Message::RenameReq(_) => "RenameReq",
+			Message::OpenReq(_) => "OpenReq",
+			Message::OpenScratchReq(_) => "OpenScratchReq",
+			Message::RecentFilesReq => "RecentFilesReq",
+			Message::CloseReq => "CloseReq",
+			Message::WriteReq(_) => "WriteReq",
+			Message::ReadReq(_) => "ReadReq",
+			Message::RemoveReq(_) => "RemoveReq",
+			Message::ReplaceReq(_) => "ReplaceReq",
+			Message::SaveReq(_) => "SaveReq",
+			Message::SaveAsReq(_) => "SaveAsReq",
+			Message::FilesListReq => "FilesListReq",
+			Message::GrepReq(_) => "GrepReq",
+			Message::UsageReq => "UsageReq",
+			Message::MetricsReq => "MetricsReq",
+			Message::StatusReq => "StatusReq",
+			Message::CompressReq => "CompressReq",
+			Message::AdminListReq(_) => "AdminListReq",
+			Message::AdminKickReq(_) => "AdminKickReq",
+			Message::AdminTraceReq(_) => "AdminTraceReq",
+			Message::MoveCursor(_) => "MoveCursor",
+			Message::WriteAtCursorReq(_) => "WriteAtCursorReq",
+			Message::RemoveAtCursorReq(_) => "RemoveAtCursorReq",
+			Message::YankReq(_) => "YankReq",
+			Message::PasteAtCursorReq => "PasteAtCursorReq",
+			Message::GetCursorsReq => "GetCursorsReq",
+			_ => "Invalid",
+		}
+	}
+
+	// Whether the message is a request editing the open file
+	fn is_edit(&self) -> bool {
+		matches!(
+			self,
+			Message::WriteReq(_)
+				| Message::RemoveReq(_)
+				| Message::ReplaceReq(_)
+				| Message::WriteAtCursorReq(_)
+				| Message::RemoveAtCursorReq(_)
+				| Message::PasteAtCursorReq
+		)
+	}
+
+	// Whether the message is a response reporting failure
+	fn is_error(&self) -> bool {
+		matches!(
+			self,
+			Message::Invalid
+				| Message::InvalidResp(_)
+				| Message::HelloResp(HelloResult::Err(_))
+				| Message::ResumeResp(ResumeResult::Err(_))
+				| Message::LoginResp(LoginResult::Err(_))
+				| Message::CreateResp(CreateResult::Err(_))
+				| Message::DeleteResp(DeleteResult::Err(_))
+				| Message::MkdirResp(MkdirResult::Err(_))
+				| Message::RenameResp(RenameResult::Err(_))
+				| Message::OpenResp(OpenResult::Err(_))
+				| Message::OpenScratchResp(OpenResult::Err(_))
+				| Message::RecentFilesResp(RecentFilesResult::Err(_))
+				| Message::CloseResp(CloseResult::Err(_))
+				| Message::WriteResp(WriteResult::Err(_))
+				| Message::ReadResp(ReadResult::Err(_))
+				| Message::RemoveResp(RemoveResult::Err(_))
+				| Message::ReplaceResp(ReplaceResult::Err(_))
+				| Message::SaveResp(SaveResult::Err(_))
+				| Message::SaveAsResp(SaveAsResult::Err(_))
+				| Message::FilesListResp(FilesListResult::Err(_))
+				| Message::GrepResp(GrepResult::Err(_))
+				| Message::UsageResp(UsageResult::Err(_))
+				| Message::MetricsResp(MetricsResult::Err(_))
+				| Message::StatusResp(StatusResult::Err(_))
+				| Message::CompressResp(CompressResult::Err(_))
+				| Message::AdminListResp(AdminListResult::Err(_))
+				| Message::AdminKickResp(AdminKickResult::Err(_))
+				| Message::AdminTraceResp(AdminTraceResult::Err(_))
+				| Message::MoveCursorResp(MoveCursorResult::Err(_))
+				| Message::WriteAtCursorResp(WriteAtCursorResult::Err(_))
+				| Message::RemoveAtCursorResp(RemoveAtCursorResult::Err(_))
+				| Message::YankResp(YankResult::Err(_))
+				| Message::PasteAtCursorResp(PasteAtCursorResult::Err(_))
+				| Message::GetCursorsResp(GetCursorsResult::Err(_))
+		)
+	}
+
+	pub fn process<T: Transport>(
+		self,
+		thread_local: &mut LocalState<T>,
+	) -> (Message, Option<DisconnectReason>) {
+		let kind = self.kind();
+		thread_local.metrics().trace().record(
+			Direction::Received,
+			kind,
+			thread_local.message_size(),
+		);
+		let edit = self.is_edit();
+		let detail = if thread_local.access_logged() {
+			self.log_detail()
+		}
+		else {
+			String::new()
+		};
+
+		// Viewers, and everyone on a read-only server, are turned away before
+		// anything is touched
+		let (response, exit) = if self.is_mutating() && !thread_local.can_write() {
+			let e = EditrError::PermissionDenied(None).to_string();
+			(self.error_response(e), None)
+		}
+		else if let Err(e) = thread_local.throttle() {
+			(self.error_response(e.to_string()), None)
+		}
+		else {
+			thread_local.start_timer();
+			let dispatched = self.dispatch(thread_local);
+			thread_local.finish_timer(kind);
+			dispatched
+		};
+
+		let error = response.is_error();
+		thread_local.metrics().record(kind, edit && !error, error);
+		thread_local.server_metrics().record(kind, error);
+		thread_local.log_access(kind, &detail, error);
+		(response, exit)
+	}
+
+	// Describes the targets of a request for the access log, such as paths,
+	// offsets and lengths, but never contents
+	fn log_detail(&self) -> String {
+		match self {
+			Message::CreateReq(inner) => format!("path={} ", inner.path),
+			Message::DeleteReq(path) => format!("path={} ", path),
+			Message::MkdirReq(inner) => format!("path={} ", inner.path),
+			Message::RenameReq(inner) => format!("from={} to={} ", inner.from, inner.to),
+			Message::OpenReq(inner) => format!("path={} ", inner.file),
+			Message::OpenScratchReq(inner) => format!("name={} ", inner.name),
+			Message::SaveAsReq(inner) => format!("path={} ", inner.path),
+			Message::WriteReq(inner) => {
+				format!("offset={} len={} ", inner.offset, inner.data.len())
+			}
+			Message::ReadReq(inner) => format!("offset={} len={} ", inner.offset, inner.len),
+			Message::RemoveReq(inner) => format!("offset={} len={} ", inner.offset, inner.len),
+			Message::ReplaceReq(inner) => format!(
+				"offset={} len={} data_len={} ",
+				inner.offset,
+				inner.len,
+				inner.data.len()
+			),
+			Message::YankReq(inner) => format!("offset={} len={} ", inner.offset, inner.len),
+			Message::MoveCursor(offset) => format!("offset={} ", offset),
+			Message::WriteAtCursorReq(inner) => format!("len={} ", inner.data.len()),
+			Message::RemoveAtCursorReq(inner) => format!("len={} ", inner.len),
+			Message::AdminKickReq(inner) => format!("client={:?} ", inner.client_id),
+			Message::AdminTraceReq(inner) => format!("client={:?} ", inner.client_id),
+			_ => String::new(),
+		}
+	}
+
+	fn dispatch<T: Transport>(
+		self,
+		thread_local: &mut LocalState<T>,
+	) -> (Message, Option<DisconnectReason>) {
 		match self {
-			Message::Echo(inner) => (Message::Echo(inner), false),
-			Message::CreateReq(inner) => match thread_local.file_create(&inner) {
-				Ok(_) => (Message::CreateResp(CreateResult::Ok), false),
-				Err(e) => (Message::CreateResp(CreateResult::Err(e.to_string())), false),
+			Message::Echo(inner) => (Message::Echo(inner), None),
+			Message::Ping => (Message::Pong, None),
+			Message::HelloReq => match thread_local.hello() {
+				Ok(session) => {
+					let permissions = thread_local.permissions();
+					let read_only = thread_local.config().read_only;
+					let workers = thread_local.config().workers;
+					let max_message_size = thread_local.config().max_message_size;
+					let compression = thread_local.config().compression;
+					(
+						Message::HelloResp(HelloResult::Ok(HelloData {
+							session,
+							permissions,
+							read_only,
+							workers,
+							max_message_size,
+							compression,
+						})),
+						None,
+					)
+				}
+				Err(e) => (Message::HelloResp(HelloResult::Err(e.to_string())), None),
+			},
+			Message::ResumeReq(inner) => match thread_local.resume(&inner) {
+				Ok(opened) => (Message::ResumeResp(ResumeResult::Ok(opened)), None),
+				Err(e) => (Message::ResumeResp(ResumeResult::Err(e.to_string())), None),
+			},
+			Message::LoginReq(inner) => match thread_local.login(&inner.user, &inner.token) {
+				Ok(permissions) => (Message::LoginResp(LoginResult::Ok(permissions)), None),
+				Err(e) => (Message::LoginResp(LoginResult::Err(e.to_string())), None),
+			},
+			Message::CreateReq(inner) => match thread_local.file_create(
+				&inner.path,
+				inner.parents,
+				inner.contents.as_deref(),
+				inner.open,
+			) {
+				Ok(opened) => (Message::CreateResp(CreateResult::Ok(opened)), None),
+				Err(e) => (Message::CreateResp(CreateResult::Err(e.to_string())), None),
 			},
 			Message::DeleteReq(inner) => match thread_local.file_delete(&inner) {
-				Ok(_) => (Message::DeleteResp(DeleteResult::Ok), false),
-				Err(e) => (Message::DeleteResp(DeleteResult::Err(e.to_string())), false),
+				Ok(_) => (Message::DeleteResp(DeleteResult::Ok), None),
+				Err(e) => (Message::DeleteResp(DeleteResult::Err(e.to_string())), None),
+			},
+			Message::MkdirReq(inner) => match thread_local.dir_create(&inner.path, inner.recursive)
+			{
+				Ok(_) => (Message::MkdirResp(MkdirResult::Ok), None),
+				Err(e) => (Message::MkdirResp(MkdirResult::Err(e.to_string())), None),
 			},
 			Message::RenameReq(inner) => match thread_local.file_rename(&inner.from, &inner.to) {
-				Ok(_) => (Message::RenameResp(RenameResult::Ok), false),
-				Err(e) => (Message::RenameResp(RenameResult::Err(e.to_string())), false),
+				Ok(_) => (Message::RenameResp(RenameResult::Ok), None),
+				Err(e) => (Message::RenameResp(RenameResult::Err(e.to_string())), None),
 			},
 			Message::OpenReq(inner) => match thread_local.file_open(&inner.file, inner.name) {
-				Ok(p) => (Message::OpenResp(OpenResult::Ok(p)), false),
-				Err(e) => (Message::OpenResp(OpenResult::Err(e.to_string())), false),
+				Ok(p) => (Message::OpenResp(OpenResult::Ok(p)), None),
+				Err(e) => (Message::OpenResp(OpenResult::Err(e.to_string())), None),
+			},
+			Message::OpenScratchReq(inner) => match thread_local.scratch_open(&inner.name) {
+				Ok(p) => (Message::OpenScratchResp(OpenResult::Ok(p)), None),
+				Err(e) => (
+					Message::OpenScratchResp(OpenResult::Err(e.to_string())),
+					None,
+				),
+			},
+			Message::RecentFilesReq => match thread_local.recent_files() {
+				Ok(files) => {
+					let files = files
+						.into_iter()
+						.map(|(path, exists)| RecentFile { path, exists })
+						.collect();
+					(Message::RecentFilesResp(RecentFilesResult::Ok(files)), None)
+				}
+				Err(e) => (
+					Message::RecentFilesResp(RecentFilesResult::Err(e.to_string())),
+					None,
+				),
 			},
 			Message::CloseReq => match thread_local.file_close() {
-				Ok(_) => (Message::CloseResp(CloseResult::Ok), false),
-				Err(e) => (Message::CloseResp(CloseResult::Err(e.to_string())), false),
+				Ok(_) => (Message::CloseResp(CloseResult::Ok), None),
+				Err(e) => (Message::CloseResp(CloseResult::Err(e.to_string())), None),
 			},
 			Message::WriteReq(inner) => match thread_local.file_write(inner.offset, &inner.data) {
-				Ok(_) => (Message::WriteResp(WriteResult::Ok), false),
-				Err(e) => (Message::WriteResp(WriteResult::Err(e.to_string())), false),
+				Ok(_) => (Message::WriteResp(WriteResult::Ok), None),
+				Err(e) => (Message::WriteResp(WriteResult::Err(e.to_string())), None),
 			},
 			Message::ReadReq(inner) => {
 				let read_from = inner.offset;
-				let read_to = inner.offset + inner.len;
+				let read_to = inner.offset.saturating_add(inner.len);
 				match thread_local.file_read(read_from, read_to) {
-					Ok(data) => (Message::ReadResp(ReadResult::Ok(data)), false),
-					Err(e) => (Message::ReadResp(ReadResult::Err(e.to_string())), false),
+					Ok(read) => (Message::ReadResp(ReadResult::Ok(read)), None),
+					Err(e) => (Message::ReadResp(ReadResult::Err(e.to_string())), None),
 				}
 			}
 			Message::RemoveReq(inner) => match thread_local.file_remove(inner.offset, inner.len) {
-				Ok(_) => (Message::RemoveResp(RemoveResult::Ok), false),
-				Err(e) => (Message::RemoveResp(RemoveResult::Err(e.to_string())), false),
+				Ok(_) => (Message::RemoveResp(RemoveResult::Ok), None),
+				Err(e) => (Message::RemoveResp(RemoveResult::Err(e.to_string())), None),
 			},
-			Message::SaveReq => match thread_local.file_save() {
-				Ok(_) => (Message::SaveResp(SaveResult::Ok), false),
-				Err(e) => (Message::SaveResp(SaveResult::Err(e.to_string())), false),
+			Message::ReplaceReq(inner) => {
+				match thread_local.file_replace(inner.offset, inner.len, &inner.data) {
+					Ok(_) => (Message::ReplaceResp(ReplaceResult::Ok), None),
+					Err(e) => (
+						Message::ReplaceResp(ReplaceResult::Err(e.to_string())),
+						None,
+					),
+				}
+			}
+			Message::SaveReq(inner) => match thread_local.file_save(inner.durability) {
+				Ok((durability, synced)) => {
+					let saved = SaveData {
+						durability,
+						sync_us: synced.as_micros() as u64,
+					};
+					(Message::SaveResp(SaveResult::Ok(saved)), None)
+				}
+				Err(e) => (Message::SaveResp(SaveResult::Err(e.to_string())), None),
 			},
+			Message::SaveAsReq(inner) => {
+				match thread_local.file_save_as(&inner.path, inner.overwrite) {
+					Ok(path) => (Message::SaveAsResp(SaveAsResult::Ok(path)), None),
+					Err(e) => (Message::SaveAsResp(SaveAsResult::Err(e.to_string())), None),
+				}
+			}
 			Message::FilesListReq => match thread_local.files_list() {
-				Ok(list) => (Message::FilesListResp(FilesListResult::Ok(list)), false),
+				Ok(list) => (Message::FilesListResp(FilesListResult::Ok(list)), None),
 				Err(e) => (
 					Message::FilesListResp(FilesListResult::Err(e.to_string())),
-					false,
+					None,
+				),
+			},
+			Message::GrepReq(inner) => {
+				match thread_local.search_files(&inner.pattern, inner.max_matches) {
+					Ok((matches, truncated, skipped)) => (
+						Message::GrepResp(GrepResult::Ok(GrepData {
+							matches,
+							truncated,
+							skipped,
+						})),
+						None,
+					),
+					Err(e) => (Message::GrepResp(GrepResult::Err(e.to_string())), None),
+				}
+			}
+			Message::UsageReq => match thread_local.usage() {
+				Ok((used, limit)) => (
+					Message::UsageResp(UsageResult::Ok(UsageData { used, limit })),
+					None,
+				),
+				Err(e) => (Message::UsageResp(UsageResult::Err(e.to_string())), None),
+			},
+			Message::MetricsReq => (
+				Message::MetricsResp(MetricsResult::Ok(thread_local.metrics().snapshot())),
+				None,
+			),
+			Message::StatusReq => match thread_local.server_status() {
+				Ok(status) => (Message::StatusResp(StatusResult::Ok(status)), None),
+				Err(e) => (Message::StatusResp(StatusResult::Err(e.to_string())), None),
+			},
+			Message::CompressReq => match thread_local.compress() {
+				Ok(()) => (Message::CompressResp(CompressResult::Ok), None),
+				Err(e) => (
+					Message::CompressResp(CompressResult::Err(e.to_string())),
+					None,
+				),
+			},
+			Message::AdminListReq(inner) => match thread_local.admin_list(&inner) {
+				Ok(connections) => (
+					Message::AdminListResp(AdminListResult::Ok(connections)),
+					None,
+				),
+				Err(e) => (
+					Message::AdminListResp(AdminListResult::Err(e.to_string())),
+					None,
 				),
 			},
+			Message::AdminKickReq(inner) => {
+				match thread_local.admin_kick(&inner.token, inner.client_id, inner.reason) {
+					Ok(_) => (Message::AdminKickResp(AdminKickResult::Ok), None),
+					Err(e) => (
+						Message::AdminKickResp(AdminKickResult::Err(e.to_string())),
+						None,
+					),
+				}
+			}
+			Message::AdminTraceReq(inner) => {
+				match thread_local.admin_trace(&inner.token, inner.client_id) {
+					Ok(trace) => (Message::AdminTraceResp(AdminTraceResult::Ok(trace)), None),
+					Err(e) => (
+						Message::AdminTraceResp(AdminTraceResult::Err(e.to_string())),
+						None,
+					),
+				}
+			}
 			Message::MoveCursor(inner) => match thread_local.move_cursor(inner) {
-				Ok(_) => (Message::MoveCursorResp(MoveCursorResult::Ok), false),
+				Ok(_) => (Message::MoveCursorResp(MoveCursorResult::Ok), None),
 				Err(e) => (
 					Message::MoveCursorResp(MoveCursorResult::Err(e.to_string())),
-					false,
+					None,
 				),
 			},
 			Message::WriteAtCursorReq(inner) => match thread_local.file_write_cursor(&inner.data) {
-				Ok(_) => (Message::WriteAtCursorResp(WriteAtCursorResult::Ok), false),
+				Ok(_) => (Message::WriteAtCursorResp(WriteAtCursorResult::Ok), None),
 				Err(e) => (
 					Message::WriteAtCursorResp(WriteAtCursorResult::Err(e.to_string())),
-					false,
+					None,
 				),
 			},
 			Message::RemoveAtCursorReq(inner) => match thread_local.file_remove_cursor(inner.len) {
-				Ok(_) => (Message::RemoveAtCursorResp(RemoveAtCursorResult::Ok), false),
+				Ok(_) => (Message::RemoveAtCursorResp(RemoveAtCursorResult::Ok), None),
 				Err(e) => (
 					Message::RemoveAtCursorResp(RemoveAtCursorResult::Err(e.to_string())),
-					false,
+					None,
 				),
 			},
-			Message::GetCursorsReq => match thread_local.get_cursors() {
-				Ok(cursors) => (
-					Message::GetCursorsResp(GetCursorsResult::Ok(cursors)),
-					false,
+			Message::YankReq(inner) => match thread_local.yank(inner.offset, inner.len) {
+				Ok(_) => (Message::YankResp(YankResult::Ok), None),
+				Err(e) => (Message::YankResp(YankResult::Err(e.to_string())), None),
+			},
+			Message::PasteAtCursorReq => match thread_local.paste_at_cursor() {
+				Ok(_) => (Message::PasteAtCursorResp(PasteAtCursorResult::Ok), None),
+				Err(e) => (
+					Message::PasteAtCursorResp(PasteAtCursorResult::Err(e.to_string())),
+					None,
 				),
+			},
+			Message::GetCursorsReq => match thread_local.get_cursors() {
+				Ok(cursors) => (Message::GetCursorsResp(GetCursorsResult::Ok(cursors)), None),
 				Err(e) => (
 					Message::GetCursorsResp(GetCursorsResult::Err(e.to_string())),
-					false,
+					None,
 				),
 			},
-			_ => (Message::Invalid, true),
+			_ => (Message::Invalid, Some(DisconnectReason::ProtocolViolation)),
+		}
+	}
+
+	// Whether the message is sent unprompted, rather than answering a request
+	pub fn is_broadcast(&self) -> bool {
+		matches!(
+			self,
+			Message::UpdateMessage(_)
+				| Message::PeerLeft(_)
+				| Message::SaveFailed(_)
+				| Message::ServerClosing
+				| Message::ServerPing
+				| Message::ServerBusy
+				| Message::Refused
+				| Message::Disconnecting(_)
+		)
+	}
+
+	// The message as Debug shows it, for the log, with the session and
+	// admin tokens that can't be kept out of Debug left out
+	pub fn redacted(&self) -> String {
+		match self {
+			Message::ResumeReq(_) => "ResumeReq(..)".to_string(),
+			Message::AdminListReq(_) => "AdminListReq(..)".to_string(),
+			_ => format!("{:?}", self),
+		}
+	}
+
+	pub fn to_vec(&self) -> EditrResult<Vec<u8>> { Ok(serde_json::to_vec(self)?) }
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::path::Path;
+
+	use super::*;
+
+	fn open(file: usize, name: usize) -> Message {
+		Message::OpenReq(OpenReqData {
+			file: "f".repeat(file),
+			name: Some("n".repeat(name)),
+		})
+	}
+
+	#[test]
+	fn fields_up_to_their_limits_are_accepted() {
+		open(MAX_PATH_LEN, MAX_NAME_LEN).check_limits().unwrap();
+		Message::WriteReq(WriteReqData {
+			offset: 0,
+			data: vec![0; MAX_DATA_LEN],
+		})
+		.check_limits()
+		.unwrap();
+	}
+
+	#[test]
+	fn fields_over_their_limits_are_named() {
+		let too_long = |msg: Message| match msg.check_limits() {
+			Err(EditrError::FieldTooLong { field, len, limit }) => {
+				assert_eq!(len, limit + 1);
+				field
+			}
+			other => panic!("Unexpected result {:?}", other),
+		};
+		assert_eq!(too_long(open(MAX_PATH_LEN + 1, 0)), "file");
+		assert_eq!(too_long(open(0, MAX_NAME_LEN + 1)), "name");
+		assert_eq!(
+			too_long(Message::WriteAtCursorReq(WriteAtCursorReqData {
+				data: vec![0; MAX_DATA_LEN + 1],
+			})),
+			"data"
+		);
+		assert_eq!(
+			too_long(Message::RenameReq(RenameReqData {
+				from: String::new(),
+				to: "t".repeat(MAX_PATH_LEN + 1),
+			})),
+			"to"
+		);
+	}
+
+	#[test]
+	fn limits_are_checked_as_messages_are_read() {
+		let data = open(MAX_PATH_LEN + 1, 0).to_vec().unwrap();
+		assert!(matches!(
+			Message::from_slice(&data),
+			Err(EditrError::FieldTooLong { field: "file", .. })
+		));
+		assert!(matches!(
+			Message::from_slice(b"{\"Echo\": [1,"),
+			Err(EditrError::InvalidMessage(_))
+		));
+	}
+
+	#[test]
+	fn the_fuzzing_corpus_is_valid() {
+		let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/message");
+		for entry in fs::read_dir(corpus).unwrap() {
+			let path = entry.unwrap().path();
+			let data = fs::read(&path).unwrap();
+			if let Err(e) = Message::from_slice(&data) {
+				panic!("{} is not a message: {}", path.display(), e);
+			}
 		}
 	}
 
-	pub fn to_vec(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-		Ok(serde_json::to_vec(self).map_err(|e| e.to_string())?)
+	#[test]
+	fn secrets_are_left_out_of_the_log() {
+		let login = Message::LoginReq(LoginReqData {
+			user: "alice".to_string(),
+			token: "hunter2".to_string(),
+		});
+		let logged = format!("{:?}", login);
+		assert!(logged.contains("alice"), "{}", logged);
+		assert!(!logged.contains("hunter2"), "{}", logged);
+
+		let hello = Message::HelloResp(HelloResult::Ok(HelloData {
+			session: "5e55102".to_string(),
+			permissions: Permissions::Editor,
+			read_only: false,
+			workers: None,
+			max_message_size: MAX_DATA_LEN,
+			compression: true,
+		}));
+		let resume = Message::ResumeReq("5e55102".to_string());
+		let list = Message::AdminListReq("5e55102".to_string());
+		let kick = Message::AdminKickReq(AdminKickReqData {
+			token: "5e55102".to_string(),
+			client_id: ClientId::next(),
+			reason: "idle".to_string(),
+		});
+		let trace = Message::AdminTraceReq(AdminTraceReqData {
+			token: "5e55102".to_string(),
+			client_id: ClientId::next(),
+		});
+		for message in [hello, resume, list, kick, trace] {
+			let logged = message.redacted();
+			assert!(!logged.contains("5e55102"), "{}", logged);
+		}
 	}
 }
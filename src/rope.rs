@@ -1,11 +1,86 @@
-use std::mem::replace;
-use std::sync::{Arc, RwLock};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::mem::{replace, take};
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+use crate::error::EditrError;
+
+type Result<T> = std::result::Result<T, EditrError>;
+
+// Subtrees deeper than twice the bits in their length plus this are rebuilt,
+// as when every edit lands at the same offset
+const MAX_DEPTH_SLACK: usize = 8;
+// Largest leaf the rope holds. Edits which would grow a leaf past this
+// split it into a subtree instead, so no edit copies more than a leaf
+const MAX_LEAF_SIZE: usize = 4096;
+// Sibling leaves are merged once either is smaller than this, if the two
+// fit in one leaf, so small edits don't leave the rope full of tiny leaves
+const MIN_LEAF_SIZE: usize = 512;
+// Bytes asked for at a time when loading a rope from a reader
+const READ_SIZE: usize = 8192;
 
 #[derive(Debug)]
 pub struct Rope {
-	root: Arc<RwLock<Node>>,
+	root: Node,
+	// Edits made since the journal was last taken, if they are recorded
+	journal: Option<EditJournal>,
+}
+
+// An edit made to a rope, holding what it takes to undo it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EditRecord {
+	// len bytes were inserted at offset
+	Insert { offset: usize, len: usize },
+	// data was removed from offset
+	Remove { offset: usize, data: Vec<u8> },
+}
+
+impl EditRecord {
+	// Undoes the edit, which must be the last made to rope that hasn't been
+	// undone
+	pub fn revert(&self, rope: &mut Rope) -> Result<()> {
+		match self {
+			EditRecord::Insert { offset, len } => rope.remove_range(*offset, offset + len),
+			EditRecord::Remove { offset, data } => rope.insert_at(*offset, data),
+		}
+	}
+}
+
+// The most recent edits made to a rope, forgetting the oldest past limit
+#[derive(Debug)]
+struct EditJournal {
+	records: VecDeque<EditRecord>,
+	limit: usize,
+}
+
+// How search_with looks for a needle
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SearchOptions {
+	// Matches letters whatever their case. Only ASCII letters are folded,
+	// so other letters must match exactly
+	pub case_insensitive: bool,
+	// Finds matches beginning before start, the closest first, rather than
+	// those beginning at or after it. Start at usize::MAX to search back
+	// from the end
+	pub backwards: bool,
+	pub start: usize,
+	// Most matches to find
+	pub limit: Option<usize>,
+}
+
+// The shape of a rope's tree, for seeing how well it is balanced
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RopeStats {
+	pub len: usize,
+	pub leaf_count: usize,
+	pub internal_count: usize,
+	pub max_depth: usize,
+	// Lengths of the shortest and longest leaves
+	pub min_leaf: usize,
+	pub max_leaf: usize,
+	// Roughly what the tree takes up on the heap, counting what each leaf
+	// has allocated rather than what it holds
+	pub heap_bytes: usize,
 }
 
 #[derive(Debug)]
@@ -16,6 +91,15 @@ enum Node {
 
 struct LeafData {
 	data: Vec<u8>,
+	// Newlines in data
+	lines: usize,
+}
+
+impl LeafData {
+	fn new(data: Vec<u8>) -> LeafData {
+		let lines = count_lines(&data);
+		LeafData { data, lines }
+	}
 }
 
 // Make it more friendly to print leaves as debug - turn it to readable characters
@@ -29,9 +113,24 @@ impl std::fmt::Debug for LeafData {
 struct InternalData {
 	index: usize,
 	size: usize,
+	// Newlines under this node
+	lines: usize,
+	// Longest path from here to a leaf, kept up to date so that checking
+	// balance doesn't walk the tree
+	depth: usize,
 	children: Box<(Node, Node)>,
 }
 
+impl InternalData {
+	// Recalculates what is kept about the children after they change
+	fn update(&mut self) {
+		self.index = self.children.0.size();
+		self.size = self.children.0.size() + self.children.1.size();
+		self.lines = self.children.0.lines() + self.children.1.lines();
+		self.depth = 1 + self.children.0.depth().max(self.children.1.depth());
+	}
+}
+
 // This will yield a leaf Vec for each call of next()
 // Maintains an internal stack for a depth first search
 struct LeafIter<'a> {
@@ -55,11 +154,66 @@ impl<'a> Iterator for LeafIter<'a> {
 	}
 }
 
+// Reads a range of a rope, a leaf at a time
+pub struct RopeReader<'a> {
+	root: &'a Node,
+	position: usize,
+	end: usize,
+}
+
+impl Read for RopeReader<'_> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let len = buf.len().min(self.end - self.position);
+		let read = self.root.read_at(self.position, &mut buf[..len]);
+		self.position += read;
+		Ok(read)
+	}
+}
+
+// Yields each line of a rope from some line on, with the offset it starts
+// at, as read_line would give it. Lines are read one at a time, so the rope
+// is never copied out whole
+pub struct RopeLines<'a> {
+	rope: &'a Rope,
+	// Where the next line starts, or None once the last has been yielded
+	position: Option<usize>,
+}
+
+impl Iterator for RopeLines<'_> {
+	type Item = (usize, Vec<u8>);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let start = self.position?;
+		let (end, next) = match self.rope.find_next(b"\n", start) {
+			Some(newline) => (newline, Some(newline + 1)),
+			None => (self.rope.len(), None),
+		};
+		let mut line = self.rope.collect(start, end).ok()?;
+		if next.is_some() && line.last() == Some(&b'\r') {
+			line.pop();
+		}
+		self.position = next;
+		Some((start, line))
+	}
+}
+
 impl Default for Rope {
 	fn default() -> Self { Self::new() }
 }
 
 impl Node {
+	fn internal(left: Node, right: Node) -> Node {
+		let mut inner = InternalData {
+			index: 0,
+			size: 0,
+			lines: 0,
+			depth: 0,
+			children: Box::new((left, right)),
+		};
+		inner.update();
+		Node::Internal(inner)
+	}
+
 	fn size(&self) -> usize {
 		match self {
 			Node::Leaf(inner) => inner.data.len(),
@@ -67,52 +221,118 @@ impl Node {
 		}
 	}
 
-	fn insert_at(&mut self, index: usize, input: &[u8]) {
+	// The byte at offset, or None past the end
+	fn byte_at(&self, offset: usize) -> Option<u8> {
+		match self {
+			Node::Leaf(inner) => inner.data.get(offset).copied(),
+			Node::Internal(inner) => {
+				if offset < inner.index {
+					inner.children.0.byte_at(offset)
+				}
+				else {
+					inner.children.1.byte_at(offset - inner.index)
+				}
+			}
+		}
+	}
+
+	// Copies from offset into buf up to the end of the leaf holding offset,
+	// returning how much was copied
+	fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
 		match self {
 			Node::Leaf(inner) => {
-				// Move Vec out of the node
-				let mut left_node_data = replace(&mut inner.data, Vec::new());
+				let data = inner.data.get(offset..).unwrap_or_default();
+				let len = data.len().min(buf.len());
+				buf[..len].copy_from_slice(&data[..len]);
+				len
+			}
+			Node::Internal(inner) => {
+				if offset < inner.index {
+					inner.children.0.read_at(offset, buf)
+				}
+				else {
+					inner.children.1.read_at(offset - inner.index, buf)
+				}
+			}
+		}
+	}
 
-				// Add bounds checking to avoid panicking
-				let index = if index > left_node_data.len() {
-					left_node_data.len()
+	fn lines(&self) -> usize {
+		match self {
+			Node::Leaf(inner) => inner.lines,
+			Node::Internal(inner) => inner.lines,
+		}
+	}
+
+	// Offset just after the line'th newline, which must exist
+	fn line_start(&self, line: usize) -> usize {
+		match self {
+			Node::Leaf(inner) => inner
+				.data
+				.iter()
+				.enumerate()
+				.filter(|(_, byte)| **byte == b'\n')
+				.nth(line - 1)
+				.map_or(inner.data.len(), |(offset, _)| offset + 1),
+			Node::Internal(inner) => {
+				let left_lines = inner.children.0.lines();
+				if line <= left_lines {
+					inner.children.0.line_start(line)
 				}
 				else {
-					index
-				};
+					inner.index + inner.children.1.line_start(line - left_lines)
+				}
+			}
+		}
+	}
+
+	// Newlines before offset, which must lie within the node
+	fn lines_before(&self, offset: usize) -> usize {
+		match self {
+			Node::Leaf(inner) => count_lines(&inner.data[..offset]),
+			Node::Internal(inner) => {
+				if offset <= inner.index {
+					inner.children.0.lines_before(offset)
+				}
+				else {
+					inner.children.0.lines() + inner.children.1.lines_before(offset - inner.index)
+				}
+			}
+		}
+	}
+
+	fn insert_at(&mut self, index: usize, input: &[u8]) {
+		match self {
+			Node::Leaf(inner) if inner.data.len() + input.len() > MAX_LEAF_SIZE => {
+				let (left, right) = inner.data.split_at(index);
+				let split = Node::from_pieces(&[left, input, right]);
+				*self = split;
+			}
+			Node::Leaf(inner) => {
+				// Move Vec out of the node
+				let mut left_node_data = take(&mut inner.data);
 
 				// Split into 2 - clone is performed here
 				let right_node_data = left_node_data.split_off(index);
 
 				// Clone our slice to the end of the left node data
-				left_node_data.extend_from_slice(&input);
+				left_node_data.extend_from_slice(input);
 
 				// Create the new node structures and move our new Vecs inside
-				let left_node = Node::Leaf(LeafData {
-					data: left_node_data,
-				});
+				let left_node = Node::Leaf(LeafData::new(left_node_data));
 
-				let right_node = Node::Leaf(LeafData {
-					data: right_node_data,
-				});
+				let right_node = Node::Leaf(LeafData::new(right_node_data));
 
 				// If a node is empty, use only the other one
 				if left_node.size() == 0 {
-					replace(self, right_node);
+					*self = right_node;
 				}
 				else if right_node.size() == 0 {
-					replace(self, left_node);
+					*self = left_node;
 				}
 				// If both nodes have data use an Internal parent node
 				else {
-					replace(
-						self,
-						Node::Internal(InternalData {
-							index: left_node.size(),
-							size: left_node.size() + right_node.size(),
-							children: Box::new((left_node, right_node)),
-						}),
-					);
+					*self = Node::internal(left_node, right_node);
 				}
 			}
 			// Recurse deeper
@@ -124,17 +344,39 @@ impl Node {
 					inner.children.1.insert_at(index - inner.index, input);
 				}
 				// Update node sizes
-				inner.index = inner.children.0.size();
-				inner.size = inner.children.0.size() + inner.children.1.size();
+				inner.update();
+			}
+		}
+		self.merge_leaves();
+		self.rebalance();
+	}
+
+	// Adds input after everything else, growing the rightmost leaf rather
+	// than splitting one
+	fn append(&mut self, input: &[u8]) {
+		match self {
+			Node::Leaf(inner) if inner.data.len() + input.len() > MAX_LEAF_SIZE => {
+				let split = Node::from_pieces(&[&inner.data, input]);
+				*self = split;
+			}
+			Node::Leaf(inner) => {
+				inner.data.extend_from_slice(input);
+				inner.lines += count_lines(input);
+			}
+			Node::Internal(inner) => {
+				inner.children.1.append(input);
+				inner.update();
 			}
 		}
+		self.merge_leaves();
+		self.rebalance();
 	}
 
 	fn remove_range(&mut self, from: usize, to: usize) {
 		match self {
 			Node::Leaf(inner) => {
 				// Move Vec out of the node
-				let mut left_node_data = replace(&mut inner.data, Vec::new());
+				let mut left_node_data = take(&mut inner.data);
 
 				// Add bounds checking to avoid panicking
 				let to = if to > left_node_data.len() {
@@ -151,230 +393,2353 @@ impl Node {
 				left_node_data.truncate(from);
 
 				// Create new node structures and move our new Vecs inside
-				let left_node = Node::Leaf(LeafData {
-					data: left_node_data,
-				});
+				let left_node = Node::Leaf(LeafData::new(left_node_data));
 
-				let right_node = Node::Leaf(LeafData {
-					data: right_node_data,
-				});
+				let right_node = Node::Leaf(LeafData::new(right_node_data));
 
 				// If a node is empty, use only the other one
 				if left_node.size() == 0 {
-					replace(self, right_node);
+					*self = right_node;
 				}
 				else if right_node.size() == 0 {
-					replace(self, left_node);
+					*self = left_node;
 				}
 				// If both nodes have data use an Internal parent node
 				else {
-					replace(
-						self,
-						Node::Internal(InternalData {
-							index: left_node.size(),
-							size: left_node.size() + right_node.size(),
-							children: Box::new((left_node, right_node)),
-						}),
-					);
+					*self = Node::internal(left_node, right_node);
 				}
 			}
 			Node::Internal(inner) => {
-				// Calculate parameters for children
-				let l_from = inner.index.min(from);
-				let l_to = inner.index.min(to);
-				let r_from = inner.index.max(from) - inner.index;
-				let r_to = inner.index.max(to) - inner.index;
-
+				let index = inner.index;
 				let left_node = &mut inner.children.0;
 				let right_node = &mut inner.children.1;
 
-				// Recurse deeper
-				left_node.remove_range(l_from, l_to);
-				right_node.remove_range(r_from, r_to);
+				// Recurse only into the children the range overlaps, each
+				// given the part within it relative to its own start. A
+				// range starting at index lies wholly in the right child
+				if from < index && from < to {
+					left_node.remove_range(from, to.min(index));
+				}
+				if to > index {
+					right_node.remove_range(from.saturating_sub(index), to - index);
+				}
 
 				// Check for empty children and replace self with nonempty child
 				if left_node.size() == 0 {
 					match right_node {
 						Node::Leaf(child_inner) => {
-							let saved_data = replace(&mut child_inner.data, Vec::new());
-							replace(self, Node::Leaf(LeafData { data: saved_data }));
+							let saved_data = take(&mut child_inner.data);
+							*self = Node::Leaf(LeafData::new(saved_data));
 						}
 						Node::Internal(child_inner) => {
 							let saved_box = replace(
 								&mut child_inner.children,
 								Box::new((
-									Node::Leaf(LeafData { data: Vec::new() }),
-									Node::Leaf(LeafData { data: Vec::new() }),
+									Node::Leaf(LeafData::new(Vec::new())),
+									Node::Leaf(LeafData::new(Vec::new())),
 								)),
 							);
-							replace(
-								self,
-								Node::Internal(InternalData {
-									index: saved_box.0.size(),
-									size: saved_box.0.size() + saved_box.1.size(),
-									children: saved_box,
-								}),
-							);
+							*self = Node::internal(saved_box.0, saved_box.1);
 						}
 					}
 				}
 				else if right_node.size() == 0 {
 					match left_node {
 						Node::Leaf(child_inner) => {
-							let saved_data = replace(&mut child_inner.data, Vec::new());
-							replace(self, Node::Leaf(LeafData { data: saved_data }));
+							let saved_data = take(&mut child_inner.data);
+							*self = Node::Leaf(LeafData::new(saved_data));
 						}
 						Node::Internal(child_inner) => {
 							let saved_box = replace(
 								&mut child_inner.children,
 								Box::new((
-									Node::Leaf(LeafData { data: Vec::new() }),
-									Node::Leaf(LeafData { data: Vec::new() }),
+									Node::Leaf(LeafData::new(Vec::new())),
+									Node::Leaf(LeafData::new(Vec::new())),
 								)),
 							);
-							replace(
-								self,
-								Node::Internal(InternalData {
-									index: saved_box.0.size(),
-									size: saved_box.0.size() + saved_box.1.size(),
-									children: saved_box,
-								}),
-							);
+							*self = Node::internal(saved_box.0, saved_box.1);
 						}
 					}
 				}
 				// Otherwise update sizes
 				else {
-					inner.index = inner.children.0.size();
-					inner.size = inner.children.0.size() + inner.children.1.size();
+					inner.update();
 				}
 			}
 		}
+		self.merge_leaves();
+		self.rebalance();
 	}
 
-	fn flatten(&mut self) {
+	// Replaces an internal node over two leaves with a single leaf, if
+	// either is small and together they fit
+	fn merge_leaves(&mut self) {
 		if let Node::Internal(inner) = self {
-			// Recurse deeper
-			inner.children.0.flatten();
-			inner.children.1.flatten();
-
-			// Replace self with leaf node containing both child leaf nodes concatenated
-			match (&mut inner.children.0, &mut inner.children.1) {
-				(Node::Leaf(left), Node::Leaf(right)) => {
-					let mut saved_data_left = replace(&mut left.data, Vec::new());
-					let mut saved_data_right = replace(&mut right.data, Vec::new());
-					saved_data_left.append(&mut saved_data_right);
-					replace(
-						self,
-						Node::Leaf(LeafData {
-							data: saved_data_left,
-						}),
-					);
+			if let (Node::Leaf(left), Node::Leaf(right)) = &mut *inner.children {
+				let (left_len, right_len) = (left.data.len(), right.data.len());
+				if left_len.min(right_len) < MIN_LEAF_SIZE && left_len + right_len <= MAX_LEAF_SIZE
+				{
+					let mut data = take(&mut left.data);
+					data.append(&mut right.data);
+					*self = Node::Leaf(LeafData::new(data));
 				}
-				_ => panic!("Flatten Failed"),
 			}
 		}
 	}
 
-	fn iterate_leaves(&self) -> LeafIter { LeafIter { stack: vec![self] } }
-}
+	// Rebuilds the tree balanced over leaves packed as full as they go
+	fn flatten(&mut self) {
+		let mut chunks = Vec::new();
+		replace(self, Node::Leaf(LeafData::new(Vec::new()))).into_chunks(&mut chunks);
+		*self = Node::balanced(&mut chunks);
+	}
 
-impl Rope {
-	pub fn new() -> Rope {
-		Rope {
-			root: Arc::new(RwLock::new(Node::Leaf(LeafData { data: Vec::new() }))),
+	// Whether the tree is already as flatten would leave it
+	fn is_flat(&self) -> bool {
+		let mut leaves = 0usize;
+		let mut last_len = 0;
+		for node in self.iterate_leaves() {
+			if let Node::Leaf(inner) = node {
+				// Only the last leaf may be short, and only empty if alone
+				if leaves > 0 && last_len < MAX_LEAF_SIZE {
+					return false;
+				}
+				last_len = inner.data.len();
+				leaves += 1;
+			}
 		}
+		let balanced_depth = (usize::BITS - (leaves - 1).leading_zeros()) as usize;
+		(leaves == 1 || last_len > 0) && self.depth() == balanced_depth
 	}
 
-	pub fn insert_at(&self, index: usize, input: &[u8]) -> Result<()> {
-		self.root
-			.write()
-			.map_err(|e| e.to_string())?
-			.insert_at(index, input);
-		Ok(())
+	fn depth(&self) -> usize {
+		match self {
+			Node::Leaf(_) => 0,
+			Node::Internal(inner) => inner.depth,
+		}
 	}
 
-	pub fn remove_range(&self, from: usize, size: usize) -> Result<()> {
-		self.root
-			.write()
-			.map_err(|e| e.to_string())?
-			.remove_range(from, size);
-		Ok(())
+	// Rebuilds this subtree if it has grown too deep for its length. Called
+	// on the way back up from an edit, so the deepest subtree to go over is
+	// rebuilt and those above it see it already balanced
+	fn rebalance(&mut self) {
+		let bound =
+			|size: usize| 2 * (usize::BITS - size.leading_zeros()) as usize + MAX_DEPTH_SLACK;
+		if matches!(self, Node::Internal(inner) if inner.depth > bound(inner.size)) {
+			self.flatten();
+		}
 	}
 
-	pub fn len(&self) -> Result<usize> { Ok(self.root.read().map_err(|e| e.to_string())?.size()) }
+	// Splits the tree at offset, which must lie within it, into what comes
+	// before and what comes after. Only the leaf offset falls in is copied
+	fn split(self, offset: usize) -> (Node, Node) {
+		match self {
+			Node::Leaf(mut inner) => {
+				let tail = inner.data.split_off(offset);
+				(
+					Node::Leaf(LeafData::new(inner.data)),
+					Node::Leaf(LeafData::new(tail)),
+				)
+			}
+			Node::Internal(inner) => {
+				let index = inner.index;
+				let (left, right) = *inner.children;
+				if offset < index {
+					let (head, tail) = left.split(offset);
+					(head, Node::join(tail, right))
+				}
+				else {
+					let (head, tail) = right.split(offset - index);
+					(Node::join(left, head), tail)
+				}
+			}
+		}
+	}
 
-	pub fn is_empty(&self) -> Result<bool> { Ok(self.len()? == 0) }
+	// Joins two trees, left before right, without copying their leaves. The
+	// shallower is hung from the edge of the deeper where the depths match,
+	// so joining trees which are each balanced keeps the result balanced
+	fn join(left: Node, right: Node) -> Node {
+		if left.size() == 0 {
+			return right;
+		}
+		if right.size() == 0 {
+			return left;
+		}
+		let mut joined = match (left, right) {
+			(Node::Internal(mut inner), right) if inner.depth > right.depth() + 1 => {
+				let (first, second) = *inner.children;
+				inner.children = Box::new((first, Node::join(second, right)));
+				inner.update();
+				Node::Internal(inner)
+			}
+			(left, Node::Internal(mut inner)) if inner.depth > left.depth() + 1 => {
+				let (first, second) = *inner.children;
+				inner.children = Box::new((Node::join(left, first), second));
+				inner.update();
+				Node::Internal(inner)
+			}
+			(left, right) => Node::internal(left, right),
+		};
+		joined.merge_leaves();
+		joined.rebalance();
+		joined
+	}
 
-	pub fn flatten(&self) -> Result<()> {
-		self.root.write().map_err(|e| e.to_string())?.flatten();
-		Ok(())
+	// Builds a balanced subtree holding each of pieces in turn
+	fn from_pieces(pieces: &[&[u8]]) -> Node {
+		let mut chunks = Vec::new();
+		for piece in pieces {
+			push_chunked(&mut chunks, piece);
+		}
+		Node::balanced(&mut chunks)
 	}
 
-	pub fn collect(&self, from: usize, to: usize) -> Result<Vec<u8>> {
-		let mut collection = Vec::new();
-		let mut counter = 0usize;
+	// Packs the data of every leaf in order into chunks
+	fn into_chunks(self, chunks: &mut Vec<Vec<u8>>) {
+		match self {
+			Node::Leaf(inner) => push_chunked(chunks, &inner.data),
+			Node::Internal(inner) => {
+				let (left, right) = *inner.children;
+				left.into_chunks(chunks);
+				right.into_chunks(chunks);
+			}
+		}
+	}
 
-		for node in self
-			.root
-			.read()
-			.map_err(|e| e.to_string())?
-			.iterate_leaves()
-		{
+	// Whether the tree begins with prefix, stopping at the first leaf which
+	// differs
+	fn starts_with(&self, mut prefix: &[u8]) -> bool {
+		if prefix.len() > self.size() {
+			return false;
+		}
+		for node in self.iterate_leaves() {
+			if prefix.is_empty() {
+				break;
+			}
 			if let Node::Leaf(inner) = node {
-				let len = inner.data.len();
-				let array_start = counter;
-				let array_end = counter + len;
-
-				if to <= array_start || array_end <= from {
-					// Requested bytes not in current array
-					counter += len;
-					continue;
+				let len = inner.data.len().min(prefix.len());
+				if inner.data[..len] != prefix[..len] {
+					return false;
 				}
+				prefix = &prefix[len..];
+			}
+		}
+		true
+	}
 
-				// Requested bytes are in current array
-
-				// Set bounds to slice current array
-				let slice_from = if array_start < from {
-					from - array_start
-				}
-				else {
-					0
-				};
-				let slice_to = if to < array_end {
-					to - array_start
+	// Adds this subtree, which is depth below the root, to stats
+	fn add_stats(&self, depth: usize, stats: &mut RopeStats) {
+		stats.max_depth = stats.max_depth.max(depth);
+		match self {
+			Node::Leaf(inner) => {
+				let len = inner.data.len();
+				stats.min_leaf = if stats.leaf_count == 0 {
+					len
 				}
 				else {
-					len
+					stats.min_leaf.min(len)
 				};
+				stats.max_leaf = stats.max_leaf.max(len);
+				stats.leaf_count += 1;
+				stats.heap_bytes += inner.data.capacity();
+			}
+			Node::Internal(inner) => {
+				stats.internal_count += 1;
+				stats.heap_bytes += std::mem::size_of::<(Node, Node)>();
+				inner.children.0.add_stats(depth + 1, stats);
+				inner.children.1.add_stats(depth + 1, stats);
+			}
+		}
+	}
 
-				// Append slice to collected bytes
-				collection.extend_from_slice(&inner.data[slice_from..slice_to]);
+	// Builds a tree of even depth over chunks, in order
+	fn balanced(chunks: &mut [Vec<u8>]) -> Node {
+		match chunks.len() {
+			0 => Node::Leaf(LeafData::new(Vec::new())),
+			1 => Node::Leaf(LeafData::new(take(&mut chunks[0]))),
+			len => {
+				let (left, right) = chunks.split_at_mut(len / 2);
+				Node::internal(Node::balanced(left), Node::balanced(right))
+			}
+		}
+	}
 
-				counter += len;
+	// Hands f the part of each leaf from 'from' up to 'to' in order.
+	// Subtrees wholly outside the range are passed over by their sizes
+	fn for_each_chunk_in<E, F: FnMut(&[u8]) -> std::result::Result<(), E>>(
+		&self,
+		from: usize,
+		to: usize,
+		f: &mut F,
+	) -> std::result::Result<(), E> {
+		match self {
+			Node::Leaf(inner) => {
+				let to = to.min(inner.data.len());
+				if from < to {
+					f(&inner.data[from..to])?;
+				}
+			}
+			Node::Internal(inner) => {
+				if from < inner.index && from < to {
+					inner
+						.children
+						.0
+						.for_each_chunk_in(from, to.min(inner.index), f)?;
+				}
+				if to > inner.index {
+					let from = from.saturating_sub(inner.index);
+					inner
+						.children
+						.1
+						.for_each_chunk_in(from, to - inner.index, f)?;
+				}
 			}
 		}
-		Ok(collection)
+		Ok(())
 	}
 
-	pub fn search(&self, needle: u8) -> Result<Vec<usize>> {
-		let mut matches = Vec::new();
-		let mut counter = 0usize;
-		for node in self
-			.root
-			.read()
-			.map_err(|e| e.to_string())?
-			.iterate_leaves()
-		{
-			if let Node::Leaf(inner) = node {
-				for byte in inner.data.iter() {
-					if *byte == needle {
-						matches.push(counter);
-					}
-					counter += 1;
+	// Hands f the part of each leaf from 'from' up to 'to' as
+	// for_each_chunk_in does, but from the last leaf to the first
+	fn for_each_chunk_rev_in<E, F: FnMut(&[u8]) -> std::result::Result<(), E>>(
+		&self,
+		from: usize,
+		to: usize,
+		f: &mut F,
+	) -> std::result::Result<(), E> {
+		match self {
+			Node::Leaf(inner) => {
+				let to = to.min(inner.data.len());
+				if from < to {
+					f(&inner.data[from..to])?;
+				}
+			}
+			Node::Internal(inner) => {
+				if to > inner.index && from < to {
+					let from = from.saturating_sub(inner.index);
+					inner
+						.children
+						.1
+						.for_each_chunk_rev_in(from, to - inner.index, f)?;
 				}
+				if from < inner.index && from < to {
+					inner
+						.children
+						.0
+						.for_each_chunk_rev_in(from, to.min(inner.index), f)?;
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn iterate_leaves(&self) -> LeafIter<'_> { LeafIter { stack: vec![self] } }
+}
+
+// Inserts input into the tree at root, as Rope::insert_at
+fn insert_into(root: &mut Node, index: usize, input: &[u8]) -> Result<()> {
+	let len = root.size();
+	if index > len {
+		return Err(EditrError::OffsetOutOfBounds { offset: index, len });
+	}
+	if index == len {
+		root.append(input);
+	}
+	else {
+		root.insert_at(index, input);
+	}
+	Ok(())
+}
+
+fn check_offset(root: &Node, offset: usize) -> Result<()> {
+	let len = root.size();
+	if offset > len {
+		return Err(EditrError::OffsetOutOfBounds { offset, len });
+	}
+	Ok(())
+}
+
+// Whether offset, which lies within root, is the start or end or isn't on
+// a UTF-8 continuation byte
+fn is_char_boundary(root: &Node, offset: usize) -> bool {
+	offset == 0 || !matches!(root.byte_at(offset), Some(byte) if byte & 0xc0 == 0x80)
+}
+
+fn count_lines(data: &[u8]) -> usize { data.iter().filter(|byte| **byte == b'\n').count() }
+
+// Adds data after what is already in chunks, filling the last chunk up to
+// MAX_LEAF_SIZE before starting another
+fn push_chunked(chunks: &mut Vec<Vec<u8>>, mut data: &[u8]) {
+	while !data.is_empty() {
+		match chunks.last_mut() {
+			Some(last) if last.len() < MAX_LEAF_SIZE => {
+				let len = (MAX_LEAF_SIZE - last.len()).min(data.len());
+				last.extend_from_slice(&data[..len]);
+				data = &data[len..];
+			}
+			_ => chunks.push(Vec::with_capacity(MAX_LEAF_SIZE.min(data.len()))),
+		}
+	}
+}
+
+impl Rope {
+	pub fn new() -> Rope { Rope::from_root(Node::Leaf(LeafData::new(Vec::new()))) }
+
+	fn from_root(root: Node) -> Rope {
+		Rope {
+			root,
+			journal: None,
+		}
+	}
+
+	// Starts recording edits, keeping the last limit of them until they are
+	// taken. Anything already recorded is kept
+	pub fn enable_journal(&mut self, limit: usize) {
+		match &mut self.journal {
+			Some(journal) => {
+				journal.limit = limit;
+				journal
+					.records
+					.drain(..journal.records.len().saturating_sub(limit));
+			}
+			None => {
+				self.journal = Some(EditJournal {
+					records: VecDeque::new(),
+					limit,
+				})
+			}
+		}
+	}
+
+	// Stops recording edits, forgetting those recorded
+	pub fn disable_journal(&mut self) { self.journal = None; }
+
+	// Hands over the edits recorded so far, oldest first, leaving the journal
+	// empty. Reverting them newest first undoes them
+	pub fn take_journal(&mut self) -> Vec<EditRecord> {
+		match &mut self.journal {
+			Some(journal) => journal.records.drain(..).collect(),
+			None => Vec::new(),
+		}
+	}
+
+	// Records an edit if edits are being recorded. Edits which change
+	// nothing aren't recorded
+	fn record(&mut self, record: EditRecord) {
+		let changes = match &record {
+			EditRecord::Insert { len, .. } => *len > 0,
+			EditRecord::Remove { data, .. } => !data.is_empty(),
+		};
+		if let Some(journal) = self.journal.as_mut().filter(|_| changes) {
+			if journal.records.len() >= journal.limit {
+				journal.records.pop_front();
+			}
+			if journal.limit > 0 {
+				journal.records.push_back(record);
+			}
+		}
+	}
+
+	// Records the removal of from 'from' up to 'to' if edits are being
+	// recorded, which must be done before the bytes are removed
+	fn record_remove(&mut self, from: usize, to: usize) -> Result<()> {
+		if self.journal.is_some() {
+			let data = self.collect(from, to)?;
+			self.record(EditRecord::Remove { offset: from, data });
+		}
+		Ok(())
+	}
+
+	// Loads everything r holds, packed into full leaves under a balanced
+	// tree, without first gathering it in one buffer
+	pub fn from_reader<R: Read>(mut r: R) -> Result<Rope> {
+		let mut chunks = Vec::new();
+		let mut buffer = [0; READ_SIZE];
+		loop {
+			match r.read(&mut buffer) {
+				Ok(0) => break,
+				Ok(len) => push_chunked(&mut chunks, &buffer[..len]),
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => return Err(e.into()),
 			}
 		}
-		Ok(matches)
+		Ok(Rope::from_root(Node::balanced(&mut chunks)))
+	}
+
+	// Inserts input at index, which may be the length of the rope to add
+	// to the end but no further
+	pub fn insert_at(&mut self, index: usize, input: &[u8]) -> Result<()> {
+		insert_into(&mut self.root, index, input)?;
+		self.record(EditRecord::Insert {
+			offset: index,
+			len: input.len(),
+		});
+		Ok(())
+	}
+
+	// Inserts input as insert_at does, refusing to split a UTF-8 character
+	pub fn insert_str_at(&mut self, index: usize, input: &str) -> Result<()> {
+		if index <= self.root.size() && !is_char_boundary(&self.root, index) {
+			return Err(EditrError::NotCharBoundary(index));
+		}
+		self.insert_at(index, input.as_bytes())
+	}
+
+	// Whether offset falls between UTF-8 characters, as it does at either
+	// end. Stray continuation bytes are taken as part of what comes before
+	pub fn is_char_boundary(&self, offset: usize) -> Result<bool> {
+		check_offset(&self.root, offset)?;
+		Ok(is_char_boundary(&self.root, offset))
+	}
+
+	// The closest character boundary at or before offset
+	pub fn prev_char_boundary(&self, offset: usize) -> Result<usize> {
+		check_offset(&self.root, offset)?;
+		let mut boundary = offset;
+		while !is_char_boundary(&self.root, boundary) {
+			boundary -= 1;
+		}
+		Ok(boundary)
+	}
+
+	// The closest character boundary at or after offset
+	pub fn next_char_boundary(&self, offset: usize) -> Result<usize> {
+		check_offset(&self.root, offset)?;
+		let mut boundary = offset;
+		while !is_char_boundary(&self.root, boundary) {
+			boundary += 1;
+		}
+		Ok(boundary)
+	}
+
+	// Removes from 'from' up to 'to', which must lie within the rope
+	pub fn remove_range(&mut self, from: usize, to: usize) -> Result<()> {
+		let len = self.root.size();
+		if from > to || to > len {
+			return Err(EditrError::RangeOutOfBounds { from, to, len });
+		}
+		self.record_remove(from, to)?;
+		self.root.remove_range(from, to);
+		Ok(())
+	}
+
+	// Leaves everything before at in the rope, returning what came after it
+	// as a rope of its own. What is split off is copied into the journal if
+	// edits are being recorded
+	pub fn split_off(&mut self, at: usize) -> Result<Rope> {
+		check_offset(&self.root, at)?;
+		self.record_remove(at, usize::MAX)?;
+		let (head, tail) = replace(&mut self.root, Node::Leaf(LeafData::new(Vec::new()))).split(at);
+		self.root = head;
+		Ok(Rope::from_root(tail))
+	}
+
+	// Adds the contents of other to the end, taking its leaves as they are
+	pub fn append(&mut self, other: Rope) {
+		self.record(EditRecord::Insert {
+			offset: self.len(),
+			len: other.len(),
+		});
+		let head = replace(&mut self.root, Node::Leaf(LeafData::new(Vec::new())));
+		self.root = Node::join(head, other.root);
+	}
+
+	// Replaces from 'from' up to 'to' with data, as one edit. It is
+	// recorded as a removal followed by an insertion
+	pub fn replace_range(&mut self, from: usize, to: usize, data: &[u8]) -> Result<()> {
+		let len = self.root.size();
+		if from > to || to > len {
+			return Err(EditrError::RangeOutOfBounds { from, to, len });
+		}
+		self.record_remove(from, to)?;
+		self.record(EditRecord::Insert {
+			offset: from,
+			len: data.len(),
+		});
+		let root = &mut self.root;
+		if from < to {
+			root.remove_range(from, to);
+		}
+		if from == root.size() {
+			root.append(data);
+		}
+		else if !data.is_empty() {
+			root.insert_at(from, data);
+		}
+		Ok(())
+	}
+
+	pub fn len(&self) -> usize { self.root.size() }
+
+	// Lines in the rope, counting what follows the last newline as a line
+	// even if empty, so never fewer than one
+	pub fn line_count(&self) -> usize { self.root.lines() + 1 }
+
+	// The line'th line, counting from 0, without its line ending of \n or
+	// \r\n. The last line is whatever follows the last newline
+	pub fn read_line(&self, line: usize) -> Result<Vec<u8>> {
+		let root = &self.root;
+		let lines = root.lines() + 1;
+		if line >= lines {
+			return Err(EditrError::LineOutOfBounds { line, lines });
+		}
+		let start = if line == 0 { 0 } else { root.line_start(line) };
+		let end = if line + 1 < lines {
+			root.line_start(line + 1) - 1
+		}
+		else {
+			root.size()
+		};
+		let mut data = Vec::with_capacity(end - start);
+		root.for_each_chunk_in::<EditrError, _>(start, end, &mut |chunk| {
+			data.extend_from_slice(chunk);
+			Ok(())
+		})?;
+		if line + 1 < lines && data.last() == Some(&b'\r') {
+			data.pop();
+		}
+		Ok(data)
+	}
+
+	// Every line in turn with the offset it starts at, as read_line gives
+	// them, so there are always line_count of them
+	pub fn lines(&self) -> RopeLines<'_> {
+		RopeLines {
+			rope: self,
+			position: Some(0),
+		}
+	}
+
+	// Lines as lines gives them, from the line'th on
+	pub fn lines_from(&self, line: usize) -> Result<RopeLines<'_>> {
+		Ok(RopeLines {
+			rope: self,
+			position: Some(self.line_to_offset(line)?),
+		})
+	}
+
+	// Offset at which line starts, counting from line 0
+	pub fn line_to_offset(&self, line: usize) -> Result<usize> {
+		let root = &self.root;
+		let lines = root.lines() + 1;
+		match line {
+			0 => Ok(0),
+			line if line < lines => Ok(root.line_start(line)),
+			line => Err(EditrError::LineOutOfBounds { line, lines }),
+		}
+	}
+
+	// Line and column of offset, both counting from 0. The column is in bytes
+	pub fn offset_to_line(&self, offset: usize) -> Result<(usize, usize)> {
+		let root = &self.root;
+		let len = root.size();
+		if offset > len {
+			return Err(EditrError::OffsetOutOfBounds { offset, len });
+		}
+		let line = root.lines_before(offset);
+		let start = if line == 0 { 0 } else { root.line_start(line) };
+		Ok((line, offset - start))
+	}
+
+	pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+	pub fn flatten(&mut self) { self.root.flatten(); }
+
+	// Whether flattening would leave the rope as it is
+	pub fn is_flat(&self) -> bool { self.root.is_flat() }
+
+	// Longest path from the root to a leaf, 0 for a rope of one leaf. Ropes
+	// longer than a leaf holds are never one leaf
+	pub fn depth(&self) -> usize { self.root.depth() }
+
+	// Describes the tree, walking it once
+	pub fn stats(&self) -> RopeStats {
+		let mut stats = RopeStats {
+			len: self.root.size(),
+			..RopeStats::default()
+		};
+		self.root.add_stats(0, &mut stats);
+		stats
+	}
+
+	// Copies out from 'from' up to 'to'. Either end past the end of the rope
+	// is taken as the end, so reading up to usize::MAX reads everything
+	// from 'from' on. A range which ends before it starts is refused
+	pub fn collect(&self, from: usize, to: usize) -> Result<Vec<u8>> {
+		if from > to {
+			let len = self.len();
+			return Err(EditrError::RangeOutOfBounds { from, to, len });
+		}
+		let mut collection = Vec::new();
+		self.for_each_chunk_in::<EditrError, _>(from, to, |chunk| {
+			collection.extend_from_slice(chunk);
+			Ok(())
+		})?;
+		Ok(collection)
+	}
+
+	// Reads the whole rope through std::io::Read
+	pub fn reader(&self) -> RopeReader<'_> { self.reader_range(0, usize::MAX) }
+
+	// Reads from 'from' up to 'to' through std::io::Read, giving the bytes
+	// collect would
+	pub fn reader_range(&self, from: usize, to: usize) -> RopeReader<'_> {
+		let root = &self.root;
+		let end = to.min(root.size());
+		RopeReader {
+			root,
+			position: from.min(end),
+			end,
+		}
+	}
+
+	// Hands f each byte from 'from' up to 'to' in order, as collect would
+	// return them but without copying the range out first
+	pub fn bytes_in<F: FnMut(u8)>(&self, from: usize, to: usize, mut f: F) {
+		self.for_each_chunk_in::<(), _>(from, to, |chunk| {
+			chunk.iter().for_each(|byte| f(*byte));
+			Ok(())
+		})
+		.ok();
+	}
+
+	// Hands f the part of each leaf from 'from' up to 'to' in order, as
+	// for_each_chunk does
+	pub fn for_each_chunk_in<E, F: FnMut(&[u8]) -> std::result::Result<(), E>>(
+		&self,
+		from: usize,
+		to: usize,
+		mut f: F,
+	) -> std::result::Result<(), E> {
+		self.root.for_each_chunk_in(from, to, &mut f)
+	}
+
+	pub fn search(&self, needle: u8) -> Vec<usize> {
+		let mut matches = Vec::new();
+		let mut counter = 0usize;
+		self.bytes_in(0, usize::MAX, |byte| {
+			if byte == needle {
+				matches.push(counter);
+			}
+			counter += 1;
+		});
+		matches
+	}
+
+	// Whether the rope holds exactly other, without copying it out
+	pub fn eq_bytes(&self, other: &[u8]) -> bool {
+		self.root.size() == other.len() && self.root.starts_with(other)
+	}
+
+	// Whether the rope begins with prefix, without copying it out
+	pub fn starts_with(&self, prefix: &[u8]) -> bool { self.root.starts_with(prefix) }
+
+	// 64 bit FNV-1a hash of the contents, fed a leaf at a time without
+	// copying them out. Ropes holding the same bytes have the same checksum
+	// however their leaves are split. Not for guarding against tampering
+	pub fn checksum(&self) -> u64 {
+		let mut hash = 0xcbf2_9ce4_8422_2325u64;
+		self.bytes_in(0, usize::MAX, |byte| {
+			hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+		});
+		hash
+	}
+
+	// Writes the whole rope to w a leaf at a time, returning the bytes written
+	pub fn write_to<W: Write>(&self, w: &mut W) -> Result<u64> {
+		let mut written = 0u64;
+		self.for_each_chunk::<EditrError, _>(|chunk| {
+			w.write_all(chunk)?;
+			written += chunk.len() as u64;
+			Ok(())
+		})?;
+		Ok(written)
+	}
+
+	// Finds the offset of every occurrence of needle, overlapping ones
+	// included. An empty needle is found nowhere
+	pub fn search_bytes(&self, needle: &[u8]) -> Vec<usize> {
+		self.search_with(needle, &SearchOptions::default())
+	}
+
+	// Finds occurrences of needle as options ask, overlapping ones included,
+	// in the order they are found. An empty needle is found nowhere
+	pub fn search_with(&self, needle: &[u8], options: &SearchOptions) -> Vec<usize> {
+		let limit = options.limit.unwrap_or(usize::MAX);
+		let mut matches = Vec::new();
+		if needle.is_empty() || limit == 0 {
+			return matches;
+		}
+		let eq = if options.case_insensitive {
+			|a: &u8, b: &u8| a.eq_ignore_ascii_case(b)
+		}
+		else {
+			|a: &u8, b: &u8| a == b
+		};
+		if options.backwards {
+			// Every match before start is found, as the closest are found last
+			self.find_from(needle, 0, eq, |offset| {
+				if offset < options.start {
+					matches.push(offset);
+				}
+				offset < options.start
+			});
+			matches.reverse();
+			matches.truncate(limit);
+		}
+		else {
+			self.find_from(needle, options.start, eq, |offset| {
+				matches.push(offset);
+				matches.len() < limit
+			});
+		}
+		matches
+	}
+
+	// The first occurrence of needle beginning at or after from. Only the
+	// leaves from there on are read, up to the match
+	pub fn find_next(&self, needle: &[u8], from: usize) -> Option<usize> {
+		let options = SearchOptions {
+			start: from,
+			limit: Some(1),
+			..SearchOptions::default()
+		};
+		self.search_with(needle, &options).first().copied()
+	}
+
+	// The last occurrence of needle beginning before 'before'. The leaves are
+	// read backwards from there, down to the match
+	pub fn find_prev(&self, needle: &[u8], before: usize) -> Option<usize> {
+		if needle.is_empty() {
+			return None;
+		}
+		let keep = needle.len() - 1;
+		// A match beginning just before 'before' ends this far on
+		let end = before.saturating_add(keep).min(self.len());
+		// The first bytes of what has been seen, where a match beginning in
+		// an earlier chunk may end
+		let mut carry = Vec::with_capacity(keep);
+		let mut counter = end;
+		let mut found = None;
+		self.root
+			.for_each_chunk_rev_in(0, end, &mut |chunk| {
+				let chunk_start = counter - chunk.len();
+				// Matches starting in this chunk which end in carry. They
+				// start after any which end in this chunk
+				let tail = &chunk[chunk.len().saturating_sub(keep)..];
+				let mut joined = tail.to_vec();
+				joined.extend_from_slice(&carry);
+				if let Some(start) = (0..tail.len())
+					.rev()
+					.find(|&start| joined[start..].starts_with(needle))
+				{
+					found = Some(counter - tail.len() + start);
+					return Err(());
+				}
+
+				// Matches starting in this chunk, which end in it
+				if let Some(start) = chunk
+					.windows(needle.len())
+					.rposition(|window| window == needle)
+				{
+					found = Some(chunk_start + start);
+					return Err(());
+				}
+
+				let mut next = chunk[..chunk.len().min(keep)].to_vec();
+				let more = (keep - next.len()).min(carry.len());
+				next.extend_from_slice(&carry[..more]);
+				carry = next;
+				counter = chunk_start;
+				Ok(())
+			})
+			.ok();
+		found
+	}
+
+	// Hands found the offset of each occurrence of needle, which must not be
+	// empty, beginning at or after from in order, until it returns false.
+	// eq decides whether two bytes match
+	fn find_from<Q: Fn(&u8, &u8) -> bool, F: FnMut(usize) -> bool>(
+		&self,
+		needle: &[u8],
+		from: usize,
+		eq: Q,
+		mut found: F,
+	) {
+		let matches_at = |haystack: &[u8]| {
+			haystack.len() >= needle.len() && haystack.iter().zip(needle).all(|(a, b)| eq(a, b))
+		};
+		let keep = needle.len() - 1;
+		// The last bytes seen, where a match may have started without yet
+		// being seen to finish
+		let mut carry = Vec::with_capacity(keep);
+		let mut counter = from.min(self.len());
+		self.for_each_chunk_in::<(), _>(from, usize::MAX, |chunk| {
+			// Matches starting in carry, which may end in this chunk
+			let mut joined = carry.clone();
+			joined.extend_from_slice(&chunk[..chunk.len().min(keep)]);
+			let carry_start = counter - carry.len();
+			for start in 0..carry.len() {
+				if matches_at(&joined[start..]) && !found(carry_start + start) {
+					return Err(());
+				}
+			}
+
+			// Matches starting in this chunk, which end in it
+			for (start, window) in chunk.windows(needle.len()).enumerate() {
+				if matches_at(window) && !found(counter + start) {
+					return Err(());
+				}
+			}
+
+			counter += chunk.len();
+			if chunk.len() >= keep {
+				carry.clear();
+				carry.extend_from_slice(&chunk[chunk.len() - keep..]);
+			}
+			// Short chunks are wholly in joined, after carry
+			else {
+				carry = joined.split_off(joined.len().saturating_sub(keep));
+			}
+			Ok(())
+		})
+		.ok();
+	}
+
+	// Finds every match of the regular expression pattern, as ranges of byte
+	// offsets from the start. The rope is copied out to be searched, as
+	// matches may run across any number of leaves
+	#[cfg(feature = "regex")]
+	pub fn search_regex(&self, pattern: &str) -> Result<Vec<(usize, usize)>> {
+		let regex = regex::bytes::Regex::new(pattern)
+			.map_err(|e| EditrError::InvalidPattern(e.to_string()))?;
+		let data = self.collect(0, usize::MAX)?;
+		Ok(regex
+			.find_iter(&data)
+			.map(|found| (found.start(), found.end()))
+			.collect())
+	}
+
+	// Hands f the bytes of each leaf in order, without copying them, stopping
+	// at the first error
+	pub fn for_each_chunk<E, F: FnMut(&[u8]) -> std::result::Result<(), E>>(
+		&self,
+		f: F,
+	) -> std::result::Result<(), E> {
+		self.for_each_chunk_in(0, usize::MAX, f)
+	}
+}
+
+// Ropes are written as their leaves in order, each as bytes, and read back
+// into a balanced tree of full leaves. Only the contents go over the wire,
+// not the shape of the tree
+#[cfg(feature = "serde")]
+impl serde::Serialize for Rope {
+	fn serialize<S: serde::Serializer>(
+		&self,
+		serializer: S,
+	) -> std::result::Result<S::Ok, S::Error> {
+		use serde::ser::SerializeSeq;
+
+		let root = &self.root;
+		let leaves = || {
+			root.iterate_leaves().filter_map(|node| match node {
+				Node::Leaf(inner) if !inner.data.is_empty() => Some(Chunk(&inner.data)),
+				_ => None,
+			})
+		};
+		let mut seq = serializer.serialize_seq(Some(leaves().count()))?;
+		for chunk in leaves() {
+			seq.serialize_element(&chunk)?;
+		}
+		seq.end()
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Rope {
+	fn deserialize<D: serde::Deserializer<'de>>(
+		deserializer: D,
+	) -> std::result::Result<Rope, D::Error> {
+		struct RopeVisitor;
+
+		impl<'de> serde::de::Visitor<'de> for RopeVisitor {
+			type Value = Rope;
+
+			fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+				f.write_str("a sequence of byte chunks")
+			}
+
+			fn visit_seq<A: serde::de::SeqAccess<'de>>(
+				self,
+				mut seq: A,
+			) -> std::result::Result<Rope, A::Error> {
+				let mut chunks = Vec::new();
+				while let Some(ChunkBuf(data)) = seq.next_element()? {
+					push_chunked(&mut chunks, &data);
+				}
+				Ok(Rope::from_root(Node::balanced(&mut chunks)))
+			}
+		}
+
+		deserializer.deserialize_seq(RopeVisitor)
+	}
+}
+
+// A leaf written as bytes rather than as a sequence of numbers, for formats
+// which tell the two apart
+#[cfg(feature = "serde")]
+struct Chunk<'a>(&'a [u8]);
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Chunk<'_> {
+	fn serialize<S: serde::Serializer>(
+		&self,
+		serializer: S,
+	) -> std::result::Result<S::Ok, S::Error> {
+		serializer.serialize_bytes(self.0)
+	}
+}
+
+// A chunk read back, whether the format gives it as bytes or as a sequence
+// of numbers, as JSON does
+#[cfg(feature = "serde")]
+struct ChunkBuf(Vec<u8>);
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ChunkBuf {
+	fn deserialize<D: serde::Deserializer<'de>>(
+		deserializer: D,
+	) -> std::result::Result<ChunkBuf, D::Error> {
+		struct ChunkVisitor;
+
+		impl<'de> serde::de::Visitor<'de> for ChunkVisitor {
+			type Value = ChunkBuf;
+
+			fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+				f.write_str("bytes")
+			}
+
+			fn visit_bytes<E: serde::de::Error>(
+				self,
+				data: &[u8],
+			) -> std::result::Result<ChunkBuf, E> {
+				Ok(ChunkBuf(data.to_vec()))
+			}
+
+			fn visit_byte_buf<E: serde::de::Error>(
+				self,
+				data: Vec<u8>,
+			) -> std::result::Result<ChunkBuf, E> {
+				Ok(ChunkBuf(data))
+			}
+
+			fn visit_seq<A: serde::de::SeqAccess<'de>>(
+				self,
+				mut seq: A,
+			) -> std::result::Result<ChunkBuf, A::Error> {
+				let mut data = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(MAX_LEAF_SIZE));
+				while let Some(byte) = seq.next_element()? {
+					data.push(byte);
+				}
+				Ok(ChunkBuf(data))
+			}
+		}
+
+		deserializer.deserialize_bytes(ChunkVisitor)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Deterministic xorshift, so failures can be replayed
+	struct Rng(u64);
+
+	impl Rng {
+		fn next(&mut self) -> u64 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			self.0
+		}
+
+		fn below(&mut self, bound: usize) -> usize { (self.next() % bound as u64) as usize }
+	}
+
+	fn rope(data: &[u8]) -> Rope { Rope::from_reader(data).unwrap() }
+
+	fn contents(rope: &Rope) -> Vec<u8> { rope.collect(0, usize::MAX).unwrap() }
+
+	// Text long enough to need many leaves, with a line every 10 bytes
+	fn lines_of(count: usize) -> Vec<u8> {
+		(0..count)
+			.flat_map(|line| format!("line {:04}\n", line % 10000).into_bytes())
+			.collect()
+	}
+
+	#[test]
+	fn random_edits_match_a_plain_buffer() {
+		let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+		let mut model = Vec::new();
+		let mut rope = Rope::new();
+		for _ in 0..5000 {
+			let at = rng.below(model.len() + 1);
+			match rng.below(4) {
+				0 | 1 => {
+					let data = vec![b'a' + rng.below(26) as u8; rng.below(600)];
+					rope.insert_at(at, &data).unwrap();
+					model.splice(at..at, data);
+				}
+				2 => {
+					let to = at + rng.below(model.len() - at + 1).min(900);
+					rope.remove_range(at, to).unwrap();
+					model.drain(at..to);
+				}
+				_ => {
+					let to = at + rng.below(model.len() - at + 1).min(50);
+					let data = vec![b'\n'; rng.below(50)];
+					rope.replace_range(at, to, &data).unwrap();
+					model.splice(at..to, data);
+				}
+			}
+			assert_eq!(rope.len(), model.len());
+		}
+		assert!(rope.eq_bytes(&model));
+		assert_eq!(
+			rope.line_count(),
+			model.iter().filter(|b| **b == b'\n').count() + 1
+		);
+		let stats = rope.stats();
+		assert!(stats.max_leaf <= MAX_LEAF_SIZE);
+		assert!(
+			rope.depth()
+				<= 2 * (usize::BITS - model.len().leading_zeros()) as usize + MAX_DEPTH_SLACK
+		);
+	}
+
+	#[test]
+	fn edits_at_one_offset_stay_balanced() {
+		let mut rope = Rope::new();
+		for _ in 0..20000 {
+			rope.insert_at(0, b"abc").unwrap();
+		}
+		assert_eq!(rope.len(), 60000);
+		assert!(rope.depth() < 40);
+		rope.flatten();
+		assert!(rope.is_flat());
+		assert!(rope.eq_bytes(&b"abc".repeat(20000)));
+	}
+
+	#[test]
+	fn reading_and_writing_round_trip() {
+		let data = lines_of(5000);
+		let rope = rope(&data);
+		assert!(rope.stats().leaf_count > 1);
+		assert_eq!(contents(&rope), data);
+		assert_eq!(rope.collect(10, 25).unwrap(), &data[10..25]);
+
+		let mut written = Vec::new();
+		assert_eq!(rope.write_to(&mut written).unwrap(), data.len() as u64);
+		assert_eq!(written, data);
+
+		let mut read = Vec::new();
+		rope.reader_range(4090, 9000)
+			.read_to_end(&mut read)
+			.unwrap();
+		assert_eq!(read, &data[4090..9000]);
+		assert_eq!(
+			rope.checksum(),
+			Rope::from_reader(&written[..]).unwrap().checksum()
+		);
+	}
+
+	#[test]
+	fn lines_are_found_across_leaves() {
+		let data = lines_of(2000);
+		let mut rope = rope(&data);
+		assert_eq!(rope.line_count(), 2001);
+		assert_eq!(rope.read_line(1234).unwrap(), b"line 1234");
+		assert_eq!(rope.line_to_offset(1234).unwrap(), 12340);
+		assert_eq!(rope.offset_to_line(12345).unwrap(), (1234, 5));
+		assert_eq!(rope.read_line(2000).unwrap(), b"");
+		assert!(matches!(
+			rope.read_line(2001),
+			Err(EditrError::LineOutOfBounds {
+				line: 2001,
+				lines: 2001
+			})
+		));
+
+		rope.insert_at(12349, b"\r").unwrap();
+		assert_eq!(rope.read_line(1234).unwrap(), b"line 1234");
+		let lines = rope.lines_from(1999).unwrap().collect::<Vec<_>>();
+		assert_eq!(lines.len(), 2);
+		assert_eq!(rope.lines().count(), 2001);
+	}
+
+	#[test]
+	fn searches_find_matches_spanning_leaves() {
+		let mut data = vec![b'x'; 3 * MAX_LEAF_SIZE];
+		let at = MAX_LEAF_SIZE - 2;
+		data[at..at + 5].copy_from_slice(b"NeedL");
+		let rope = rope(&data);
+
+		assert_eq!(rope.search_bytes(b"NeedL"), [at]);
+		assert_eq!(rope.find_next(b"NeedL", 0), Some(at));
+		assert_eq!(rope.find_next(b"NeedL", at + 1), None);
+		assert_eq!(rope.find_prev(b"NeedL", usize::MAX), Some(at));
+		assert_eq!(rope.find_prev(b"NeedL", at), None);
+		let options = SearchOptions {
+			case_insensitive: true,
+			..SearchOptions::default()
+		};
+		assert_eq!(rope.search_with(b"needl", &options), [at]);
+		// Every pair of x but the six overlapping the needle
+		assert_eq!(rope.search_bytes(b"xx").len(), data.len() - 1 - 6);
+		assert!(rope.search_bytes(b"").is_empty());
+		assert_eq!(rope.search(b'N'), [at]);
+	}
+
+	#[test]
+	fn splitting_and_appending_keep_the_contents() {
+		let data = lines_of(1000);
+		let mut head = rope(&data);
+		let tail = head.split_off(4321).unwrap();
+		assert_eq!(contents(&head), &data[..4321]);
+		assert_eq!(contents(&tail), &data[4321..]);
+		head.append(tail);
+		assert!(head.eq_bytes(&data));
+		assert!(head.starts_with(&data[..5000]));
+	}
+
+	#[test]
+	fn the_journal_undoes_edits() {
+		let mut rope = rope(b"hello world");
+		rope.enable_journal(10);
+		rope.insert_at(5, b",").unwrap();
+		rope.replace_range(7, 12, b"there").unwrap();
+		rope.remove_range(0, 1).unwrap();
+		assert!(rope.eq_bytes(b"ello, there"));
+
+		let journal = rope.take_journal();
+		assert_eq!(journal.len(), 4);
+		for record in journal.iter().rev() {
+			record.revert(&mut rope).unwrap();
+		}
+		assert!(rope.eq_bytes(b"hello world"));
+
+		rope.enable_journal(1);
+		rope.insert_at(0, b"a").unwrap();
+		rope.insert_at(0, b"b").unwrap();
+		assert_eq!(
+			rope.take_journal(),
+			[EditRecord::Insert { offset: 0, len: 1 }]
+		);
+	}
+
+	#[test]
+	fn bad_offsets_are_refused() {
+		let mut rope = rope("héllo".as_bytes());
+		assert!(matches!(
+			rope.insert_at(7, b"x"),
+			Err(EditrError::OffsetOutOfBounds { offset: 7, len: 6 })
+		));
+		assert!(matches!(
+			rope.remove_range(3, 2),
+			Err(EditrError::RangeOutOfBounds {
+				from: 3,
+				to: 2,
+				len: 6
+			})
+		));
+		assert!(matches!(
+			rope.insert_str_at(2, "x"),
+			Err(EditrError::NotCharBoundary(2))
+		));
+		assert_eq!(rope.prev_char_boundary(2).unwrap(), 1);
+		assert_eq!(rope.next_char_boundary(2).unwrap(), 3);
+		assert!(rope.eq_bytes("héllo".as_bytes()));
+	}
+
+	#[test]
+	fn removes_must_lie_within_the_rope() {
+		let mut rope = rope(b"hello");
+		rope.remove_range(3, 5).unwrap();
+		assert!(rope.eq_bytes(b"hel"));
+		rope.remove_range(3, 3).unwrap();
+		let e = rope.remove_range(2, 4).unwrap_err();
+		assert!(matches!(
+			e,
+			EditrError::RangeOutOfBounds {
+				from: 2,
+				to: 4,
+				len: 3
+			}
+		));
+		assert_eq!(
+			e.to_string(),
+			"Range 2..4 out of bounds for rope of length 3"
+		);
+		let e = rope.remove_range(2, 1).unwrap_err();
+		assert_eq!(e.to_string(), "Range 2..1 ends before it starts");
+		assert!(rope.eq_bytes(b"hel"));
+
+		let mut empty = Rope::new();
+		empty.remove_range(0, 0).unwrap();
+		assert!(matches!(
+			empty.remove_range(0, 1),
+			Err(EditrError::RangeOutOfBounds {
+				from: 0,
+				to: 1,
+				len: 0
+			})
+		));
+		assert!(empty.is_empty());
+	}
+
+	#[test]
+	fn inserts_may_reach_the_end_but_not_past_it() {
+		let mut rope = rope(b"hello");
+		rope.insert_at(5, b" world").unwrap();
+		assert!(rope.eq_bytes(b"hello world"));
+		assert!(matches!(
+			rope.insert_at(12, b"!"),
+			Err(EditrError::OffsetOutOfBounds {
+				offset: 12,
+				len: 11
+			})
+		));
+		assert!(rope.insert_at(usize::MAX, b"!").is_err());
+		assert!(rope.eq_bytes(b"hello world"));
+
+		let mut empty = Rope::new();
+		assert!(empty.insert_at(1, b"x").is_err());
+		empty.insert_at(0, b"x").unwrap();
+		assert!(empty.eq_bytes(b"x"));
+	}
+
+	#[test]
+	fn appending_fills_leaves_rather_than_splitting_them() {
+		let mut rope = Rope::new();
+		for _ in 0..MAX_LEAF_SIZE {
+			let len = rope.len();
+			rope.insert_at(len, b"ab").unwrap();
+		}
+		let stats = rope.stats();
+		assert_eq!(stats.len, 2 * MAX_LEAF_SIZE);
+		// Typing at the end leaves nothing but full leaves behind it
+		assert!(stats.leaf_count <= 3, "{:?}", stats);
+	}
+
+	#[test]
+	fn typing_at_the_start_keeps_the_tree_shallow() {
+		const TYPED: usize = 100_000;
+		let mut rope = Rope::new();
+		let mut expected = Vec::with_capacity(TYPED);
+		for i in 0..TYPED {
+			let byte = b'a' + (i % 26) as u8;
+			rope.insert_at(0, &[byte]).unwrap();
+			expected.push(byte);
+		}
+		expected.reverse();
+
+		// log2 of the length is 17
+		let bound = 2 * 17 + MAX_DEPTH_SLACK;
+		assert!(rope.depth() <= bound, "Depth {}", rope.depth());
+		assert_eq!(rope.collect(0, usize::MAX).unwrap(), expected);
+		assert_eq!(rope.search(b'z').len(), TYPED / 26);
+		assert_eq!(
+			rope.collect(TYPED - 3, TYPED).unwrap(),
+			&expected[TYPED - 3..]
+		);
+	}
+
+	#[test]
+	fn large_inserts_are_split_into_capped_leaves() {
+		let pasted = (0..1 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		let mut rope = rope(b"beforeafter");
+		rope.insert_at(6, &pasted).unwrap();
+
+		let stats = rope.stats();
+		assert!(stats.max_leaf <= MAX_LEAF_SIZE, "{:?}", stats);
+		assert!(stats.leaf_count >= pasted.len() / MAX_LEAF_SIZE);
+		let mut expected = b"before".to_vec();
+		expected.extend_from_slice(&pasted);
+		expected.extend_from_slice(b"after");
+		assert_eq!(rope.collect(0, rope.len()).unwrap(), expected);
+
+		// Files are loaded as they would be pasted
+		let loaded = Rope::from_reader(&pasted[..]).unwrap();
+		assert!(loaded.stats().max_leaf <= MAX_LEAF_SIZE);
+		assert!(loaded.eq_bytes(&pasted));
+	}
+
+	#[test]
+	fn small_leaves_left_by_removals_are_merged() {
+		let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+		let mut rope = rope(&lines_of(20_000));
+		// Nibbles everywhere, leaving leaves a few bytes long if unmerged
+		for _ in 0..50_000 {
+			let len = rope.len();
+			let at = rng.below(len - 4);
+			if rng.below(3) == 0 {
+				rope.insert_at(at, b"xy").unwrap();
+			}
+			else {
+				rope.remove_range(at, at + 3).unwrap();
+			}
+		}
+
+		let stats = rope.stats();
+		// Each leaf averages well over MIN_LEAF_SIZE / 2 bytes
+		let most = 2 * stats.len / MIN_LEAF_SIZE + 1;
+		assert!(stats.leaf_count <= most, "{:?}", stats);
+		assert!(stats.max_leaf <= MAX_LEAF_SIZE);
+	}
+
+	#[test]
+	fn replacing_matches_removing_then_inserting() {
+		let mut rng = Rng(0x1234_5678_9abc_def1);
+		let mut model = lines_of(2000);
+		let mut rope = rope(&model);
+		for _ in 0..2000 {
+			let from = rng.below(model.len() + 1);
+			let to = from + rng.below(model.len() - from + 1).min(5000);
+			let data = vec![b'r'; rng.below(3000)];
+			rope.replace_range(from, to, &data).unwrap();
+			model.splice(from..to, data);
+		}
+		assert!(rope.eq_bytes(&model));
+		assert!(rope.stats().max_leaf <= MAX_LEAF_SIZE);
+	}
+
+	#[test]
+	fn degenerate_replacements_insert_or_remove() {
+		let mut rope = rope(b"hello world");
+		// Nothing replaced is an insert
+		rope.replace_range(5, 5, b",").unwrap();
+		assert!(rope.eq_bytes(b"hello, world"));
+		// Nothing put in its place is a remove
+		rope.replace_range(5, 6, b"").unwrap();
+		assert!(rope.eq_bytes(b"hello world"));
+		// Up to the end appends
+		rope.replace_range(6, 11, b"there!").unwrap();
+		assert!(rope.eq_bytes(b"hello there!"));
+		rope.replace_range(0, 0, b"").unwrap();
+		assert!(rope.eq_bytes(b"hello there!"));
+
+		assert!(matches!(
+			rope.replace_range(5, 13, b"x"),
+			Err(EditrError::RangeOutOfBounds {
+				from: 5,
+				to: 13,
+				len: 12
+			})
+		));
+		assert!(rope.replace_range(3, 2, b"x").is_err());
+		assert!(rope.eq_bytes(b"hello there!"));
+	}
+
+	// Two full leaves, too big to be merged, with the first split bytes of
+	// middle ending the first and the rest starting the second. Returns
+	// the rope and where middle starts
+	fn straddling(middle: &[u8], split: usize) -> (Rope, usize) {
+		let mut first = vec![b'.'; MAX_LEAF_SIZE - split];
+		first.extend_from_slice(&middle[..split]);
+		let mut second = middle[split..].to_vec();
+		second.resize(MAX_LEAF_SIZE, b'.');
+		let mut rope = rope(&first);
+		rope.append(Rope::from_reader(&second[..]).unwrap());
+		assert_eq!(rope.stats().leaf_count, 2);
+		(rope, MAX_LEAF_SIZE - split)
+	}
+
+	#[test]
+	fn needles_split_between_two_leaves_are_found() {
+		for split in 1..4 {
+			let (mut rope, at) = straddling(b"TODO", split);
+			assert_eq!(rope.search_bytes(b"TODO"), [at]);
+			assert_eq!(rope.find_next(b"TODO", 0), Some(at));
+			assert_eq!(rope.find_prev(b"TODO", usize::MAX), Some(at));
+			let options = SearchOptions {
+				case_insensitive: true,
+				..SearchOptions::default()
+			};
+			assert_eq!(rope.search_with(b"todo", &options), [at]);
+
+			// Still found once the leaves shift
+			rope.insert_at(0, b"TODO").unwrap();
+			assert_eq!(rope.search_bytes(b"TODO"), [0, at + 4]);
+		}
+	}
+
+	#[test]
+	fn overlapping_matches_are_all_found() {
+		assert_eq!(rope(b"aaaa").search_bytes(b"aaa"), [0, 1]);
+		assert_eq!(rope(b"abababa").search_bytes(b"aba"), [0, 2, 4]);
+		let (split, at) = straddling(b"aaaa", 2);
+		assert_eq!(split.search_bytes(b"aaa"), [at, at + 1]);
+		assert_eq!(split.search_bytes(b"aa"), [at, at + 1, at + 2]);
+		assert!(split.search_bytes(b"aaaaa").is_empty());
+	}
+
+	// Offsets at which each line starts, found by scanning
+	fn line_starts(data: &[u8]) -> Vec<usize> {
+		let newlines = data.iter().enumerate().filter(|(_, byte)| **byte == b'\n');
+		std::iter::once(0)
+			.chain(newlines.map(|(at, _)| at + 1))
+			.collect()
+	}
+
+	#[test]
+	fn line_index_matches_a_scan_through_random_edits() {
+		let mut rng = Rng(0xdead_beef_cafe_f00d);
+		let mut model = lines_of(1000);
+		let mut rope = rope(&model);
+		for round in 0..3000 {
+			let at = rng.below(model.len() + 1);
+			if rng.below(2) == 0 {
+				// Newlines land mid leaf as well as at its ends
+				let data = (0..rng.below(40))
+					.map(|_| {
+						if rng.below(4) == 0 {
+							b'\n'
+						}
+						else {
+							b'x'
+						}
+					})
+					.collect::<Vec<_>>();
+				rope.insert_at(at, &data).unwrap();
+				model.splice(at..at, data);
+			}
+			else {
+				let to = at + rng.below(model.len() - at + 1).min(60);
+				rope.remove_range(at, to).unwrap();
+				model.drain(at..to);
+			}
+
+			if round % 100 != 0 {
+				continue;
+			}
+			let starts = line_starts(&model);
+			assert_eq!(rope.line_count(), starts.len());
+			for (line, start) in starts.iter().enumerate() {
+				assert_eq!(rope.line_to_offset(line).unwrap(), *start);
+			}
+			for _ in 0..50 {
+				let offset = rng.below(model.len() + 1);
+				let line = starts.partition_point(|start| *start <= offset) - 1;
+				assert_eq!(
+					rope.offset_to_line(offset).unwrap(),
+					(line, offset - starts[line])
+				);
+			}
+		}
+		assert!(rope.line_to_offset(rope.line_count()).is_err());
+		assert!(rope.offset_to_line(model.len() + 1).is_err());
+	}
+
+	#[test]
+	fn lines_are_read_without_their_endings() {
+		let empty = Rope::new();
+		assert_eq!(empty.line_count(), 1);
+		assert_eq!(empty.read_line(0).unwrap(), b"");
+		assert!(empty.read_line(1).is_err());
+
+		let newline = rope(b"\n");
+		assert_eq!(newline.line_count(), 2);
+		assert_eq!(newline.read_line(0).unwrap(), b"");
+		assert_eq!(newline.read_line(1).unwrap(), b"");
+
+		let crlf = rope(b"first\r\nsecond\r\nlast");
+		assert_eq!(crlf.line_count(), 3);
+		assert_eq!(crlf.read_line(0).unwrap(), b"first");
+		assert_eq!(crlf.read_line(1).unwrap(), b"second");
+		// The last line has no ending, so a lone \r there is kept
+		assert_eq!(crlf.read_line(2).unwrap(), b"last");
+		assert_eq!(rope(b"a\r").read_line(0).unwrap(), b"a\r");
+		assert!(matches!(
+			crlf.read_line(3),
+			Err(EditrError::LineOutOfBounds { line: 3, lines: 3 })
+		));
+	}
+
+	#[test]
+	fn characters_split_between_leaves_are_kept_whole() {
+		let emoji = "\u{1f600}".as_bytes();
+		for split in 1..emoji.len() {
+			let (mut rope, at) = straddling(emoji, split);
+			for inside in at + 1..at + emoji.len() {
+				assert!(!rope.is_char_boundary(inside).unwrap());
+				assert_eq!(rope.prev_char_boundary(inside).unwrap(), at);
+				assert_eq!(rope.next_char_boundary(inside).unwrap(), at + emoji.len());
+				assert!(matches!(
+					rope.insert_str_at(inside, "x"),
+					Err(EditrError::NotCharBoundary(offset)) if offset == inside
+				));
+			}
+			assert!(rope.is_char_boundary(at).unwrap());
+			rope.insert_str_at(at + emoji.len(), "\u{e9}").unwrap();
+			assert_eq!(
+				rope.collect(at, at + 6).unwrap(),
+				"\u{1f600}\u{e9}".as_bytes()
+			);
+		}
+
+		let (rope, at) = straddling("h\u{e9}llo".as_bytes(), 2);
+		assert!(!rope.is_char_boundary(at + 2).unwrap());
+		assert_eq!(rope.prev_char_boundary(at + 2).unwrap(), at + 1);
+		assert!(rope.is_char_boundary(rope.len()).unwrap());
+		assert!(rope.is_char_boundary(rope.len() + 1).is_err());
+	}
+
+	// Takes limit bytes, then fails
+	struct Full {
+		written: Vec<u8>,
+		limit: usize,
+	}
+
+	impl Write for Full {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			let room = self.limit - self.written.len();
+			if room == 0 {
+				return Err(ErrorKind::StorageFull.into());
+			}
+			let len = buf.len().min(room);
+			self.written.extend_from_slice(&buf[..len]);
+			Ok(len)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+	}
+
+	#[test]
+	fn streaming_out_matches_flattening_first() {
+		let mut rope = rope(&lines_of(3000));
+		for at in (0..20_000).step_by(997) {
+			rope.insert_at(at, b"edit\n").unwrap();
+			rope.remove_range(at + 2000, at + 2003).unwrap();
+		}
+		assert!(rope.stats().leaf_count > 1);
+
+		let mut streamed = Vec::new();
+		let written = rope.write_to(&mut streamed).unwrap();
+		// As saving used to, copying everything out of one leaf
+		rope.flatten();
+		assert!(rope.is_flat());
+		let flattened = rope.collect(0, rope.len()).unwrap();
+		assert_eq!(written, flattened.len() as u64);
+		assert_eq!(streamed, flattened);
+
+		let mut full = Full {
+			written: Vec::new(),
+			limit: 5000,
+		};
+		assert!(matches!(rope.write_to(&mut full), Err(EditrError::Io(_))));
+		assert_eq!(full.written, &flattened[..5000]);
+	}
+
+	// Hands out at most most bytes a read, interrupting every other one
+	struct Dribble<'a> {
+		data: &'a [u8],
+		most: usize,
+		interrupt: bool,
+	}
+
+	impl Read for Dribble<'_> {
+		fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+			self.interrupt = !self.interrupt;
+			if self.interrupt {
+				return Err(ErrorKind::Interrupted.into());
+			}
+			let len = buf.len().min(self.most).min(self.data.len());
+			buf[..len].copy_from_slice(&self.data[..len]);
+			self.data = &self.data[len..];
+			Ok(len)
+		}
+	}
+
+	#[test]
+	fn loading_builds_a_balanced_tree_of_full_leaves() {
+		let data = (0..1 << 20)
+			.map(|i| (i * 7 % 256) as u8)
+			.collect::<Vec<_>>();
+		let reader = Dribble {
+			data: &data,
+			most: 1000,
+			interrupt: false,
+		};
+		let rope = Rope::from_reader(reader).unwrap();
+
+		assert!(rope.eq_bytes(&data));
+		let stats = rope.stats();
+		// Short reads are packed together rather than left as small leaves
+		assert_eq!(stats.leaf_count, data.len() / MAX_LEAF_SIZE);
+		assert_eq!(stats.min_leaf, MAX_LEAF_SIZE);
+		assert_eq!(stats.max_leaf, MAX_LEAF_SIZE);
+		// 256 leaves, as balanced as they can be
+		assert_eq!(rope.depth(), 8);
+
+		let empty = Rope::from_reader(&b""[..]).unwrap();
+		assert!(empty.is_empty());
+		assert_eq!(empty.depth(), 0);
+	}
+
+	// Everything reader gives, asked for size bytes at a time
+	fn read_in_pieces<R: Read>(mut reader: R, size: usize) -> Vec<u8> {
+		let mut read = Vec::new();
+		let mut buf = vec![0; size];
+		loop {
+			match reader.read(&mut buf).unwrap() {
+				0 => return read,
+				len => read.extend_from_slice(&buf[..len]),
+			}
+		}
+	}
+
+	#[test]
+	fn readers_give_what_collect_does() {
+		let mut rope = rope(&lines_of(3000));
+		rope.insert_at(5000, b"spanning leaves").unwrap();
+		assert!(rope.stats().leaf_count > 1);
+
+		let whole = rope.collect(0, usize::MAX).unwrap();
+		assert_eq!(read_in_pieces(rope.reader(), 7), whole);
+		for (from, to) in [(0, 0), (4090, 4100), (5001, 20_000), (29_000, usize::MAX)] {
+			let expected = rope.collect(from, to).unwrap();
+			assert_eq!(read_in_pieces(rope.reader_range(from, to), 7), expected);
+		}
+		// Ranges past the end read nothing
+		assert!(read_in_pieces(rope.reader_range(usize::MAX, usize::MAX), 7).is_empty());
+		assert!(read_in_pieces(rope.reader_range(10, 5), 7).is_empty());
+
+		let mut hashed = 0xcbf2_9ce4_8422_2325u64;
+		for byte in read_in_pieces(rope.reader(), 4096) {
+			hashed = (hashed ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+		}
+		assert_eq!(hashed, rope.checksum());
+	}
+
+	#[test]
+	fn splits_at_random_offsets_append_back_together() {
+		let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+		let data = lines_of(3000);
+		let mut rope = rope(&data);
+		for _ in 0..200 {
+			let at = rng.below(data.len() + 1);
+			let tail = rope.split_off(at).unwrap();
+			assert_eq!(rope.len(), at);
+			assert_eq!(tail.len(), data.len() - at);
+			assert!(rope.eq_bytes(&data[..at]));
+			assert!(tail.eq_bytes(&data[at..]));
+			assert_eq!(
+				rope.line_count() + tail.line_count() - 1,
+				data.iter().filter(|b| **b == b'\n').count() + 1
+			);
+			rope.append(tail);
+			assert!(rope.eq_bytes(&data));
+		}
+		assert!(rope.split_off(data.len() + 1).is_err());
+
+		// Edits after joining still find their way through the sizes
+		let bound = 2 * (usize::BITS - data.len().leading_zeros()) as usize + MAX_DEPTH_SLACK;
+		let mut model = data.clone();
+		for _ in 0..500 {
+			let at = rng.below(model.len() + 1);
+			let tail = rope.split_off(at).unwrap();
+			let insert = vec![b'+'; rng.below(100)];
+			rope.insert_at(at / 2, &insert).unwrap();
+			rope.append(tail);
+			model.splice(at / 2..at / 2, insert);
+			let to = at + rng.below(model.len() - at + 1).min(200);
+			rope.remove_range(at, to).unwrap();
+			model.drain(at..to);
+		}
+		assert!(rope.eq_bytes(&model));
+		assert!(rope.depth() <= bound, "Depth {}", rope.depth());
+		assert!(rope.stats().max_leaf <= MAX_LEAF_SIZE);
+	}
+
+	#[test]
+	fn comparisons_find_differences_in_any_leaf() {
+		let data = lines_of(2000);
+		let rope = rope(&data);
+		assert!(rope.stats().leaf_count > 4);
+		assert!(rope.eq_bytes(&data));
+		assert!(rope.starts_with(&data));
+		assert!(rope.starts_with(b""));
+
+		// A byte changed at the start, end or across a leaf boundary
+		for at in [0, MAX_LEAF_SIZE - 1, MAX_LEAF_SIZE, 10_000, data.len() - 1] {
+			let mut other = data.clone();
+			other[at] ^= 1;
+			assert!(!rope.eq_bytes(&other), "Changed at {}", at);
+			assert!(!rope.starts_with(&other[..=at]), "Changed at {}", at);
+			assert!(rope.starts_with(&other[..at]), "Changed at {}", at);
+		}
+
+		// Lengths differing by a byte either way
+		assert!(!rope.eq_bytes(&data[..data.len() - 1]));
+		assert!(rope.starts_with(&data[..data.len() - 1]));
+		let mut longer = data.clone();
+		longer.push(b'\n');
+		assert!(!rope.eq_bytes(&longer));
+		assert!(!rope.starts_with(&longer));
+
+		let empty = Rope::new();
+		assert!(empty.eq_bytes(b""));
+		assert!(empty.starts_with(b""));
+		assert!(!empty.eq_bytes(b"x"));
+		assert!(!empty.starts_with(b"x"));
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn serialized_ropes_come_back_balanced() {
+		let mut rope = rope(&lines_of(5000));
+		for i in 0..200 {
+			rope.insert_at(i * 211, b"inserted").unwrap();
+		}
+		let expected = contents(&rope);
+
+		let json = serde_json::to_vec(&rope).unwrap();
+		let from_json: Rope = serde_json::from_slice(&json).unwrap();
+		let encoded = bincode::serialize(&rope).unwrap();
+		let from_bincode: Rope = bincode::deserialize(&encoded).unwrap();
+		for read in [from_json, from_bincode] {
+			assert!(read.eq_bytes(&expected));
+			assert_eq!(read.line_count(), rope.line_count());
+			let stats = read.stats();
+			assert!(stats.max_leaf <= MAX_LEAF_SIZE);
+			assert!(read.depth() <= rope.depth());
+			assert_eq!(read.checksum(), rope.checksum());
+		}
+
+		// Only the contents are written, a leaf at a time
+		let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+		let leaves = value.as_array().unwrap();
+		assert_eq!(leaves.len(), rope.stats().leaf_count);
+
+		// Chunks of any size are put back into leaves no longer than the cap
+		let oversized =
+			serde_json::to_vec(&[vec![b'x'; 3 * MAX_LEAF_SIZE], vec![], vec![b'y']]).unwrap();
+		let read: Rope = serde_json::from_slice(&oversized).unwrap();
+		assert_eq!(read.len(), 3 * MAX_LEAF_SIZE + 1);
+		assert!(read.stats().max_leaf <= MAX_LEAF_SIZE);
+		assert!(read.stats().leaf_count >= 3);
+
+		let empty: Rope = bincode::deserialize(&bincode::serialize(&Rope::new()).unwrap()).unwrap();
+		assert!(empty.is_empty());
+	}
+
+	fn leaf(data: &[u8]) -> Node {
+		let mut exact = Vec::with_capacity(data.len());
+		exact.extend_from_slice(data);
+		Node::Leaf(LeafData::new(exact))
+	}
+
+	#[test]
+	fn stats_describe_hand_built_trees() {
+		// An empty rope is still one, empty, leaf
+		assert_eq!(
+			Rope::new().stats(),
+			RopeStats {
+				leaf_count: 1,
+				..RopeStats::default()
+			}
+		);
+
+		let single = Rope::from_root(leaf(b"hello"));
+		assert_eq!(
+			single.stats(),
+			RopeStats {
+				len: 5,
+				leaf_count: 1,
+				internal_count: 0,
+				max_depth: 0,
+				min_leaf: 5,
+				max_leaf: 5,
+				heap_bytes: 5,
+			}
+		);
+
+		// ((ab, cdef), ((g, hi), jklmn)), leaning right
+		let node = std::mem::size_of::<(Node, Node)>();
+		let rope = Rope::from_root(Node::internal(
+			Node::internal(leaf(b"ab"), leaf(b"cdef")),
+			Node::internal(Node::internal(leaf(b"g"), leaf(b"hi")), leaf(b"jklmn")),
+		));
+		assert!(rope.eq_bytes(b"abcdefghijklmn"));
+		assert_eq!(
+			rope.stats(),
+			RopeStats {
+				len: 14,
+				leaf_count: 5,
+				internal_count: 4,
+				max_depth: 3,
+				min_leaf: 1,
+				max_leaf: 5,
+				heap_bytes: 14 + 4 * node,
+			}
+		);
+		assert_eq!(rope.stats().max_depth, rope.depth());
+	}
+
+	#[test]
+	fn collecting_out_of_range_is_cut_short_or_refused() {
+		let data = lines_of(1000);
+		let len = data.len();
+		let rope = rope(&data);
+
+		// Starting past the end reads nothing
+		assert!(rope.collect(len, len).unwrap().is_empty());
+		assert!(rope.collect(len + 1, len + 10).unwrap().is_empty());
+		assert!(rope.collect(usize::MAX, usize::MAX).unwrap().is_empty());
+		// Ending past it reads up to the end
+		assert_eq!(rope.collect(len - 5, len + 5).unwrap(), &data[len - 5..]);
+		assert_eq!(rope.collect(4000, usize::MAX).unwrap(), &data[4000..]);
+		// Ending before the start is refused, even past the end
+		assert!(matches!(
+			rope.collect(20, 10),
+			Err(EditrError::RangeOutOfBounds {
+				from: 20,
+				to: 10,
+				len: _
+			})
+		));
+		assert!(rope.collect(len + 20, len + 10).is_err());
+		assert!(rope.collect(1, 1).unwrap().is_empty());
+	}
+
+	#[test]
+	fn equal_contents_checksum_equally_however_they_were_built() {
+		let data = lines_of(2000);
+		let loaded = rope(&data);
+
+		// Typed backwards a line at a time
+		let mut typed = Rope::new();
+		for line in data.chunks(10).rev() {
+			typed.insert_at(0, line).unwrap();
+		}
+
+		// Padded out, then the padding removed from the middle outwards
+		let mut trimmed = rope(&data[..9000]);
+		trimmed.insert_at(9000, &vec![b'#'; 20_000]).unwrap();
+		trimmed.insert_at(29_000, &data[9000..]).unwrap();
+		for _ in 0..100 {
+			let middle = 9000 + (trimmed.len() - data.len()) / 2;
+			trimmed.remove_range(middle - 100, middle + 100).unwrap();
+		}
+
+		// Split up and joined back in a different order of appends
+		let mut joined = rope(&data[..7]);
+		joined.append(rope(&data[7..12_345]));
+		joined.append(rope(&data[12_345..]));
+
+		let ropes = [&typed, &trimmed, &joined];
+		assert!(ropes.iter().all(|other| other.eq_bytes(&data)));
+		let shapes = ropes.iter().map(|other| other.stats()).collect::<Vec<_>>();
+		assert!(shapes.iter().any(|shape| *shape != loaded.stats()));
+		for other in ropes {
+			assert_eq!(other.checksum(), loaded.checksum());
+		}
+
+		// Any change to the contents changes the checksum
+		let mut changed = rope(&data);
+		changed.replace_range(5000, 5001, b"X").unwrap();
+		assert_ne!(changed.checksum(), loaded.checksum());
+		changed
+			.replace_range(5000, 5001, &data[5000..5001])
+			.unwrap();
+		assert_eq!(changed.checksum(), loaded.checksum());
+		assert_ne!(Rope::new().checksum(), rope(b"\0").checksum());
+	}
+
+	// Every offset of needle in data, as search_with should give them
+	fn scan(data: &[u8], needle: &[u8], options: &SearchOptions) -> Vec<usize> {
+		let found = (0..data.len().saturating_sub(needle.len() - 1)).filter(|&at| {
+			let window = &data[at..at + needle.len()];
+			if options.case_insensitive {
+				window.eq_ignore_ascii_case(needle)
+			}
+			else {
+				window == needle
+			}
+		});
+		let mut found = if options.backwards {
+			found.filter(|&at| at < options.start).collect::<Vec<_>>()
+		}
+		else {
+			found.filter(|&at| at >= options.start).collect()
+		};
+		if options.backwards {
+			found.reverse();
+		}
+		found.truncate(options.limit.unwrap_or(usize::MAX));
+		found
+	}
+
+	#[test]
+	fn searches_in_either_direction_and_case_match_a_scan() {
+		let mut rng = Rng(0x1234_5678_9abc_def1);
+		// Mostly one letter in either case, so matches overlap and cross
+		// leaf boundaries
+		let data = (0..20_000)
+			.map(|_| match rng.below(8) {
+				0 => b'B',
+				1 => b'b',
+				2 => b' ',
+				_ => b"aA"[rng.below(2)],
+			})
+			.collect::<Vec<_>>();
+		let rope = rope(&data);
+		assert!(rope.stats().leaf_count > 4);
+
+		for _ in 0..200 {
+			let needle = (0..1 + rng.below(4))
+				.map(|_| b"aAbB"[rng.below(4)])
+				.collect::<Vec<_>>();
+			let options = SearchOptions {
+				case_insensitive: rng.below(2) == 0,
+				backwards: rng.below(2) == 0,
+				start: rng.below(data.len() + 10),
+				limit: [None, Some(0), Some(1), Some(rng.below(50))][rng.below(4)],
+			};
+			let found = rope.search_with(&needle, &options);
+			assert_eq!(
+				found,
+				scan(&data, &needle, &options),
+				"{:?} {:?}",
+				needle,
+				options
+			);
+			if options.backwards {
+				assert!(found.windows(2).all(|pair| pair[0] > pair[1]));
+				assert!(found.iter().all(|&at| at < options.start));
+			}
+		}
+
+		// Only ASCII letters are folded
+		let accented = Rope::from_reader("caf\u{e9} CAF\u{c9}".as_bytes()).unwrap();
+		let options = SearchOptions {
+			case_insensitive: true,
+			..SearchOptions::default()
+		};
+		assert_eq!(accented.search_with(b"CAF", &options), [0, 6]);
+		assert_eq!(accented.search_with("caf\u{e9}".as_bytes(), &options), [0]);
+		let backwards = SearchOptions {
+			backwards: true,
+			start: usize::MAX,
+			..options
+		};
+		assert_eq!(accented.search_with(b"caf", &backwards), [6, 0]);
+	}
+
+	#[test]
+	fn reverting_a_random_journal_restores_the_contents() {
+		let mut rng = Rng(0x0dd_ba11_cafe_f00d);
+		let original = lines_of(3000);
+		let mut rope = rope(&original);
+		rope.enable_journal(usize::MAX);
+		for _ in 0..1000 {
+			let at = rng.below(rope.len() + 1);
+			match rng.below(4) {
+				0 => rope.insert_at(at, &vec![b'+'; rng.below(300)]).unwrap(),
+				// Up to a few leaves' worth, so removals span leaves
+				1 => {
+					let to = (at + rng.below(3 * MAX_LEAF_SIZE)).min(rope.len());
+					rope.remove_range(at, to).unwrap();
+				}
+				2 => {
+					let to = (at + rng.below(100)).min(rope.len());
+					rope.replace_range(at, to, b"replaced").unwrap();
+				}
+				_ => {
+					let tail = rope.split_off(at).unwrap();
+					rope.append(tail);
+				}
+			}
+		}
+
+		let journal = rope.take_journal();
+		assert!(rope.take_journal().is_empty());
+		for record in journal.iter().rev() {
+			record.revert(&mut rope).unwrap();
+		}
+		assert!(rope.eq_bytes(&original));
+	}
+
+	#[test]
+	fn a_bounded_journal_undoes_the_latest_edits() {
+		let mut rng = Rng(0x5eed_f00d_7e57_ab1e);
+		let mut rope = rope(&lines_of(20_000));
+		rope.enable_journal(10);
+		// What the rope held before each edit
+		let mut before = Vec::new();
+		for _ in 0..50 {
+			before.push(contents(&rope));
+			let at = rng.below(rope.len());
+			let to = (at + rng.below(2 * MAX_LEAF_SIZE)).min(rope.len());
+			rope.remove_range(at, to).unwrap();
+		}
+
+		let journal = rope.take_journal();
+		assert_eq!(journal.len(), 10);
+		for record in journal.iter().rev() {
+			record.revert(&mut rope).unwrap();
+		}
+		assert!(rope.eq_bytes(&before[40]));
+	}
+
+	// Offsets at which each leaf but the first begins
+	fn leaf_boundaries(rope: &Rope) -> Vec<usize> {
+		let mut offset = 0;
+		let mut boundaries = Vec::new();
+		for leaf in rope.root.iterate_leaves() {
+			if offset > 0 {
+				boundaries.push(offset);
+			}
+			offset += leaf.size();
+		}
+		boundaries
+	}
+
+	#[test]
+	fn removes_around_leaf_boundaries_match_a_plain_buffer() {
+		let mut rng = Rng(0x7f4a_7c15_9e37_79b9);
+		for sequence in 0..300 {
+			let mut model = lines_of(2000);
+			let mut rope = rope(&model);
+			for _ in 0..20 {
+				let boundaries = leaf_boundaries(&rope);
+				// Mostly start or end exactly on, or a byte either side of,
+				// where two leaves meet
+				let near = |rng: &mut Rng, len: usize| match boundaries.len() {
+					0 => rng.below(len + 1),
+					count => (boundaries[rng.below(count)] + rng.below(3))
+						.saturating_sub(1)
+						.min(len),
+				};
+				let a = near(&mut rng, model.len());
+				let b = if rng.below(2) == 0 {
+					near(&mut rng, model.len())
+				}
+				else {
+					rng.below(model.len() + 1)
+				};
+				let (from, to) = (a.min(b), a.max(b));
+				if rng.below(3) == 0 {
+					let data = vec![b'0' + (sequence % 10) as u8; rng.below(2 * MAX_LEAF_SIZE)];
+					rope.insert_at(from, &data).unwrap();
+					model.splice(from..from, data);
+				}
+				else {
+					rope.remove_range(from, to).unwrap();
+					model.drain(from..to);
+				}
+				assert_eq!(rope.len(), model.len(), "Sequence {}", sequence);
+			}
+			assert!(rope.eq_bytes(&model), "Sequence {}", sequence);
+			assert_eq!(
+				rope.line_count(),
+				model.iter().filter(|b| **b == b'\n').count() + 1
+			);
+			// Children emptied by removals are collapsed away
+			if !rope.is_empty() {
+				assert!(rope.root.iterate_leaves().all(|leaf| leaf.size() > 0));
+			}
+		}
+	}
+
+	#[test]
+	fn removes_within_hand_built_trees_collapse_emptied_children() {
+		// ((ab, cd), (ef, gh)), removed from and to every pair of offsets
+		let build = || {
+			Rope::from_root(Node::internal(
+				Node::internal(leaf(b"ab"), leaf(b"cd")),
+				Node::internal(leaf(b"ef"), leaf(b"gh")),
+			))
+		};
+		let full = b"abcdefgh";
+		for from in 0..=full.len() {
+			for to in from..=full.len() {
+				let mut rope = build();
+				rope.remove_range(from, to).unwrap();
+				let mut expected = full.to_vec();
+				expected.drain(from..to);
+				assert!(rope.eq_bytes(&expected), "{}..{}", from, to);
+				let stats = rope.stats();
+				if !expected.is_empty() {
+					assert!(stats.min_leaf > 0, "{}..{} {:?}", from, to, stats);
+				}
+				assert_eq!(stats.internal_count + 1, stats.leaf_count);
+			}
+		}
+
+		// Removing a whole child leaves the other in its place, whose small
+		// leaves may then be merged
+		let mut rope = build();
+		rope.remove_range(0, 4).unwrap();
+		assert!(rope.eq_bytes(b"efgh"));
+		assert!(rope.depth() <= 1);
+		let mut rope = build();
+		rope.remove_range(4, 8).unwrap();
+		assert!(rope.eq_bytes(b"abcd"));
+		assert!(rope.depth() <= 1);
+	}
+
+	// Lines of data with their offsets, split at each newline
+	fn split_lines(data: &[u8]) -> Vec<(usize, Vec<u8>)> {
+		let mut start = 0;
+		data.split(|byte| *byte == b'\n')
+			.map(|line| {
+				let at = start;
+				start += line.len() + 1;
+				(at, line.to_vec())
+			})
+			.collect()
+	}
+
+	#[test]
+	fn lines_are_yielded_however_long_or_few() {
+		// No newlines at all, over many leaves
+		let unbroken = vec![b'x'; 5 * MAX_LEAF_SIZE + 3];
+		let lines = rope(&unbroken).lines().collect::<Vec<_>>();
+		assert_eq!(lines, [(0, unbroken.clone())]);
+
+		// Nothing but newlines, with an empty line after the last
+		let newlines = vec![b'\n'; 3 * MAX_LEAF_SIZE];
+		let lines = rope(&newlines).lines().collect::<Vec<_>>();
+		assert_eq!(lines.len(), newlines.len() + 1);
+		assert!(lines
+			.iter()
+			.enumerate()
+			.all(|(n, (at, line))| *at == n && line.is_empty()));
+
+		// Lines longer than several leaves between short ones, and a last
+		// line without a newline
+		let mut mixed = b"short\n".to_vec();
+		mixed.extend(vec![b'y'; 3 * MAX_LEAF_SIZE]);
+		mixed.extend_from_slice(b"\n\nmiddle\n");
+		mixed.extend(vec![b'z'; MAX_LEAF_SIZE + 7]);
+		let mut rope = rope(&mixed);
+		assert!(rope.stats().leaf_count > 3);
+		assert_eq!(rope.lines().collect::<Vec<_>>(), split_lines(&mixed));
+
+		// Still found after edits reshape the leaves
+		rope.insert_at(6 + MAX_LEAF_SIZE, b"\nsplit\n").unwrap();
+		mixed.splice(6 + MAX_LEAF_SIZE..6 + MAX_LEAF_SIZE, b"\nsplit\n".to_vec());
+		rope.remove_range(2, 4).unwrap();
+		mixed.drain(2..4);
+		assert_eq!(rope.lines().collect::<Vec<_>>(), split_lines(&mixed));
+		assert_eq!(rope.lines().count(), rope.line_count());
+
+		assert_eq!(Rope::new().lines().collect::<Vec<_>>(), [(0, Vec::new())]);
+	}
+
+	#[test]
+	fn chunks_cover_the_rope_in_order() {
+		let mut rope = rope(&lines_of(5000));
+		rope.insert_at(12_345, &[b'!'; 700]).unwrap();
+		rope.remove_range(30_000, 31_000).unwrap();
+		let whole = rope.collect(0, rope.len()).unwrap();
+
+		let mut lengths = Vec::new();
+		let mut joined = Vec::new();
+		rope.for_each_chunk::<(), _>(|chunk| {
+			lengths.push(chunk.len());
+			joined.extend_from_slice(chunk);
+			Ok(())
+		})
+		.unwrap();
+		assert!(lengths.len() > 1);
+		assert_eq!(lengths.len(), rope.stats().leaf_count);
+		assert_eq!(lengths.iter().sum::<usize>(), rope.len());
+		assert_eq!(joined, whole);
+
+		// An error stops the walk at the chunk which gave it
+		let mut seen = 0;
+		let stopped = rope.for_each_chunk(|chunk| {
+			seen += 1;
+			if chunk.contains(&b'!') {
+				Err(seen)
+			}
+			else {
+				Ok(())
+			}
+		});
+		assert_eq!(stopped, Err(seen));
+		assert!(seen < lengths.len());
+	}
+
+	#[test]
+	fn bytes_in_random_ranges_match_collect() {
+		let mut rng = Rng(0xb5ad_4ece_da1c_e2a9);
+		let mut rope = rope(&lines_of(4000));
+		rope.insert_at(777, &[b'#'; 5000]).unwrap();
+		let len = rope.len();
+		let boundaries = leaf_boundaries(&rope);
+		assert!(boundaries.len() > 4);
+
+		for round in 0..500 {
+			let (from, to) = if round % 2 == 0 {
+				// Within one leaf
+				let leaf = rng.below(boundaries.len() - 1);
+				let (start, end) = (boundaries[leaf], boundaries[leaf + 1]);
+				let from = start + rng.below(end - start);
+				(from, from + rng.below(end - from + 1))
+			}
+			else {
+				let from = rng.below(len + 1);
+				(from, from + rng.below(len - from + 1))
+			};
+			let mut bytes = Vec::new();
+			rope.bytes_in(from, to, |byte| bytes.push(byte));
+			assert_eq!(bytes, rope.collect(from, to).unwrap(), "{}..{}", from, to);
+		}
+
+		// Past the end, as collect, gives what there is
+		let mut bytes = Vec::new();
+		rope.bytes_in(len - 3, usize::MAX, |byte| bytes.push(byte));
+		assert_eq!(bytes, rope.collect(len - 3, len).unwrap());
+		let mut count = 0;
+		rope.bytes_in(len, usize::MAX, |_| count += 1);
+		assert_eq!(count, 0);
+	}
+
+	#[cfg(feature = "regex")]
+	#[test]
+	fn regex_searches_match_across_leaves() {
+		assert!(matches!(
+			rope(b"abc").search_regex("(unclosed"),
+			Err(EditrError::InvalidPattern(_))
+		));
+
+		let (rope, at) = straddling(b"id=12345;", 6);
+		assert!(rope.stats().leaf_count > 1);
+		assert_eq!(rope.search_regex(r"id=\d+").unwrap(), [(at, at + 8)]);
+
+		let mut rope = self::rope(&lines_of(3000));
+		rope.insert_at(MAX_LEAF_SIZE - 3, b"Error: disk full\n")
+			.unwrap();
+		let whole = rope.collect(0, rope.len()).unwrap();
+		for pattern in [
+			r"line 00\d7",
+			r"(?m)^line 1[0-9]{3}$",
+			r"Error: \w+ \w+",
+			r"\n\n",
+			"x",
+		] {
+			let expected = regex::bytes::Regex::new(pattern)
+				.unwrap()
+				.find_iter(&whole)
+				.map(|found| (found.start(), found.end()))
+				.collect::<Vec<_>>();
+			assert_eq!(rope.search_regex(pattern).unwrap(), expected, "{}", pattern);
+		}
 	}
 }
@@ -0,0 +1,362 @@
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Barrier};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+
+use crate::config::ServerConfig;
+use crate::error::{EditrError, EditrResult};
+use crate::message::{Message, UpdateData};
+use crate::text_client::Client;
+use crate::text_server::{spawn, ServerHandle};
+
+// File every client edits, under the temporary home
+const FILE: &str = "self-test.txt";
+const INITIAL_CONTENTS: &[u8] = b"editr self test\n";
+// Length asked for when reading the whole file
+const WHOLE_FILE: usize = isize::MAX as usize;
+// Longest the observer may take to see every edit once editing has stopped
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+const SETTLE_POLL: Duration = Duration::from_millis(10);
+
+// Settings for self_test
+#[derive(Clone, Debug)]
+pub struct SelfTestConfig {
+	// Clients editing at once
+	pub clients: usize,
+	// Requests each client makes
+	pub operations: usize,
+	// Seeds the edits each client makes, so that a failure can be run
+	// again with the same scripts. Taken from the clock if None
+	pub seed: Option<u64>,
+	// Settings for the server under test
+	pub server: ServerConfig,
+}
+
+impl Default for SelfTestConfig {
+	fn default() -> Self {
+		SelfTestConfig {
+			clients: 8,
+			operations: 500,
+			seed: None,
+			server: ServerConfig::default(),
+		}
+	}
+}
+
+// What a successful self test did
+#[derive(Debug)]
+pub struct SelfTestReport {
+	pub seed: u64,
+	pub clients: usize,
+	// Edits the server applied, and requests it turned down
+	pub edits: u64,
+	pub rejected: u64,
+	// Length of the file every view agreed on
+	pub final_len: usize,
+	pub elapsed: Duration,
+	// Latency of requests at the 50th, 90th and 99th percentiles, and the
+	// slowest of all
+	pub p50: Duration,
+	pub p90: Duration,
+	pub p99: Duration,
+	pub max: Duration,
+}
+
+// What one editing client did
+struct Editor {
+	client: Client,
+	latencies: Vec<Duration>,
+	edits: u64,
+	rejected: u64,
+}
+
+// The file as rebuilt from the broadcasts a client which never edits is
+// sent, which arrive in the order the server applied them. Broadcasts
+// queued together may be merged, so there can be fewer than there were edits
+#[derive(Default)]
+struct Replica {
+	data: Vec<u8>,
+}
+
+impl Replica {
+	// Applies an update as the server did. The server only broadcasts
+	// ranges within the file, but any past the end are cut short here
+	fn apply(&mut self, update: &UpdateData) {
+		match update {
+			UpdateData::Add(add) => {
+				let offset = add.offset.min(self.data.len());
+				self.data.splice(offset..offset, add.data.iter().copied());
+			}
+			UpdateData::Remove(remove) => {
+				let from = remove.offset.min(self.data.len());
+				let to = remove
+					.offset
+					.saturating_add(remove.len)
+					.min(self.data.len());
+				self.data.drain(from..to);
+			}
+			UpdateData::Replace(replace) => {
+				let from = replace.offset.min(self.data.len());
+				let to = replace
+					.offset
+					.saturating_add(replace.len)
+					.min(self.data.len());
+				self.data.splice(from..to, replace.data.iter().copied());
+			}
+		}
+	}
+}
+
+// Proves that concurrent clients converge. Starts a server on an ephemeral
+// port over a temporary home, has clients.clients make random interleaved
+// cursor edits to one file, then checks that every client's view, a
+// replica built from the broadcasts, the server's rope and the file saved
+// to disk are all the same. Failures name the seed to run them again with
+pub fn self_test(config: SelfTestConfig) -> EditrResult<SelfTestReport> {
+	let seed = config.seed.unwrap_or_else(|| {
+		SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(1, |time| time.as_nanos() as u64)
+	});
+	let home =
+		std::env::temp_dir().join(format!("editr-self-test-{}-{}", std::process::id(), seed));
+	fs::create_dir_all(&home)?;
+	let result = fs::write(home.join(FILE), INITIAL_CONTENTS)
+		.map_err(EditrError::from)
+		.and_then(|_| spawn(&home, "127.0.0.1:0", config.server.clone()))
+		.and_then(|server| {
+			let result = run(&server, &config, seed);
+			server.stop().and(result)
+		});
+	fs::remove_dir_all(&home).ok();
+	result.map_err(|e| EditrError::Internal(format!("Self test with seed {} failed: {}", seed, e)))
+}
+
+fn run(server: &ServerHandle, config: &SelfTestConfig, seed: u64) -> EditrResult<SelfTestReport> {
+	if config.clients == 0 {
+		return Err(EditrError::Config(
+			"At least one client is required".to_string(),
+		));
+	}
+
+	let replica = Arc::new(Mutex::new(Replica::default()));
+	let observer = {
+		let replica = replica.clone();
+		let stream = TcpStream::connect(server.local_addr())?;
+		Client::with_handler(stream, move |msg| {
+			if let Message::UpdateMessage(update) = msg {
+				replica.lock().apply(&update);
+			}
+		})?
+	};
+	let path = observer.open(FILE, Some("observer"))?;
+	replica.lock().data = observer.read(0, WHOLE_FILE)?;
+
+	let began = Instant::now();
+	let start = Arc::new(Barrier::new(config.clients));
+	let editors = (0..config.clients)
+		.map(|index| {
+			let (address, start) = (server.local_addr(), start.clone());
+			let operations = config.operations;
+			thread::spawn(move || edit(address, index, operations, seed, &start))
+		})
+		.collect::<Vec<_>>()
+		.into_iter()
+		.map(|editor| {
+			editor
+				.join()
+				.unwrap_or_else(|_| Err(EditrError::Internal("Client panicked".to_string())))
+		})
+		.collect::<EditrResult<Vec<_>>>()?;
+	let elapsed = began.elapsed();
+
+	// Each edit is sent to the observer once it has been applied, so it
+	// catches up unless something was lost or misapplied
+	let edits = editors.iter().map(|editor| editor.edits).sum::<u64>();
+	let expected = server.files().read(&path, 0, usize::MAX)?;
+	let settled = Instant::now();
+	while replica.lock().data != expected && settled.elapsed() < SETTLE_TIMEOUT {
+		sleep(SETTLE_POLL);
+	}
+	compare("Replica", &replica.lock().data, &expected)?;
+	for (index, editor) in editors.iter().enumerate() {
+		let view = editor.client.read(0, WHOLE_FILE)?;
+		compare(&format!("Client {}", index), &view, &expected)?;
+	}
+	observer.save()?;
+	compare("Saved file", &fs::read(&path)?, &expected)?;
+
+	let mut latencies = editors
+		.iter()
+		.flat_map(|editor| editor.latencies.iter().copied())
+		.collect::<Vec<_>>();
+	latencies.sort();
+	Ok(SelfTestReport {
+		seed,
+		clients: config.clients,
+		edits,
+		rejected: editors.iter().map(|editor| editor.rejected).sum(),
+		final_len: expected.len(),
+		elapsed,
+		p50: percentile(&latencies, 50.0),
+		p90: percentile(&latencies, 90.0),
+		p99: percentile(&latencies, 99.0),
+		max: latencies.last().copied().unwrap_or_default(),
+	})
+}
+
+// Connects as the index'th client and makes its scripted edits, keeping
+// the file open so its view can be checked afterwards
+fn edit(
+	address: SocketAddr,
+	index: usize,
+	operations: usize,
+	seed: u64,
+	start: &Barrier,
+) -> EditrResult<Editor> {
+	// Waits at the barrier even if connecting fails, so the others carry on
+	let connected = Client::connect(address).and_then(|client| {
+		client.open(FILE, Some(&format!("self-test-{}", index)))?;
+		Ok(client)
+	});
+	start.wait();
+	let mut editor = Editor {
+		client: connected?,
+		latencies: Vec::with_capacity(operations),
+		edits: 0,
+		rejected: 0,
+	};
+
+	let mut rng = Rng::new(seed, index);
+	for _ in 0..operations {
+		let pick = rng.below(10);
+		let sent = Instant::now();
+		let result = if pick < 5 {
+			let len = 1 + rng.below(4) as usize;
+			let data = (0..len)
+				.map(|_| b'a' + rng.below(26) as u8)
+				.collect::<Vec<_>>();
+			editor.client.write_at_cursor(&data).map(|_| true)
+		}
+		else if pick < 7 {
+			let len = 1 + rng.below(3) as usize;
+			editor.client.remove_at_cursor(len).map(|_| true)
+		}
+		else {
+			let offset = rng.below(17) as isize - 8;
+			editor.client.move_cursor(offset).map(|_| false)
+		};
+		editor.latencies.push(sent.elapsed());
+		match result {
+			Ok(true) => editor.edits += 1,
+			Ok(false) => (),
+			Err(EditrError::Rejected(_)) => editor.rejected += 1,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(editor)
+}
+
+// Fails naming what differed from expected, and where it first did
+fn compare(what: &str, actual: &[u8], expected: &[u8]) -> EditrResult<()> {
+	if actual == expected {
+		return Ok(());
+	}
+	let at = actual
+		.iter()
+		.zip(expected)
+		.position(|(a, b)| a != b)
+		.unwrap_or_else(|| actual.len().min(expected.len()));
+	Err(EditrError::Internal(format!(
+		"{} diverged from the server's rope at byte {} ({} bytes against {})",
+		what,
+		at,
+		actual.len(),
+		expected.len()
+	)))
+}
+
+// The latency below which percent of requests were answered
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+	if sorted.is_empty() {
+		return Duration::ZERO;
+	}
+	let index = ((sorted.len() - 1) as f64 * percent / 100.0).round() as usize;
+	sorted[index]
+}
+
+// Xorshift, which is plenty for scripting edits and reproducible from a seed
+struct Rng(u64);
+
+impl Rng {
+	fn new(seed: u64, index: usize) -> Rng {
+		// Never zero, which xorshift would be stuck at
+		Rng((seed ^ (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1)
+	}
+
+	// A number from 0 up to but not including below
+	fn below(&mut self, below: u32) -> u32 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		(self.0 % u64::from(below)) as u32
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn the_same_seed_scripts_the_same_edits() {
+		let script = |seed, index| {
+			let mut rng = Rng::new(seed, index);
+			(0..100).map(|_| rng.below(10)).collect::<Vec<_>>()
+		};
+		assert_eq!(script(9, 1), script(9, 1));
+		assert_ne!(script(9, 1), script(9, 2));
+		// A zero seed doesn't leave xorshift stuck
+		assert!(script(0, 0).iter().any(|pick| *pick != 0));
+	}
+
+	#[test]
+	fn divergence_is_reported_where_it_starts() {
+		assert!(compare("Client 0", b"abc", b"abc").is_ok());
+		let e = compare("Client 0", b"abxd", b"abc").unwrap_err();
+		assert!(e.to_string().contains("Client 0 diverged"), "{}", e);
+		assert!(
+			e.to_string().contains("at byte 2 (4 bytes against 3)"),
+			"{}",
+			e
+		);
+		let e = compare("Replica", b"ab", b"abc").unwrap_err();
+		assert!(e.to_string().contains("at byte 2"), "{}", e);
+	}
+
+	#[test]
+	fn percentiles_are_picked_from_sorted_latencies() {
+		let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+		assert_eq!(percentile(&latencies, 50.0), Duration::from_millis(51));
+		assert_eq!(percentile(&latencies, 99.0), Duration::from_millis(99));
+		assert_eq!(percentile(&latencies, 100.0), Duration::from_millis(100));
+		assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+	}
+
+	#[test]
+	fn replicas_apply_updates_as_the_server_did() {
+		use crate::message::{UpdateAdd, UpdateRemove};
+
+		let mut replica = Replica {
+			data: b"hello".to_vec(),
+		};
+		replica.apply(&UpdateData::Add(UpdateAdd {
+			offset: 5,
+			data: b" world".to_vec(),
+		}));
+		replica.apply(&UpdateData::Remove(UpdateRemove { offset: 0, len: 1 }));
+		assert_eq!(replica.data, b"ello world");
+	}
+}
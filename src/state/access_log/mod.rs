@@ -0,0 +1,151 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{spawn, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+
+use crate::config::AccessLogConfig;
+use crate::error::EditrResult;
+
+// Appends a line per operation to a file, from a thread of its own so that
+// requests never wait on the disk
+pub struct AccessLog {
+	queue: Mutex<Option<Sender<String>>>,
+	thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AccessLog {
+	pub fn start(config: &AccessLogConfig) -> EditrResult<AccessLog> {
+		let path = config.path.clone();
+		let max_size = config.max_size;
+		let mut file = open(&path)?;
+		let mut size = file.get_ref().metadata()?.len();
+
+		let (queue, receiver) = channel::<String>();
+		let thread = spawn(move || {
+			while let Ok(line) = receiver.recv() {
+				for line in once(line).chain(receiver.try_iter()) {
+					if file.write_all(line.as_bytes()).is_err() {
+						error!("Failed to write to the access log");
+					}
+					size += line.len() as u64;
+				}
+				file.flush().ok();
+
+				// Keep one old log around, and start afresh
+				if size > max_size {
+					match rotate(&path) {
+						Ok(new_file) => {
+							file = new_file;
+							size = 0;
+						}
+						Err(e) => error!("Failed to rotate the access log: {}", e),
+					}
+				}
+			}
+		});
+
+		Ok(AccessLog {
+			queue: Mutex::new(Some(queue)),
+			thread: Mutex::new(Some(thread)),
+		})
+	}
+
+	// Queues a line for the log, stamped with the time
+	pub fn log(&self, entry: &str) {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default();
+		let line = format!("{}.{:03} {}\n", now.as_secs(), now.subsec_millis(), entry);
+		if let Some(queue) = &*self.queue.lock() {
+			queue.send(line).ok();
+		}
+	}
+
+	// Writes out everything queued and stops the logging thread
+	pub fn close(&self) {
+		self.queue.lock().take();
+		if let Some(thread) = self.thread.lock().take() {
+			thread.join().ok();
+		}
+	}
+}
+
+fn open(path: &Path) -> EditrResult<BufWriter<File>> {
+	Ok(BufWriter::new(
+		OpenOptions::new().create(true).append(true).open(path)?,
+	))
+}
+
+// Moves the log at path aside to path.1, replacing any older one
+fn rotate(path: &Path) -> EditrResult<BufWriter<File>> {
+	let mut old_path = PathBuf::from(path).into_os_string();
+	old_path.push(".1");
+	fs::rename(path, old_path)?;
+	open(path)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use crate::config::AccessLogConfig;
+
+	use super::AccessLog;
+
+	fn log_dir(name: &str) -> std::path::PathBuf {
+		let dir = std::env::temp_dir().join(format!("editr-log-{}-{}", name, std::process::id()));
+		fs::remove_dir_all(&dir).ok();
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	#[test]
+	fn closing_writes_out_everything_queued() {
+		let dir = log_dir("close");
+		let log = AccessLog::start(&AccessLogConfig {
+			path: dir.join("access.log"),
+			max_size: u64::MAX,
+		})
+		.unwrap();
+		for i in 0..1000 {
+			log.log(&format!("entry {}", i));
+		}
+		log.close();
+
+		let written = fs::read_to_string(dir.join("access.log")).unwrap();
+		fs::remove_dir_all(&dir).ok();
+		let entries: Vec<&str> = written
+			.lines()
+			.map(|line| line.split_once(' ').unwrap().1)
+			.collect();
+		assert_eq!(entries.len(), 1000);
+		assert_eq!(entries[999], "entry 999");
+	}
+
+	#[test]
+	fn large_logs_are_moved_aside() {
+		let dir = log_dir("rotate");
+		let log = AccessLog::start(&AccessLogConfig {
+			path: dir.join("access.log"),
+			max_size: 1024,
+		})
+		.unwrap();
+		for i in 0..100 {
+			log.log(&format!("entry {}", i));
+		}
+		log.close();
+
+		let current = fs::read_to_string(dir.join("access.log")).unwrap();
+		let old = fs::read_to_string(dir.join("access.log.1")).unwrap();
+		fs::remove_dir_all(&dir).ok();
+		// Rotation happens between batches, so the newest entry may have been
+		// moved aside along with the rest of its batch
+		assert!(!old.is_empty());
+		assert!(format!("{}{}", old, current).ends_with("entry 99\n"));
+	}
+}
@@ -0,0 +1,14 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Identifies a client across the connections its session spans
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ClientId(u64);
+
+impl ClientId {
+	// Allocates an id never handed out before
+	pub fn next() -> ClientId { ClientId(NEXT_ID.fetch_add(1, Ordering::Relaxed)) }
+}
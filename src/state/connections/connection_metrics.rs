@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::MessageTrace;
+
+// Counters describing what a single connection has been doing
+#[derive(Default)]
+pub struct ConnectionMetrics {
+	bytes_read: AtomicU64,
+	bytes_written: AtomicU64,
+	edits: AtomicU64,
+	errors: AtomicU64,
+	throttled: AtomicU64,
+	messages: Mutex<HashMap<&'static str, u64>>,
+	trace: MessageTrace,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MetricsSnapshot {
+	pub messages: HashMap<String, u64>,
+	pub bytes_read: u64,
+	pub bytes_written: u64,
+	pub edits: u64,
+	pub errors: u64,
+	// Messages delayed or refused by the rate limit
+	pub throttled: u64,
+}
+
+impl ConnectionMetrics {
+	// Counts a processed message, and whether it edited a file or failed
+	pub fn record(&self, kind: &'static str, edit: bool, error: bool) {
+		*self.messages.lock().entry(kind).or_insert(0) += 1;
+		if edit {
+			self.edits.fetch_add(1, Ordering::Relaxed);
+		}
+		if error {
+			self.errors.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	pub fn trace(&self) -> &MessageTrace { &self.trace }
+
+	pub fn add_throttled(&self) { self.throttled.fetch_add(1, Ordering::Relaxed); }
+
+	// Sets the total bytes read from the socket so far
+	pub fn set_bytes_read(&self, total: u64) { self.bytes_read.store(total, Ordering::Relaxed); }
+
+	pub fn add_bytes_written(&self, len: u64) {
+		self.bytes_written.fetch_add(len, Ordering::Relaxed);
+	}
+
+	pub fn snapshot(&self) -> MetricsSnapshot {
+		MetricsSnapshot {
+			messages: self
+				.messages
+				.lock()
+				.iter()
+				.map(|(kind, count)| (kind.to_string(), *count))
+				.collect(),
+			bytes_read: self.bytes_read.load(Ordering::Relaxed),
+			bytes_written: self.bytes_written.load(Ordering::Relaxed),
+			edits: self.edits.load(Ordering::Relaxed),
+			errors: self.errors.load(Ordering::Relaxed),
+			throttled: self.throttled.load(Ordering::Relaxed),
+		}
+	}
+}
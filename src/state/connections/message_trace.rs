@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+// Messages kept per connection, the oldest making way for new ones
+pub const TRACE_LEN: usize = 64;
+// Longest message kind kept, longer ones being cut short
+const KIND_LEN: usize = 24;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+	Received,
+	Sent,
+}
+
+// What was recorded of a message, kept inline so recording never allocates
+#[derive(Clone, Copy)]
+struct Traced {
+	direction: Direction,
+	at: SystemTime,
+	kind: [u8; KIND_LEN],
+	kind_len: u8,
+	size: usize,
+}
+
+// A message as shown to administrators and in the log, without its contents
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TraceEntry {
+	pub direction: Direction,
+	// Milliseconds since the Unix epoch
+	pub time_ms: u64,
+	pub kind: String,
+	pub size: u64,
+}
+
+impl fmt::Display for TraceEntry {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let arrow = match self.direction {
+			Direction::Received => "<=",
+			Direction::Sent => "=>",
+		};
+		write!(
+			f,
+			"{}.{:03} {} {} ({} bytes)",
+			self.time_ms / 1000,
+			self.time_ms % 1000,
+			arrow,
+			self.kind,
+			self.size
+		)
+	}
+}
+
+// The last messages to pass over a connection, for debugging
+pub struct MessageTrace {
+	entries: Mutex<VecDeque<Traced>>,
+}
+
+impl Default for MessageTrace {
+	fn default() -> Self {
+		MessageTrace {
+			entries: Mutex::new(VecDeque::with_capacity(TRACE_LEN)),
+		}
+	}
+}
+
+impl MessageTrace {
+	pub fn record(&self, direction: Direction, kind: &str, size: usize) {
+		let kind_len = kind.len().min(KIND_LEN);
+		let mut traced = Traced {
+			direction,
+			at: SystemTime::now(),
+			kind: [0; KIND_LEN],
+			kind_len: kind_len as u8,
+			size,
+		};
+		traced.kind[..kind_len].copy_from_slice(&kind.as_bytes()[..kind_len]);
+
+		let mut entries = self.entries.lock();
+		if entries.len() == TRACE_LEN {
+			entries.pop_front();
+		}
+		entries.push_back(traced);
+	}
+
+	// Lists what has been recorded, oldest first
+	pub fn entries(&self) -> Vec<TraceEntry> {
+		self.entries
+			.lock()
+			.iter()
+			.map(|traced| TraceEntry {
+				direction: traced.direction,
+				time_ms: traced
+					.at
+					.duration_since(UNIX_EPOCH)
+					.map_or(0, |time| time.as_millis() as u64),
+				kind: String::from_utf8_lossy(&traced.kind[..traced.kind_len as usize])
+					.into_owned(),
+				size: traced.size as u64,
+			})
+			.collect()
+	}
+}
+
+// Names the message serialised in data, from its leading "Name" or {"Name":
+pub fn message_kind(data: &[u8]) -> &str {
+	let data = data.strip_prefix(b"{").unwrap_or(data);
+	let name = match data.strip_prefix(b"\"") {
+		Some(name) => name,
+		None => return "Unknown",
+	};
+	let end = name.iter().position(|&byte| byte == b'"').unwrap_or(0);
+	std::str::from_utf8(&name[..end]).unwrap_or("Unknown")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Direction, MessageTrace, KIND_LEN, TRACE_LEN};
+
+	#[test]
+	fn the_oldest_messages_rotate_out() {
+		let trace = MessageTrace::default();
+		for size in 0..TRACE_LEN + 10 {
+			trace.record(Direction::Received, "Ping", size);
+		}
+
+		let entries = trace.entries();
+		assert_eq!(entries.len(), TRACE_LEN);
+		let sizes = entries.iter().map(|entry| entry.size).collect::<Vec<_>>();
+		let expected = (10..TRACE_LEN as u64 + 10).collect::<Vec<_>>();
+		assert_eq!(sizes, expected);
+	}
+
+	#[test]
+	fn long_kinds_are_cut_short() {
+		let trace = MessageTrace::default();
+		let kind = "K".repeat(KIND_LEN * 2);
+		trace.record(Direction::Sent, &kind, 1);
+
+		let entries = trace.entries();
+		assert_eq!(entries[0].kind, kind[..KIND_LEN]);
+		assert_eq!(entries[0].direction, Direction::Sent);
+	}
+}
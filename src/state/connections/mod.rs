@@ -0,0 +1,255 @@
+mod connection_metrics;
+mod message_trace;
+
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+pub use self::connection_metrics::*;
+pub use self::message_trace::*;
+use crate::error::EditrResult;
+use crate::message::Message;
+use crate::state::shared_out::SharedOut;
+use crate::state::{ClientId, Transport};
+
+// Time given to the last writes to a kicked connection
+const KICK_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Why the server ended a connection, which the client is told before it goes
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DisconnectReason {
+	Kicked,
+	IdleTimeout,
+	// Nothing was received after connecting
+	HandshakeTimeout,
+	// Heartbeats went unanswered
+	HeartbeatTimeout,
+	MessageTooLarge,
+	TooManyInvalidMessages,
+	// A message the server never accepts was sent
+	ProtocolViolation,
+	ServerShutdown,
+}
+
+struct Connection<T> {
+	stream: T,
+	opened: Instant,
+	last_active: Instant,
+	// Whether a whole message has been received yet
+	greeted: bool,
+	evicted: bool,
+	metrics: Arc<ConnectionMetrics>,
+	// Who the connection is serving, kept up to date by its thread
+	client: ClientId,
+	user: Option<String>,
+	opened_file: Option<PathBuf>,
+}
+
+// What an administrator is shown about a connection
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConnectionInfo {
+	pub id: ClientId,
+	pub client: ClientId,
+	pub user: Option<String>,
+	pub address: Option<String>,
+	pub opened_file: Option<PathBuf>,
+	pub idle_ms: u64,
+	pub metrics: MetricsSnapshot,
+}
+
+// Every live connection, so they can be supervised from outside their threads
+pub struct Connections<T: Transport = TcpStream> {
+	container: Arc<Mutex<HashMap<ClientId, Connection<T>>>>,
+}
+
+impl<T: Transport> Clone for Connections<T> {
+	fn clone(&self) -> Self {
+		Connections {
+			container: self.container.clone(),
+		}
+	}
+}
+
+impl<T: Transport> Default for Connections<T> {
+	fn default() -> Self { Self::new() }
+}
+
+impl<T: Transport> Connections<T> {
+	pub fn new() -> Connections<T> {
+		Connections {
+			container: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	// Registers a new connection by id, returning its metrics
+	pub fn insert(&self, id: ClientId, stream: &T) -> EditrResult<Arc<ConnectionMetrics>> {
+		let metrics = Arc::new(ConnectionMetrics::default());
+		let connection = Connection {
+			stream: stream.try_clone()?,
+			opened: Instant::now(),
+			last_active: Instant::now(),
+			greeted: false,
+			evicted: false,
+			metrics: metrics.clone(),
+			client: id,
+			user: None,
+			opened_file: None,
+		};
+		self.container.lock().insert(id, connection);
+		Ok(metrics)
+	}
+
+	// Unregisters a connection, returning true if it had been evicted
+	pub fn remove(&self, id: ClientId) -> bool {
+		matches!(
+			self.container.lock().remove(&id),
+			Some(Connection { evicted: true, .. })
+		)
+	}
+
+	// Records activity on the connection
+	pub fn touch(&self, id: ClientId) {
+		if let Some(connection) = self.container.lock().get_mut(&id) {
+			connection.last_active = Instant::now();
+			connection.greeted = true;
+		}
+	}
+
+	// Records who the connection is serving now
+	pub fn describe(
+		&self,
+		id: ClientId,
+		client: ClientId,
+		user: Option<String>,
+		opened_file: Option<PathBuf>,
+	) {
+		if let Some(connection) = self.container.lock().get_mut(&id) {
+			connection.client = client;
+			connection.user = user;
+			connection.opened_file = opened_file;
+		}
+	}
+
+	// Describes every connection
+	pub fn list(&self) -> Vec<ConnectionInfo> {
+		self.container
+			.lock()
+			.iter()
+			.map(|(id, connection)| ConnectionInfo {
+				id: *id,
+				client: connection.client,
+				user: connection.user.clone(),
+				address: connection
+					.stream
+					.peer_addr()
+					.map(|address| address.to_string()),
+				opened_file: connection.opened_file.clone(),
+				idle_ms: connection.last_active.elapsed().as_millis() as u64,
+				metrics: connection.metrics.snapshot(),
+			})
+			.collect()
+	}
+
+	// Lists the last messages to pass over a connection
+	pub fn trace(&self, id: ClientId) -> Option<Vec<TraceEntry>> {
+		self.container
+			.lock()
+			.get(&id)
+			.map(|connection| connection.metrics.trace().entries())
+	}
+
+	// Looks up the client a connection is serving
+	pub fn client(&self, id: ClientId) -> Option<ClientId> {
+		self.container
+			.lock()
+			.get(&id)
+			.map(|connection| connection.client)
+	}
+
+	// Ends a connection for reason, telling the client why first as best it
+	// can. Returns false if there was no such connection
+	pub fn end(
+		&self,
+		id: ClientId,
+		reason: DisconnectReason,
+		detail: Option<String>,
+		out: &SharedOut<T>,
+	) -> bool {
+		let client = match self.client(id) {
+			Some(client) => client,
+			None => return false,
+		};
+		info!("Disconnecting {:?}: {:?}", id, reason);
+		if let Ok(notice) = Message::make_disconnecting(reason, detail).to_vec() {
+			out.write(client, &notice).ok();
+		}
+		self.kick(id)
+	}
+
+	// Evicts a connection. Only reading is shut down, so what is already
+	// queued for it, such as word of why, still goes out as its thread
+	// cleans up. Writes are given little time, in case the peer is gone
+	pub fn kick(&self, id: ClientId) -> bool {
+		match self.container.lock().get_mut(&id) {
+			Some(connection) => {
+				connection.evicted = true;
+				connection
+					.stream
+					.set_write_timeout(Some(KICK_WRITE_TIMEOUT))
+					.ok();
+				connection.stream.shutdown(Shutdown::Read).ok();
+				true
+			}
+			None => false,
+		}
+	}
+
+	// Snapshots the metrics of every connection
+	pub fn metrics(&self) -> Vec<(ClientId, MetricsSnapshot)> {
+		self.container
+			.lock()
+			.iter()
+			.map(|(id, connection)| (*id, connection.metrics.snapshot()))
+			.collect()
+	}
+
+	// Lists every connection not already evicted
+	pub fn ids(&self) -> Vec<ClientId> {
+		self.container
+			.lock()
+			.iter()
+			.filter(|(_, connection)| !connection.evicted)
+			.map(|(id, _)| *id)
+			.collect()
+	}
+
+	// Lists connections which have not sent a whole message within timeout
+	// of opening, along with where they came from
+	pub fn silent(&self, timeout: Duration) -> Vec<(ClientId, Option<SocketAddr>)> {
+		self.container
+			.lock()
+			.iter()
+			.filter(|(_, connection)| {
+				!connection.evicted && !connection.greeted && connection.opened.elapsed() > timeout
+			})
+			.map(|(id, connection)| (*id, connection.stream.peer_addr()))
+			.collect()
+	}
+
+	// Lists connections idle for longer than timeout
+	pub fn idle(&self, timeout: Duration) -> Vec<ClientId> {
+		self.container
+			.lock()
+			.iter()
+			.filter(|(_, connection)| {
+				!connection.evicted && connection.last_active.elapsed() > timeout
+			})
+			.map(|(id, _)| *id)
+			.collect()
+	}
+}
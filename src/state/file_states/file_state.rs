@@ -1,121 +1,404 @@
 use std::collections::HashMap;
-use std::error::Error;
-use std::ops::Deref;
-use std::sync::{Mutex, MutexGuard};
-use std::thread::ThreadId;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::error::EditrResult;
-use crate::rope::Rope;
+use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use serde::{Deserialize, Serialize};
 
-pub(super) struct FileState {
-	rope: Rope,
-	clients: Mutex<HashMap<ThreadId, (usize, Option<String>)>>,
+use crate::error::{EditrError, EditrResult};
+use crate::message::Cursors;
+use crate::rope::{Rope, RopeStats};
+use crate::state::{ClientId, Journal, JournalEdit, OnDisk, OpTimer, Phase};
+
+pub struct FileState {
+	// Written while an edit is applied and recorded
+	rope: RwLock<Rope>,
+	clients: Mutex<HashMap<ClientId, (usize, Option<String>)>>,
+	path: PathBuf,
+	journal: Option<Arc<Journal>>,
+	// What the file on disk holds, as of when it was read in or last saved.
+	// Only kept track of for journaled files
+	on_disk: Mutex<OnDisk>,
+	// Holds edits which have not been saved
+	dirty: AtomicBool,
+	// The last attempt to save failed
+	persist_failing: AtomicBool,
+	// Holds UTF-8 text, so edits at cursors are kept off the middle of
+	// characters
+	utf8: AtomicBool,
+	last_edited: Mutex<Instant>,
+	last_compacted: Mutex<Option<SystemTime>>,
+}
+
+// Describes an open file for the server's metrics
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileStats {
+	pub path: PathBuf,
+	pub bytes: u64,
+	pub depth: u64,
+	// Seconds since the Unix epoch
+	pub last_compacted: Option<u64>,
+	pub persist_failing: bool,
 }
 
-impl Deref for FileState {
-	type Target = Rope;
-	fn deref(&self) -> &Self::Target { &self.rope }
+// Where the journal was when a snapshot was taken, and what saving the
+// snapshot leaves on disk
+pub struct Mark {
+	seq: u64,
+	on_disk: OnDisk,
 }
 
 impl FileState {
-	pub fn new(rope: Rope) -> FileState {
+	pub fn new(rope: Rope, path: PathBuf, journal: Option<Arc<Journal>>) -> FileState {
+		let on_disk = match journal {
+			Some(_) => OnDisk::of(&rope),
+			None => OnDisk::default(),
+		};
+		FileState::recovered(rope, path, journal, on_disk, false)
+	}
+
+	// A file read in as rope, with what is on disk described by on_disk.
+	// dirty if rope holds edits which were never saved
+	pub fn recovered(
+		rope: Rope,
+		path: PathBuf,
+		journal: Option<Arc<Journal>>,
+		on_disk: OnDisk,
+		dirty: bool,
+	) -> FileState {
 		FileState {
-			rope,
+			rope: RwLock::new(rope),
 			clients: Mutex::new(HashMap::new()),
+			path,
+			journal,
+			on_disk: Mutex::new(on_disk),
+			dirty: AtomicBool::new(dirty),
+			persist_failing: AtomicBool::new(false),
+			utf8: AtomicBool::new(false),
+			last_edited: Mutex::new(Instant::now()),
+			last_compacted: Mutex::new(None),
 		}
 	}
 
-	// Inserts a new client by their ThreadId
-	pub fn add_client(&self, id: ThreadId, name: Option<String>) -> EditrResult<()> {
+	// Reads the rope, holding off edits until the guard is dropped
+	pub fn rope(&self) -> RwLockReadGuard<'_, Rope> { self.rope.read() }
+
+	// Inserts input at index, recording the edit in the journal
+	pub fn insert_at(&self, index: usize, input: &[u8], timer: &OpTimer) -> EditrResult<()> {
+		self.edit(
+			timer,
+			|rope| rope.insert_at(index, input),
+			|_| JournalEdit::Insert {
+				offset: index,
+				data: input.to_vec(),
+			},
+		)
+	}
+
+	// Removes from 'from' to 'to', recording the edit in the journal
+	pub fn remove_range(&self, from: usize, to: usize, timer: &OpTimer) -> EditrResult<()> {
+		self.edit(
+			timer,
+			|rope| rope.remove_range(from, to),
+			|_| JournalEdit::Remove { from, to },
+		)
+	}
+
+	// Replaces from 'from' to 'to' with data, recording the edit in the journal
+	pub fn replace_range(
+		&self,
+		from: usize,
+		to: usize,
+		data: &[u8],
+		timer: &OpTimer,
+	) -> EditrResult<()> {
+		self.edit(
+			timer,
+			|rope| rope.replace_range(from, to, data),
+			|_| JournalEdit::Replace {
+				from,
+				to,
+				data: data.to_vec(),
+			},
+		)
+	}
+
+	pub fn path(&self) -> &PathBuf { &self.path }
+
+	pub fn is_dirty(&self) -> bool { self.dirty.load(Ordering::SeqCst) }
+
+	pub fn mark_dirty(&self) { self.dirty.store(true, Ordering::SeqCst); }
+
+	pub fn persist_failing(&self) -> bool { self.persist_failing.load(Ordering::SeqCst) }
+
+	pub fn is_utf8(&self) -> bool { self.utf8.load(Ordering::SeqCst) }
+
+	// Flags the file as UTF-8 text, or not. Writes at cursors in the middle
+	// of a character land at its start instead
+	pub fn set_utf8(&self, utf8: bool) { self.utf8.store(utf8, Ordering::SeqCst); }
+
+	// Notes that saving failed, so the edits taken by snapshot are still unsaved
+	pub fn save_failed(&self) {
+		self.persist_failing.store(true, Ordering::SeqCst);
+		self.mark_dirty();
+	}
+
+	// Takes the whole contents to be saved, along with where the journal
+	// was at the time, which is handed back to saved once they are on disk.
+	// The file counts as clean from here on unless the save fails
+	pub fn snapshot(&self) -> EditrResult<(Vec<u8>, Mark)> {
+		let mut rope = self.rope.write();
+		rope.flatten();
+		let contents = rope.collect(0, rope.len())?;
+		Ok((contents, self.take_mark(&rope)))
+	}
+
+	// Hands the rope to save with no edits made until it returns, along
+	// with where the journal was at the time, as snapshot does. Lets the
+	// contents be written out without first being copied
+	pub fn snapshot_with<T, F: FnOnce(&Rope) -> EditrResult<T>>(
+		&self,
+		save: F,
+	) -> EditrResult<(T, Mark)> {
+		let rope = self.rope.read();
+		let saved = save(&rope)?;
+		Ok((saved, self.take_mark(&rope)))
+	}
+
+	// Where the journal is, with the file counted as clean from here on.
+	// Called with the rope locked, so no edit falls between the two
+	fn take_mark(&self, rope: &Rope) -> Mark {
+		let mark = match &self.journal {
+			Some(journal) => Mark {
+				seq: journal.mark(),
+				on_disk: OnDisk::of(rope),
+			},
+			None => Mark {
+				seq: 0,
+				on_disk: OnDisk::default(),
+			},
+		};
+		self.dirty.store(false, Ordering::SeqCst);
+		mark
+	}
+
+	// Called once a snapshot has been written to disk
+	pub fn saved(&self, mark: Mark) {
+		self.persist_failing.store(false, Ordering::SeqCst);
+		if let Some(journal) = &self.journal {
+			*self.on_disk.lock() = mark.on_disk;
+			journal.saved(&self.path, mark.seq, mark.on_disk);
+		}
+	}
+
+	// Called once the file is closed without its edits being saved
+	pub fn discarded(&self) {
+		let _rope = self.rope.read();
+		if self.dirty.swap(false, Ordering::SeqCst) {
+			if let Some(journal) = &self.journal {
+				journal.discarded(&self.path, journal.mark());
+			}
+		}
+	}
+
+	// Flattens the rope once it has gone idle without edits, returning whether
+	// it did. Files being edited or read are left for another time
+	pub fn compact(&self, idle: Duration) -> EditrResult<bool> {
+		let mut rope = match self.rope.try_write() {
+			Some(rope) => rope,
+			None => return Ok(false),
+		};
+		let last_edited = *self.last_edited.lock();
+		if last_edited.elapsed() < idle || rope.is_flat() {
+			return Ok(false);
+		}
+		rope.flatten();
+		*self.last_compacted.lock() = Some(SystemTime::now());
+		Ok(true)
+	}
+
+	pub fn stats(&self) -> EditrResult<FileStats> {
+		let last_compacted = *self.last_compacted.lock();
+		let rope = self.rope.read();
+		Ok(FileStats {
+			path: self.path.clone(),
+			bytes: rope.len() as u64,
+			depth: rope.depth() as u64,
+			last_compacted: last_compacted
+				.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+				.map(|time| time.as_secs()),
+			persist_failing: self.persist_failing(),
+		})
+	}
+
+	// The shape of the rope, as opposed to stats on the file
+	pub fn rope_stats(&self) -> RopeStats { self.rope.read().stats() }
+
+	// Applies an edit and records it, so edits reach the journal in the
+	// order they reach the rope. edit describes what apply did, given what
+	// it returned. Every edit goes through here, so this is where timer
+	// learns how long the locks and the rope took
+	fn edit<T, A: FnOnce(&mut Rope) -> EditrResult<T>, E: FnOnce(&T) -> JournalEdit>(
+		&self,
+		timer: &OpTimer,
+		apply: A,
+		edit: E,
+	) -> EditrResult<T> {
+		let mut rope = self.rope.write();
+		timer.mark(Phase::Acquire);
+		let applied = apply(&mut rope)?;
+		self.dirty.store(true, Ordering::SeqCst);
+		*self.last_edited.lock() = Instant::now();
+		if let Some(journal) = &self.journal {
+			journal.edit(&self.path, edit(&applied), *self.on_disk.lock());
+		}
+		timer.mark(Phase::Apply);
+		Ok(applied)
+	}
+
+	// Inserts a new client by their ClientId
+	pub fn add_client(&self, id: ClientId, name: Option<String>) -> EditrResult<()> {
 		self.clients_op(|mut clients| Ok(clients.insert(id, (0, name))))?;
 		Ok(())
 	}
 
-	// Removes a client by their ThreadId
-	pub fn remove_client(&self, id: ThreadId) -> EditrResult<()> {
-		self.clients_op(|mut clients| Ok(clients.remove(&id)))?;
-		Ok(())
+	// Removes a client by their ClientId.
+	// broadcast is handed the client's name and the remaining clients
+	pub fn remove_client<F: FnOnce(Option<String>, Vec<ClientId>) -> EditrResult<()>>(
+		&self,
+		id: ClientId,
+		broadcast: F,
+	) -> EditrResult<()> {
+		self.clients_op(|mut clients| match clients.remove(&id) {
+			Some((_, name)) => broadcast(name, clients.keys().cloned().collect()),
+			None => Ok(()),
+		})
 	}
 
 	// Returns true if self doesn't have any clients
 	pub fn no_clients(&self) -> EditrResult<bool> {
-		Ok(self.clients_op(|clients| Ok(clients.is_empty()))?)
+		self.clients_op(|clients| Ok(clients.is_empty()))
 	}
 
-	// Calls a closure f on each client
-	pub fn for_each_client<F: Fn(ThreadId) -> EditrResult<()>>(
-		&self,
-		f: F,
-	) -> Result<(), Box<dyn Error>> {
-		self.clients_op(|clients| {
-			for (key, _) in clients.iter() {
-				f(*key)?;
-			}
-			Ok(())
-		})
+	// Returns every client in the file
+	pub fn clients(&self) -> EditrResult<Vec<ClientId>> {
+		self.clients_op(|clients| Ok(clients.keys().copied().collect()))
+	}
+
+	// Lists every client other than id
+	pub fn neighbours(&self, id: ClientId) -> EditrResult<Vec<ClientId>> {
+		self.clients_op(|clients| Ok(neighbours(&clients, id)))
 	}
 
-	pub fn move_cursor(&self, id: ThreadId, offset: isize) -> EditrResult<()> {
-		Ok(self.clients_op(|mut clients| {
+	pub fn move_cursor(&self, id: ClientId, offset: isize) -> EditrResult<()> {
+		self.clients_op(|mut clients| {
 			if let Some((found_offset, name)) = clients.get(&id) {
 				let name_clone = name.clone();
-				let new_offset_signed = *found_offset as isize + offset;
-				let new_offset_unsigned = new_offset_signed as usize;
-				clients.insert(id, (new_offset_unsigned, name_clone));
+				// Cursors stop at the start rather than wrapping round
+				let new_offset = found_offset.saturating_add_signed(offset);
+				clients.insert(id, (new_offset, name_clone));
 			}
 			Ok(())
-		})?)
+		})
 	}
 
-	pub fn write_at_cursor(&self, id: ThreadId, data: &[u8]) -> EditrResult<usize> {
+	// Inserts data at id's cursor, shifting every cursor at or after it.
+	// broadcast is handed the effective offset and the other clients while
+	// the clients lock is still held, so peers see edits in the order applied
+	pub fn write_at_cursor<F: FnOnce(usize, Vec<ClientId>) -> EditrResult<()>>(
+		&self,
+		id: ClientId,
+		data: &[u8],
+		timer: &OpTimer,
+		broadcast: F,
+	) -> EditrResult<usize> {
 		self.clients_op(|mut clients| {
 			let found_value = match clients.get(&id) {
 				Some((found_offset, _)) => *found_offset,
-				None => return Err("ID not found in clients".into()),
+				None => return Err(EditrError::Internal("ID not found in clients".to_string())),
 			};
 
-			self.insert_at(found_value, data)?;
+			// A cursor moved past the end writes at the end
+			let at = self.edit(
+				timer,
+				|rope| {
+					let mut at = found_value.min(rope.len());
+					if self.is_utf8() {
+						at = rope.prev_char_boundary(at)?;
+					}
+					rope.insert_at(at, data)?;
+					Ok(at)
+				},
+				|at| JournalEdit::Insert {
+					offset: *at,
+					data: data.to_vec(),
+				},
+			)?;
 
 			for (_, (found_offset, _)) in clients.iter_mut() {
-				if *found_offset >= found_value {
-					let new_offset_signed = *found_offset as isize + data.len() as isize;
-					*found_offset = new_offset_signed as usize;
+				if *found_offset >= at {
+					*found_offset = found_offset.saturating_add(data.len());
 				}
 			}
-			Ok(found_value)
+
+			broadcast(at, neighbours(&clients, id))?;
+			Ok(at)
 		})
 	}
 
-	pub fn remove_at_cursor(&self, id: ThreadId, len: usize) -> EditrResult<usize> {
-		Ok(self.clients_op(|mut clients| {
+	// Removes len bytes at id's cursor, pulling back every cursor after it.
+	// broadcast is called under the clients lock, as in write_at_cursor.
+	// Returns the offset along with the bytes removed
+	pub fn remove_at_cursor<F: FnOnce(usize, usize, Vec<ClientId>) -> EditrResult<()>>(
+		&self,
+		id: ClientId,
+		len: usize,
+		timer: &OpTimer,
+		broadcast: F,
+	) -> EditrResult<(usize, Vec<u8>)> {
+		self.clients_op(|mut clients| {
 			let found_value = match clients.get(&id) {
 				Some((found_offset, _)) => *found_offset,
-				None => return Err("ID not found in clients".into()),
+				None => return Err(EditrError::Internal("ID not found in clients".to_string())),
 			};
 
-			self.remove_range(found_value, found_value + len)?;
+			// Removing past the end only removes what there is, which is
+			// nothing if the cursor has been moved past it
+			let (from, removed) = self.edit(
+				timer,
+				|rope| {
+					let from = found_value.min(rope.len());
+					let end = found_value.saturating_add(len).min(rope.len());
+					let removed = rope.collect(from, end)?;
+					rope.remove_range(from, end)?;
+					Ok((from, removed))
+				},
+				|(from, removed)| JournalEdit::Remove {
+					from: *from,
+					to: from + removed.len(),
+				},
+			)?;
 
+			// Only what was actually removed moves cursors, so a removal cut
+			// short at the end leaves cursors past it where they were
 			for (_, (found_offset, _)) in clients.iter_mut() {
-				if *found_offset >= found_value {
-					let new_offset_signed = *found_offset as isize - len as isize;
-					let new_offset_signed = if new_offset_signed < found_value as isize {
-						found_value
-					}
-					else {
-						new_offset_signed as usize
-					};
-					*found_offset = new_offset_signed as usize;
+				if *found_offset >= from {
+					*found_offset = found_offset.saturating_sub(removed.len()).max(from);
 				}
 			}
-			Ok(found_value)
-		})?)
+
+			broadcast(from, removed.len(), neighbours(&clients, id))?;
+			Ok((from, removed))
+		})
 	}
 
-	pub fn get_cursors(&self, id: ThreadId) -> EditrResult<(usize, Vec<(usize, Option<String>)>)> {
-		Ok(self.clients_op(|clients| {
+	pub fn get_cursors(&self, id: ClientId) -> EditrResult<Cursors> {
+		self.clients_op(|clients| {
 			let found_value = match clients.get(&id) {
 				Some((found_offset, _)) => *found_offset,
-				None => return Err("ID not found in clients".into()),
+				None => return Err(EditrError::Internal("ID not found in clients".to_string())),
 			};
 
 			let others = clients
@@ -124,17 +407,182 @@ impl FileState {
 				.collect();
 
 			Ok((found_value, others))
-		})?)
+		})
 	}
 
-	// Locks clients and applies op
+	// Locks clients and applies op.
+	// Locks are never poisoned, so clients which panicked mid-operation can
+	// still be removed
 	fn clients_op<
 		T,
-		F: FnOnce(MutexGuard<HashMap<ThreadId, (usize, Option<String>)>>) -> EditrResult<T>,
+		F: FnOnce(MutexGuard<HashMap<ClientId, (usize, Option<String>)>>) -> EditrResult<T>,
 	>(
 		&self,
 		op: F,
-	) -> Result<T, Box<dyn Error>> {
-		op(self.clients.lock().map_err(|e| e.to_string())?)
+	) -> EditrResult<T> {
+		op(self.clients.lock())
+	}
+}
+
+// Lists every client other than id
+fn neighbours(clients: &HashMap<ClientId, (usize, Option<String>)>, id: ClientId) -> Vec<ClientId> {
+	clients
+		.keys()
+		.filter(|&&client| client != id)
+		.cloned()
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::panic::{catch_unwind, AssertUnwindSafe};
+	use std::path::PathBuf;
+	use std::thread;
+
+	use parking_lot::Mutex;
+
+	use super::FileState;
+	use crate::error::EditrResult;
+	use crate::rope::Rope;
+	use crate::state::{ClientId, JournalEdit, OpTimer};
+
+	// Runs op, which is expected to panic
+	fn panics<F: FnOnce()>(op: F) {
+		assert!(catch_unwind(AssertUnwindSafe(op)).is_err());
+	}
+
+	#[test]
+	fn a_panic_holding_a_lock_leaves_the_file_usable() {
+		let file = FileState::new(Rope::new(), PathBuf::from("file.txt"), None);
+		let timer = OpTimer::start();
+		let (id, other) = (ClientId::next(), ClientId::next());
+		file.add_client(id, None).unwrap();
+		file.add_client(other, None).unwrap();
+
+		// Mid-edit, mid-save and mid-broadcast
+		panics(|| {
+			file.edit(
+				&timer,
+				|_| -> EditrResult<()> { panic!("Panicked editing") },
+				|_| -> JournalEdit { unreachable!() },
+			)
+			.ok();
+		});
+		panics(|| {
+			file.snapshot_with(|_| -> EditrResult<()> { panic!("Panicked saving") })
+				.ok();
+		});
+		panics(|| {
+			file.write_at_cursor(id, b"lost", &timer, |_, _| panic!("Panicked broadcasting"))
+				.ok();
+		});
+
+		file.insert_at(0, b"hello", &timer).unwrap();
+		file.move_cursor(other, 5).unwrap();
+		file.write_at_cursor(other, b"!", &timer, |_, _| Ok(()))
+			.unwrap();
+		file.remove_client(id, |_, _| Ok(())).unwrap();
+		assert_eq!(file.clients().unwrap(), [other]);
+		// The write made before its broadcast panicked stands
+		let (contents, _) = file.snapshot().unwrap();
+		assert_eq!(contents, b"hellolost!");
+	}
+
+	#[test]
+	fn writes_at_cursors_in_utf8_files_land_between_characters() {
+		let rope = Rope::from_reader("h\u{e9}llo".as_bytes()).unwrap();
+		let file = FileState::new(rope, PathBuf::from("file.txt"), None);
+		let timer = OpTimer::start();
+		let id = ClientId::next();
+		file.add_client(id, None).unwrap();
+
+		// Into the middle of the e-acute
+		file.move_cursor(id, 2).unwrap();
+		file.set_utf8(true);
+		file.write_at_cursor(id, b"x", &timer, |_, _| Ok(()))
+			.unwrap();
+		let (contents, _) = file.snapshot().unwrap();
+		assert_eq!(contents, "hx\u{e9}llo".as_bytes());
+
+		// The cursor moved along with the write, so is mid character again.
+		// Files not flagged as UTF-8 are taken as bytes
+		file.set_utf8(false);
+		file.write_at_cursor(id, b"y", &timer, |_, _| Ok(()))
+			.unwrap();
+		let (contents, _) = file.snapshot().unwrap();
+		assert_eq!(contents, b"hx\xc3y\xa9llo");
+	}
+
+	// An edit as a neighbour is told of it
+	enum Seen {
+		Add(usize, Vec<u8>),
+		Remove(usize, usize),
+	}
+
+	#[test]
+	fn threads_editing_one_file_agree_with_what_neighbours_see() {
+		const EDITORS: usize = 6;
+		const EDITS: usize = 500;
+		let file = FileState::new(Rope::new(), PathBuf::from("file.txt"), None);
+		let ids = (0..EDITORS).map(|_| ClientId::next()).collect::<Vec<_>>();
+		for id in &ids {
+			file.add_client(*id, None).unwrap();
+		}
+		// Broadcasts are made under the clients lock, so this is the order
+		// the edits were applied in
+		let seen = Mutex::new(Vec::new());
+
+		thread::scope(|scope| {
+			for (n, id) in ids.iter().enumerate() {
+				let (file, seen) = (&file, &seen);
+				scope.spawn(move || {
+					let timer = OpTimer::start();
+					let data = vec![b'a' + n as u8; 1 + n % 3];
+					for i in 0..EDITS {
+						file.write_at_cursor(*id, &data, &timer, |at, _| {
+							seen.lock().push(Seen::Add(at, data.clone()));
+							Ok(())
+						})
+						.unwrap();
+						if i % 5 == 0 {
+							file.move_cursor(*id, -2).unwrap();
+							file.remove_at_cursor(*id, 1, &timer, |at, len, _| {
+								seen.lock().push(Seen::Remove(at, len));
+								Ok(())
+							})
+							.unwrap();
+						}
+					}
+				});
+			}
+			// Readers only ever see whole edits
+			scope.spawn(|| {
+				for _ in 0..200 {
+					let rope = file.rope();
+					assert_eq!(rope.collect(0, usize::MAX).unwrap().len(), rope.len());
+				}
+			});
+		});
+
+		let mut replayed = Vec::new();
+		for edit in seen.into_inner() {
+			match edit {
+				Seen::Add(at, data) => {
+					replayed.splice(at..at, data);
+				}
+				Seen::Remove(at, len) => {
+					replayed.drain(at..at + len);
+				}
+			}
+		}
+		let (contents, _) = file.snapshot().unwrap();
+		assert_eq!(contents, replayed);
+		let written = (0..EDITORS).map(|n| EDITS * (1 + n % 3)).sum::<usize>();
+		assert!(contents.len() <= written);
+		assert!(contents.len() >= written - EDITORS * EDITS / 5);
+		// Every cursor was kept within the file as it changed
+		let (own, others) = file.get_cursors(ids[0]).unwrap();
+		assert!(own <= contents.len());
+		assert!(others.iter().all(|(at, _)| *at <= contents.len()));
 	}
 }
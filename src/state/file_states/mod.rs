@@ -1,133 +1,492 @@
 mod file_state;
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::thread::ThreadId;
+use std::time::{Duration, Instant};
 
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use self::file_state::FileState;
-use crate::error::EditrResult;
+pub use self::file_state::{FileState, FileStats, Mark};
+use crate::config::Durability;
+use crate::error::{Context, EditrError, EditrResult};
 use crate::rope::Rope;
+use crate::state::{new_token, ClientId, Journal, JournalEdit, OnDisk, OpTimer};
+
+// Files under this are scratch buffers, held only in memory
+pub const SCRATCH_PREFIX: &str = "scratch://";
+
+// True if path names a scratch buffer rather than a file on disk
+pub fn is_scratch(path: &Path) -> bool {
+	path.to_str()
+		.is_some_and(|path| path.starts_with(SCRATCH_PREFIX))
+}
+
+// Files under this are documents supplied by an embedding application,
+// held in memory and saved however it sees fit
+pub const VIRTUAL_PREFIX: &str = "virtual://";
+
+// True if path names a virtual document rather than a file on disk
+pub fn is_virtual(path: &Path) -> bool {
+	path.to_str()
+		.is_some_and(|path| path.starts_with(VIRTUAL_PREFIX))
+}
+
+// Decides what saving a virtual document means, such as storing it in a
+// database. Handed the document's name without VIRTUAL_PREFIX
+pub trait DocumentHost: Send + Sync {
+	fn save(&self, name: &str, contents: &[u8]) -> EditrResult<()>;
+}
+
+impl<F: Fn(&str, &[u8]) -> EditrResult<()> + Send + Sync> DocumentHost for F {
+	fn save(&self, name: &str, contents: &[u8]) -> EditrResult<()> { self(name, contents) }
+}
+
+// Makes saved files durable. Replaced to check when syncs are asked for
+pub trait SyncHook: Send + Sync {
+	fn sync_file(&self, file: &File) -> io::Result<()>;
+	fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+}
+
+// Syncs through the OS, as used unless FileStates::synced_with says otherwise
+pub struct OsSync;
+
+impl SyncHook for OsSync {
+	fn sync_file(&self, file: &File) -> io::Result<()> { file.sync_all() }
+
+	fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+		// Windows cannot open directories as files, and makes renames
+		// durable along with the file
+		if cfg!(windows) {
+			return Ok(());
+		}
+		File::open(dir)?.sync_all()
+	}
+}
 
 #[derive(Clone, Default)]
 pub struct FileStates {
-	container: Arc<RwLock<HashMap<PathBuf, FileState>>>,
+	container: Arc<RwLock<HashMap<PathBuf, Arc<FileState>>>>,
+	journal: Option<Arc<Journal>>,
+	host: Option<Arc<dyn DocumentHost>>,
+	sync: Option<Arc<dyn SyncHook>>,
 }
 
 impl FileStates {
 	pub fn new() -> FileStates {
 		FileStates {
 			container: Arc::new(RwLock::new(HashMap::new())),
+			journal: None,
+			host: None,
+			sync: None,
+		}
+	}
+
+	// Records every edit made to open files in journal
+	pub fn with_journal(journal: Option<Arc<Journal>>) -> FileStates {
+		FileStates {
+			container: Arc::new(RwLock::new(HashMap::new())),
+			journal,
+			host: None,
+			sync: None,
+		}
+	}
+
+	// Serves documents held in memory alongside the files on disk. Each is
+	// opened as VIRTUAL_PREFIX followed by its name, which may contain '/'
+	// but no empty, '.' or '..' parts. Documents stay loaded while nobody
+	// has them open, and saving one hands its contents to host.
+	// Edits to them are never journaled, as host is where they persist
+	pub fn with_documents<I: IntoIterator<Item = (String, Rope)>>(
+		host: Arc<dyn DocumentHost>,
+		documents: I,
+	) -> EditrResult<FileStates> {
+		let mut container = HashMap::new();
+		for (name, rope) in documents {
+			let valid =
+				!name.is_empty() && name.split('/').all(|part| !matches!(part, "" | "." | ".."));
+			if !valid {
+				return Err(EditrError::InvalidPath(PathBuf::from(name)));
+			}
+			let path = PathBuf::from(format!("{}{}", VIRTUAL_PREFIX, name));
+			let file = Arc::new(FileState::new(rope, path.clone(), None));
+			if container.insert(path.clone(), file).is_some() {
+				return Err(EditrError::AlreadyExists(path));
+			}
+		}
+		Ok(FileStates {
+			container: Arc::new(RwLock::new(container)),
+			journal: None,
+			host: Some(host),
+			sync: None,
+		})
+	}
+
+	// The same files, with edits to files read in from now on recorded
+	// in journal
+	pub fn journaled(self, journal: Option<Arc<Journal>>) -> FileStates {
+		FileStates { journal, ..self }
+	}
+
+	// The same files, with durable saves synced through sync
+	pub fn synced_with(self, sync: Arc<dyn SyncHook>) -> FileStates {
+		FileStates {
+			sync: Some(sync),
+			..self
 		}
 	}
 
+	// Paths of every virtual document, sorted
+	pub fn documents(&self) -> EditrResult<Vec<PathBuf>> {
+		let mut documents = self.op(|container| {
+			Ok(container
+				.keys()
+				.filter(|path| is_virtual(path))
+				.cloned()
+				.collect::<Vec<_>>())
+		})?;
+		documents.sort();
+		Ok(documents)
+	}
+
+	// Loads the file at path with edits left unsaved by an earlier run
+	// applied, keeping it open and unsaved until a client saves or closes it.
+	// Fails if the file is not what on_disk says the edits were made to
+	pub fn recover(
+		&self,
+		path: PathBuf,
+		on_disk: Option<OnDisk>,
+		edits: &[JournalEdit],
+	) -> EditrResult<()> {
+		// The file may never have been saved at all
+		let mut rope = read_to_rope(&path).unwrap_or_default();
+		// Edits made to other contents than the file now holds, such as ones
+		// saved just before the server went down, would be applied twice
+		let found = OnDisk::of(&rope);
+		if on_disk.is_some_and(|on_disk| on_disk != found) {
+			return Err(EditrError::ChangedOnDisk(path));
+		}
+		for edit in edits {
+			match edit {
+				JournalEdit::Insert { offset, data } => rope.insert_at(*offset, data)?,
+				JournalEdit::Remove { from, to } => rope.remove_range(*from, *to)?,
+				JournalEdit::Replace { from, to, data } => rope.replace_range(*from, *to, data)?,
+			}
+		}
+		let file = FileState::recovered(rope, path.clone(), self.journal.clone(), found, true);
+		self.mut_op(|mut container| {
+			container.insert(path, Arc::new(file));
+			Ok(())
+		})
+	}
+
 	// True if container contains file at path
 	pub fn contains(&self, path: &PathBuf) -> EditrResult<bool> {
 		self.op(|container| Ok(container.contains_key(path)))
 	}
 
-	// Opens the file at path for the client.
+	// Describes every open file
+	pub fn stats(&self) -> EditrResult<Vec<FileStats>> {
+		self.op(|container| container.values().map(|file| file.stats()).collect())
+	}
+
+	// Compacts every file which has gone idle for at least idle, returning
+	// the paths of those compacted
+	pub fn compact_idle(&self, idle: Duration) -> EditrResult<Vec<PathBuf>> {
+		let files = self.op(|container| Ok(container.values().cloned().collect::<Vec<_>>()))?;
+		let mut compacted = Vec::new();
+		for file in files {
+			if file.compact(idle)? {
+				compacted.push(file.path().clone());
+			}
+		}
+		Ok(compacted)
+	}
+
+	// Every open file holding edits which are yet to be saved, other than
+	// scratch buffers, which have nowhere to be saved to
+	pub fn unsaved(&self) -> EditrResult<Vec<Arc<FileState>>> {
+		self.op(|container| {
+			Ok(container
+				.iter()
+				.filter(|(path, file)| !is_scratch(path) && file.is_dirty())
+				.map(|(_, file)| file.clone())
+				.collect())
+		})
+	}
+
+	// Opens the file at path for the client, returning a handle to it.
 	// If the file isn't in container, it will be read in.
+	// The handle stays valid for as long as the client remains in the file
 	// TODO: Minimise write lock while avoiding race on insertion
-	pub fn open(&self, path: PathBuf, id: ThreadId, name: Option<String>) -> EditrResult<()> {
+	pub fn open(
+		&self,
+		path: PathBuf,
+		id: ClientId,
+		name: Option<String>,
+	) -> EditrResult<Arc<FileState>> {
 		self.mut_op(|mut container| {
-			match container.get(&path) {
-				Some(file) => file.add_client(id, name)?,
+			let file = match container.get(&path) {
+				Some(file) => file.clone(),
+				// Scratch buffers are gone once their last client leaves
+				None if is_scratch(&path) => {
+					return Err(EditrError::Protocol(format!(
+						"No such scratch buffer: {}",
+						path.display()
+					)))
+				}
+				// Virtual documents only come from the embedder
+				None if is_virtual(&path) => {
+					return Err(EditrError::Protocol(format!(
+						"No such document: {}",
+						path.display()
+					)))
+				}
 				// Read into container if not present
 				None => {
-					let file = FileState::new(read_to_rope(&path)?);
-					file.add_client(id, name)?;
-					container.insert(path.clone(), file);
+					let file = Arc::new(FileState::new(
+						read_to_rope(&path).context("read", Some(&path))?,
+						path.clone(),
+						self.journal.clone(),
+					));
+					container.insert(path, file.clone());
+					file
 				}
-			}
-			Ok(())
+			};
+			file.add_client(id, name)?;
+			Ok(file)
 		})
 	}
 
+	// Opens a new, empty scratch buffer called name for the client,
+	// returning the path others can open it by along with a handle to it.
+	// Edits to it are never journaled, as it has nowhere to be replayed to
+	pub fn open_scratch(&self, name: &str, id: ClientId) -> EditrResult<(PathBuf, Arc<FileState>)> {
+		let path = PathBuf::from(format!("{}{}/{}", SCRATCH_PREFIX, new_token()?, name));
+		let file = Arc::new(FileState::new(Rope::new(), path.clone(), None));
+		file.add_client(id, None)?;
+		self.mut_op(|mut container| {
+			container.insert(path.clone(), file.clone());
+			Ok(())
+		})?;
+		Ok((path, file))
+	}
+
+	// Returns a handle to the file at path, which must already be open
+	pub fn get(&self, path: &PathBuf) -> EditrResult<Arc<FileState>> {
+		self.file_op(path, |file| Ok(file.clone()))
+	}
+
 	// Closes the file at path for client.
-	pub fn close(&self, path: &PathBuf, id: ThreadId) -> EditrResult<()> {
-		self.file_op(path, |file| file.remove_client(id))?;
-		// Remove file from container if there are no clients remaining
+	// broadcast is handed the client's name and the clients left in the file
+	pub fn close<F: FnOnce(Option<String>, Vec<ClientId>) -> EditrResult<()>>(
+		&self,
+		path: &PathBuf,
+		id: ClientId,
+		broadcast: F,
+	) -> EditrResult<()> {
+		let removed = self.file_op(path, |file| file.remove_client(id, broadcast));
+		// Remove file from container if there are no clients remaining,
+		// even if the broadcast failed. Virtual documents have nowhere to
+		// be read back in from, so stay
 		self.mut_op(|mut container| {
+			if is_virtual(path) {
+				return Ok(());
+			}
 			if let Some(state) = container.get(path) {
 				if state.no_clients()? {
+					state.discarded();
 					container.remove(path);
 				}
 			}
 			Ok(())
-		})
+		})?;
+		removed
 	}
 
-	// Reads from the file at path starting from 'from' and ending at 'to'
-	pub fn read(&self, path: &PathBuf, from: usize, to: usize) -> EditrResult<Vec<u8>> {
-		self.file_op(path, |file| file.collect(from, to))
+	// Returns every client in the file at path
+	pub fn clients(&self, path: &PathBuf) -> EditrResult<Vec<ClientId>> {
+		self.file_op(path, |file| file.clients())
 	}
 
-	// Writes to file at path at offset
-	pub fn write(&self, path: &PathBuf, offset: usize, data: &[u8]) -> EditrResult<()> {
-		self.file_op(path, |file| file.insert_at(offset, data))
+	// Reads from the file at path starting from 'from' and ending at 'to'
+	pub fn read(&self, path: &PathBuf, from: usize, to: usize) -> EditrResult<Vec<u8>> {
+		self.file_op(path, |file| file.rope().collect(from, to))
 	}
 
-	// Removes from the file at path, starting from offset
-	pub fn remove(&self, path: &PathBuf, offset: usize, len: usize) -> EditrResult<()> {
-		self.file_op(path, |file| file.remove_range(offset, offset + len))
+	// Reads up to count lines of the file at path from the first'th on, each
+	// with the offset it starts at, as Rope::lines_from
+	pub fn read_lines(
+		&self,
+		path: &PathBuf,
+		first: usize,
+		count: usize,
+	) -> EditrResult<Vec<(usize, Vec<u8>)>> {
+		self.file_op(path, |file| {
+			Ok(file.rope().lines_from(first)?.take(count).collect())
+		})
 	}
 
-	// Flushes file to disk
-	pub fn flush(&self, path: &PathBuf) -> EditrResult<()> {
-		let rope = self.file_op(path, |file| {
-			file.flatten()?;
-			file.collect(0, file.len()?)
-		})?;
-		File::create(&path)?.write_all(&rope)?;
-		Ok(())
+	// Reads the line'th line of the file at path, as Rope::read_line
+	pub fn read_line(&self, path: &PathBuf, line: usize) -> EditrResult<Vec<u8>> {
+		self.file_op(path, |file| file.rope().read_line(line))
 	}
 
-	// Calls a closure f on each client in the file at path
-	pub fn for_each_client<F: Fn(ThreadId) -> EditrResult<()>>(
+	// Replaces from 'from' up to 'to' in the file at path with data, as a
+	// single edit
+	pub fn replace(
 		&self,
 		path: &PathBuf,
-		f: F,
+		from: usize,
+		to: usize,
+		data: &[u8],
+		timer: &OpTimer,
 	) -> EditrResult<()> {
-		self.file_op(path, |file| file.for_each_client(|id| f(id)))
+		self.file_op(path, |file| file.replace_range(from, to, data, timer))
 	}
 
-	pub fn move_cursor(&self, path: &PathBuf, id: ThreadId, offset: isize) -> EditrResult<()> {
-		self.file_op(path, |file| file.move_cursor(id, offset))
+	// Flushes file to disk as durably as asked, or hands a virtual document
+	// to the host. reserve is given the new file size and may refuse the
+	// write, though virtual documents are not on disk so are not counted.
+	// Returns the time spent syncing
+	pub fn flush<F: FnOnce(u64) -> EditrResult<()>>(
+		&self,
+		path: &PathBuf,
+		durability: Durability,
+		reserve: F,
+	) -> EditrResult<Duration> {
+		if is_scratch(path) {
+			return Err(EditrError::ScratchBuffer(path.clone()));
+		}
+		let file = self.get(path)?;
+		let result = match (&self.host, virtual_name(path)) {
+			(Some(host), Some(name)) => file.snapshot().and_then(|(contents, mark)| {
+				host.save(name, &contents)?;
+				Ok((Duration::ZERO, mark))
+			}),
+			(None, Some(_)) => Err(EditrError::NotOpen),
+			// Streamed from the rope, so large files aren't copied first.
+			// Edits wait until the file is written
+			(_, None) => file
+				.snapshot_with(|rope| {
+					reserve(rope.len() as u64)?;
+					self.write_atomic(path, durability, |out| rope.write_to(out))
+				})
+				.context("save", Some(path)),
+		};
+		match result {
+			Ok((synced, mark)) => {
+				file.saved(mark);
+				Ok(synced)
+			}
+			Err(e) => {
+				file.save_failed();
+				Err(e)
+			}
+		}
 	}
 
-	pub fn file_write_cursor(
+	// Flushes every open file to disk, and every virtual document to the
+	// host, returning the outcome for each.
+	// Used when the server is going away, so quotas are not enforced.
+	// Scratch buffers are left to go with it
+	pub fn flush_all(
 		&self,
-		path: &PathBuf,
-		id: ThreadId,
-		data: &[u8],
-	) -> EditrResult<usize> {
-		self.file_op(path, |file| file.write_at_cursor(id, data))
+		durability: Durability,
+	) -> EditrResult<Vec<(PathBuf, EditrResult<Duration>)>> {
+		let paths = self.op(|container| {
+			Ok(container
+				.keys()
+				.filter(|path| !is_scratch(path))
+				.cloned()
+				.collect::<Vec<_>>())
+		})?;
+		Ok(paths
+			.into_iter()
+			.map(|path| {
+				let result = self.flush(&path, durability, |_| Ok(()));
+				(path, result)
+			})
+			.collect())
 	}
 
-	pub fn file_remove_cursor(
+	// Writes the contents of the file at path out to dest, leaving the
+	// file itself untouched. reserve is given the size of the copy
+	pub fn save_as<F: FnOnce(u64) -> EditrResult<()>>(
 		&self,
 		path: &PathBuf,
-		id: ThreadId,
-		len: usize,
-	) -> EditrResult<usize> {
-		self.file_op(path, |file| file.remove_at_cursor(id, len))
+		dest: &Path,
+		durability: Durability,
+		reserve: F,
+	) -> EditrResult<Duration> {
+		let file = self.get(path)?;
+		let rope = file.rope();
+		reserve(rope.len() as u64)?;
+		self.write_atomic(dest, durability, |out| rope.write_to(out))
 	}
 
-	pub fn get_cursors(
+	// Replaces the file at path with what write writes, so readers only ever
+	// see the whole old or the whole new contents. Permissions of an existing
+	// file are kept. Returns the time spent syncing for durability
+	fn write_atomic<F: FnOnce(&mut BufWriter<&File>) -> EditrResult<u64>>(
 		&self,
-		path: &PathBuf,
-		id: ThreadId,
-	) -> EditrResult<(usize, Vec<(usize, Option<String>)>)> {
-		self.file_op(path, |file| file.get_cursors(id))
+		path: &Path,
+		durability: Durability,
+		write: F,
+	) -> EditrResult<Duration> {
+		let sync = self.sync.as_deref().unwrap_or(&OsSync);
+		let (temp_path, temp) = create_temp(path)?;
+		let mut synced = Duration::ZERO;
+		let fill = || -> EditrResult<()> {
+			let mut out = BufWriter::new(&temp);
+			write(&mut out)?;
+			out.flush()?;
+			drop(out);
+			if let Ok(metadata) = fs::metadata(path) {
+				temp.set_permissions(metadata.permissions())?;
+			}
+			if durability == Durability::Fsync {
+				let started = Instant::now();
+				sync.sync_file(&temp)?;
+				synced += started.elapsed();
+			}
+			Ok(())
+		};
+		let result = fill().and_then(|_| Ok(fs::rename(&temp_path, path)?));
+		if let Err(e) = result {
+			fs::remove_file(&temp_path).ok();
+			return Err(e);
+		}
+
+		// The new contents are in place whether or not this works, and some
+		// filesystems refuse to sync directories at all
+		if durability == Durability::Fsync {
+			let dir = match path.parent() {
+				Some(dir) if dir != Path::new("") => dir,
+				_ => Path::new("."),
+			};
+			let started = Instant::now();
+			if let Err(e) = sync.sync_dir(dir) {
+				error!("Failed to sync {}: {}", dir.display(), e);
+			}
+			synced += started.elapsed();
+		}
+		Ok(synced)
+	}
+
+	// Runs f with the container locked, so tests can show what doesn't
+	// need it
+	#[cfg(test)]
+	pub(crate) fn locked<T, F: FnOnce() -> T>(&self, f: F) -> T {
+		let _container = self.container.write();
+		f()
 	}
 
 	// Applies an op that requires a read lock on the underlying container
-	fn op<T, F: FnOnce(RwLockReadGuard<HashMap<PathBuf, FileState>>) -> EditrResult<T>>(
+	fn op<T, F: FnOnce(RwLockReadGuard<HashMap<PathBuf, Arc<FileState>>>) -> EditrResult<T>>(
 		&self,
 		op: F,
 	) -> EditrResult<T> {
@@ -135,7 +494,10 @@ impl FileStates {
 	}
 
 	// Applies an op that requires a write lock on the underlying container
-	fn mut_op<T, F: FnOnce(RwLockWriteGuard<HashMap<PathBuf, FileState>>) -> EditrResult<T>>(
+	fn mut_op<
+		T,
+		F: FnOnce(RwLockWriteGuard<HashMap<PathBuf, Arc<FileState>>>) -> EditrResult<T>,
+	>(
 		&self,
 		op: F,
 	) -> EditrResult<T> {
@@ -143,26 +505,126 @@ impl FileStates {
 	}
 
 	// Applies an op on path's FileState
-	fn file_op<T, F: FnOnce(&FileState) -> EditrResult<T>>(
+	fn file_op<T, F: FnOnce(&Arc<FileState>) -> EditrResult<T>>(
 		&self,
 		path: &PathBuf,
 		op: F,
 	) -> EditrResult<T> {
-		self.op(|container| {
-			op(container
-				.get(path)
-				.ok_or("Thread local storage does not exist")?)
-		})
+		self.op(|container| op(container.get(path).ok_or(EditrError::NotOpen)?))
+	}
+}
+
+// The name of the virtual document at path, if it is one
+fn virtual_name(path: &Path) -> Option<&str> { path.to_str()?.strip_prefix(VIRTUAL_PREFIX) }
+
+// Creates a new file at path holding data. The file only appears once it
+// is complete, and creation fails if something already exists at path
+pub fn create_atomic(path: &Path, data: &[u8]) -> EditrResult<()> {
+	let (temp_path, mut temp) = create_temp(path)?;
+	// Linking, unlike renaming, refuses to replace an existing file
+	let result = temp
+		.write_all(data)
+		.and_then(|_| fs::hard_link(&temp_path, path));
+	fs::remove_file(&temp_path).ok();
+	result?;
+	Ok(())
+}
+
+// Creates a new hidden file beside path to stage its new contents in,
+// returning its name along with it. Each gets a random name, and never
+// takes over an existing file, so saves to the same path don't collide
+fn create_temp(path: &Path) -> EditrResult<(PathBuf, File)> {
+	let file_name = path
+		.file_name()
+		.ok_or_else(|| EditrError::InvalidPath(path.to_path_buf()))?;
+	loop {
+		let mut temp_name = OsString::from(".");
+		temp_name.push(file_name);
+		temp_name.push(format!(".{}.editr-tmp", new_token()?));
+		let temp_path = path.with_file_name(temp_name);
+		match OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.open(&temp_path)
+		{
+			Ok(temp) => return Ok((temp_path, temp)),
+			// Only a clash of random names, so another is tried
+			Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+			Err(e) => return Err(e.into()),
+		}
 	}
 }
 
 // Loads contents of file at path into a Rope
-fn read_to_rope(path: &PathBuf) -> EditrResult<Rope> {
-	let mut buffer = Vec::new();
-	let mut file = File::open(&path)?;
-	file.read_to_end(&mut buffer)?;
-
-	let rope = Rope::new();
-	rope.insert_at(0, &buffer)?;
-	Ok(rope)
+fn read_to_rope(path: &PathBuf) -> EditrResult<Rope> { Rope::from_reader(File::open(path)?) }
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::FileStates;
+	use crate::state::ClientId;
+
+	#[test]
+	fn lines_are_read_from_open_files() {
+		let path = std::env::temp_dir().join(format!("editr-lines-{}.txt", std::process::id()));
+		fs::write(&path, "first\r\nsecond\n\nlast").unwrap();
+		let files = FileStates::new();
+		files.open(path.clone(), ClientId::next(), None).unwrap();
+
+		assert_eq!(files.read_line(&path, 0).unwrap(), b"first");
+		assert_eq!(files.read_line(&path, 2).unwrap(), b"");
+		assert_eq!(files.read_line(&path, 3).unwrap(), b"last");
+		assert!(files.read_line(&path, 4).is_err());
+		let lines = files.read_lines(&path, 1, 2).unwrap();
+		assert_eq!(lines, [(7, b"second".to_vec()), (14, Vec::new())]);
+		fs::remove_file(path).ok();
+	}
+
+	#[test]
+	fn large_files_are_opened_as_balanced_ropes() {
+		let path = std::env::temp_dir().join(format!("editr-large-{}.txt", std::process::id()));
+		let data = (0..1 << 20)
+			.map(|i| b'a' + (i % 26) as u8)
+			.collect::<Vec<_>>();
+		fs::write(&path, &data).unwrap();
+		let files = FileStates::new();
+		let file = files.open(path.clone(), ClientId::next(), None).unwrap();
+
+		let stats = file.rope_stats();
+		assert!(stats.max_leaf <= 8192, "{:?}", stats);
+		assert!(stats.max_depth <= 10, "{:?}", stats);
+		assert_eq!(files.read(&path, 0, usize::MAX).unwrap(), data);
+		fs::remove_file(path).ok();
+	}
+
+	#[test]
+	fn lines_are_read_from_files_without_newlines_or_of_only_newlines() {
+		let dir = std::env::temp_dir();
+		let unbroken = dir.join(format!("editr-unbroken-{}.txt", std::process::id()));
+		let newlines = dir.join(format!("editr-newlines-{}.txt", std::process::id()));
+		fs::write(&unbroken, vec![b'x'; 10_000]).unwrap();
+		fs::write(&newlines, "\n\n\n").unwrap();
+		let files = FileStates::new();
+		files
+			.open(unbroken.clone(), ClientId::next(), None)
+			.unwrap();
+		files
+			.open(newlines.clone(), ClientId::next(), None)
+			.unwrap();
+
+		assert_eq!(
+			files.read_lines(&unbroken, 0, 10).unwrap(),
+			[(0, vec![b'x'; 10_000])]
+		);
+		let lines = files.read_lines(&newlines, 0, 10).unwrap();
+		assert_eq!(lines, (0..4).map(|at| (at, Vec::new())).collect::<Vec<_>>());
+		assert_eq!(
+			files.read_lines(&newlines, 3, 10).unwrap(),
+			[(3, Vec::new())]
+		);
+		assert!(files.read_lines(&newlines, 4, 1).is_err());
+		fs::remove_file(unbroken).ok();
+		fs::remove_file(newlines).ok();
+	}
 }
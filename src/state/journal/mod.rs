@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{spawn, JoinHandle};
+use std::time::Instant;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::JournalConfig;
+use crate::error::EditrResult;
+use crate::rope::Rope;
+
+// First line of every journal, naming the format of the records after it
+const HEADER: &str = "editr-journal 1\n";
+
+// A change made to an open file, replayed the same way it was first applied
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum JournalEdit {
+	Insert {
+		offset: usize,
+		data: Vec<u8>,
+	},
+	Remove {
+		from: usize,
+		to: usize,
+	},
+	Replace {
+		from: usize,
+		to: usize,
+		data: Vec<u8>,
+	},
+}
+
+// What a file on disk held, so edits are only replayed onto the contents
+// they were made to
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct OnDisk {
+	pub len: u64,
+	pub checksum: u64,
+}
+
+impl OnDisk {
+	pub fn of(rope: &Rope) -> OnDisk {
+		OnDisk {
+			len: rope.len() as u64,
+			checksum: rope.checksum(),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Record {
+	Edit {
+		seq: u64,
+		path: PathBuf,
+		edit: JournalEdit,
+	},
+	// The file on disk holds every edit to it numbered below seq, and is
+	// described by on_disk. Also written ahead of the first edit to a file
+	// with nothing unsaved, describing the file the edit was made to
+	Saved {
+		seq: u64,
+		path: PathBuf,
+		on_disk: OnDisk,
+	},
+	// Every edit to the file numbered below seq was thrown away
+	Discarded {
+		seq: u64,
+		path: PathBuf,
+	},
+}
+
+// Edits which were never saved, by file, in the order they were made,
+// along with what the file on disk held when the first was made
+pub type Recovered = Vec<(PathBuf, Option<OnDisk>, Vec<JournalEdit>)>;
+
+// Records every edit to disk until the file it was made to is saved, so
+// unsaved work survives the server going down. Records are written by a
+// thread of their own and synced to disk every so often
+// A record for the journal's thread to write. Edits come with what the file
+// on disk holds, recorded ahead of them when the file has nothing unsaved
+type Queued = (Record, Option<OnDisk>);
+
+pub struct Journal {
+	queue: Mutex<Option<Sender<Queued>>>,
+	thread: Mutex<Option<JoinHandle<()>>>,
+	seq: AtomicU64,
+}
+
+impl Journal {
+	// Opens the journal, returning the edits it holds which were never saved.
+	// Anything after the first damaged record is dropped
+	pub fn open(config: &JournalConfig) -> EditrResult<(Journal, Recovered)> {
+		let (records, valid_len) = match File::open(&config.path) {
+			Ok(mut file) => {
+				let mut contents = Vec::new();
+				file.read_to_end(&mut contents)?;
+				parse(&contents)
+			}
+			Err(_) => (Vec::new(), 0),
+		};
+
+		// Collect what is left unsaved for each file, keeping files in the
+		// order they were first edited
+		let mut order = Vec::new();
+		let mut pending: HashMap<PathBuf, Vec<(u64, JournalEdit)>> = HashMap::new();
+		let mut on_disk: HashMap<PathBuf, OnDisk> = HashMap::new();
+		let mut next_seq = 0;
+		for record in records {
+			match record {
+				Record::Edit { seq, path, edit } => {
+					next_seq = next_seq.max(seq + 1);
+					if !pending.contains_key(&path) {
+						order.push(path.clone());
+					}
+					pending.entry(path).or_default().push((seq, edit));
+				}
+				Record::Saved {
+					seq,
+					path,
+					on_disk: saved,
+				} => {
+					next_seq = next_seq.max(seq);
+					if let Some(edits) = pending.get_mut(&path) {
+						edits.retain(|(edit_seq, _)| *edit_seq >= seq);
+					}
+					on_disk.insert(path, saved);
+				}
+				Record::Discarded { seq, path } => {
+					next_seq = next_seq.max(seq);
+					if let Some(edits) = pending.get_mut(&path) {
+						edits.retain(|(edit_seq, _)| *edit_seq >= seq);
+					}
+				}
+			}
+		}
+		let recovered: Recovered = order
+			.into_iter()
+			.filter_map(|path| {
+				let edits = pending.remove(&path)?;
+				let edits: Vec<_> = edits.into_iter().map(|(_, edit)| edit).collect();
+				(!edits.is_empty()).then(|| {
+					let on_disk = on_disk.get(&path).copied();
+					(path, on_disk, edits)
+				})
+			})
+			.collect();
+
+		// New records go straight after the last good one
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&config.path)?;
+		file.set_len(valid_len)?;
+		let mut writer = BufWriter::new(file);
+		if valid_len == 0 {
+			writer.write_all(HEADER.as_bytes())?;
+			writer.flush()?;
+		}
+
+		// Files with unsaved edits, by the last edit made to each
+		let dirty = recovered
+			.iter()
+			.map(|(path, _, _)| (path.clone(), next_seq - 1))
+			.collect();
+		let interval = config.sync_interval;
+		let (queue, receiver) = channel::<Queued>();
+		let thread = spawn(move || {
+			let mut dirty: HashMap<PathBuf, u64> = dirty;
+			let mut unsynced = false;
+			let mut last_synced = Instant::now();
+			loop {
+				match receiver.recv_timeout(interval) {
+					Ok((record, on_disk)) => {
+						if let (Record::Edit { seq, path, .. }, Some(on_disk)) = (&record, on_disk)
+						{
+							if !dirty.contains_key(path) {
+								let base = Record::Saved {
+									seq: *seq,
+									path: path.clone(),
+									on_disk,
+								};
+								if let Err(e) = write_record(&mut writer, &base) {
+									error!("Failed to write to the journal: {}", e);
+								}
+							}
+						}
+						if let Err(e) = write_record(&mut writer, &record) {
+							error!("Failed to write to the journal: {}", e);
+						}
+						unsynced = true;
+						match record {
+							Record::Edit { seq, path, .. } => {
+								dirty.insert(path, seq);
+							}
+							Record::Saved { seq, path, .. } | Record::Discarded { seq, path } => {
+								if dirty.get(&path).is_some_and(|&last| last < seq) {
+									dirty.remove(&path);
+								}
+								// Nothing is left to recover, so start afresh
+								if dirty.is_empty() {
+									if let Err(e) = truncate(&mut writer) {
+										error!("Failed to truncate the journal: {}", e);
+									}
+								}
+							}
+						}
+					}
+					Err(RecvTimeoutError::Timeout) => (),
+					Err(RecvTimeoutError::Disconnected) => break,
+				}
+
+				if unsynced && last_synced.elapsed() >= interval {
+					if let Err(e) = sync(&mut writer) {
+						error!("Failed to sync the journal: {}", e);
+					}
+					unsynced = false;
+					last_synced = Instant::now();
+				}
+			}
+			sync(&mut writer).ok();
+		});
+
+		let journal = Journal {
+			queue: Mutex::new(Some(queue)),
+			thread: Mutex::new(Some(thread)),
+			seq: AtomicU64::new(next_seq),
+		};
+		Ok((journal, recovered))
+	}
+
+	// Records an edit made to the file at path, which on disk is described
+	// by on_disk
+	pub fn edit(&self, path: &Path, edit: JournalEdit, on_disk: OnDisk) {
+		let seq = self.next_seq();
+		let record = Record::Edit {
+			seq,
+			path: path.to_path_buf(),
+			edit,
+		};
+		self.send_with(record, Some(on_disk));
+	}
+
+	// Number the next edit will be given. Edits numbered below it have
+	// already been recorded
+	pub fn mark(&self) -> u64 { self.seq.load(Ordering::SeqCst) }
+
+	// The file at path has been saved with every edit below mark, leaving
+	// on disk what on_disk describes
+	pub fn saved(&self, path: &Path, mark: u64, on_disk: OnDisk) {
+		self.send(Record::Saved {
+			seq: mark,
+			path: path.to_path_buf(),
+			on_disk,
+		});
+	}
+
+	// The edits to the file at path below mark will never be saved
+	pub fn discarded(&self, path: &Path, mark: u64) {
+		self.send(Record::Discarded {
+			seq: mark,
+			path: path.to_path_buf(),
+		});
+	}
+
+	// Writes out and syncs everything recorded, then stops the journal
+	pub fn close(&self) {
+		self.queue.lock().take();
+		if let Some(thread) = self.thread.lock().take() {
+			thread.join().ok();
+		}
+	}
+
+	fn next_seq(&self) -> u64 { self.seq.fetch_add(1, Ordering::SeqCst) }
+
+	fn send(&self, record: Record) { self.send_with(record, None); }
+
+	fn send_with(&self, record: Record, on_disk: Option<OnDisk>) {
+		if let Some(queue) = &*self.queue.lock() {
+			queue.send((record, on_disk)).ok();
+		}
+	}
+}
+
+// Reads records up to the first damaged one, returning them along with
+// the length of journal they took up
+fn parse(contents: &[u8]) -> (Vec<Record>, u64) {
+	if !contents.starts_with(HEADER.as_bytes()) {
+		if !contents.is_empty() {
+			warn!("Journal is not in a known format, ignoring it");
+		}
+		return (Vec::new(), 0);
+	}
+
+	let mut records = Vec::new();
+	let mut valid_len = HEADER.len();
+	for line in contents[HEADER.len()..].split_inclusive(|&byte| byte == b'\n') {
+		match parse_record(line) {
+			Some(record) => {
+				records.push(record);
+				valid_len += line.len();
+			}
+			None => {
+				warn!(
+					"Journal is damaged after {} records, replaying those",
+					records.len()
+				);
+				break;
+			}
+		}
+	}
+	(records, valid_len as u64)
+}
+
+// Parses a line of the form "<checksum> <record>\n"
+fn parse_record(line: &[u8]) -> Option<Record> {
+	let line = line.strip_suffix(b"\n")?;
+	let (checksum, body) = line.split_at(line.iter().position(|&byte| byte == b' ')?);
+	let body = &body[1..];
+	let checksum = u64::from_str_radix(std::str::from_utf8(checksum).ok()?, 16).ok()?;
+	if checksum != fnv1a(body) {
+		return None;
+	}
+	serde_json::from_slice(body).ok()
+}
+
+fn write_record(writer: &mut BufWriter<File>, record: &Record) -> EditrResult<()> {
+	let body = serde_json::to_vec(record)?;
+	write!(writer, "{:016x} ", fnv1a(&body))?;
+	writer.write_all(&body)?;
+	writer.write_all(b"\n")?;
+	Ok(())
+}
+
+fn truncate(writer: &mut BufWriter<File>) -> EditrResult<()> {
+	writer.flush()?;
+	writer.get_ref().set_len(0)?;
+	writer.write_all(HEADER.as_bytes())?;
+	Ok(())
+}
+
+fn sync(writer: &mut BufWriter<File>) -> EditrResult<()> {
+	writer.flush()?;
+	writer.get_ref().sync_data()?;
+	Ok(())
+}
+
+// Checksum guarding each record against torn writes and corruption
+fn fnv1a(data: &[u8]) -> u64 {
+	data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+		(hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use std::net::TcpStream;
+	use std::sync::atomic::AtomicUsize;
+	use std::sync::Arc;
+	use std::time::Duration;
+
+	use super::*;
+	use crate::config::{Durability, ServerConfig};
+	use crate::error::EditrError;
+	use crate::state::{ClientId, FileStates, OpTimer, SharedState};
+
+	// A directory of its own for each test, holding the journal and the file
+	// edited, which starts off holding "hello"
+	struct Setup {
+		dir: PathBuf,
+		file: PathBuf,
+		config: JournalConfig,
+	}
+
+	impl Setup {
+		fn new() -> Setup {
+			static COUNT: AtomicUsize = AtomicUsize::new(0);
+			let dir = std::env::temp_dir().join(format!(
+				"editr-journal-{}-{}",
+				std::process::id(),
+				COUNT.fetch_add(1, Ordering::SeqCst)
+			));
+			fs::create_dir_all(&dir).unwrap();
+			let file = dir.join("file");
+			fs::write(&file, "hello").unwrap();
+			let config = JournalConfig {
+				path: dir.join("journal"),
+				sync_interval: Duration::from_millis(10),
+			};
+			Setup { dir, file, config }
+		}
+
+		// Opens the journal and the file, returning what was recovered
+		fn open(&self) -> (Arc<Journal>, FileStates, Recovered) {
+			let (journal, recovered) = Journal::open(&self.config).unwrap();
+			let journal = Arc::new(journal);
+			let files = FileStates::with_journal(Some(journal.clone()));
+			(journal, files, recovered)
+		}
+
+		fn contents(&self, files: &FileStates) -> Vec<u8> {
+			let file = files.get(&self.file).unwrap();
+			let rope = file.rope();
+			rope.collect(0, rope.len()).unwrap()
+		}
+	}
+
+	impl Drop for Setup {
+		fn drop(&mut self) { fs::remove_dir_all(&self.dir).ok(); }
+	}
+
+	fn save(files: &FileStates, path: &PathBuf) {
+		files.flush(path, Durability::Fast, |_| Ok(())).unwrap();
+	}
+
+	#[test]
+	fn unsaved_edits_are_replayed() {
+		let setup = Setup::new();
+		let (journal, files, recovered) = setup.open();
+		assert!(recovered.is_empty());
+		let file = files
+			.open(setup.file.clone(), ClientId::next(), None)
+			.unwrap();
+		file.insert_at(5, b" world", &OpTimer::start()).unwrap();
+		journal.close();
+
+		let (_, files, recovered) = setup.open();
+		assert_eq!(recovered.len(), 1);
+		let (path, on_disk, edits) = recovered.into_iter().next().unwrap();
+		assert_eq!(on_disk.map(|on_disk| on_disk.len), Some(5));
+		files.recover(path, on_disk, &edits).unwrap();
+		assert_eq!(setup.contents(&files), b"hello world");
+	}
+
+	#[test]
+	fn edits_already_on_disk_are_not_replayed_twice() {
+		let setup = Setup::new();
+		let (journal, files, _) = setup.open();
+		let file = files
+			.open(setup.file.clone(), ClientId::next(), None)
+			.unwrap();
+		file.insert_at(5, b" world", &OpTimer::start()).unwrap();
+		journal.close();
+		// The save reached the disk, but not the journal
+		fs::write(&setup.file, "hello world").unwrap();
+
+		let (_, files, recovered) = setup.open();
+		let (path, on_disk, edits) = recovered.into_iter().next().unwrap();
+		let e = files.recover(path, on_disk, &edits).unwrap_err();
+		assert!(matches!(e, EditrError::ChangedOnDisk(_)));
+		assert_eq!(fs::read(&setup.file).unwrap(), b"hello world");
+	}
+
+	#[test]
+	fn saved_edits_are_not_recovered() {
+		let setup = Setup::new();
+		let (journal, files, _) = setup.open();
+		let file = files
+			.open(setup.file.clone(), ClientId::next(), None)
+			.unwrap();
+		file.insert_at(5, b" world", &OpTimer::start()).unwrap();
+		save(&files, &setup.file);
+		journal.close();
+
+		let (_, _, recovered) = setup.open();
+		assert!(recovered.is_empty());
+		// With nothing left unsaved, the journal started afresh
+		assert_eq!(fs::read(&setup.config.path).unwrap(), HEADER.as_bytes());
+	}
+
+	#[test]
+	fn edits_after_a_save_replay_onto_the_saved_file() {
+		let setup = Setup::new();
+		let (journal, files, _) = setup.open();
+		let file = files
+			.open(setup.file.clone(), ClientId::next(), None)
+			.unwrap();
+		file.insert_at(5, b" world", &OpTimer::start()).unwrap();
+		save(&files, &setup.file);
+		file.insert_at(0, b"> ", &OpTimer::start()).unwrap();
+		journal.close();
+
+		let (_, files, recovered) = setup.open();
+		let (path, on_disk, edits) = recovered.into_iter().next().unwrap();
+		assert_eq!(on_disk.map(|on_disk| on_disk.len), Some(11));
+		files.recover(path, on_disk, &edits).unwrap();
+		assert_eq!(setup.contents(&files), b"> hello world");
+	}
+
+	#[test]
+	fn edits_which_cannot_be_recovered_are_given_up() {
+		let setup = Setup::new();
+		let (journal, files, _) = setup.open();
+		let file = files
+			.open(setup.file.clone(), ClientId::next(), None)
+			.unwrap();
+		file.insert_at(5, b" world", &OpTimer::start()).unwrap();
+		journal.close();
+		fs::write(&setup.file, "changed").unwrap();
+
+		let config = ServerConfig {
+			journal: Some(setup.config.clone()),
+			..ServerConfig::default()
+		};
+		let shared =
+			SharedState::<TcpStream>::new(config, setup.dir.clone(), FileStates::new()).unwrap();
+		shared.journal.as_ref().unwrap().close();
+
+		let (_, _, recovered) = setup.open();
+		assert!(recovered.is_empty());
+		assert_eq!(fs::read(&setup.config.path).unwrap(), HEADER.as_bytes());
+	}
+}
@@ -1,201 +1,1140 @@
+mod rate_limiter;
+mod search;
+
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fs::{self, OpenOptions};
+use std::io;
 use std::net::TcpStream;
 
-use std::path::PathBuf;
-use std::thread::{current, ThreadId};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
 
-use crate::error::EditrResult;
-use crate::message::Message;
+use crate::config::{Durability, Permissions, RateLimitAction, ServerConfig};
+use crate::error::{Context, EditrError, EditrResult};
+use crate::message::{Cursors, Message, ReadData};
 use crate::state::*;
 
-pub struct LocalState {
-	thread_id: ThreadId,
-	socket: Socket,
+use self::rate_limiter::RateLimiter;
+pub use self::search::FileMatch;
+use self::search::Search;
+
+// Directory entries named when a directory is opened as a file
+const MAX_LISTED_ENTRIES: usize = 32;
+// Files remembered as recently opened
+const MAX_RECENT_FILES: usize = 20;
+// Most bytes held by the yank register
+const MAX_REGISTER_LEN: usize = 1 << 20;
+// Most matches a single search may return
+const MAX_SEARCH_MATCHES: usize = 1000;
+
+pub struct LocalState<T: Transport = TcpStream> {
+	client_id: ClientId,
+	connection_id: ClientId,
+	socket: Socket<T>,
+	metrics: Arc<ConnectionMetrics>,
+	server_metrics: Arc<ServerMetrics>,
+	access_log: Option<Arc<AccessLog>>,
 	files: FileStates,
+	quotas: Quotas,
+	sessions: Sessions,
+	session: Option<String>,
+	connections: Connections<T>,
+	config: Arc<ServerConfig>,
+	home_root: PathBuf,
 	canonical_home: PathBuf,
+	user: Option<String>,
+	permissions: Permissions,
 	opened_file: Option<PathBuf>,
+	// Handle to opened_file, so edits skip the container lookup
+	opened_state: Option<Arc<FileState>>,
+	recent_files: VecDeque<PathBuf>,
+	register: Vec<u8>,
+	rate_limiter: Option<RateLimiter>,
+	// Size in bytes of the message last read
+	message_size: usize,
+	compressed: bool,
+	// Times the message being handled
+	timer: OpTimer,
 }
 
-impl LocalState {
-	pub fn new(
-		threads_out: shared_out::SharedOut,
-		files: FileStates,
-		canonical_home: PathBuf,
-		stream: TcpStream,
-	) -> EditrResult<LocalState> {
+impl LocalState<MemoryStream> {
+	// Wires a new connection straight to an in-process client, returning the
+	// client's end, so messages can be processed without a server running
+	pub fn in_memory(
+		shared: SharedState<MemoryStream>,
+	) -> EditrResult<(LocalState<MemoryStream>, MemoryStream)> {
+		let (client, server) = MemoryStream::pair();
+		Ok((LocalState::new(shared, server)?, client))
+	}
+}
+
+impl<T: Transport> LocalState<T> {
+	pub fn new(shared: SharedState<T>, stream: T) -> EditrResult<LocalState<T>> {
+		let client_id = ClientId::next();
+		let permissions = shared.config.default_permissions;
+		let metrics = shared.connections.insert(client_id, &stream)?;
+		let rate_limiter = shared.config.rate_limit.map(RateLimiter::new);
+		// Half set up connections would otherwise hold their streams open
+		let socket = Socket::new(
+			client_id,
+			stream,
+			shared.shared_out.clone(),
+			metrics.clone(),
+			shared.config.max_message_size,
+		)
+		.inspect_err(|_| {
+			shared.connections.remove(client_id);
+		})?;
 		Ok(LocalState {
-			thread_id: current().id(),
-			socket: Socket::new(current().id(), stream, threads_out)?,
-			files,
-			canonical_home,
+			client_id,
+			connection_id: client_id,
+			socket,
+			metrics,
+			server_metrics: shared.metrics,
+			access_log: shared.access_log,
+			files: shared.files,
+			quotas: shared.quotas,
+			sessions: shared.sessions,
+			session: None,
+			connections: shared.connections,
+			config: shared.config,
+			home_root: shared.canonical_home.clone(),
+			canonical_home: shared.canonical_home,
+			user: None,
+			permissions,
 			opened_file: None,
+			opened_state: None,
+			recent_files: VecDeque::new(),
+			register: Vec::new(),
+			rate_limiter,
+			message_size: 0,
+			compressed: false,
+			timer: OpTimer::start(),
 		})
 	}
 
-	pub fn get_message(&mut self) -> EditrResult<Message> { self.socket.get_message() }
+	pub fn get_message(&mut self) -> EditrResult<Message> {
+		let before = self.socket.bytes_read();
+		let msg = self.socket.get_message();
+		self.message_size = (self.socket.bytes_read() - before) as usize;
+		msg
+	}
+
+	// As get_message, for a connection served by tokio, which waits for the
+	// message without holding up a thread
+	#[cfg(feature = "async-net")]
+	pub async fn next_message(&mut self) -> EditrResult<Message> {
+		let before = self.socket.bytes_read();
+		let msg = self.socket.next_message().await;
+		self.message_size = (self.socket.bytes_read() - before) as usize;
+		msg
+	}
+
+	// Holds the message last read to the rate limit, either waiting until
+	// it fits or refusing it, depending on config
+	pub fn throttle(&mut self) -> EditrResult<()> {
+		let limiter = match &mut self.rate_limiter {
+			Some(limiter) => limiter,
+			None => return Ok(()),
+		};
+		let wait = match limiter.take(self.message_size) {
+			Ok(()) => return Ok(()),
+			Err(wait) => wait,
+		};
+
+		self.metrics.add_throttled();
+		match self.config.rate_limit.map(|limit| limit.action) {
+			Some(RateLimitAction::Delay) => {
+				sleep(wait);
+				// Nothing else takes from the buckets, so they are full enough now
+				limiter.take(self.message_size).ok();
+				Ok(())
+			}
+			_ => Err(EditrError::RateLimited(wait)),
+		}
+	}
+
+	// Starts timing a message as it is handled
+	pub fn start_timer(&mut self) { self.timer = OpTimer::start(); }
+
+	// Stops timing the message, a kind, adding it to the metrics and
+	// logging it if it was slow
+	pub fn finish_timer(&self, kind: &'static str) {
+		let timing = self.timer.finish();
+		self.server_metrics.record_timing(kind, &timing);
+		if self.config.slow_op.is_some_and(|slow| timing.total >= slow) {
+			warn!(
+				"Slow {} on connection {:?}: {}",
+				kind,
+				self.connection_id,
+				timing.summary()
+			);
+		}
+	}
+
+	pub fn connection_id(&self) -> ClientId { self.connection_id }
+
+	pub fn metrics(&self) -> &ConnectionMetrics { &self.metrics }
+
+	// Size in bytes of the message last read
+	pub fn message_size(&self) -> usize { self.message_size }
+
+	pub fn server_metrics(&self) -> &ServerMetrics { &self.server_metrics }
+
+	pub fn access_logged(&self) -> bool { self.access_log.is_some() }
+
+	// Records an operation in the access log, if there is one
+	pub fn log_access(&self, kind: &str, detail: &str, error: bool) {
+		if let Some(access_log) = &self.access_log {
+			let file = self
+				.opened_file
+				.as_ref()
+				.map_or("-".into(), |path| path.to_string_lossy());
+			access_log.log(&format!(
+				"{:?} {} {} file={} {}{}",
+				self.connection_id,
+				self.user.as_deref().unwrap_or("-"),
+				kind,
+				file,
+				detail,
+				if error { "error" } else { "ok" }
+			));
+		}
+	}
+
+	// Agrees to compress the connection, which starts once the response has
+	// been sent
+	pub fn compress(&self) -> EditrResult<()> {
+		if !self.config.compression {
+			Err(EditrError::Protocol("Compression is disabled".to_string()))
+		}
+		else if self.compressed {
+			Err(EditrError::Protocol("Already compressed".to_string()))
+		}
+		else {
+			Ok(())
+		}
+	}
+
+	// Compresses everything sent after the response agreeing to it
+	pub fn start_compression(&mut self) -> EditrResult<()> {
+		self.compressed = true;
+		self.socket.compress(self.client_id)
+	}
+
+	// Reports on the server as a whole
+	pub fn server_status(&self) -> EditrResult<ServerSnapshot> {
+		Ok(self.server_metrics.snapshot(self.files.stats()?))
+	}
+
+	// Records activity, holding off the idle timeout
+	pub fn touch(&self) { self.connections.touch(self.connection_id) }
+
+	// Runs once the connection has ended, however it ended. A resumable
+	// session is parked, unless the connection was evicted or resumable is
+	// unset, otherwise everything is cleaned up
+	pub fn disconnect(&mut self, resumable: bool) -> EditrResult<()> {
+		let evicted = self.connections.remove(self.connection_id);
+		if evicted || !resumable {
+			if let Some(token) = self.session.take() {
+				self.sessions.remove(&token);
+			}
+		}
+		else if self.suspend()? {
+			return Ok(());
+		}
+
+		// Every step is attempted, so one failure can't leave the rest behind
+		let closed = self.file_close();
+		let removed = self.remove_thread_io();
+		closed.and(removed)
+	}
 
 	pub fn canonical_home(&self) -> &PathBuf { &self.canonical_home }
 
+	pub fn permissions(&self) -> Permissions { self.permissions }
+
+	// Whether this connection may change anything on a server which allows it
+	pub fn can_write(&self) -> bool { self.permissions.can_write() && !self.config.read_only }
+
+	pub fn config(&self) -> &ServerConfig { &self.config }
+
+	// Establishes the identity of the client, which must give the token
+	// configured for user. With per-user homes, the client is confined to
+	// home/<user>/ from now on
+	pub fn login(&mut self, user: &str, token: &str) -> EditrResult<Permissions> {
+		if self.user.is_some() {
+			return Err(EditrError::Protocol("Already logged in".to_string()));
+		}
+
+		let mut components = Path::new(user).components();
+		let valid_name = match (components.next(), components.next()) {
+			(Some(Component::Normal(_)), None) => self.config.shared_dir.as_deref() != Some(user),
+			_ => false,
+		};
+		if !valid_name {
+			return Err(EditrError::Protocol("Invalid user name".to_string()));
+		}
+		// Unknown users are refused just as a wrong token is, so neither
+		// tells a client which names exist
+		match self.config.user_tokens.get(user) {
+			Some(expected) if same_secret(expected, token) => (),
+			_ => return Err(EditrError::PermissionDenied(None)),
+		}
+
+		if self.config.user_homes {
+			// Paths resolved so far belong to the old home
+			self.file_close()?;
+
+			let user_home = self.home_root.join(user);
+			if !user_home.exists() {
+				fs::create_dir(&user_home)?;
+			}
+			self.canonical_home = canonical_path(&user_home)?;
+		}
+
+		// Only reached once the token has proven who this is, so a client
+		// can't take on another user's permissions by claiming their name
+		if let Some(permissions) = self.config.user_permissions.get(user) {
+			self.permissions = *permissions;
+		}
+		self.user = Some(user.to_string());
+		self.describe();
+		Ok(self.permissions)
+	}
+
+	// Starts a resumable session, returning its token
+	pub fn hello(&mut self) -> EditrResult<String> {
+		if self.session.is_some() {
+			return Err(EditrError::Protocol("Session already started".to_string()));
+		}
+		let token = self.sessions.create(self.client_id)?;
+		self.session = Some(token.clone());
+		Ok(token)
+	}
+
+	// Takes over a session whose connection dropped, restoring its state.
+	// Updates the session missed are delivered before this returns
+	pub fn resume(&mut self, token: &str) -> EditrResult<Option<PathBuf>> {
+		if self.session.is_some() || self.user.is_some() || self.opened_file.is_some() {
+			return Err(EditrError::Protocol(
+				"Only a fresh connection can resume a session".to_string(),
+			));
+		}
+
+		let (session_id, saved) = self.sessions.attach(token, self.config.session_grace)?;
+		if let Err(e) = self.socket.attach(session_id, self.client_id) {
+			// The session can no longer be brought up to date
+			self.sessions.detach(token, saved)?;
+			return Err(e);
+		}
+
+		self.client_id = session_id;
+		self.session = Some(token.to_string());
+		self.user = saved.user;
+		self.permissions = saved.permissions;
+		self.canonical_home = saved.canonical_home;
+		self.opened_state = match &saved.opened_file {
+			// The session stayed in its file while detached
+			Some(path) => Some(self.files.get(path)?),
+			None => None,
+		};
+		self.opened_file = saved.opened_file;
+		self.recent_files = saved.recent_files;
+		self.register = saved.register;
+		self.describe();
+		Ok(self.opened_file.clone())
+	}
+
+	// Parks the session for resumption after the connection is lost.
+	// Returns false if there is no session to keep, so the caller must clean up
+	fn suspend(&mut self) -> EditrResult<bool> {
+		let token = match self.session.take() {
+			Some(token) => token,
+			None => return Ok(false),
+		};
+		if self.config.session_grace == Duration::from_secs(0) {
+			self.sessions.remove(&token);
+			return Ok(false);
+		}
+
+		let saved = SavedSession {
+			user: self.user.clone(),
+			permissions: self.permissions,
+			canonical_home: self.canonical_home.clone(),
+			opened_file: self.opened_file.clone(),
+			recent_files: self.recent_files.clone(),
+			register: self.register.clone(),
+		};
+		self.socket.detach(self.client_id)?;
+		self.sessions.detach(&token, saved)?;
+		Ok(true)
+	}
+
 	pub fn contains_file(&self, path: &PathBuf) -> EditrResult<bool> { self.files.contains(path) }
 
-	pub fn remove_thread_io(&mut self) -> EditrResult<()> { self.socket.close(self.thread_id) }
+	pub fn remove_thread_io(&mut self) -> EditrResult<()> { self.socket.close(self.client_id) }
 
-	// Creates a new file at path
-	pub fn file_create(&self, path: &str) -> EditrResult<()> {
-		OpenOptions::new()
-			.write(true)
-			.create_new(true)
-			.open(self.prepend_home(path))?;
-		Ok(())
+	// Creates a new file at path, along with any missing parents if parents
+	// is set. The file starts out holding contents, if given.
+	// If open is set the file is also opened, and its path returned
+	pub fn file_create(
+		&mut self,
+		path: &str,
+		parents: bool,
+		contents: Option<&[u8]>,
+		open: bool,
+	) -> EditrResult<Option<PathBuf>> {
+		let path = self.resolve_new_path(path)?;
+
+		// Even an empty file is refused once the home is over quota. Checked
+		// before any parents are made, so a refused create leaves nothing
+		let size = contents.map_or(0, |contents| contents.len());
+		self.quotas.reserve(&self.quota_home(&path), size as i64)?;
+		let result = match path.parent() {
+			Some(parent) if parents => fs::create_dir_all(parent).map_err(EditrError::from),
+			_ => Ok(()),
+		}
+		.and_then(|_| match contents {
+			Some(contents) => create_atomic(&path, contents),
+			None => OpenOptions::new()
+				.write(true)
+				.create_new(true)
+				.open(&path)
+				.map(|_| ())
+				.map_err(|e| e.into()),
+		});
+		if let Err(e) = result {
+			self.quotas.release(&self.quota_home(&path), size as i64)?;
+			return Err(e);
+		}
+
+		if open {
+			Ok(Some(self.file_open(&path.to_string_lossy(), None)?))
+		}
+		else {
+			Ok(None)
+		}
 	}
 
 	// Deletes the file at path
 	pub fn file_delete(&self, path: &str) -> EditrResult<()> {
-		let path = self.prepend_home(path).canonicalize()?;
+		let path = self.resolve_path(path)?;
 		// File must not be open by anyone
 		if self.contains_file(&path)? {
-			Err("File is busy".into())
+			Err(EditrError::Busy(path))
 		}
 		else {
-			fs::remove_file(path)?;
+			let size = fs::metadata(&path)?.len();
+			fs::remove_file(&path)?;
+			self.quotas
+				.reserve(&self.quota_home(&path), -(size as i64))?;
 			Ok(())
 		}
 	}
 
 	// Renames the file at 'from' into 'to'
 	pub fn file_rename(&self, from: &str, to: &str) -> EditrResult<()> {
-		let from = self.prepend_home(from).canonicalize()?;
-		let to = self.prepend_home(to);
+		let from = self
+			.resolve_path(from)
+			.context("rename", Some(Path::new(from)))?;
+		let to = self
+			.resolve_new_path(to)
+			.context("rename to", Some(Path::new(to)))?;
 
 		if to.exists() {
-			Err("File already exists".into())
+			Err(EditrError::AlreadyExists(to))
 		}
 		else {
 			// File must not be open by anyone
 			if self.contains_file(&from)? {
-				Err("File is busy".into())
+				Err(EditrError::Busy(from))
 			}
 			else {
-				fs::rename(from, to)?;
+				// Moving between homes moves the usage with it
+				let size = fs::metadata(&from)?.len() as i64;
+				let (from_home, to_home) = (self.quota_home(&from), self.quota_home(&to));
+				if from_home != to_home {
+					self.quotas.reserve(&to_home, size)?;
+				}
+				if let Err(e) = fs::rename(&from, to) {
+					if from_home != to_home {
+						self.quotas.reserve(&to_home, -size)?;
+					}
+					return Err(EditrError::from(e).context("rename", Some(&from)));
+				}
+				if from_home != to_home {
+					self.quotas.reserve(&from_home, -size)?;
+				}
 				Ok(())
 			}
 		}
 	}
 
-	// Returns a list of filenames in canonical_home as Strings.
+	// Creates a new directory at path, along with any missing parents if recursive
+	pub fn dir_create(&self, path: &str, recursive: bool) -> EditrResult<()> {
+		let path = self.resolve_new_path(path)?;
+		let result = if recursive {
+			fs::create_dir_all(&path)
+		}
+		else {
+			fs::create_dir(&path)
+		};
+		result.map_err(|e| match e.kind() {
+			io::ErrorKind::AlreadyExists => EditrError::AlreadyExists(path),
+			io::ErrorKind::PermissionDenied => EditrError::PermissionDenied(Some(path)),
+			_ => e.into(),
+		})
+	}
+
+	// Returns a list of filenames in canonical_home as Strings, after any
+	// virtual documents. Directories are marked with a trailing '/'
 	pub fn files_list(&self) -> EditrResult<Vec<String>> {
-		let mut list = Vec::new();
-		for f in self.canonical_home.read_dir()? {
-			if let Ok(name) = f?.file_name().into_string() {
+		let mut list = self
+			.files
+			.documents()?
+			.into_iter()
+			.map(|path| path.to_string_lossy().into_owned())
+			.collect::<Vec<_>>();
+		// The shared area lives beside user homes, so list it explicitly
+		if self.config.user_homes {
+			if let Some(shared) = &self.config.shared_dir {
+				list.push(format!("{}/", shared));
+			}
+		}
+		let home = self.home()?;
+		for f in home.read_dir()? {
+			let f = f?;
+			if let Ok(mut name) = f.file_name().into_string() {
+				let mut file_type = f.file_type()?;
+				// Symlinks are listed as what they lead to, if they may be
+				// followed at all
+				if file_type.is_symlink() {
+					match resolve_within(home, &f.path(), self.config.symlinks, true) {
+						Ok(target) => file_type = fs::metadata(target)?.file_type(),
+						Err(_) => continue,
+					}
+				}
+				if file_type.is_dir() {
+					name.push('/');
+				}
 				list.push(name)
 			}
 		}
 		Ok(list)
 	}
 
+	// Searches every file in the client's home (and the shared area) for
+	// pattern within lines, including unsaved edits to open files.
+	// Also returns whether the search stopped early
+	pub fn search_files(
+		&self,
+		pattern: &[u8],
+		max_matches: usize,
+	) -> EditrResult<(Vec<FileMatch>, bool, Vec<String>)> {
+		let mut search = Search::new(pattern, max_matches.min(MAX_SEARCH_MATCHES));
+		search.walk(self.home()?, "", &self.files)?;
+		if self.config.user_homes {
+			if let Some(shared) = &self.config.shared_dir {
+				let shared_home = self.home_root.join(shared);
+				search.walk(&shared_home, &format!("{}/", shared), &self.files)?;
+			}
+		}
+		Ok((search.matches, search.truncated, search.skipped))
+	}
+
 	pub fn file_open(&mut self, path: &str, name: Option<String>) -> EditrResult<PathBuf> {
 		// (currently) clients can only have one file open
 		self.file_close()?;
 
-		let canonical_path = self.prepend_home(path).canonicalize()?;
+		// Scratch buffers and virtual documents are joined by the path
+		// they were opened as
+		if is_scratch(Path::new(path)) || is_virtual(Path::new(path)) {
+			let state = self.files.open(PathBuf::from(path), self.client_id, name)?;
+			return Ok(self.opened(PathBuf::from(path), state));
+		}
 
-		// Check that path is valid given client home
-		if !canonical_path.starts_with(self.canonical_home()) {
-			return Err("Invalid file path".into());
+		let canonical_path = self
+			.resolve_path(path)
+			.context("open", Some(Path::new(path)))?;
+
+		// Only regular files can be edited
+		let file_type = fs::metadata(&canonical_path)
+			.context("open", Some(&canonical_path))?
+			.file_type();
+		if file_type.is_dir() {
+			let entries = canonical_path
+				.read_dir()?
+				.filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+				.take(MAX_LISTED_ENTRIES)
+				.collect();
+			return Err(EditrError::IsADirectory {
+				path: canonical_path,
+				entries,
+			});
+		}
+		else if !file_type.is_file() {
+			return Err(EditrError::NotARegularFile(canonical_path));
 		}
 
-		self.files
-			.open(canonical_path.clone(), self.thread_id, name)?;
+		let state = self
+			.files
+			.open(canonical_path.clone(), self.client_id, name)?;
+
+		// Move the file to the front of the recent files
+		self.recent_files.retain(|recent| recent != &canonical_path);
+		self.recent_files.push_front(canonical_path.clone());
+		self.recent_files.truncate(MAX_RECENT_FILES);
+
+		Ok(self.opened(canonical_path, state))
+	}
+
+	// Opens a new scratch buffer called name, which is never saved unless
+	// saved as a file. Returns the path others may open it by
+	pub fn scratch_open(&mut self, name: &str) -> EditrResult<PathBuf> {
+		let mut components = Path::new(name).components();
+		match (components.next(), components.next()) {
+			(Some(Component::Normal(_)), None) => (),
+			_ => return Err(EditrError::InvalidPath(PathBuf::from(name))),
+		}
 
-		self.opened_file = Some(canonical_path.clone());
+		self.file_close()?;
+		let (path, state) = self.files.open_scratch(name, self.client_id)?;
+		Ok(self.opened(path, state))
+	}
 
-		Ok(canonical_path)
+	// Lists the files recently opened by the client, most recent first,
+	// relative to its home along with whether they still exist
+	pub fn recent_files(&self) -> EditrResult<Vec<(String, bool)>> {
+		Ok(self
+			.recent_files
+			.iter()
+			.map(|path| {
+				let relative = path
+					.strip_prefix(&self.canonical_home)
+					.or_else(|_| path.strip_prefix(&self.home_root))
+					.unwrap_or(path);
+				(protocol_path(relative), path.exists())
+			})
+			.collect())
 	}
 
 	pub fn file_close(&mut self) -> EditrResult<()> {
 		// Check whether a file is currently open
-		if let Some(path) = &self.opened_file {
-			self.files.close(&path, self.thread_id)?;
-			self.opened_file = None;
+		if let Some(path) = self.opened_file.take() {
+			self.opened_state = None;
+			self.describe();
+			// Let the remaining clients know this one has gone
+			self.files
+				.close(&path, self.client_id, |name, neighbours| {
+					self.send_to(&neighbours, Message::make_peer_left(self.client_id, name))
+				})?;
 		}
 		Ok(())
 	}
 
-	pub fn socket_write(&self, buffer: &[u8]) -> EditrResult<usize> {
-		self.socket.write(self.thread_id, buffer)
+	pub fn socket_write(&self, buffer: &[u8]) -> EditrResult<()> {
+		self.socket.write(self.client_id, buffer)
 	}
 
-	pub fn file_read(&self, from: usize, to: usize) -> EditrResult<Vec<u8>> {
-		self.files.read(self.get_opened()?, from, to)
+	// Reads from 'from' up to 'to', cut short where the file ends
+	pub fn file_read(&self, from: usize, to: usize) -> EditrResult<ReadData> {
+		let state = self.get_opened_state()?;
+		let rope = state.rope();
+		Ok(ReadData {
+			offset: from.min(rope.len()),
+			data: rope.collect(from, to)?,
+			file_len: rope.len(),
+		})
+	}
+
+	// Reads a line of the open file, counting from 0
+	pub fn file_read_line(&self, line: usize) -> EditrResult<Vec<u8>> {
+		self.files.read_line(self.get_opened()?, line)
 	}
 
 	pub fn file_write(&self, offset: usize, data: &[u8]) -> EditrResult<()> {
-		self.files.write(self.get_opened()?, offset, data)?;
+		self.get_opened_state()?
+			.insert_at(offset, data, &self.timer)?;
 		// Sync neigbours with the data just written
 		self.broadcast_neighbours(Message::make_add_broadcast(offset, data))?;
+		self.timer.mark(Phase::Broadcast);
 		Ok(())
 	}
 
 	// Removes data from the file, starting from offset
 	pub fn file_remove(&self, offset: usize, len: usize) -> EditrResult<()> {
-		self.files.remove(self.get_opened()?, offset, len)?;
+		self.get_opened_state()?
+			.remove_range(offset, offset.saturating_add(len), &self.timer)?;
 		// Sync neighbours with deletion
 		self.broadcast_neighbours(Message::make_del_broadcast(offset, len))?;
+		self.timer.mark(Phase::Broadcast);
+		Ok(())
+	}
+
+	// Replaces len bytes of the file from offset with data, which neighbours
+	// are sent as the one update
+	pub fn file_replace(&self, offset: usize, len: usize, data: &[u8]) -> EditrResult<()> {
+		let path = self.get_opened()?;
+		self.files
+			.replace(path, offset, offset.saturating_add(len), data, &self.timer)?;
+		self.broadcast_neighbours(Message::make_replace_broadcast(offset, len, data))?;
+		self.timer.mark(Phase::Broadcast);
 		Ok(())
 	}
 
-	// Saves file to disk
-	pub fn file_save(&self) -> EditrResult<()> { self.files.flush(self.get_opened()?) }
+	// Saves file to disk, provided the growth fits within the home's quota.
+	// The save is at least as durable as the server is configured for, and
+	// more if asked. Returns the durability applied and the time it took.
+	// Scratch buffers can only be saved as a new file
+	pub fn file_save(&self, durability: Option<Durability>) -> EditrResult<(Durability, Duration)> {
+		let path = self.get_opened()?;
+		// Virtual documents are as durable as their host makes them
+		let durability = match durability {
+			_ if is_virtual(path) => Durability::Fast,
+			Some(durability) => durability.max(self.config.durability),
+			None => self.config.durability,
+		};
+		// Whatever was reserved is given back if the write then fails
+		let reserved = Cell::new(0);
+		let result = self.files.flush(path, durability, |new_size| {
+			let old_size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+			let delta = new_size as i64 - old_size as i64;
+			self.quotas.reserve(&self.quota_home(path), delta)?;
+			reserved.set(delta);
+			Ok(())
+		});
+		if result.is_err() {
+			self.quotas
+				.release(&self.quota_home(path), reserved.get())?;
+		}
+		// Everyone editing the file needs to know their work is not safe.
+		// Scratch buffers were never going to be saved, so nothing has changed
+		if let (Err(e), false) = (&result, is_scratch(path)) {
+			let clients = self.get_opened_state()?.clients()?;
+			self.send_to(&clients, Message::make_save_failed(path.clone(), e))?;
+		}
+		Ok((durability, result?))
+	}
+
+	// Saves a copy of the open file to path, which must not be open itself.
+	// The open file stays as it is
+	pub fn file_save_as(&self, path: &str, overwrite: bool) -> EditrResult<PathBuf> {
+		let opened = self.get_opened()?;
+		let dest = self.resolve_new_path(path)?;
+
+		if dest.exists() && !overwrite {
+			return Err(EditrError::AlreadyExists(dest));
+		}
+		if self.contains_file(&dest)? {
+			return Err(EditrError::Busy(dest));
+		}
+
+		let reserved = Cell::new(0);
+		let result = self
+			.files
+			.save_as(opened, &dest, self.config.durability, |new_size| {
+				let old_size = fs::metadata(&dest).map(|m| m.len()).unwrap_or(0);
+				let delta = new_size as i64 - old_size as i64;
+				self.quotas.reserve(&self.quota_home(&dest), delta)?;
+				reserved.set(delta);
+				Ok(())
+			});
+		if let Err(e) = result {
+			self.quotas
+				.release(&self.quota_home(&dest), reserved.get())?;
+			return Err(e);
+		}
+		Ok(dest)
+	}
+
+	// Returns the bytes used by the client's home along with its quota
+	pub fn usage(&self) -> EditrResult<(u64, Option<u64>)> { self.quotas.usage(self.home()?) }
 
 	pub fn move_cursor(&self, offset: isize) -> EditrResult<()> {
-		self.files
-			.move_cursor(self.get_opened()?, self.thread_id, offset)
+		self.get_opened_state()?.move_cursor(self.client_id, offset)
 	}
 
 	pub fn file_write_cursor(&self, data: &[u8]) -> EditrResult<()> {
-		let op_offset = self
-			.files
-			.file_write_cursor(self.get_opened()?, self.thread_id, &data)?;
-		// Sync neigbours with the data just written
-		self.broadcast_neighbours(Message::make_add_broadcast(op_offset, data))?;
+		// Sync neigbours with the data just written, at the offset it landed
+		self.get_opened_state()?.write_at_cursor(
+			self.client_id,
+			data,
+			&self.timer,
+			|op_offset, neighbours| {
+				let sent = self.send_to(&neighbours, Message::make_add_broadcast(op_offset, data));
+				self.timer.mark(Phase::Broadcast);
+				sent
+			},
+		)?;
 		Ok(())
 	}
 
-	pub fn file_remove_cursor(&self, len: usize) -> EditrResult<()> {
-		let op_offset = self
-			.files
-			.file_remove_cursor(self.get_opened()?, self.thread_id, len)?;
+	pub fn file_remove_cursor(&mut self, len: usize) -> EditrResult<()> {
 		// Sync neighbours with deletion
-		self.broadcast_neighbours(Message::make_del_broadcast(op_offset, len))?;
+		let (_, removed) = self.get_opened_state()?.remove_at_cursor(
+			self.client_id,
+			len,
+			&self.timer,
+			|op_offset, removed_len, neighbours| {
+				let sent = self.send_to(
+					&neighbours,
+					Message::make_del_broadcast(op_offset, removed_len),
+				);
+				self.timer.mark(Phase::Broadcast);
+				sent
+			},
+		)?;
+
+		// Cut text can be pasted back, if it fits in the register
+		if removed.len() <= MAX_REGISTER_LEN {
+			self.register = removed;
+		}
+		Ok(())
+	}
+
+	// Copies a range of the open file into the register.
+	// The register is left as it was if the range is invalid
+	pub fn yank(&mut self, offset: usize, len: usize) -> EditrResult<()> {
+		let state = self.get_opened_state()?;
+		if len > MAX_REGISTER_LEN {
+			return Err(EditrError::Protocol("Range too large to yank".to_string()));
+		}
+		let rope = state.rope();
+		let register = match offset.checked_add(len) {
+			Some(end) if end <= rope.len() => rope.collect(offset, end)?,
+			_ => {
+				return Err(EditrError::RangeOutOfBounds {
+					from: offset,
+					to: offset.saturating_add(len),
+					len: rope.len(),
+				})
+			}
+		};
+		drop(rope);
+		self.register = register;
 		Ok(())
 	}
 
-	pub fn get_cursors(&self) -> EditrResult<(usize, Vec<(usize, Option<String>)>)> {
-		self.files.get_cursors(self.get_opened()?, self.thread_id)
+	// Inserts the register at the cursor
+	pub fn paste_at_cursor(&self) -> EditrResult<()> { self.file_write_cursor(&self.register) }
+
+	pub fn get_cursors(&self) -> EditrResult<Cursors> {
+		self.get_opened_state()?.get_cursors(self.client_id)
+	}
+
+	// Lists every connection, for administrators only
+	pub fn admin_list(&self, token: &str) -> EditrResult<Vec<ConnectionInfo>> {
+		self.check_admin(token)?;
+		Ok(self.connections.list())
+	}
+
+	// Disconnects a connection, telling it why first, for administrators only
+	pub fn admin_kick(&self, token: &str, id: ClientId, reason: String) -> EditrResult<()> {
+		self.check_admin(token)?;
+		match self.end_connection(id, DisconnectReason::Kicked, Some(reason)) {
+			true => Ok(()),
+			false => Err(EditrError::Protocol("No such connection".to_string())),
+		}
+	}
+
+	// Ends the connection with id for reason, telling it why first.
+	// Returns false if there was no such connection
+	pub fn end_connection(
+		&self,
+		id: ClientId,
+		reason: DisconnectReason,
+		detail: Option<String>,
+	) -> bool {
+		self.connections
+			.end(id, reason, detail, self.socket.shared_out())
+	}
+
+	// Ends this connection for reason, as end_connection
+	pub fn end(&self, reason: DisconnectReason, detail: Option<String>) {
+		self.end_connection(self.connection_id, reason, detail);
+	}
+
+	// Lists the last messages to pass over a connection, for administrators only
+	pub fn admin_trace(&self, token: &str, id: ClientId) -> EditrResult<Vec<TraceEntry>> {
+		self.check_admin(token)?;
+		self.connections
+			.trace(id)
+			.ok_or_else(|| EditrError::Protocol("No such connection".to_string()))
+	}
+
+	fn check_admin(&self, token: &str) -> EditrResult<()> {
+		match &self.config.admin_token {
+			Some(admin_token) if same_secret(admin_token, token) => Ok(()),
+			_ => Err(EditrError::PermissionDenied(None)),
+		}
+	}
+
+	// Makes path the open file, returning it
+	fn opened(&mut self, path: PathBuf, state: Arc<FileState>) -> PathBuf {
+		self.opened_file = Some(path.clone());
+		self.opened_state = Some(state);
+		self.describe();
+		path
+	}
+
+	// Keeps the connection registry up to date with who this is serving
+	fn describe(&self) {
+		self.connections.describe(
+			self.connection_id,
+			self.client_id,
+			self.user.clone(),
+			self.opened_file.clone(),
+		);
 	}
 
 	fn get_opened(&self) -> EditrResult<&PathBuf> {
-		self.opened_file
-			.as_ref()
-			.ok_or_else(|| "File not open".into())
+		self.opened_file.as_ref().ok_or(EditrError::NotOpen)
+	}
+
+	fn get_opened_state(&self) -> EditrResult<&Arc<FileState>> {
+		self.opened_state.as_ref().ok_or(EditrError::NotOpen)
 	}
 
 	// Broadcasts a message to other clients in the same file as self
 	fn broadcast_neighbours(&self, msg: Message) -> EditrResult<()> {
 		let data = msg.to_vec()?;
-		self.files.for_each_client(self.get_opened()?, |client| {
-			if client != self.thread_id {
-				self.socket.write(client, &data)?;
+		self.server_metrics.broadcast_sent();
+		let neighbours = self.get_opened_state()?.neighbours(self.client_id)?;
+		self.socket
+			.write_many(&neighbours, &data)
+			.context("broadcast to", self.opened_file.as_deref())
+	}
+
+	// Sends a message to each of the given clients
+	fn send_to(&self, clients: &[ClientId], msg: Message) -> EditrResult<()> {
+		let data = msg.to_vec()?;
+		self.server_metrics.broadcast_sent();
+		self.socket.write_many(clients, &data)
+	}
+
+	// Returns the client's home, which is only available after login when
+	// users have their own homes
+	fn home(&self) -> EditrResult<&PathBuf> {
+		if self.config.user_homes && self.user.is_none() {
+			Err(EditrError::Protocol("Login required".to_string()))
+		}
+		else {
+			Ok(&self.canonical_home)
+		}
+	}
+
+	// Returns the home whose quota covers the canonical path
+	fn quota_home(&self, path: &Path) -> PathBuf {
+		if let Some(shared) = &self.config.shared_dir {
+			let shared_home = self.home_root.join(shared);
+			if path.starts_with(&shared_home) {
+				return shared_home;
 			}
-			Ok(())
-		})?;
-		Ok(())
+		}
+		self.canonical_home.clone()
+	}
+
+	// Finds the directory a user input path is confined to, and joins the
+	// path onto it. Paths into the shared area are confined to that area.
+	// Absolute paths, such as those in an OpenResp, are taken as they are
+	fn locate(&self, path: &str) -> EditrResult<(PathBuf, PathBuf)> {
+		// Scratch buffers and virtual documents may look like relative
+		// paths, but are not on disk
+		if is_scratch(Path::new(path)) || is_virtual(Path::new(path)) {
+			return Err(EditrError::InvalidPath(PathBuf::from(path)));
+		}
+		let path = native_path(path);
+		let path = path.as_path();
+		if let Some(shared) = &self.config.shared_dir {
+			let shared_home = self.home_root.join(shared);
+			if path.is_absolute() {
+				if path.starts_with(&shared_home) {
+					return Ok((shared_home, path.to_path_buf()));
+				}
+			}
+			else if let Some(Component::Normal(first)) = path.components().next() {
+				if first == shared.as_str() {
+					return Ok((shared_home, self.home_root.join(path)));
+				}
+			}
+		}
+		let home = self.home()?;
+		Ok((home.clone(), home.join(path)))
+	}
+
+	// Resolves a user input path to an existing file, which must lie within
+	// the client's home
+	fn resolve_path(&self, path: &str) -> EditrResult<PathBuf> {
+		let (boundary, full_path) = self.locate(path)?;
+		resolve_within(&boundary, &full_path, self.config.symlinks, true)
+	}
+
+	// Resolves a user input path which may not exist yet, within the
+	// client's home. The missing remainder may only contain plain names
+	fn resolve_new_path(&self, path: &str) -> EditrResult<PathBuf> {
+		let (boundary, full_path) = self.locate(path)?;
+		resolve_within(&boundary, &full_path, self.config.symlinks, false)
 	}
+}
+
+// Compares secrets in time which depends only on their lengths, so a
+// client can't find a token a byte at a time by timing refusals
+fn same_secret(expected: &str, given: &str) -> bool {
+	expected.len() == given.len()
+		&& expected
+			.bytes()
+			.zip(given.bytes())
+			.fold(0, |diff, (a, b)| diff | (a ^ b))
+			== 0
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::mpsc::channel;
+	use std::thread;
+	use std::time::Instant;
+
+	use super::*;
+	use crate::error::ErrorCode;
+
+	const EDITS: usize = 1000;
+
+	// A connection served straight from memory, over a home of its own
+	// holding an empty file.txt
+	struct Setup {
+		home: PathBuf,
+		files: FileStates,
+		local: LocalState<MemoryStream>,
+		_client: MemoryStream,
+	}
+
+	impl Setup {
+		fn new() -> Setup {
+			static COUNT: AtomicUsize = AtomicUsize::new(0);
+			let home = std::env::temp_dir().join(format!(
+				"editr-local-{}-{}",
+				std::process::id(),
+				COUNT.fetch_add(1, Ordering::SeqCst)
+			));
+			fs::create_dir_all(&home).unwrap();
+			fs::write(home.join("file.txt"), "").unwrap();
+			let files = FileStates::new();
+			let shared = SharedState::new(
+				ServerConfig::default(),
+				home.canonicalize().unwrap(),
+				files.clone(),
+			)
+			.unwrap();
+			let (local, client) = LocalState::in_memory(shared).unwrap();
+			Setup {
+				home,
+				files,
+				local,
+				_client: client,
+			}
+		}
+	}
+
+	impl Drop for Setup {
+		fn drop(&mut self) { fs::remove_dir_all(&self.home).ok(); }
+	}
+
+	#[test]
+	fn edits_skip_the_file_container() {
+		let mut setup = Setup::new();
+		setup.local.file_open("file.txt", None).unwrap();
+
+		// Another thread holds the container for as long as the edits take,
+		// giving up after a while if they are stuck waiting on it
+		let (locked, held) = channel();
+		let (release, released) = channel::<()>();
+		let holder = {
+			let files = setup.files.clone();
+			thread::spawn(move || {
+				files.locked(|| {
+					locked.send(()).unwrap();
+					released.recv_timeout(Duration::from_secs(5)).is_ok()
+				})
+			})
+		};
+		held.recv().unwrap();
+
+		let local = &setup.local;
+		let started = Instant::now();
+		for offset in 0..EDITS {
+			local.file_write(offset, b"x").unwrap();
+			local.file_read(0, offset + 1).unwrap();
+		}
+		local.file_remove(0, EDITS / 2).unwrap();
+		local.move_cursor(10).unwrap();
+		local.file_write_cursor(b"y").unwrap();
+		let elapsed = started.elapsed();
+		release.send(()).unwrap();
+
+		assert!(holder.join().unwrap(), "Edits waited on the container");
+		println!("{} edits in {:?} with the container locked", EDITS, elapsed);
+		assert_eq!(
+			local.file_read(0, usize::MAX).unwrap().file_len,
+			EDITS / 2 + 1
+		);
+	}
+
+	#[test]
+	fn closing_drops_the_cached_handle() {
+		let mut setup = Setup::new();
+		fs::write(setup.home.join("other.txt"), "").unwrap();
+		setup.local.file_open("file.txt", None).unwrap();
+		setup.local.file_write(0, b"first").unwrap();
+
+		setup.local.file_close().unwrap();
+		assert!(matches!(
+			setup.local.file_write(0, b"x"),
+			Err(EditrError::NotOpen)
+		));
+
+		// Edits go to whichever file is open now
+		setup.local.file_open("other.txt", None).unwrap();
+		setup.local.file_write(0, b"second").unwrap();
+		assert_eq!(setup.local.file_read(0, 100).unwrap().data, b"second");
+		setup.local.file_open("file.txt", None).unwrap();
+		assert_eq!(setup.local.file_read(0, 100).unwrap().data, b"");
+	}
+
+	#[test]
+	fn common_failures_have_their_own_variants() {
+		let mut setup = Setup::new();
+		fs::write(setup.home.join("other.txt"), "").unwrap();
+		let local = &mut setup.local;
+		assert!(matches!(local.file_read(0, 1), Err(EditrError::NotOpen)));
+		let escape = local.file_open("../outside.txt", None).unwrap_err();
+		assert!(matches!(escape.root(), EditrError::InvalidPath(_)));
+		let missing = local.file_open("missing.txt", None).unwrap_err();
+		assert!(matches!(missing.root(), EditrError::Io(e) if e.kind() == io::ErrorKind::NotFound));
+		// Clients are told the same, whether the file system or the server
+		// noticed
+		let created = local
+			.file_create("file.txt", false, Some(b""), false)
+			.unwrap_err();
+		assert_eq!(ErrorCode::from(&created), ErrorCode::AlreadyExists);
 
-	// Prepends user input paths with canonical home
-	fn prepend_home(&self, path: &str) -> PathBuf {
-		let mut new_path = self.canonical_home().clone();
-		new_path.push(path);
-		new_path
+		local.file_open("file.txt", None).unwrap();
+		local.file_write(0, b"hello").unwrap();
+		assert!(matches!(
+			local.file_save_as("other.txt", false),
+			Err(EditrError::AlreadyExists(_))
+		));
+		assert!(matches!(
+			local.file_write(10, b"x").unwrap_err().root(),
+			EditrError::OffsetOutOfBounds { offset: 10, len: 5 }
+		));
+		assert!(matches!(
+			local.file_delete("file.txt"),
+			Err(EditrError::Busy(_))
+		));
 	}
 }
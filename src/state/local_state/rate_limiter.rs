@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimit;
+
+// Token buckets for messages and bytes, each holding up to a second's worth.
+// A message larger than the bucket is let through once it is full, leaving
+// the bucket in debt until it refills
+pub(super) struct RateLimiter {
+	limit: RateLimit,
+	messages: f64,
+	bytes: f64,
+	last: Instant,
+}
+
+impl RateLimiter {
+	pub fn new(limit: RateLimit) -> RateLimiter {
+		RateLimiter {
+			limit,
+			messages: limit.messages_per_sec as f64,
+			bytes: limit.bytes_per_sec as f64,
+			last: Instant::now(),
+		}
+	}
+
+	// Takes the tokens for a message of size bytes, or returns how long
+	// until there will be enough
+	pub fn take(&mut self, size: usize) -> Result<(), Duration> {
+		self.refill();
+		if self.messages >= 1.0 && self.bytes >= 0.0 {
+			self.messages -= 1.0;
+			self.bytes -= size as f64;
+			return Ok(());
+		}
+
+		let messages_wait = (1.0 - self.messages).max(0.0) / self.limit.messages_per_sec as f64;
+		let bytes_wait = (-self.bytes).max(0.0) / self.limit.bytes_per_sec as f64;
+		Err(Duration::from_secs_f64(messages_wait.max(bytes_wait)))
+	}
+
+	fn refill(&mut self) {
+		let elapsed = self.last.elapsed().as_secs_f64();
+		self.last = Instant::now();
+		self.messages = (self.messages + elapsed * self.limit.messages_per_sec as f64)
+			.min(self.limit.messages_per_sec as f64);
+		self.bytes = (self.bytes + elapsed * self.limit.bytes_per_sec as f64)
+			.min(self.limit.bytes_per_sec as f64);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::config::{RateLimit, RateLimitAction};
+
+	use super::RateLimiter;
+
+	fn limiter(messages_per_sec: u32, bytes_per_sec: u64) -> RateLimiter {
+		RateLimiter::new(RateLimit {
+			messages_per_sec,
+			bytes_per_sec,
+			action: RateLimitAction::Reject,
+		})
+	}
+
+	#[test]
+	fn a_seconds_worth_of_messages_gets_through() {
+		let mut limiter = limiter(10, 1 << 20);
+		for _ in 0..10 {
+			limiter.take(1).unwrap();
+		}
+		let wait = limiter.take(1).unwrap_err();
+		assert!(wait.as_millis() > 0 && wait.as_millis() <= 100);
+	}
+
+	#[test]
+	fn large_messages_leave_the_bytes_in_debt() {
+		let mut limiter = limiter(100, 1000);
+		// Let through as the bucket is full, though it is over a second's worth
+		limiter.take(3000).unwrap();
+		let wait = limiter.take(1).unwrap_err();
+		assert!(wait.as_secs_f64() > 1.9 && wait.as_secs_f64() <= 2.0);
+	}
+}
@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{EditrError, EditrResult};
+use crate::state::FileStates;
+
+// Bytes read from all files before a search gives up
+const MAX_SCANNED: usize = 64 << 20;
+// Leading bytes checked for NULs to tell binary files apart
+const BINARY_CHECK_LEN: usize = 8192;
+// Longest excerpt of a matching line returned
+const MAX_EXCERPT: usize = 200;
+// Most of a line held at once. Longer lines are searched a piece at a time
+const MAX_LINE_LEN: usize = 64 << 10;
+// Most files and directories which couldn't be read named in the results
+const MAX_SKIPPED: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileMatch {
+	pub path: String,
+	pub offset: usize,
+	pub excerpt: String,
+}
+
+// A search for a pattern across files, matching within single lines
+pub(super) struct Search<'a> {
+	pattern: &'a [u8],
+	max_matches: usize,
+	scanned: usize,
+	pub matches: Vec<FileMatch>,
+	pub truncated: bool,
+	// Files and directories which couldn't be read, so weren't searched
+	pub skipped: Vec<String>,
+}
+
+impl<'a> Search<'a> {
+	pub fn new(pattern: &'a [u8], max_matches: usize) -> Search<'a> {
+		Search {
+			pattern,
+			max_matches,
+			scanned: 0,
+			matches: Vec::new(),
+			truncated: false,
+			skipped: Vec::new(),
+		}
+	}
+
+	// Searches every regular file under dir, naming them relative to it
+	// after prefix. Open files are searched as they are in memory.
+	// Symlinks are not followed. Files and directories below dir which
+	// can't be read are skipped, and listed in skipped
+	pub fn walk(&mut self, dir: &Path, prefix: &str, files: &FileStates) -> EditrResult<()> {
+		for entry in dir.read_dir()? {
+			if self.truncated {
+				break;
+			}
+			let entry = match entry {
+				Ok(entry) => entry,
+				Err(_) => {
+					self.skip(prefix.to_string());
+					continue;
+				}
+			};
+			let name = match entry.file_name().into_string() {
+				Ok(name) => format!("{}{}", prefix, name),
+				Err(_) => continue,
+			};
+			let path = entry.path();
+			let searched = match entry.file_type() {
+				Ok(file_type) if file_type.is_dir() => {
+					self.walk(&path, &format!("{}/", name), files)
+				}
+				Ok(file_type) if file_type.is_file() => {
+					if files.contains(&path)? {
+						let content = files.read(&path, 0, usize::MAX)?;
+						self.search_reader(&name, &content[..])
+					}
+					else {
+						File::open(&path)
+							.map_err(EditrError::from)
+							.and_then(|file| self.search_reader(&name, BufReader::new(file)))
+					}
+				}
+				Ok(_) => Ok(()),
+				Err(e) => Err(e.into()),
+			};
+			if searched.is_err() {
+				self.skip(name);
+			}
+		}
+		Ok(())
+	}
+
+	fn skip(&mut self, name: String) {
+		if self.skipped.len() < MAX_SKIPPED {
+			self.skipped.push(name);
+		}
+	}
+
+	// Searches one file line by line, skipping it if it looks binary
+	fn search_reader<R: BufRead>(&mut self, name: &str, mut reader: R) -> EditrResult<()> {
+		let head = reader.fill_buf()?;
+		if head[..head.len().min(BINARY_CHECK_LEN)].contains(&0) {
+			return Ok(());
+		}
+
+		// Holds a line, or a piece of one too long to hold whole along with
+		// the end of the piece before, so matches across the cut are found
+		let mut line = Vec::new();
+		let mut line_start = 0;
+		loop {
+			let len = (&mut reader)
+				.take(MAX_LINE_LEN as u64)
+				.read_until(b'\n', &mut line)?;
+			if len == 0 {
+				break;
+			}
+
+			self.scanned += len;
+			if self.scanned > MAX_SCANNED {
+				self.truncated = true;
+				break;
+			}
+
+			for found in find_all(&line, self.pattern) {
+				if self.matches.len() == self.max_matches {
+					self.truncated = true;
+					return Ok(());
+				}
+				self.matches.push(FileMatch {
+					path: name.to_string(),
+					offset: line_start + found,
+					excerpt: excerpt(&line, found),
+				});
+			}
+
+			// Too little is kept for a whole match, so none is found twice
+			let keep = match line.last() {
+				Some(b'\n') => 0,
+				_ => self.pattern.len().saturating_sub(1).min(line.len()),
+			};
+			line_start += line.len() - keep;
+			line.drain(..line.len() - keep);
+		}
+		Ok(())
+	}
+}
+
+// Finds the start of every occurrence of pattern in haystack
+fn find_all(haystack: &[u8], pattern: &[u8]) -> Vec<usize> {
+	if pattern.is_empty() {
+		return Vec::new();
+	}
+	haystack
+		.windows(pattern.len())
+		.enumerate()
+		.filter(|(_, window)| *window == pattern)
+		.map(|(index, _)| index)
+		.collect()
+}
+
+// Cuts the line down to at most MAX_EXCERPT bytes around the match at found
+fn excerpt(line: &[u8], found: usize) -> String {
+	let line = line.strip_suffix(b"\n").unwrap_or(line);
+	let start = found.saturating_sub(MAX_EXCERPT / 2);
+	let end = line.len().min(start + MAX_EXCERPT);
+	String::from_utf8_lossy(&line[start..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	#[cfg(unix)]
+	use std::os::unix::fs::PermissionsExt;
+
+	use super::*;
+
+	fn search(pattern: &[u8], contents: &[u8]) -> Search<'static> {
+		let pattern = Box::leak(pattern.to_vec().into_boxed_slice());
+		let mut search = Search::new(pattern, 100);
+		search.search_reader("file", contents).unwrap();
+		search
+	}
+
+	fn offsets(search: &Search) -> Vec<usize> {
+		search.matches.iter().map(|found| found.offset).collect()
+	}
+
+	#[test]
+	fn finds_matches_on_every_line() {
+		let found = search(b"ab", b"ab ab\nxx\nxab\n");
+		assert_eq!(offsets(&found), [0, 3, 10]);
+		assert_eq!(found.matches[2].excerpt, "xab");
+		assert!(!found.truncated);
+	}
+
+	#[test]
+	fn skips_binary_files() {
+		assert!(search(b"ab", b"ab\0ab").matches.is_empty());
+	}
+
+	#[test]
+	fn searches_long_lines_in_pieces() {
+		// A match straddling each cut between pieces, and one at the very end
+		let mut line = vec![b'x'; MAX_LINE_LEN * 3 + 10];
+		line[MAX_LINE_LEN - 2..MAX_LINE_LEN + 2].copy_from_slice(b"abcd");
+		line[MAX_LINE_LEN * 2 - 1..MAX_LINE_LEN * 2 + 3].copy_from_slice(b"abcd");
+		let end = line.len() - 4;
+		line[end..].copy_from_slice(b"abcd");
+		let found = search(b"abcd", &line);
+		assert_eq!(
+			offsets(&found),
+			[MAX_LINE_LEN - 2, MAX_LINE_LEN * 2 - 1, end]
+		);
+		assert!(found
+			.matches
+			.iter()
+			.all(|found| found.excerpt.contains("abcd")));
+	}
+
+	#[test]
+	fn stops_at_max_matches() {
+		let mut search = Search::new(b"a", 2);
+		search.search_reader("file", &b"aaaa"[..]).unwrap();
+		assert_eq!(search.matches.len(), 2);
+		assert!(search.truncated);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn unreadable_entries_are_skipped() {
+		let dir = std::env::temp_dir().join(format!("editr-search-{}", std::process::id()));
+		fs::create_dir_all(dir.join("locked")).unwrap();
+		fs::write(dir.join("locked/hidden.txt"), b"needle").unwrap();
+		fs::write(dir.join("open.txt"), b"needle").unwrap();
+		let mut locked = fs::metadata(dir.join("locked")).unwrap().permissions();
+		locked.set_mode(0o000);
+		fs::set_permissions(dir.join("locked"), locked).unwrap();
+		// Permissions don't stop everyone, such as root
+		let enforced = fs::read_dir(dir.join("locked")).is_err();
+
+		let mut search = Search::new(b"needle", 100);
+		let walked = search.walk(&dir, "", &FileStates::new());
+		let mut unlocked = fs::metadata(dir.join("locked")).unwrap().permissions();
+		unlocked.set_mode(0o755);
+		fs::set_permissions(dir.join("locked"), unlocked).unwrap();
+		fs::remove_dir_all(&dir).ok();
+
+		walked.unwrap();
+		assert!(search.matches.iter().any(|found| found.path == "open.txt"));
+		if enforced {
+			assert_eq!(search.skipped, ["locked"]);
+		}
+	}
+}
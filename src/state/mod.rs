@@ -1,7 +1,25 @@
+mod access_log;
+mod client_id;
+mod connections;
 mod file_states;
+mod journal;
 mod local_state;
+mod paths;
+mod quotas;
+mod server_metrics;
+mod sessions;
+mod shared_state;
 mod socket;
 
+pub use access_log::*;
+pub use client_id::*;
+pub use connections::*;
 pub use file_states::*;
+pub use journal::*;
 pub use local_state::*;
+pub use paths::*;
+pub use quotas::*;
+pub use server_metrics::*;
+pub use sessions::*;
+pub use shared_state::*;
 pub use socket::*;
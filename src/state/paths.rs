@@ -0,0 +1,247 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::SymlinkPolicy;
+use crate::error::{EditrError, EditrResult};
+
+// Longest path Windows accepts without the \\?\ prefix
+const MAX_PATH: usize = 260;
+// Names Windows reserves for devices in every directory, when not verbatim
+const RESERVED_NAMES: &[&str] = &[
+	"CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+	"COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Canonicalizes path. On Windows the \\?\ prefix is dropped where possible,
+// so that every path the server compares has been through the same steps
+pub fn canonical_path(path: &Path) -> io::Result<PathBuf> {
+	let canonical = path.canonicalize()?;
+	if cfg!(windows) {
+		if let Some(text) = canonical.to_str() {
+			return Ok(PathBuf::from(strip_verbatim(text).into_owned()));
+		}
+	}
+	Ok(canonical)
+}
+
+// Strips the \\?\ prefix from a Windows path where what is left names the
+// same file, as \\?\C:\dir to C:\dir and \\?\UNC\host\share to
+// \\host\share. Anything else is left as it is. Works on the text alone,
+// so behaves the same on every platform
+pub fn strip_verbatim(path: &str) -> Cow<'_, str> {
+	let (stripped, rest) = if let Some(rest) = path.strip_prefix(r"\\?\UNC\") {
+		(Cow::Owned(format!(r"\\{}", rest)), rest)
+	}
+	else {
+		match path.strip_prefix(r"\\?\") {
+			Some(rest) if is_drive_path(rest) => (Cow::Borrowed(rest), &rest[3..]),
+			_ => return Cow::Borrowed(path),
+		}
+	};
+	// Without the prefix, long paths fail and some names are reinterpreted
+	let plain = stripped.len() < MAX_PATH
+		&& rest.split('\\').all(|part| {
+			let stem = part.split('.').next().unwrap_or(part);
+			!part.ends_with(['.', ' '])
+				&& !RESERVED_NAMES
+					.iter()
+					.any(|name| name.eq_ignore_ascii_case(stem))
+		});
+	if plain {
+		stripped
+	}
+	else {
+		Cow::Borrowed(path)
+	}
+}
+
+// True if path starts with a drive, as in C:\
+fn is_drive_path(path: &str) -> bool {
+	matches!(path.as_bytes(), [drive, b':', b'\\', ..] if drive.is_ascii_alphabetic())
+}
+
+// Writes a path relative to a home the way the protocol does, with '/'
+// between each part whatever the platform. Absolute paths are left as
+// they are, as there is nothing to make them relative to
+pub fn protocol_path(path: &Path) -> String {
+	if path.is_absolute() {
+		return path.to_string_lossy().into_owned();
+	}
+	path.components()
+		.map(|component| match component {
+			Component::Normal(part) => part.to_string_lossy(),
+			other => other.as_os_str().to_string_lossy(),
+		})
+		.collect::<Vec<_>>()
+		.join("/")
+}
+
+// Reads a path as sent by a client, who separates parts with '/' on every
+// platform, into one the platform understands
+pub fn native_path(path: &str) -> PathBuf {
+	if cfg!(windows) {
+		PathBuf::from(path.replace('/', r"\"))
+	}
+	else {
+		PathBuf::from(path)
+	}
+}
+
+// Resolves path, which is under boundary or relative to it, to where it
+// leads on disk. Every filesystem operation a client asks for goes through
+// here, so symlinks are treated the same whatever the operation.
+// Each part is walked in turn, with '..' never leaving boundary and
+// symlinks followed only as symlinks allows. If exists is false, the path
+// may end in parts which do not exist yet, which must be plain names.
+// Boundary must already be canonical
+pub fn resolve_within(
+	boundary: &Path,
+	path: &Path,
+	symlinks: SymlinkPolicy,
+	exists: bool,
+) -> EditrResult<PathBuf> {
+	let invalid = || EditrError::InvalidPath(path.to_path_buf());
+	let relative = if path.is_absolute() {
+		path.strip_prefix(boundary).map_err(|_| invalid())?
+	}
+	else {
+		path
+	};
+
+	let mut resolved = boundary.to_path_buf();
+	let mut components = relative.components();
+	while let Some(component) = components.next() {
+		let name = match component {
+			Component::Normal(name) => name,
+			Component::CurDir => continue,
+			Component::ParentDir if resolved != boundary => {
+				resolved.pop();
+				continue;
+			}
+			_ => return Err(invalid()),
+		};
+		resolved.push(name);
+		let metadata = match fs::symlink_metadata(&resolved) {
+			Ok(metadata) => metadata,
+			Err(e) if e.kind() == io::ErrorKind::NotFound && !exists => {
+				// Nothing below a missing directory can exist either
+				for rest in components {
+					match rest {
+						Component::Normal(name) => resolved.push(name),
+						_ => return Err(invalid()),
+					}
+				}
+				return Ok(resolved);
+			}
+			Err(e) => return Err(e.into()),
+		};
+		if metadata.file_type().is_symlink() {
+			if symlinks == SymlinkPolicy::Refuse {
+				return Err(invalid());
+			}
+			// A dangling symlink could be created through to anywhere
+			let target = canonical_path(&resolved).map_err(|_| invalid())?;
+			if !target.starts_with(boundary) {
+				return Err(invalid());
+			}
+			resolved = target;
+		}
+	}
+	Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::{native_path, protocol_path, resolve_within, strip_verbatim};
+	use crate::config::SymlinkPolicy;
+
+	#[test]
+	fn verbatim_prefixes_are_stripped_where_safe() {
+		assert_eq!(strip_verbatim(r"\\?\C:\home\file.txt"), r"C:\home\file.txt");
+		assert_eq!(
+			strip_verbatim(r"\\?\UNC\host\share\dir"),
+			r"\\host\share\dir"
+		);
+		// Paths which were never verbatim are left alone
+		assert_eq!(strip_verbatim(r"C:\home"), r"C:\home");
+		assert_eq!(strip_verbatim("/home/user"), "/home/user");
+	}
+
+	#[test]
+	fn verbatim_prefixes_are_kept_where_needed() {
+		let kept = |path: &str| assert_eq!(strip_verbatim(path), path);
+		// Not a drive or share
+		kept(r"\\?\Volume{1234}\dir");
+		// Reserved names, with or without an extension, in any case
+		kept(r"\\?\C:\home\con");
+		kept(r"\\?\C:\home\Nul.txt");
+		// Names Windows would trim
+		kept(r"\\?\C:\home\dir.");
+		kept(r"\\?\C:\home\dir ");
+		// Too long once stripped
+		kept(&format!(r"\\?\C:\{}", "a".repeat(300)));
+	}
+
+	#[test]
+	fn protocol_paths_use_forward_slashes() {
+		let relative = Path::new("dir").join("sub").join("file.txt");
+		assert_eq!(protocol_path(&relative), "dir/sub/file.txt");
+		assert_eq!(protocol_path(Path::new("file.txt")), "file.txt");
+	}
+
+	#[cfg(not(windows))]
+	#[test]
+	fn client_paths_are_used_as_they_are() {
+		assert_eq!(native_path("dir/file.txt"), Path::new("dir/file.txt"));
+	}
+
+	#[cfg(windows)]
+	#[test]
+	fn client_paths_are_given_backslashes() {
+		assert_eq!(native_path("dir/file.txt"), Path::new(r"dir\file.txt"));
+	}
+
+	#[cfg(windows)]
+	#[test]
+	fn canonical_paths_compare_with_plain_ones() {
+		let dir = std::env::temp_dir();
+		let canonical = super::canonical_path(&dir).unwrap();
+		assert!(!canonical.to_str().unwrap().starts_with(r"\\?\"));
+		assert!(super::canonical_path(&dir.join("."))
+			.unwrap()
+			.starts_with(&canonical));
+	}
+
+	#[test]
+	fn resolved_paths_stay_within_the_boundary() {
+		let dir = std::env::temp_dir().join(format!("editr-paths-{}", std::process::id()));
+		std::fs::create_dir_all(dir.join("sub")).unwrap();
+		let boundary = super::canonical_path(&dir).unwrap();
+		let resolve = |path: &str, exists| {
+			resolve_within(
+				&boundary,
+				Path::new(path),
+				SymlinkPolicy::WithinHome,
+				exists,
+			)
+		};
+		assert_eq!(
+			resolve("./sub/../sub/file.txt", false).unwrap(),
+			boundary.join("sub/file.txt")
+		);
+		// '..' never climbs out, and missing parts must be plain names
+		assert!(resolve("..", true).is_err());
+		assert!(resolve("sub/../..", true).is_err());
+		assert!(resolve("missing/../file.txt", false).is_err());
+		assert!(resolve("missing", true).is_err());
+		// Absolute paths must already be under the boundary
+		assert!(resolve("/", true).is_err());
+		let absolute = boundary.join("sub");
+		assert_eq!(resolve(absolute.to_str().unwrap(), true).unwrap(), absolute);
+		std::fs::remove_dir_all(dir).ok();
+	}
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::error::{EditrError, EditrResult};
+
+// Tracked usage drifts whenever files change behind the server's back
+const RESCAN_INTERVAL: Duration = Duration::from_secs(300);
+
+struct HomeUsage {
+	used: u64,
+	scanned: Instant,
+}
+
+// Tracks disk usage of each home against an optional byte limit
+#[derive(Clone, Default)]
+pub struct Quotas {
+	limit: Option<u64>,
+	container: Arc<Mutex<HashMap<PathBuf, HomeUsage>>>,
+}
+
+impl Quotas {
+	pub fn new(limit: Option<u64>) -> Quotas {
+		Quotas {
+			limit,
+			container: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	// Returns the bytes used by home along with the limit
+	pub fn usage(&self, home: &Path) -> EditrResult<(u64, Option<u64>)> {
+		self.home_op(home, |usage| Ok((usage.used, self.limit)))
+	}
+
+	// Accounts for a change of delta bytes in home, refusing growth past the limit
+	pub fn reserve(&self, home: &Path, delta: i64) -> EditrResult<()> {
+		if self.limit.is_none() {
+			return Ok(());
+		}
+		self.home_op(home, |usage| {
+			let used = (usage.used as i64 + delta).max(0) as u64;
+			if let Some(limit) = self.limit {
+				if delta > 0 && used > limit {
+					return Err(EditrError::QuotaExceeded { used, limit });
+				}
+			}
+			usage.used = used;
+			Ok(())
+		})
+	}
+
+	// Gives back delta bytes reserved for a change which didn't happen. Never
+	// refused, even if giving back a shrink takes home past the limit
+	pub fn release(&self, home: &Path, delta: i64) -> EditrResult<()> {
+		if self.limit.is_none() {
+			return Ok(());
+		}
+		self.home_op(home, |usage| {
+			usage.used = (usage.used as i64 - delta).max(0) as u64;
+			Ok(())
+		})
+	}
+
+	// Applies op to home's usage, rescanning home if its usage is unknown or stale
+	fn home_op<T, F: FnOnce(&mut HomeUsage) -> EditrResult<T>>(
+		&self,
+		home: &Path,
+		op: F,
+	) -> EditrResult<T> {
+		let mut container = self.container.lock();
+		let stale = match container.get(home) {
+			Some(usage) => usage.scanned.elapsed() > RESCAN_INTERVAL,
+			None => true,
+		};
+		if stale {
+			let usage = HomeUsage {
+				used: dir_size(home)?,
+				scanned: Instant::now(),
+			};
+			container.insert(home.to_path_buf(), usage);
+		}
+		op(container
+			.get_mut(home)
+			.ok_or_else(|| EditrError::Internal("Home usage does not exist".to_string()))?)
+	}
+}
+
+// Sums the sizes of all files under path
+fn dir_size(path: &Path) -> EditrResult<u64> {
+	let mut size = 0;
+	for entry in fs::read_dir(path)? {
+		let entry = entry?;
+		let file_type = entry.file_type()?;
+		if file_type.is_dir() {
+			size += dir_size(&entry.path())?;
+		}
+		else if file_type.is_file() {
+			size += entry.metadata()?.len();
+		}
+	}
+	Ok(size)
+}
@@ -0,0 +1,136 @@
+mod timing;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+pub use self::timing::{OpTimer, OpTiming, Phase, TimingTotals};
+use crate::state::FileStats;
+
+// Counters describing what the whole server has been doing
+#[derive(Default)]
+pub struct ServerMetrics {
+	connections: AtomicUsize,
+	total_connections: AtomicU64,
+	broadcasts: AtomicU64,
+	messages: Mutex<HashMap<&'static str, u64>>,
+	errors: Mutex<HashMap<&'static str, u64>>,
+	timings: Mutex<HashMap<&'static str, TimingTotals>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerSnapshot {
+	pub connections: u64,
+	pub total_connections: u64,
+	pub open_files: u64,
+	pub rope_bytes: u64,
+	pub broadcasts: u64,
+	pub messages: HashMap<String, u64>,
+	pub errors: HashMap<String, u64>,
+	// Time spent on each kind of message, split between phases
+	#[serde(default)]
+	pub timings: HashMap<String, TimingTotals>,
+	pub files: Vec<FileStats>,
+}
+
+impl ServerMetrics {
+	// Counts a connection coming in, returning how many were already open
+	#[inline]
+	pub fn connection_opened(&self) -> usize {
+		self.total_connections.fetch_add(1, Ordering::Relaxed);
+		self.connections.fetch_add(1, Ordering::SeqCst)
+	}
+
+	#[inline]
+	pub fn connection_closed(&self) { self.connections.fetch_sub(1, Ordering::SeqCst); }
+
+	// Connections being served or waiting to be
+	#[inline]
+	pub fn connections(&self) -> usize { self.connections.load(Ordering::SeqCst) }
+
+	#[inline]
+	pub fn broadcast_sent(&self) { self.broadcasts.fetch_add(1, Ordering::Relaxed); }
+
+	// Counts a processed message by kind, along with whether it failed
+	pub fn record(&self, kind: &'static str, error: bool) {
+		*self.messages.lock().entry(kind).or_insert(0) += 1;
+		if error {
+			*self.errors.lock().entry(kind).or_insert(0) += 1;
+		}
+	}
+
+	// Adds the time a message of kind took to the totals for its kind
+	pub fn record_timing(&self, kind: &'static str, timing: &OpTiming) {
+		self.timings.lock().entry(kind).or_default().add(timing);
+	}
+
+	// Files are kept track of by FileStates, so they are handed in
+	pub fn snapshot(&self, files: Vec<FileStats>) -> ServerSnapshot {
+		ServerSnapshot {
+			connections: self.connections.load(Ordering::SeqCst) as u64,
+			total_connections: self.total_connections.load(Ordering::Relaxed),
+			open_files: files.len() as u64,
+			rope_bytes: files.iter().map(|file| file.bytes).sum(),
+			broadcasts: self.broadcasts.load(Ordering::Relaxed),
+			messages: to_owned_keys(&self.messages.lock()),
+			errors: to_owned_keys(&self.errors.lock()),
+			timings: self
+				.timings
+				.lock()
+				.iter()
+				.map(|(kind, totals)| (kind.to_string(), totals.clone()))
+				.collect(),
+			files,
+		}
+	}
+}
+
+// Summary of a snapshot logged every so often, counting operations since
+// the one before
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StatsReport {
+	// Seconds since the Unix epoch
+	pub time: u64,
+	pub connections: u64,
+	pub open_files: u64,
+	pub rope_bytes: u64,
+	pub operations: u64,
+	pub errors: u64,
+	// Every error since the server started, by kind of message
+	pub total_errors: HashMap<String, u64>,
+}
+
+impl ServerSnapshot {
+	// Summarises the snapshot, counting operations since previous, or
+	// since the server started if there wasn't one
+	pub fn report(&self, previous: Option<&ServerSnapshot>) -> StatsReport {
+		let (operations, errors) = previous.map_or((0, 0), |previous| {
+			(
+				previous.messages.values().sum(),
+				previous.errors.values().sum(),
+			)
+		});
+		let time = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |time| time.as_secs());
+		StatsReport {
+			time,
+			connections: self.connections,
+			open_files: self.open_files,
+			rope_bytes: self.rope_bytes,
+			operations: self.messages.values().sum::<u64>() - operations,
+			errors: self.errors.values().sum::<u64>() - errors,
+			total_errors: self.errors.clone(),
+		}
+	}
+}
+
+fn to_owned_keys(counts: &HashMap<&'static str, u64>) -> HashMap<String, u64> {
+	counts
+		.iter()
+		.map(|(kind, count)| (kind.to_string(), *count))
+		.collect()
+}
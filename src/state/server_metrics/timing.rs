@@ -0,0 +1,192 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+// Parts of a message's handling which are timed separately
+#[derive(Clone, Copy, Debug)]
+pub enum Phase {
+	// Waiting for the locks on the file being edited
+	Acquire,
+	// Changing the rope
+	Apply,
+	// Sending the change to everyone else in the file
+	Broadcast,
+}
+
+// Splits the time taken by a message between phases. The clock is read
+// once as each phase ends, counting the time since the last one ended,
+// so time before the first phase counts towards it
+pub struct OpTimer {
+	started: Instant,
+	last: Cell<Instant>,
+	phases: Cell<[Duration; 3]>,
+}
+
+impl OpTimer {
+	pub fn start() -> OpTimer {
+		let now = Instant::now();
+		OpTimer {
+			started: now,
+			last: Cell::new(now),
+			phases: Cell::new([Duration::ZERO; 3]),
+		}
+	}
+
+	// Ends phase, which may be ended more than once
+	pub fn mark(&self, phase: Phase) {
+		let now = Instant::now();
+		let mut phases = self.phases.get();
+		phases[phase as usize] += now - self.last.replace(now);
+		self.phases.set(phases);
+	}
+
+	// Stops the clock, with whatever was not in a phase counting as other
+	pub fn finish(&self) -> OpTiming {
+		let total = self.started.elapsed();
+		let [acquire, apply, broadcast] = self.phases.get();
+		OpTiming {
+			total,
+			acquire,
+			apply,
+			broadcast,
+		}
+	}
+}
+
+// Time taken by a single message
+#[derive(Clone, Copy, Debug)]
+pub struct OpTiming {
+	pub total: Duration,
+	pub acquire: Duration,
+	pub apply: Duration,
+	pub broadcast: Duration,
+}
+
+impl OpTiming {
+	pub fn other(&self) -> Duration {
+		self.total
+			.saturating_sub(self.acquire + self.apply + self.broadcast)
+	}
+
+	// The phase which took longest, or None if most of the time was
+	// spent outside them
+	pub fn slowest(&self) -> Option<Phase> {
+		let phases = [
+			(Phase::Acquire, self.acquire),
+			(Phase::Apply, self.apply),
+			(Phase::Broadcast, self.broadcast),
+		];
+		let (phase, longest) = phases.iter().copied().max_by_key(|(_, time)| *time)?;
+		(longest > self.other()).then_some(phase)
+	}
+
+	// Where the time went, as slow messages are logged
+	pub fn summary(&self) -> String {
+		let slowest = self
+			.slowest()
+			.map_or("elsewhere".to_string(), |phase| format!("in {:?}", phase));
+		format!(
+			"{:?}, mostly {} (acquire {:?}, apply {:?}, broadcast {:?}, other {:?})",
+			self.total,
+			slowest,
+			self.acquire,
+			self.apply,
+			self.broadcast,
+			self.other()
+		)
+	}
+}
+
+// Time taken by every message of one kind, in microseconds
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct TimingTotals {
+	pub count: u64,
+	pub total_us: u64,
+	pub acquire_us: u64,
+	pub apply_us: u64,
+	pub broadcast_us: u64,
+	pub max_us: u64,
+}
+
+impl TimingTotals {
+	pub fn add(&mut self, timing: &OpTiming) {
+		let total = timing.total.as_micros() as u64;
+		self.count += 1;
+		self.total_us += total;
+		self.acquire_us += timing.acquire.as_micros() as u64;
+		self.apply_us += timing.apply.as_micros() as u64;
+		self.broadcast_us += timing.broadcast.as_micros() as u64;
+		self.max_us = self.max_us.max(total);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread::sleep;
+
+	use super::*;
+
+	const PAUSE: Duration = Duration::from_millis(30);
+
+	#[test]
+	fn a_slow_broadcast_is_blamed() {
+		let timer = OpTimer::start();
+		timer.mark(Phase::Acquire);
+		timer.mark(Phase::Apply);
+		// As if the recipients took their time
+		sleep(PAUSE);
+		timer.mark(Phase::Broadcast);
+		let timing = timer.finish();
+
+		assert!(timing.broadcast >= PAUSE);
+		assert!(timing.acquire + timing.apply < PAUSE);
+		assert!(matches!(timing.slowest(), Some(Phase::Broadcast)));
+		assert!(timing.summary().contains("mostly in Broadcast"));
+	}
+
+	#[test]
+	fn phases_ended_twice_add_up() {
+		let timer = OpTimer::start();
+		sleep(PAUSE);
+		timer.mark(Phase::Acquire);
+		timer.mark(Phase::Apply);
+		sleep(PAUSE);
+		timer.mark(Phase::Acquire);
+		let timing = timer.finish();
+
+		assert!(timing.acquire >= 2 * PAUSE);
+		assert!(timing.acquire + timing.apply + timing.broadcast <= timing.total);
+		assert!(matches!(timing.slowest(), Some(Phase::Acquire)));
+	}
+
+	#[test]
+	fn time_outside_the_phases_blames_none() {
+		let timer = OpTimer::start();
+		timer.mark(Phase::Apply);
+		sleep(PAUSE);
+		let timing = timer.finish();
+
+		assert!(timing.other() >= PAUSE);
+		assert!(timing.slowest().is_none());
+		assert!(timing.summary().contains("mostly elsewhere"));
+	}
+
+	#[test]
+	fn totals_are_kept_per_kind_in_microseconds() {
+		let mut totals = TimingTotals::default();
+		let timing = |total| OpTiming {
+			total: Duration::from_micros(total),
+			acquire: Duration::from_micros(1),
+			apply: Duration::from_micros(2),
+			broadcast: Duration::from_micros(3),
+		};
+		totals.add(&timing(10));
+		totals.add(&timing(40));
+
+		assert_eq!(totals.count, 2);
+		assert_eq!(totals.total_us, 50);
+		assert_eq!(totals.broadcast_us, 6);
+		assert_eq!(totals.max_us, 40);
+	}
+}
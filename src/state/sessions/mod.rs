@@ -0,0 +1,167 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::Permissions;
+use crate::error::{EditrError, EditrResult};
+use crate::state::ClientId;
+
+// Connection state kept while a session waits to be resumed
+pub struct SavedSession {
+	pub user: Option<String>,
+	pub permissions: Permissions,
+	pub canonical_home: PathBuf,
+	pub opened_file: Option<PathBuf>,
+	pub recent_files: VecDeque<PathBuf>,
+	pub register: Vec<u8>,
+}
+
+struct Session {
+	id: ClientId,
+	// Set while no connection is attached
+	detached: Option<(SavedSession, Instant)>,
+}
+
+// Sessions by token, which outlive the connections they are attached to
+#[derive(Clone, Default)]
+pub struct Sessions {
+	container: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Sessions {
+	pub fn new() -> Sessions {
+		Sessions {
+			container: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	// Starts a session for the connected client id, returning its token
+	pub fn create(&self, id: ClientId) -> EditrResult<String> {
+		let token = new_token()?;
+		self.container
+			.lock()
+			.insert(token.clone(), Session { id, detached: None });
+		Ok(token)
+	}
+
+	// Marks the session as waiting to be resumed
+	pub fn detach(&self, token: &str, saved: SavedSession) -> EditrResult<()> {
+		let mut container = self.container.lock();
+		let session = container
+			.get_mut(token)
+			.ok_or_else(|| EditrError::Protocol("Session does not exist".to_string()))?;
+		session.detached = Some((saved, Instant::now()));
+		Ok(())
+	}
+
+	// Claims a detached session which has not outlived grace
+	pub fn attach(&self, token: &str, grace: Duration) -> EditrResult<(ClientId, SavedSession)> {
+		let mut container = self.container.lock();
+		let session = container
+			.get_mut(token)
+			.ok_or_else(|| EditrError::Protocol("Session does not exist".to_string()))?;
+		match session.detached.take() {
+			Some((saved, since)) if since.elapsed() <= grace => Ok((session.id, saved)),
+			Some(detached) => {
+				// Leave expired sessions for the reaper to clean up
+				session.detached = Some(detached);
+				Err(EditrError::Protocol("Session expired".to_string()))
+			}
+			None => Err(EditrError::Protocol("Session is in use".to_string())),
+		}
+	}
+
+	// Forgets a session which will not be resumed
+	pub fn remove(&self, token: &str) { self.container.lock().remove(token); }
+
+	// Removes sessions detached for longer than grace, returning their state
+	pub fn reap(&self, grace: Duration) -> Vec<(ClientId, SavedSession)> {
+		let mut container = self.container.lock();
+		let expired = container
+			.iter()
+			.filter(|(_, session)| match &session.detached {
+				Some((_, since)) => since.elapsed() > grace,
+				None => false,
+			})
+			.map(|(token, _)| token.clone())
+			.collect::<Vec<_>>();
+		expired
+			.into_iter()
+			.filter_map(|token| container.remove(&token))
+			.filter_map(|Session { id, detached }| detached.map(|(saved, _)| (id, saved)))
+			.collect()
+	}
+}
+
+// An unguessable identifier, 128 bits from the OS random source. Tokens
+// stand in for the connection that made them, so must not be predictable
+pub fn new_token() -> EditrResult<String> {
+	let mut bytes = [0; 16];
+	getrandom::getrandom(&mut bytes)
+		.map_err(|e| EditrError::Internal(format!("No randomness for a token: {}", e)))?;
+	Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn saved() -> SavedSession {
+		SavedSession {
+			user: None,
+			permissions: Permissions::Editor,
+			canonical_home: PathBuf::from("/home"),
+			opened_file: None,
+			recent_files: VecDeque::new(),
+			register: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn tokens_are_random_hex() {
+		let tokens = (0..100).map(|_| new_token().unwrap()).collect::<Vec<_>>();
+		for token in &tokens {
+			assert_eq!(token.len(), 32);
+			assert!(token.bytes().all(|byte| byte.is_ascii_hexdigit()));
+		}
+		let mut unique = tokens.clone();
+		unique.sort();
+		unique.dedup();
+		assert_eq!(unique.len(), tokens.len());
+	}
+
+	#[test]
+	fn detached_sessions_attach_once() {
+		let sessions = Sessions::new();
+		let id = ClientId::next();
+		let token = sessions.create(id).unwrap();
+		assert!(sessions.attach(&token, Duration::from_secs(60)).is_err());
+
+		sessions.detach(&token, saved()).unwrap();
+		let (attached, _) = sessions.attach(&token, Duration::from_secs(60)).unwrap();
+		assert_eq!(attached, id);
+		assert!(sessions.attach(&token, Duration::from_secs(60)).is_err());
+		assert!(sessions
+			.attach("not a token", Duration::from_secs(60))
+			.is_err());
+	}
+
+	#[test]
+	fn expired_sessions_are_reaped() {
+		let sessions = Sessions::new();
+		let token = sessions.create(ClientId::next()).unwrap();
+		let kept = sessions.create(ClientId::next()).unwrap();
+		sessions.detach(&token, saved()).unwrap();
+		std::thread::sleep(Duration::from_millis(10));
+
+		assert!(sessions.attach(&token, Duration::ZERO).is_err());
+		assert_eq!(sessions.reap(Duration::ZERO).len(), 1);
+		assert!(sessions.attach(&token, Duration::from_secs(60)).is_err());
+		// Sessions still attached are never reaped
+		assert!(sessions.reap(Duration::ZERO).is_empty());
+		sessions.remove(&kept);
+	}
+}
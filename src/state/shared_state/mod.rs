@@ -0,0 +1,154 @@
+use std::net::TcpStream;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use parking_lot::Mutex;
+
+use crate::config::ServerConfig;
+use crate::error::EditrResult;
+use crate::state::*;
+
+// Server wide state handed to every connection
+pub struct SharedState<T: Transport = TcpStream> {
+	pub shared_out: shared_out::SharedOut<T>,
+	pub files: FileStates,
+	pub quotas: Quotas,
+	pub sessions: Sessions,
+	pub connections: Connections<T>,
+	pub config: Arc<ServerConfig>,
+	pub canonical_home: PathBuf,
+	pub metrics: Arc<ServerMetrics>,
+	pub access_log: Option<Arc<AccessLog>>,
+	pub journal: Option<Arc<Journal>>,
+	// Thread reporting on the metrics, if there is one
+	pub stats_reporter: Arc<Mutex<Option<JoinHandle<()>>>>,
+	stopping: Arc<AtomicBool>,
+}
+
+// A connection's place within max_connections, released when dropped so
+// the count stays right however the connection ends
+pub struct ConnectionSlot {
+	metrics: Arc<ServerMetrics>,
+}
+
+impl Drop for ConnectionSlot {
+	fn drop(&mut self) { self.metrics.connection_closed(); }
+}
+
+impl<T: Transport> Clone for SharedState<T> {
+	fn clone(&self) -> Self {
+		SharedState {
+			shared_out: self.shared_out.clone(),
+			files: self.files.clone(),
+			quotas: self.quotas.clone(),
+			sessions: self.sessions.clone(),
+			connections: self.connections.clone(),
+			config: self.config.clone(),
+			canonical_home: self.canonical_home.clone(),
+			metrics: self.metrics.clone(),
+			access_log: self.access_log.clone(),
+			journal: self.journal.clone(),
+			stats_reporter: self.stats_reporter.clone(),
+			stopping: self.stopping.clone(),
+		}
+	}
+}
+
+impl<T: Transport> SharedState<T> {
+	// files may already hold virtual documents supplied by an embedder
+	pub fn new(
+		config: ServerConfig,
+		canonical_home: PathBuf,
+		files: FileStates,
+	) -> EditrResult<SharedState<T>> {
+		let access_log = match &config.access_log {
+			Some(access_log) => Some(Arc::new(AccessLog::start(access_log)?)),
+			None => None,
+		};
+		let (journal, recovered) = match &config.journal {
+			Some(journal) => {
+				let (journal, recovered) = Journal::open(journal)?;
+				(Some(Arc::new(journal)), recovered)
+			}
+			None => (None, Vec::new()),
+		};
+		let files = files.journaled(journal.clone());
+		for (path, on_disk, edits) in recovered {
+			match files.recover(path.clone(), on_disk, &edits) {
+				Ok(_) => info!(
+					"Recovered {} unsaved edits to {}",
+					edits.len(),
+					path.display()
+				),
+				// Nothing will ever save or close the file, so its edits are
+				// given up on to let the journal be cleared
+				Err(e) => {
+					error!("Failed to recover {}: {}", path.display(), e);
+					if let Some(journal) = &journal {
+						journal.discarded(&path, journal.mark());
+					}
+				}
+			}
+		}
+		Ok(SharedState {
+			shared_out: shared_out::SharedOut::new(),
+			files,
+			quotas: Quotas::new(config.quota),
+			sessions: Sessions::new(),
+			connections: Connections::new(),
+			config: Arc::new(config),
+			canonical_home,
+			metrics: Arc::new(ServerMetrics::default()),
+			access_log,
+			journal,
+			stats_reporter: Arc::new(Mutex::new(None)),
+			stopping: Arc::new(AtomicBool::new(false)),
+		})
+	}
+
+	// Asks the server to shut down
+	pub fn stop(&self) { self.stopping.store(true, Ordering::SeqCst); }
+
+	pub fn is_stopping(&self) -> bool { self.stopping.load(Ordering::SeqCst) }
+
+	// Takes a slot for a new connection, unless the server is full.
+	// Turned away connections still count towards the total
+	pub fn acquire_slot(&self) -> Option<ConnectionSlot> {
+		let taken = self.metrics.connection_opened();
+		let slot = ConnectionSlot {
+			metrics: self.metrics.clone(),
+		};
+		match self.config.max_connections {
+			// Dropping the slot gives it straight back
+			Some(limit) if taken >= limit => None,
+			_ => Some(slot),
+		}
+	}
+
+	// The home whose quota the file at path counts against, which is the
+	// one the client saving it would be confined to
+	pub fn quota_home(&self, path: &Path) -> PathBuf {
+		if let Some(shared) = &self.config.shared_dir {
+			let shared_home = self.canonical_home.join(shared);
+			if path.starts_with(&shared_home) {
+				return shared_home;
+			}
+		}
+		if self.config.user_homes {
+			let user = path
+				.strip_prefix(&self.canonical_home)
+				.ok()
+				.and_then(|rest| rest.components().next());
+			if let Some(Component::Normal(user)) = user {
+				return self.canonical_home.join(user);
+			}
+		}
+		self.canonical_home.clone()
+	}
+
+	pub fn snapshot(&self) -> EditrResult<ServerSnapshot> {
+		Ok(self.metrics.snapshot(self.files.stats()?))
+	}
+}
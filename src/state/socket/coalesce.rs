@@ -0,0 +1,236 @@
+use std::io;
+
+use crate::message::{Message, UpdateData, UpdateRemove};
+use crate::state::message_kind;
+
+use super::thread_io::Queued;
+
+// Most bytes a merged update may add or remove
+const MAX_COALESCED: usize = 64 << 10;
+
+// Updates merged so far, along with the first as it was queued, which is
+// written as it is if nothing was merged into it
+struct Run {
+	first: Vec<u8>,
+	update: UpdateData,
+	merged: bool,
+}
+
+impl Run {
+	// Extends the run with next, where applying the two in turn leaves the
+	// file the same as applying the one. Otherwise next is handed back
+	fn extend(&mut self, next: UpdateData) -> Option<UpdateData> {
+		match (&mut self.update, &next) {
+			// Typing, each add landing just after the last
+			(UpdateData::Add(add), UpdateData::Add(next_add))
+				if add.offset.checked_add(add.data.len()) == Some(next_add.offset)
+					&& add.data.len().saturating_add(next_add.data.len()) <= MAX_COALESCED =>
+			{
+				add.data.extend_from_slice(&next_add.data);
+			}
+			// Deleting forwards, each remove starting where the last did
+			(UpdateData::Remove(remove), UpdateData::Remove(next_remove))
+				if next_remove.offset == remove.offset
+					&& remove.len.saturating_add(next_remove.len) <= MAX_COALESCED =>
+			{
+				remove.len += next_remove.len;
+			}
+			// Deleting backwards, each remove ending where the last started
+			(UpdateData::Remove(remove), UpdateData::Remove(next_remove))
+				if next_remove.offset.checked_add(next_remove.len) == Some(remove.offset)
+					&& remove.len.saturating_add(next_remove.len) <= MAX_COALESCED =>
+			{
+				*remove = UpdateRemove {
+					offset: next_remove.offset,
+					len: remove.len + next_remove.len,
+				};
+			}
+			_ => return Some(next),
+		}
+		self.merged = true;
+		None
+	}
+
+	fn finish(self) -> io::Result<Queued> {
+		if !self.merged {
+			return Ok(Queued::Data(self.first));
+		}
+		Ok(Queued::Data(serde_json::to_vec(&Message::UpdateMessage(
+			self.update,
+		))?))
+	}
+}
+
+// Merges runs of queued updates which carry on from one another into single
+// updates, so a client which has fallen behind a fast typist catches up in
+// fewer messages. Only neighbours are merged, so nothing is reordered
+pub(super) fn coalesce(batch: Vec<Queued>) -> io::Result<Vec<Queued>> {
+	if batch.len() < 2 {
+		return Ok(batch);
+	}
+
+	let mut coalesced = Vec::with_capacity(batch.len());
+	let mut run: Option<Run> = None;
+	for queued in batch {
+		let (buf, update) = match queued {
+			Queued::Data(buf) => match parse_update(&buf) {
+				Some(update) => (buf, update),
+				None => {
+					coalesced.extend(run.take().map(Run::finish).transpose()?);
+					coalesced.push(Queued::Data(buf));
+					continue;
+				}
+			},
+			queued => {
+				coalesced.extend(run.take().map(Run::finish).transpose()?);
+				coalesced.push(queued);
+				continue;
+			}
+		};
+		let unmerged = match &mut run {
+			Some(run) => run.extend(update),
+			None => Some(update),
+		};
+		// Anything which can't be merged starts a run of its own
+		if let Some(update) = unmerged {
+			coalesced.extend(run.take().map(Run::finish).transpose()?);
+			run = Some(Run {
+				first: buf,
+				update,
+				merged: false,
+			});
+		}
+	}
+	coalesced.extend(run.map(Run::finish).transpose()?);
+	Ok(coalesced)
+}
+
+// The update in buf, if it holds one
+fn parse_update(buf: &[u8]) -> Option<UpdateData> {
+	if message_kind(buf) != "UpdateMessage" {
+		return None;
+	}
+	match serde_json::from_slice(buf) {
+		Ok(Message::UpdateMessage(update)) => Some(update),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{coalesce, MAX_COALESCED};
+	use crate::message::{Message, UpdateData};
+	use crate::state::socket::thread_io::Queued;
+
+	fn queued(msg: Message) -> Queued { Queued::Data(msg.to_vec().unwrap()) }
+
+	fn add(offset: usize, data: &[u8]) -> Queued {
+		queued(Message::make_add_broadcast(offset, data))
+	}
+
+	fn remove(offset: usize, len: usize) -> Queued {
+		queued(Message::make_del_broadcast(offset, len))
+	}
+
+	// What was queued, as messages, with None for a Compress
+	fn messages(batch: Vec<Queued>) -> Vec<Option<Message>> {
+		coalesce(batch)
+			.unwrap()
+			.into_iter()
+			.map(|queued| match queued {
+				Queued::Data(buf) => Some(Message::from_slice(&buf).unwrap()),
+				Queued::Compress => None,
+			})
+			.collect()
+	}
+
+	fn is_add(msg: &Option<Message>, offset: usize, data: &[u8]) -> bool {
+		matches!(
+			msg,
+			Some(Message::UpdateMessage(UpdateData::Add(add)))
+				if add.offset == offset && add.data == data
+		)
+	}
+
+	fn is_remove(msg: &Option<Message>, offset: usize, len: usize) -> bool {
+		matches!(
+			msg,
+			Some(Message::UpdateMessage(UpdateData::Remove(remove)))
+				if remove.offset == offset && remove.len == len
+		)
+	}
+
+	#[test]
+	fn typing_merges_into_one_add() {
+		let merged = messages(vec![
+			add(3, b"a"),
+			add(4, b"b"),
+			add(5, b"cd"),
+			add(7, b"e"),
+		]);
+		assert_eq!(merged.len(), 1);
+		assert!(is_add(&merged[0], 3, b"abcde"));
+	}
+
+	#[test]
+	fn deleting_either_way_merges_into_one_remove() {
+		let forwards = messages(vec![remove(5, 1), remove(5, 2), remove(5, 1)]);
+		assert_eq!(forwards.len(), 1);
+		assert!(is_remove(&forwards[0], 5, 4));
+
+		let backwards = messages(vec![remove(9, 1), remove(8, 1), remove(6, 2)]);
+		assert_eq!(backwards.len(), 1);
+		assert!(is_remove(&backwards[0], 6, 4));
+	}
+
+	#[test]
+	fn edits_elsewhere_are_left_apart() {
+		let merged = messages(vec![add(0, b"a"), add(5, b"b"), remove(0, 1), add(0, b"c")]);
+		assert_eq!(merged.len(), 4);
+		assert!(is_add(&merged[0], 0, b"a"));
+		assert!(is_add(&merged[1], 5, b"b"));
+		assert!(is_remove(&merged[2], 0, 1));
+		assert!(is_add(&merged[3], 0, b"c"));
+	}
+
+	#[test]
+	fn other_messages_are_never_merged_across() {
+		let merged = messages(vec![
+			add(0, b"a"),
+			add(1, b"b"),
+			queued(Message::Pong),
+			add(2, b"c"),
+			Queued::Compress,
+			add(3, b"d"),
+		]);
+		assert_eq!(merged.len(), 5);
+		assert!(is_add(&merged[0], 0, b"ab"));
+		assert!(matches!(merged[1], Some(Message::Pong)));
+		assert!(is_add(&merged[2], 2, b"c"));
+		assert!(merged[3].is_none());
+		assert!(is_add(&merged[4], 3, b"d"));
+	}
+
+	#[test]
+	fn merged_updates_are_kept_under_the_limit() {
+		let chunk = vec![b'x'; MAX_COALESCED / 2];
+		let batch = (0..3)
+			.map(|index| add(index * chunk.len(), &chunk))
+			.collect();
+		let merged = messages(batch);
+		assert_eq!(merged.len(), 2);
+		assert!(is_add(&merged[0], 0, &[b'x'; MAX_COALESCED]));
+		assert!(is_add(&merged[1], MAX_COALESCED, &chunk));
+	}
+
+	#[test]
+	fn updates_merged_with_nothing_are_written_as_queued() {
+		let mut buf = Message::make_add_broadcast(0, b"a").to_vec().unwrap();
+		buf.push(b'\n');
+		let batch = vec![Queued::Data(buf.clone()), add(9, b"b")];
+		match &coalesce(batch).unwrap()[0] {
+			Queued::Data(written) => assert_eq!(*written, buf),
+			Queued::Compress => panic!("Expected data"),
+		}
+	}
+}
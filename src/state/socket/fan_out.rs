@@ -0,0 +1,55 @@
+use std::sync::mpsc::{channel, Sender};
+use std::sync::OnceLock;
+use std::thread::{available_parallelism, spawn};
+
+// Most threads writing out a single broadcast
+const MAX_WORKERS: usize = 4;
+
+pub(super) type Job = Box<dyn FnOnce() + Send>;
+
+// Threads sharing out the writes of large broadcasts, started the first
+// time one is needed
+#[derive(Default)]
+pub(super) struct FanOut {
+	workers: OnceLock<Vec<Sender<Job>>>,
+}
+
+impl FanOut {
+	// Runs the jobs across the workers, returning once all of them have
+	// finished, so nothing written overtakes a later broadcast
+	pub fn run(&self, jobs: Vec<Job>) {
+		let workers = self.workers.get_or_init(start_workers);
+		let (done, finished) = channel::<()>();
+		for (job, worker) in jobs.into_iter().zip(workers.iter().cycle()) {
+			// Each job holds a sender until it is over, however it ends
+			let done = done.clone();
+			let job: Job = Box::new(move || {
+				job();
+				drop(done);
+			});
+			// A worker which has died leaves its job to be run here instead
+			if let Err(failed) = worker.send(job) {
+				(failed.0)();
+			}
+		}
+		drop(done);
+		finished.recv().ok();
+	}
+}
+
+fn start_workers() -> Vec<Sender<Job>> {
+	let count = available_parallelism()
+		.map_or(1, usize::from)
+		.min(MAX_WORKERS);
+	(0..count)
+		.map(|_| {
+			let (sender, receiver) = channel::<Job>();
+			spawn(move || {
+				while let Ok(job) = receiver.recv() {
+					job();
+				}
+			});
+			sender
+		})
+		.collect()
+}
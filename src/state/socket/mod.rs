@@ -1,38 +1,137 @@
+mod coalesce;
+mod fan_out;
 pub mod shared_out;
+#[cfg(feature = "async-net")]
+mod task_io;
 mod thread_io;
+mod transport;
 
 use std::net::TcpStream;
-use std::thread::ThreadId;
+use std::sync::Arc;
 
 use shared_out::SharedOut;
-use thread_io::ThreadIn;
+#[cfg(feature = "async-net")]
+use task_io::TaskIn;
+#[cfg(feature = "async-net")]
+pub use task_io::TaskStream;
+pub(crate) use thread_io::ThreadIn;
+#[cfg(unix)]
+pub use transport::StdioStream;
+pub use transport::{MemoryStream, Transport};
 
+#[cfg(feature = "async-net")]
+use crate::error::EditrError;
 use crate::error::EditrResult;
 use crate::message::Message;
+use crate::state::{ClientId, ConnectionMetrics};
 
-pub struct Socket {
-	local_in: ThreadIn,
-	shared_out: SharedOut,
+pub struct Socket<T: Transport = TcpStream> {
+	local_in: Incoming<T>,
+	shared_out: SharedOut<T>,
+	metrics: Arc<ConnectionMetrics>,
 }
 
-impl Socket {
-	pub fn new(thread_id: ThreadId, stream: TcpStream, out: SharedOut) -> EditrResult<Socket> {
-		out.insert(thread_id, stream.try_clone()?)?;
+// Where a connection's messages are read from, by its own thread or by a
+// task waiting on tokio
+enum Incoming<T> {
+	Thread(ThreadIn<T>),
+	#[cfg(feature = "async-net")]
+	Task(TaskIn),
+}
+
+impl<T: Transport> Incoming<T> {
+	fn new(stream: &T, limit: usize) -> EditrResult<Incoming<T>> {
+		#[cfg(feature = "async-net")]
+		if let Some(task) = stream.task() {
+			return Ok(Incoming::Task(TaskIn::new(task.clone(), limit)));
+		}
+		Ok(Incoming::Thread(ThreadIn::new(stream.try_clone()?, limit)?))
+	}
+}
+
+impl<T: Transport> Socket<T> {
+	pub fn new(
+		id: ClientId,
+		stream: T,
+		out: SharedOut<T>,
+		metrics: Arc<ConnectionMetrics>,
+		max_message_size: usize,
+	) -> EditrResult<Socket<T>> {
+		let local_in = Incoming::new(&stream, max_message_size)?;
+		out.insert(id, stream, metrics.clone())?;
 		Ok(Socket {
-			local_in: ThreadIn::new(stream)?,
+			local_in,
 			shared_out: out,
+			metrics,
 		})
 	}
 
-	pub fn get_message(&mut self) -> EditrResult<Message> { self.local_in.get_message() }
+	pub fn get_message(&mut self) -> EditrResult<Message> {
+		let msg = match &mut self.local_in {
+			Incoming::Thread(local_in) => local_in.get_message(),
+			#[cfg(feature = "async-net")]
+			Incoming::Task(_) => Err(EditrError::Internal(
+				"Connection is read by a task".to_string(),
+			)),
+		};
+		self.metrics.set_bytes_read(self.bytes_read());
+		msg
+	}
 
-	// Writes from buffer into thread_id's writer
-	pub fn write(&self, thread_id: ThreadId, buf: &[u8]) -> EditrResult<usize> {
-		self.shared_out.write(thread_id, buf)
+	// Waits for the next message without holding up the thread
+	#[cfg(feature = "async-net")]
+	pub async fn next_message(&mut self) -> EditrResult<Message> {
+		let msg = match &mut self.local_in {
+			Incoming::Task(local_in) => local_in.get_message().await,
+			Incoming::Thread(_) => Err(EditrError::Internal(
+				"Connection is read by a thread".to_string(),
+			)),
+		};
+		self.metrics.set_bytes_read(self.bytes_read());
+		msg
 	}
 
-	// Closes the socket
-	pub fn close(&self, thread_id: ThreadId) -> EditrResult<()> {
-		self.shared_out.remove(thread_id)
+	// Total bytes read from the connection so far
+	pub fn bytes_read(&self) -> u64 {
+		match &self.local_in {
+			Incoming::Thread(local_in) => local_in.bytes_read(),
+			#[cfg(feature = "async-net")]
+			Incoming::Task(local_in) => local_in.bytes_read(),
+		}
 	}
+
+	// Writes from buffer into id's writer
+	pub fn write(&self, id: ClientId, buf: &[u8]) -> EditrResult<()> {
+		self.shared_out.write(id, buf)
+	}
+
+	// Output to every client, which this connection's is one of
+	pub fn shared_out(&self) -> &SharedOut<T> { &self.shared_out }
+
+	// Writes buffer to each of the clients
+	pub fn write_many(&self, clients: &[ClientId], buf: &[u8]) -> EditrResult<()> {
+		self.shared_out.write_many(clients, buf)
+	}
+
+	// Compresses the connection both ways, from the next message read and
+	// everything written to id after what is already queued
+	pub fn compress(&mut self, id: ClientId) -> EditrResult<()> {
+		match &mut self.local_in {
+			Incoming::Thread(local_in) => local_in.decompress(),
+			#[cfg(feature = "async-net")]
+			Incoming::Task(local_in) => local_in.decompress(),
+		}
+		self.shared_out.compress(id)
+	}
+
+	// Keeps id's outgoing messages for a later attach
+	pub fn detach(&self, id: ClientId) -> EditrResult<()> { self.shared_out.detach(id) }
+
+	// Hands this connection's output, registered under connection, to session
+	pub fn attach(&self, session: ClientId, connection: ClientId) -> EditrResult<()> {
+		self.shared_out.attach(session, connection)
+	}
+
+	// Closes the socket
+	pub fn close(&self, id: ClientId) -> EditrResult<()> { self.shared_out.remove(id) }
 }
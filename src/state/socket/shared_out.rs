@@ -1,79 +1,282 @@
 use std::collections::HashMap;
 use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread::ThreadId;
 
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+use super::fan_out::{FanOut, Job};
 use super::thread_io::ThreadOut;
-use crate::error::EditrResult;
+use super::Transport;
+use crate::error::{EditrError, EditrResult};
+use crate::state::{ClientId, ConnectionMetrics};
 
-#[derive(Default, Clone)]
-pub struct SharedOut {
-	shared_out: Arc<RwLock<HashMap<ThreadId, ThreadOut>>>,
+// Recipients written to by one thread. Broadcasts to more are shared out
+// between several
+const FAN_OUT_CHUNK: usize = 32;
+
+pub struct SharedOut<T: Transport = TcpStream> {
+	shared_out: Arc<RwLock<HashMap<ClientId, ThreadOut<T>>>>,
+	fan_out: Arc<FanOut>,
 }
 
-impl SharedOut {
+impl<T: Transport> Clone for SharedOut<T> {
+	fn clone(&self) -> Self {
+		SharedOut {
+			shared_out: self.shared_out.clone(),
+			fan_out: self.fan_out.clone(),
+		}
+	}
+}
+
+impl<T: Transport> Default for SharedOut<T> {
+	fn default() -> Self { Self::new() }
+}
+
+impl<T: Transport> SharedOut<T> {
 	// Constructs empty SharedOutContainer
-	pub fn new() -> SharedOut {
+	pub fn new() -> SharedOut<T> {
 		SharedOut {
 			shared_out: Arc::new(RwLock::new(HashMap::new())),
+			fan_out: Arc::new(FanOut::default()),
 		}
 	}
 
 	// Inserts a new stream
-	pub fn insert(&self, thread_id: ThreadId, stream: TcpStream) -> EditrResult<()> {
+	pub fn insert(
+		&self,
+		id: ClientId,
+		stream: T,
+		metrics: Arc<ConnectionMetrics>,
+	) -> EditrResult<()> {
 		self.hashmap_mut_op(|mut hashmap| {
-			hashmap.insert(thread_id, ThreadOut::new(stream)?);
+			hashmap.insert(id, ThreadOut::new(stream, metrics)?);
 			Ok(())
 		})
 	}
 
-	// Removes thread_id's stream
-	pub fn remove(&self, thread_id: ThreadId) -> EditrResult<()> {
+	// Removes id's stream, once what is already queued has been written
+	pub fn remove(&self, id: ClientId) -> EditrResult<()> {
+		// Dropped outside the lock, as it waits on the writer thread
+		let removed = self.hashmap_mut_op(|mut hashmap| Ok(hashmap.remove(&id)))?;
+		drop(removed);
+		Ok(())
+	}
+
+	// Holds writes to id until a connection is attached to it
+	pub fn detach(&self, id: ClientId) -> EditrResult<()> {
+		self.thread_out_op(id, |io| io.detach())
+	}
+
+	// Moves connection's stream over to the detached session, delivering
+	// everything the session missed before any new writes
+	pub fn attach(&self, session: ClientId, connection: ClientId) -> EditrResult<()> {
 		self.hashmap_mut_op(|mut hashmap| {
-			hashmap.remove(&thread_id);
+			let missed = hashmap
+				.get(&session)
+				.ok_or_else(|| {
+					EditrError::Internal("Thread local storage does not exist".to_string())
+				})?
+				.take_missed()?;
+			let io = hashmap.remove(&connection).ok_or_else(|| {
+				EditrError::Internal("Thread local storage does not exist".to_string())
+			})?;
+			io.write_all(&missed)?;
+			hashmap.insert(session, io);
+			Ok(())
+		})
+	}
+
+	// Compresses everything written to id from now on
+	pub fn compress(&self, id: ClientId) -> EditrResult<()> {
+		self.thread_out_op(id, |io| io.compress())
+	}
+
+	// Given a valid id, queues buffer to be written to its stream
+	pub fn write(&self, id: ClientId, buffer: &[u8]) -> EditrResult<()> {
+		self.thread_out_op(id, |io| io.write(buffer))
+	}
+
+	// Queues buffer for each of the clients, failing if any of them can't be
+	// written to. Each client is sent the whole buffer before this returns,
+	// so broadcasts reach every recipient in the order they were made
+	pub fn write_many(&self, clients: &[ClientId], buffer: &[u8]) -> EditrResult<()> {
+		if clients.len() <= FAN_OUT_CHUNK {
+			return self.write_each(clients, buffer);
+		}
+
+		let buffer = Arc::new(buffer.to_vec());
+		let failed = Arc::new(AtomicBool::new(false));
+		let jobs = clients
+			.chunks(FAN_OUT_CHUNK)
+			.map(|chunk| {
+				let (out, chunk, buffer, failed) =
+					(self.clone(), chunk.to_vec(), buffer.clone(), failed.clone());
+				Box::new(move || {
+					if out.write_each(&chunk, &buffer).is_err() {
+						failed.store(true, Ordering::SeqCst);
+					}
+				}) as Job
+			})
+			.collect();
+		self.fan_out.run(jobs);
+
+		if failed.load(Ordering::SeqCst) {
+			Err(EditrError::Internal(
+				"Thread local storage does not exist".to_string(),
+			))
+		}
+		else {
+			Ok(())
+		}
+	}
+
+	// Writes buffer to each of the clients in turn, carrying on past failures.
+	// Clients dropped for falling behind are no fault of the writer's
+	fn write_each(&self, clients: &[ClientId], buffer: &[u8]) -> EditrResult<()> {
+		let mut result = Ok(());
+		for client in clients {
+			match self.write(*client, buffer) {
+				Ok(_) | Err(EditrError::Disconnected) => (),
+				Err(e) => result = Err(e),
+			}
+		}
+		result
+	}
+
+	// Writes buffer to every stream, regardless of failures on some of them
+	pub fn broadcast(&self, buffer: &[u8]) -> EditrResult<()> {
+		self.hashmap_op(|hashmap| {
+			for io in hashmap.values() {
+				io.write(buffer).ok();
+			}
 			Ok(())
 		})
 	}
 
-	// Given a valid thread_id, reads from its stream and
-	// places read data into buffer
-	pub fn write(&self, thread_id: ThreadId, buffer: &[u8]) -> EditrResult<usize> {
-		self.thread_out_op(thread_id, |io| io.write(buffer))
+	// Writes buffer to every stream with a connection attached, skipping
+	// sessions waiting to be resumed
+	pub fn broadcast_attached(&self, buffer: &[u8]) -> EditrResult<()> {
+		self.hashmap_op(|hashmap| {
+			for io in hashmap.values() {
+				io.write_attached(buffer).ok();
+			}
+			Ok(())
+		})
 	}
 
 	// Performs an operation on ThreadOut object belonging to id
-	fn thread_out_op<T, F: FnOnce(&ThreadOut) -> EditrResult<T>>(
+	fn thread_out_op<R, F: FnOnce(&ThreadOut<T>) -> EditrResult<R>>(
 		&self,
-		id: ThreadId,
+		id: ClientId,
 		op: F,
-	) -> EditrResult<T> {
+	) -> EditrResult<R> {
 		self.hashmap_op(|hashmap| {
-			op(hashmap
-				.get(&id)
-				.ok_or("Thread local storage does not exist")?)
+			op(hashmap.get(&id).ok_or_else(|| {
+				EditrError::Internal("Thread local storage does not exist".to_string())
+			})?)
 		})
 	}
 
 	// Performs an operation that requires read access to the
 	// underlying container
-	fn hashmap_op<T, F: FnOnce(RwLockReadGuard<HashMap<ThreadId, ThreadOut>>) -> EditrResult<T>>(
+	fn hashmap_op<
+		R,
+		F: FnOnce(RwLockReadGuard<HashMap<ClientId, ThreadOut<T>>>) -> EditrResult<R>,
+	>(
 		&self,
 		op: F,
-	) -> EditrResult<T> {
+	) -> EditrResult<R> {
 		op(self.shared_out.read())
 	}
 
 	// Performs an operation that requires write access to the
 	// underlying container
 	fn hashmap_mut_op<
-		T,
-		F: FnOnce(RwLockWriteGuard<HashMap<ThreadId, ThreadOut>>) -> EditrResult<T>,
+		R,
+		F: FnOnce(RwLockWriteGuard<HashMap<ClientId, ThreadOut<T>>>) -> EditrResult<R>,
 	>(
 		&self,
 		op: F,
-	) -> EditrResult<T> {
+	) -> EditrResult<R> {
 		op(self.shared_out.write())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::io::{BufRead, BufReader};
+	use std::panic::{catch_unwind, AssertUnwindSafe};
+	use std::thread;
+	use std::time::Instant;
+
+	use super::*;
+	use crate::state::MemoryStream;
+
+	// Enough to be shared out between several writers
+	const RECIPIENTS: usize = 100;
+	const BROADCASTS: usize = 500;
+
+	#[test]
+	fn large_broadcasts_reach_everyone_in_order() {
+		let out = SharedOut::<MemoryStream>::new();
+		let mut readers = Vec::new();
+		let clients = (0..RECIPIENTS)
+			.map(|_| {
+				let (client, server) = MemoryStream::pair();
+				let id = ClientId::next();
+				out.insert(id, server, Arc::new(ConnectionMetrics::default()))
+					.unwrap();
+				readers.push(thread::spawn(move || {
+					BufReader::new(client)
+						.lines()
+						.take(BROADCASTS)
+						.map(|line| line.unwrap().parse::<usize>().unwrap())
+						.collect::<Vec<_>>()
+				}));
+				id
+			})
+			.collect::<Vec<_>>();
+
+		// Each broadcast carries its sequence number
+		let started = Instant::now();
+		for seq in 0..BROADCASTS {
+			out.write_many(&clients, format!("{}\n", seq).as_bytes())
+				.unwrap();
+		}
+		println!(
+			"{} broadcasts to {} recipients queued in {:?}",
+			BROADCASTS,
+			RECIPIENTS,
+			started.elapsed()
+		);
+
+		let expected = (0..BROADCASTS).collect::<Vec<_>>();
+		for reader in readers {
+			assert_eq!(reader.join().unwrap(), expected);
+		}
+	}
+
+	#[test]
+	fn a_panic_holding_the_container_leaves_it_usable() {
+		let out = SharedOut::<MemoryStream>::new();
+		let (client, server) = MemoryStream::pair();
+		let id = ClientId::next();
+		out.insert(id, server, Arc::new(ConnectionMetrics::default()))
+			.unwrap();
+		let reading = catch_unwind(AssertUnwindSafe(|| {
+			out.hashmap_op(|_| -> EditrResult<()> { panic!("Panicked reading the container") })
+		}));
+		assert!(reading.is_err());
+		let writing = catch_unwind(AssertUnwindSafe(|| {
+			out.hashmap_mut_op(|_| -> EditrResult<()> { panic!("Panicked writing the container") })
+		}));
+		assert!(writing.is_err());
+
+		out.write_many(&[id], b"still here\n").unwrap();
+		let mut line = String::new();
+		BufReader::new(client).read_line(&mut line).unwrap();
+		assert_eq!(line, "still here\n");
+		out.remove(id).unwrap();
+	}
+}
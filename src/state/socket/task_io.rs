@@ -0,0 +1,547 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use socket2::SockRef;
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::time::timeout;
+
+use crate::error::{EditrError, EditrResult};
+use crate::message::Message;
+use crate::state::ConnectionMetrics;
+
+use super::coalesce::coalesce;
+use super::thread_io::{Queued, MAX_QUEUED};
+use super::Transport;
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Decompress, FlushDecompress, Status};
+use serde_json::error::Category;
+use serde_json::{Deserializer, Value};
+
+// Bytes taken from the stream, or made by inflating, at a time
+const CHUNK: usize = 8 << 10;
+
+struct Inner {
+	stream: TcpStream,
+	peer: Option<SocketAddr>,
+	write_timeout: Mutex<Option<Duration>>,
+	// Where the connection's writer task runs
+	runtime: Handle,
+}
+
+// A connection served by tokio tasks rather than threads of its own, so an
+// idle client holds no thread. Reads and writes through Read and Write
+// never wait, failing with WouldBlock instead, and are only meant for
+// turning a client away. Handles made by try_clone share the connection
+#[derive(Clone)]
+pub struct TaskStream {
+	inner: Arc<Inner>,
+}
+
+impl TaskStream {
+	// Must be called from within a tokio runtime
+	pub fn new(stream: TcpStream, peer: SocketAddr) -> TaskStream {
+		TaskStream {
+			inner: Arc::new(Inner {
+				stream,
+				peer: Some(peer),
+				write_timeout: Mutex::new(None),
+				runtime: Handle::current(),
+			}),
+		}
+	}
+
+	// The socket underneath, for setting options on
+	pub fn socket(&self) -> SockRef<'_> { SockRef::from(&self.inner.stream) }
+
+	// Waits for something to read, returning 0 once the other end is gone
+	async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+		loop {
+			self.inner.stream.readable().await?;
+			match self.inner.stream.try_read(buf) {
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+				result => return result,
+			}
+		}
+	}
+
+	// Writes the whole of buf, failing if any one write stalls for longer
+	// than the write timeout
+	async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+		while !buf.is_empty() {
+			let write = async {
+				loop {
+					self.inner.stream.writable().await?;
+					match self.inner.stream.try_write(buf) {
+						Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+						result => return result,
+					}
+				}
+			};
+			let limit = *self.inner.write_timeout.lock();
+			let written = match limit {
+				Some(limit) => timeout(limit, write)
+					.await
+					.map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+				None => write.await?,
+			};
+			if written == 0 {
+				return Err(io::ErrorKind::WriteZero.into());
+			}
+			buf = &buf[written..];
+		}
+		Ok(())
+	}
+}
+
+impl Read for TaskStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { (&*self.socket()).read(buf) }
+}
+
+impl Write for TaskStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> { (&*self.socket()).write(buf) }
+
+	fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl Transport for TaskStream {
+	fn try_clone(&self) -> io::Result<Self> { Ok(self.clone()) }
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.socket().shutdown(how) }
+
+	// Kept for the writer task, which gives up on a write taking longer
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		*self.inner.write_timeout.lock() = timeout;
+		Ok(())
+	}
+
+	fn peer_addr(&self) -> Option<SocketAddr> { self.inner.peer }
+
+	fn task(&self) -> Option<&TaskStream> { Some(self) }
+}
+
+// Starts a task writing whatever is queued to stream, batched and merged as
+// the writer thread does. It finishes off what is queued once the queue is
+// dropped, and shuts the stream down if a write fails
+pub(super) fn spawn_writer(stream: TaskStream, metrics: Arc<ConnectionMetrics>) -> Sender<Queued> {
+	let (queue, mut receiver) = channel::<Queued>(MAX_QUEUED);
+	let runtime = stream.inner.runtime.clone();
+	runtime.spawn(async move {
+		let mut deflate = None;
+		while let Some(queued) = receiver.recv().await {
+			let mut batch = vec![queued];
+			while let Ok(queued) = receiver.try_recv() {
+				batch.push(queued);
+			}
+			let written = match encode(batch, &mut deflate, &metrics) {
+				Ok(buf) => stream.write_all(&buf).await,
+				Err(e) => Err(e),
+			};
+			// The reading side sees the stream end and cleans up
+			if written.is_err() {
+				stream.shutdown(Shutdown::Both).ok();
+				break;
+			}
+		}
+	});
+	queue
+}
+
+// Lays a batch out as it goes on the wire, deflating everything after a
+// Compress, in deflate from then on
+fn encode(
+	batch: Vec<Queued>,
+	deflate: &mut Option<DeflateEncoder<Vec<u8>>>,
+	metrics: &ConnectionMetrics,
+) -> io::Result<Vec<u8>> {
+	let mut out = Vec::new();
+	for queued in coalesce(batch)? {
+		match queued {
+			Queued::Data(buf) => {
+				match deflate {
+					Some(deflate) => deflate.write_all(&buf)?,
+					None => out.extend_from_slice(&buf),
+				}
+				metrics.add_bytes_written(buf.len() as u64);
+			}
+			Queued::Compress => {
+				*deflate = Some(DeflateEncoder::new(Vec::new(), Compression::fast()));
+			}
+		}
+	}
+	// Compressed output is flushed a batch at a time, as the thread does
+	if let Some(deflate) = deflate {
+		deflate.flush()?;
+		out.append(deflate.get_mut());
+	}
+	Ok(out)
+}
+
+// Reads messages from a TaskStream as ThreadIn does, but without holding a
+// thread while waiting for them. Whatever has been read is kept in buf
+// until a whole message is there to parse
+pub(crate) struct TaskIn {
+	stream: TaskStream,
+	// Bytes read, and inflated, which have not been parsed yet
+	buf: Vec<u8>,
+	// Bytes read which have not been inflated yet
+	pending: Vec<u8>,
+	inflate: Option<Decompress>,
+	// Set once the client has agreed to compress what it sends from its
+	// next message on
+	decompress: bool,
+	limit: usize,
+	total: u64,
+	ended: bool,
+}
+
+impl TaskIn {
+	pub fn new(stream: TaskStream, limit: usize) -> TaskIn {
+		TaskIn {
+			stream,
+			buf: Vec::new(),
+			pending: Vec::new(),
+			inflate: None,
+			decompress: false,
+			limit,
+			total: 0,
+			ended: false,
+		}
+	}
+
+	// Inflates everything read from the next message on
+	pub fn decompress(&mut self) { self.decompress = true; }
+
+	// Total bytes consumed from the stream so far
+	pub fn bytes_read(&self) -> u64 { self.total }
+
+	// Switches to inflating the stream, once the whitespace ending the
+	// last plain message has been skipped. Whatever was read past it is
+	// deflated already
+	async fn start_decompressing(&mut self) -> io::Result<()> {
+		self.decompress = false;
+		loop {
+			let blank = self
+				.buf
+				.iter()
+				.take_while(|b| b.is_ascii_whitespace())
+				.count();
+			self.consume(blank);
+			if !self.buf.is_empty() || !self.fill().await? {
+				break;
+			}
+		}
+		self.pending = std::mem::take(&mut self.buf);
+		self.inflate = Some(Decompress::new(false));
+		Ok(())
+	}
+
+	// Takes more of the stream into buf, returning false once it has ended
+	async fn fill(&mut self) -> io::Result<bool> {
+		loop {
+			if self.inflate_some()? {
+				return Ok(true);
+			}
+			if self.ended {
+				return Ok(false);
+			}
+			let mut chunk = [0u8; CHUNK];
+			let read = self.stream.read(&mut chunk).await?;
+			if read == 0 {
+				self.ended = true;
+				return Ok(false);
+			}
+			match self.inflate {
+				Some(_) => self.pending.extend_from_slice(&chunk[..read]),
+				None => {
+					self.buf.extend_from_slice(&chunk[..read]);
+					return Ok(true);
+				}
+			}
+		}
+	}
+
+	// Inflates up to a chunk of what is pending into buf, returning whether
+	// anything came of it. Taking a chunk at a time keeps a small message
+	// which inflates enormously from being held in full
+	fn inflate_some(&mut self) -> io::Result<bool> {
+		let inflate = match &mut self.inflate {
+			Some(inflate) => inflate,
+			None => return Ok(false),
+		};
+		let mut chunk = [0u8; CHUNK];
+		let (before_in, before_out) = (inflate.total_in(), inflate.total_out());
+		let status = inflate
+			.decompress(&self.pending, &mut chunk, FlushDecompress::None)
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+		let used = (inflate.total_in() - before_in) as usize;
+		let made = (inflate.total_out() - before_out) as usize;
+		self.pending.drain(..used);
+		self.buf.extend_from_slice(&chunk[..made]);
+		// Nothing follows the end of the deflate stream
+		if status == Status::StreamEnd {
+			self.ended = true;
+			self.pending.clear();
+		}
+		Ok(used > 0 || made > 0)
+	}
+
+	// Drops the first count bytes of buf, which have been dealt with
+	fn consume(&mut self, count: usize) {
+		self.buf.drain(..count);
+		self.total += count as u64;
+	}
+
+	// Reads the next message, with the same limits and recovery from
+	// invalid JSON as ThreadIn::get_message
+	pub async fn get_message(&mut self) -> EditrResult<Message> {
+		if self.decompress {
+			self.start_decompressing().await?;
+		}
+		// Parsing again only once something which could end the message has
+		// arrived, or the buffer has doubled, keeps large messages linear
+		let mut parsed = 0;
+		loop {
+			let fresh = &self.buf[parsed.min(self.buf.len())..];
+			let worth_parsing = parsed == 0
+				|| self.ended
+				|| self.buf.len() >= self.limit
+				|| self.buf.len() >= parsed * 2
+				|| fresh
+					.iter()
+					.any(|b| b"}]\"el".contains(b) || b.is_ascii_whitespace());
+			if worth_parsing && !self.buf.is_empty() {
+				parsed = self.buf.len();
+				if let Some(result) = self.parse() {
+					return result;
+				}
+			}
+			if self.buf.len() >= self.limit {
+				return Err(EditrError::MessageTooLarge(self.limit));
+			}
+			if !self.fill().await? {
+				// A message cut short ends with the connection
+				return match self.parse() {
+					Some(result) => result,
+					None => Err(EditrError::Disconnected),
+				};
+			}
+		}
+	}
+
+	// Parses the message at the start of buf, or None if it hasn't all
+	// arrived yet
+	fn parse(&mut self) -> Option<EditrResult<Message>> {
+		let mut values = Deserializer::from_slice(&self.buf).into_iter::<Value>();
+		let value = values.next();
+		let used = values.byte_offset();
+		match value {
+			// A number at the end of what has arrived may carry on
+			Some(Ok(Value::Number(_))) if used == self.buf.len() && !self.ended => None,
+			Some(Ok(_)) if used > self.limit => Some(Err(EditrError::MessageTooLarge(self.limit))),
+			// Valid JSON which isn't a message has been read in full
+			Some(Ok(value)) => {
+				self.consume(used);
+				Some(
+					serde_json::from_value::<Message>(value)
+						.map_err(|e| EditrError::InvalidMessage(e.to_string()))
+						.and_then(|msg| msg.check_limits().map(|_| msg)),
+				)
+			}
+			Some(Err(e)) if e.classify() == Category::Eof => None,
+			Some(Err(e)) if e.is_syntax() => {
+				let at = error_offset(&self.buf, e.line(), e.column());
+				if at >= self.limit {
+					return Some(Err(EditrError::MessageTooLarge(self.limit)));
+				}
+				// The rest of the line goes with it, if it has arrived
+				let end = self.buf[at..].iter().position(|&b| b == b'\n')?;
+				self.consume(at + end + 1);
+				Some(Err(EditrError::InvalidMessage(e.to_string())))
+			}
+			Some(Err(e)) => Some(Err(e.into())),
+			// Nothing but whitespace so far
+			None => None,
+		}
+	}
+}
+
+// Where in buf the byte at line and column, as serde_json counts them, is
+fn error_offset(buf: &[u8], line: usize, column: usize) -> usize {
+	let line_start = match line {
+		0 | 1 => 0,
+		_ => buf
+			.iter()
+			.enumerate()
+			.filter(|(_, &b)| b == b'\n')
+			.nth(line - 2)
+			.map_or(buf.len(), |(i, _)| i + 1),
+	};
+	(line_start + column.saturating_sub(1)).min(buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+	use std::net::{self, TcpListener};
+	use std::sync::Arc;
+
+	use tokio::runtime::{Builder, Runtime};
+
+	use crate::error::EditrError;
+	use crate::message::Message;
+	use crate::state::ConnectionMetrics;
+
+	use super::super::thread_io::{ThreadIn, ThreadOut};
+	use super::{TaskIn, TaskStream};
+
+	fn runtime() -> Runtime { Builder::new_multi_thread().enable_all().build().unwrap() }
+
+	// A client's socket, connected to one served by runtime
+	fn connected(runtime: &Runtime) -> (net::TcpStream, TaskStream) {
+		let _runtime = runtime.enter();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (server, peer) = listener.accept().unwrap();
+		server.set_nonblocking(true).unwrap();
+		let server = tokio::net::TcpStream::from_std(server).unwrap();
+		(client, TaskStream::new(server, peer))
+	}
+
+	fn line(msg: Message) -> Vec<u8> {
+		let mut buf = msg.to_vec().unwrap();
+		buf.push(b'\n');
+		buf
+	}
+
+	#[test]
+	fn messages_arriving_in_pieces_are_read_whole() {
+		let runtime = runtime();
+		let (mut client, server) = connected(&runtime);
+		let mut input = TaskIn::new(server, 1 << 20);
+		let sent = [line(Message::Echo(vec![7; 3000])), b"12".to_vec()].concat();
+		let reading = runtime.spawn(async move {
+			let msg = input.get_message().await;
+			(msg, input.get_message().await)
+		});
+		for piece in sent.chunks(5) {
+			client.write_all(piece).unwrap();
+		}
+		// A number only ends once something comes after it
+		client.write_all(b"34\n").unwrap();
+
+		let (first, second) = runtime.block_on(reading).unwrap();
+		assert!(matches!(first.unwrap(), Message::Echo(data) if data == vec![7; 3000]));
+		assert!(matches!(second, Err(EditrError::InvalidMessage(e)) if e.contains("1234")));
+	}
+
+	#[test]
+	fn the_limit_applies_to_each_message() {
+		let runtime = runtime();
+		let (mut client, server) = connected(&runtime);
+		let mut input = TaskIn::new(server, 1024);
+		for _ in 0..10 {
+			client
+				.write_all(&line(Message::Echo(vec![0; 100])))
+				.unwrap();
+		}
+		client
+			.write_all(&line(Message::Echo(vec![0; 4096])))
+			.unwrap();
+
+		runtime.block_on(async {
+			for _ in 0..10 {
+				assert!(
+					matches!(input.get_message().await, Ok(Message::Echo(data)) if data.len() == 100)
+				);
+			}
+			assert!(matches!(
+				input.get_message().await,
+				Err(EditrError::MessageTooLarge(1024))
+			));
+		});
+		assert!(input.bytes_read() > 1024);
+	}
+
+	#[test]
+	fn garbage_takes_the_rest_of_its_line() {
+		let runtime = runtime();
+		let (mut client, server) = connected(&runtime);
+		let mut input = TaskIn::new(server, 1024);
+		client
+			.write_all(b"{\"Echo\": [1, 2,,]} trailing\n\n  this is not json\n")
+			.unwrap();
+		client
+			.write_all(&line(Message::Echo(b"after".to_vec())))
+			.unwrap();
+		drop(client);
+
+		runtime.block_on(async {
+			for _ in 0..2 {
+				assert!(matches!(
+					input.get_message().await,
+					Err(EditrError::InvalidMessage(_))
+				));
+			}
+			assert!(
+				matches!(input.get_message().await, Ok(Message::Echo(data)) if data == b"after")
+			);
+			assert!(matches!(
+				input.get_message().await,
+				Err(EditrError::Disconnected)
+			));
+		});
+	}
+
+	#[test]
+	fn both_ways_are_deflated_once_agreed() {
+		let runtime = runtime();
+		let (mut client, server) = connected(&runtime);
+		let mut input = TaskIn::new(server.clone(), 1 << 20);
+		let out = ThreadOut::new(server, Arc::new(ConnectionMetrics::default())).unwrap();
+		let mut client_in = ThreadIn::new(client.try_clone().unwrap(), 1 << 20).unwrap();
+
+		// The request and whatever follows it in the same write
+		let mut deflate =
+			flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+		for i in 0..50 {
+			deflate
+				.write_all(&Message::Echo(vec![i; 40]).to_vec().unwrap())
+				.unwrap();
+		}
+		deflate.flush().unwrap();
+		let sent = [line(Message::CompressReq), deflate.get_ref().clone()].concat();
+		client.write_all(&sent).unwrap();
+
+		runtime.block_on(async {
+			assert!(matches!(
+				input.get_message().await,
+				Ok(Message::CompressReq)
+			));
+			input.decompress();
+			for i in 0..50 {
+				assert!(
+					matches!(input.get_message().await, Ok(Message::Echo(data)) if data == vec![i; 40])
+				);
+			}
+		});
+
+		// Queued together, so the writer task switches partway through a batch
+		out.write(&line(Message::Echo(b"plain".to_vec()))).unwrap();
+		out.compress().unwrap();
+		out.write(&line(Message::Echo(b"deflated".to_vec())))
+			.unwrap();
+		assert!(
+			matches!(client_in.get_message().unwrap(), Message::Echo(data) if data == b"plain")
+		);
+		client_in.decompress();
+		assert!(
+			matches!(client_in.get_message().unwrap(), Message::Echo(data) if data == b"deflated")
+		);
+	}
+}
@@ -1,49 +1,692 @@
-use std::io::{BufReader, BufWriter, Write};
-use std::net::TcpStream;
-use std::sync::Mutex;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::iter::once;
+use std::mem::replace;
+use std::net::Shutdown;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Arc;
+use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
 
-use crate::error::EditrResult;
+use parking_lot::Mutex;
+
+use crate::error::{EditrError, EditrResult};
 use crate::message::Message;
+use crate::state::ConnectionMetrics;
+
+use super::coalesce::coalesce;
+#[cfg(feature = "async-net")]
+use super::task_io::spawn_writer;
+use super::Transport;
+
+use flate2::bufread::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde_json::{Deserializer, Value};
+
+// Stops reading once limit bytes have been read since the count was reset
+struct LimitedReader<R> {
+	inner: R,
+	used: usize,
+	limit: usize,
+	total: u64,
+}
 
-use serde_json::de::IoRead;
-use serde_json::{Deserializer, StreamDeserializer};
+impl<R: Read> Read for LimitedReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.used >= self.limit {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"Message too large",
+			));
+		}
+		let max = buf.len().min(self.limit - self.used);
+		let read = self.inner.read(&mut buf[..max])?;
+		self.used += read;
+		self.total += read as u64;
+		Ok(read)
+	}
+}
 
-pub(super) struct ThreadIn {
-	reader: StreamDeserializer<'static, IoRead<BufReader<TcpStream>>, Message>,
+// The stream as read, inflated once the connection is compressed
+enum Input<T> {
+	Plain(BufReader<T>),
+	Deflate(BufReader<DeflateDecoder<BufReader<T>>>),
+	// Only seen while switching between the others
+	Switching,
 }
 
-impl ThreadIn {
-	pub fn new(stream: TcpStream) -> EditrResult<ThreadIn> {
-		let reader_copy = stream.try_clone()?;
+impl<T: Read> Read for Input<T> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self {
+			Input::Plain(reader) => reader.read(buf),
+			Input::Deflate(reader) => reader.read(buf),
+			Input::Switching => Ok(0),
+		}
+	}
+}
+
+pub(crate) struct ThreadIn<T> {
+	reader: LimitedReader<Input<T>>,
+	// Set once the client has agreed to compress what it sends from its
+	// next message on
+	decompress: bool,
+}
+
+impl<T: Transport> ThreadIn<T> {
+	pub fn new(stream: T, limit: usize) -> EditrResult<ThreadIn<T>> {
+		// The deserializer reads a byte at a time, so the limit sits above the
+		// buffering and counts exactly what each message consumes
 		Ok(ThreadIn {
-			reader: Deserializer::from_reader(BufReader::new(reader_copy)).into_iter(),
+			reader: LimitedReader {
+				inner: Input::Plain(BufReader::new(stream)),
+				used: 0,
+				limit,
+				total: 0,
+			},
+			decompress: false,
 		})
 	}
 
+	// Inflates everything read from the next message on
+	pub fn decompress(&mut self) { self.decompress = true; }
+
+	// Switches to inflating the stream, once the whitespace ending the
+	// last plain message has been skipped
+	fn start_decompressing(&mut self) -> EditrResult<()> {
+		self.decompress = false;
+		if let Input::Plain(mut reader) = replace(&mut self.reader.inner, Input::Switching) {
+			loop {
+				let buf = reader.fill_buf()?;
+				let blank = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+				let done = blank < buf.len() || buf.is_empty();
+				reader.consume(blank);
+				if done {
+					break;
+				}
+			}
+			self.reader.inner = Input::Deflate(BufReader::new(DeflateDecoder::new(reader)));
+		}
+		Ok(())
+	}
+
+	// Total bytes consumed from the stream so far
+	pub fn bytes_read(&self) -> u64 { self.reader.total }
+
+	// Reads the next message. Messages are self-delimiting, so each is read
+	// exactly, leaving the stream at the start of the next.
+	// A message which isn't valid JSON takes the rest of its line with it,
+	// so clients should end each message with a newline
 	pub fn get_message(&mut self) -> EditrResult<Message> {
-		Ok(self
-			.reader
-			.next()
-			.ok_or("Could not get message")
-			.map_err(|e| e.to_string())?
-			.map_err(|e| e.to_string())?)
+		if self.decompress {
+			self.start_decompressing()?;
+		}
+		self.reader.used = 0;
+		let value = Deserializer::from_reader(&mut self.reader)
+			.into_iter::<Value>()
+			.next();
+		match value {
+			// Valid JSON which isn't a message has been read in full
+			Some(Ok(value)) => {
+				let msg: Message = serde_json::from_value(value)
+					.map_err(|e| EditrError::InvalidMessage(e.to_string()))?;
+				msg.check_limits()?;
+				Ok(msg)
+			}
+			Some(Err(_)) if self.reader.used >= self.reader.limit => {
+				Err(EditrError::MessageTooLarge(self.reader.limit))
+			}
+			Some(Err(e)) if e.is_syntax() => {
+				self.skip_line()?;
+				Err(EditrError::InvalidMessage(e.to_string()))
+			}
+			Some(Err(e)) => Err(e.into()),
+			None => Err(EditrError::Disconnected),
+		}
+	}
+
+	// Discards input up to and including the next newline
+	fn skip_line(&mut self) -> EditrResult<()> {
+		self.reader.used = 0;
+		let mut byte = [0u8];
+		while self.reader.read(&mut byte)? != 0 && byte[0] != b'\n' {}
+		Ok(())
 	}
 }
 
-pub(super) struct ThreadOut {
-	writer: Mutex<BufWriter<TcpStream>>,
+// Bytes of updates held for a detached session before it is given up on
+const MAX_MISSED: usize = 1 << 20;
+// Writes queued for a client before it is deemed too slow and disconnected
+pub(super) const MAX_QUEUED: usize = 1024;
+// Longest a single write may stall before the client is given up on
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What is handed to the writer thread
+pub(super) enum Queued {
+	Data(Vec<u8>),
+	// Deflate everything written after this
+	Compress,
+}
+
+enum Output<T: Transport> {
+	Attached(Writer<T>),
+	// Updates held for a disconnected session, None once too many were missed
+	Detached(Option<Vec<u8>>),
+}
+
+// Where writes are handed to, a thread of the connection's own or a task
+// for one served by tokio
+enum Queue {
+	Thread(SyncSender<Queued>),
+	#[cfg(feature = "async-net")]
+	Task(tokio::sync::mpsc::Sender<Queued>),
 }
 
-impl ThreadOut {
-	pub fn new(stream: TcpStream) -> EditrResult<ThreadOut> {
-		let writer_copy = stream.try_clone()?;
+impl Queue {
+	// Queues without waiting, failing if the queue is full or closed
+	fn try_send(&self, queued: Queued) -> bool {
+		match self {
+			Queue::Thread(queue) => queue.try_send(queued).is_ok(),
+			#[cfg(feature = "async-net")]
+			Queue::Task(queue) => queue.try_send(queued).is_ok(),
+		}
+	}
+
+	// Queues, waiting for room if need be, failing only if closed
+	fn send(&self, queued: Queued) -> EditrResult<()> {
+		let sent = match self {
+			Queue::Thread(queue) => queue.send(queued).is_ok(),
+			#[cfg(feature = "async-net")]
+			Queue::Task(queue) => queue.blocking_send(queued).is_ok(),
+		};
+		match sent {
+			true => Ok(()),
+			false => Err(EditrError::Internal("Output is closed".to_string())),
+		}
+	}
+}
+
+// Writes are handed to a thread of their own, so a slow client never holds
+// up whoever is writing to it
+struct Writer<T: Transport> {
+	queue: Option<Queue>,
+	stream: T,
+	thread: Option<JoinHandle<()>>,
+}
+
+impl<T: Transport> Writer<T> {
+	fn new(stream: T, metrics: Arc<ConnectionMetrics>) -> EditrResult<Writer<T>> {
+		stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+		// Streams served by tokio are written by a task rather than a thread
+		#[cfg(feature = "async-net")]
+		if let Some(task) = stream.task() {
+			let queue = spawn_writer(task.clone(), metrics);
+			return Ok(Writer {
+				queue: Some(Queue::Task(queue)),
+				stream,
+				thread: None,
+			});
+		}
+		let thread_stream = stream.try_clone()?;
+		let mut writer: Box<dyn Write + Send> = Box::new(BufWriter::new(stream.try_clone()?));
+		let (queue, receiver) = sync_channel::<Queued>(MAX_QUEUED);
+		let thread = spawn(move || {
+			while let Ok(queued) = receiver.recv() {
+				// Everything queued so far goes out together, in one flush,
+				// with updates merged where they can be
+				let batch = once(queued).chain(receiver.try_iter()).collect();
+				let result = coalesce(batch)
+					.and_then(|batch| {
+						batch.into_iter().try_for_each(|queued| match queued {
+							Queued::Data(buf) => {
+								writer.write_all(&buf)?;
+								metrics.add_bytes_written(buf.len() as u64);
+								Ok(())
+							}
+							Queued::Compress => {
+								writer.flush()?;
+								let plain = replace(&mut writer, Box::new(io::sink()));
+								writer = Box::new(DeflateEncoder::new(plain, Compression::fast()));
+								Ok(())
+							}
+						})
+					})
+					.and_then(|_| writer.flush());
+				// The reading side sees the stream end and cleans up
+				if result.is_err() {
+					thread_stream.shutdown(Shutdown::Both).ok();
+					break;
+				}
+			}
+		});
+		Ok(Writer {
+			queue: Some(Queue::Thread(queue)),
+			stream,
+			thread: Some(thread),
+		})
+	}
+
+	// Queues buf without waiting on the network. A client which has fallen
+	// too far behind is disconnected instead, returning false
+	fn send(&self, buf: &[u8]) -> bool {
+		match self
+			.queue
+			.as_ref()
+			.map(|queue| queue.try_send(Queued::Data(buf.to_vec())))
+		{
+			Some(true) => true,
+			_ => {
+				self.stream.shutdown(Shutdown::Both).ok();
+				false
+			}
+		}
+	}
+
+	// Queues buf, waiting for room if need be
+	fn send_all(&self, buf: &[u8]) -> EditrResult<()> {
+		self.queue
+			.as_ref()
+			.ok_or_else(|| EditrError::Internal("Output is closed".to_string()))?
+			.send(Queued::Data(buf.to_vec()))
+	}
+
+	// Compresses everything queued after what is already queued
+	fn compress(&self) -> EditrResult<()> {
+		self.queue
+			.as_ref()
+			.ok_or_else(|| EditrError::Internal("Output is closed".to_string()))?
+			.send(Queued::Compress)
+	}
+}
+
+impl<T: Transport> Drop for Writer<T> {
+	// Lets the thread finish off what is queued, then waits for it. A task
+	// finishes off on its own
+	fn drop(&mut self) {
+		self.queue.take();
+		if let Some(thread) = self.thread.take() {
+			thread.join().ok();
+		}
+	}
+}
+
+pub(super) struct ThreadOut<T: Transport> {
+	writer: Mutex<Output<T>>,
+}
+
+impl<T: Transport> ThreadOut<T> {
+	pub fn new(stream: T, metrics: Arc<ConnectionMetrics>) -> EditrResult<ThreadOut<T>> {
 		Ok(ThreadOut {
-			writer: Mutex::new(BufWriter::with_capacity(0, writer_copy)),
+			writer: Mutex::new(Output::Attached(Writer::new(stream, metrics)?)),
 		})
 	}
 
-	// Writes from buffer into writer
-	pub fn write(&self, buf: &[u8]) -> EditrResult<usize> {
-		Ok(self.writer.lock().map_err(|e| e.to_string())?.write(buf)?)
+	// Queues the whole of buffer for writing, failing with Disconnected if
+	// the client has been disconnected for not keeping up
+	pub fn write(&self, buf: &[u8]) -> EditrResult<()> {
+		match &mut *self.writer.lock() {
+			Output::Attached(writer) => match writer.send(buf) {
+				true => Ok(()),
+				false => Err(EditrError::Disconnected),
+			},
+			Output::Detached(missed) => {
+				if missed.as_ref().map_or(0, Vec::len) + buf.len() > MAX_MISSED {
+					*missed = None;
+				}
+				if let Some(missed) = missed {
+					missed.extend_from_slice(buf);
+				}
+				Ok(())
+			}
+		}
+	}
+
+	// Queues buffer for writing, unless the output is detached
+	pub fn write_attached(&self, buf: &[u8]) -> EditrResult<()> {
+		if let Output::Attached(writer) = &*self.writer.lock() {
+			writer.send(buf);
+		}
+		Ok(())
+	}
+
+	// Queues the whole buffer for writing, however long it takes
+	pub fn write_all(&self, buf: &[u8]) -> EditrResult<()> {
+		match &mut *self.writer.lock() {
+			Output::Attached(writer) => writer.send_all(buf),
+			Output::Detached(_) => Err(EditrError::Internal("Output is detached".to_string())),
+		}
+	}
+
+	// Deflates everything written from now on
+	pub fn compress(&self) -> EditrResult<()> {
+		match &*self.writer.lock() {
+			Output::Attached(writer) => writer.compress(),
+			Output::Detached(_) => Err(EditrError::Internal("Output is detached".to_string())),
+		}
+	}
+
+	// Drops the stream and starts holding writes for a later reattach
+	pub fn detach(&self) -> EditrResult<()> {
+		let old = replace(&mut *self.writer.lock(), Output::Detached(Some(Vec::new())));
+		// The connection is gone, so there is no point finishing its writes
+		if let Output::Attached(writer) = &old {
+			writer.stream.shutdown(Shutdown::Both).ok();
+		}
+		Ok(())
+	}
+
+	// Takes the writes held since detaching
+	pub fn take_missed(&self) -> EditrResult<Vec<u8>> {
+		match &mut *self.writer.lock() {
+			Output::Attached(_) => Err(EditrError::Internal("Output is attached".to_string())),
+			Output::Detached(missed) => missed
+				.take()
+				.ok_or_else(|| EditrError::Protocol("Too many updates missed".to_string())),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{self, BufRead, BufReader, Read, Write};
+	use std::net::{Shutdown, SocketAddr};
+	use std::panic::{catch_unwind, AssertUnwindSafe};
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::Arc;
+	use std::time::Duration;
+
+	use parking_lot::Mutex;
+
+	use crate::error::EditrError;
+	use crate::message::{Message, UpdateData};
+	use crate::state::{ConnectionMetrics, MemoryStream, Transport};
+
+	use super::{ThreadIn, ThreadOut};
+
+	// Counts the writes which reach the stream, each of which would be a
+	// syscall on a socket. Writes wait while gate is held
+	struct Counting {
+		stream: MemoryStream,
+		writes: Arc<AtomicUsize>,
+		gate: Arc<Mutex<()>>,
+	}
+
+	impl Read for Counting {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.stream.read(buf) }
+	}
+
+	impl Write for Counting {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			let _gate = self.gate.lock();
+			self.writes.fetch_add(1, Ordering::Relaxed);
+			self.stream.write(buf)
+		}
+
+		fn flush(&mut self) -> io::Result<()> { self.stream.flush() }
+	}
+
+	impl Transport for Counting {
+		fn try_clone(&self) -> io::Result<Self> {
+			Ok(Counting {
+				stream: self.stream.clone(),
+				writes: self.writes.clone(),
+				gate: self.gate.clone(),
+			})
+		}
+
+		fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.stream.shutdown(how) }
+
+		fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> { Ok(()) }
+
+		fn peer_addr(&self) -> Option<SocketAddr> { None }
+	}
+
+	// Takes at most most bytes of each write, as a full socket buffer
+	// would, and none at all if most is 0
+	#[derive(Clone)]
+	struct Trickle {
+		stream: MemoryStream,
+		most: usize,
+	}
+
+	impl Read for Trickle {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.stream.read(buf) }
+	}
+
+	impl Write for Trickle {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			let most = buf.len().min(self.most);
+			self.stream.write(&buf[..most])
+		}
+
+		fn flush(&mut self) -> io::Result<()> { self.stream.flush() }
+	}
+
+	impl Transport for Trickle {
+		fn try_clone(&self) -> io::Result<Self> { Ok(self.clone()) }
+
+		fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.stream.shutdown(how) }
+
+		fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> { Ok(()) }
+
+		fn peer_addr(&self) -> Option<SocketAddr> { None }
+	}
+
+	fn send(stream: &mut MemoryStream, msg: Message) {
+		let mut buf = msg.to_vec().unwrap();
+		buf.push(b'\n');
+		stream.write_all(&buf).unwrap();
+	}
+
+	#[test]
+	fn messages_over_the_limit_are_refused() {
+		let (mut client, server) = MemoryStream::pair();
+		let mut input = ThreadIn::new(server, 1024).unwrap();
+		send(&mut client, Message::Echo(vec![0; 4096]));
+		assert!(matches!(
+			input.get_message(),
+			Err(EditrError::MessageTooLarge(1024))
+		));
+	}
+
+	#[test]
+	fn the_limit_applies_to_each_message() {
+		let (mut client, server) = MemoryStream::pair();
+		let mut input = ThreadIn::new(server, 1024).unwrap();
+		// Together these are well over the limit
+		for _ in 0..10 {
+			send(&mut client, Message::Echo(vec![0; 100]));
+		}
+		for _ in 0..10 {
+			match input.get_message().unwrap() {
+				Message::Echo(data) => assert_eq!(data.len(), 100),
+				msg => panic!("Unexpected message {:?}", msg),
+			}
+		}
+		assert!(input.bytes_read() > 1024);
+	}
+
+	#[test]
+	fn garbage_between_messages_is_skipped() {
+		let (mut client, server) = MemoryStream::pair();
+		let mut input = ThreadIn::new(server, 1024).unwrap();
+		send(&mut client, Message::Echo(b"first".to_vec()));
+		client
+			.write_all(b"{\"Echo\": [1, 2,,]} trailing\n")
+			.unwrap();
+		send(&mut client, Message::Echo(b"second".to_vec()));
+
+		assert!(matches!(input.get_message().unwrap(), Message::Echo(data) if data == b"first"));
+		assert!(matches!(
+			input.get_message(),
+			Err(EditrError::InvalidMessage(_))
+		));
+		assert!(matches!(input.get_message().unwrap(), Message::Echo(data) if data == b"second"));
+	}
+
+	#[test]
+	fn json_which_is_not_a_message_is_skipped() {
+		let (mut client, server) = MemoryStream::pair();
+		let mut input = ThreadIn::new(server, 1024).unwrap();
+		client.write_all(b"{\"Nonsense\": 1}\n").unwrap();
+		send(&mut client, Message::Echo(b"after".to_vec()));
+
+		assert!(matches!(
+			input.get_message(),
+			Err(EditrError::InvalidMessage(_))
+		));
+		assert!(matches!(input.get_message().unwrap(), Message::Echo(data) if data == b"after"));
+	}
+
+	#[test]
+	fn bursts_are_written_together() {
+		const BURST: usize = 500;
+		let (mut client, server) = MemoryStream::pair();
+		let writes = Arc::new(AtomicUsize::new(0));
+		let gate = Arc::new(Mutex::new(()));
+		let out = ThreadOut::new(
+			Counting {
+				stream: server,
+				writes: writes.clone(),
+				gate: gate.clone(),
+			},
+			Arc::new(ConnectionMetrics::default()),
+		)
+		.unwrap();
+
+		// Holding the writer up lets the burst queue behind it
+		let mut expected = Vec::new();
+		{
+			let _gate = gate.lock();
+			for i in 0..BURST {
+				let msg = Message::Echo(i.to_string().into_bytes()).to_vec().unwrap();
+				out.write(&msg).unwrap();
+				expected.extend_from_slice(&msg);
+			}
+		}
+		// Dropping the output waits for everything queued to be written
+		drop(out);
+
+		let mut received = Vec::new();
+		client.read_to_end(&mut received).unwrap();
+		assert_eq!(received, expected);
+		let writes = writes.load(Ordering::Relaxed);
+		assert!(
+			writes <= BURST / 50,
+			"{} writes for {} messages",
+			writes,
+			BURST
+		);
+	}
+
+	#[test]
+	fn a_burst_of_typing_arrives_merged() {
+		const TYPED: usize = 1000;
+		let (client, server) = MemoryStream::pair();
+		let gate = Arc::new(Mutex::new(()));
+		let out = ThreadOut::new(
+			Counting {
+				stream: server,
+				writes: Arc::new(AtomicUsize::new(0)),
+				gate: gate.clone(),
+			},
+			Arc::new(ConnectionMetrics::default()),
+		)
+		.unwrap();
+
+		// As a peer typing faster than this client takes updates
+		let mut expected = Vec::new();
+		{
+			let _gate = gate.lock();
+			for offset in 0..TYPED {
+				let byte = b'a' + (offset % 26) as u8;
+				let msg = Message::make_add_broadcast(offset, &[byte]);
+				out.write(&msg.to_vec().unwrap()).unwrap();
+				expected.push(byte);
+			}
+		}
+		drop(out);
+
+		let mut input = ThreadIn::new(client, usize::MAX).unwrap();
+		let mut replica = Vec::new();
+		let mut received = 0;
+		loop {
+			match input.get_message() {
+				Ok(Message::UpdateMessage(UpdateData::Add(add))) => {
+					replica.splice(add.offset..add.offset, add.data);
+				}
+				Ok(msg) => panic!("Unexpected message {:?}", msg),
+				Err(EditrError::Disconnected) => break,
+				Err(e) => panic!("Failed to read: {}", e),
+			}
+			received += 1;
+		}
+		assert_eq!(replica, expected);
+		assert!(
+			received <= TYPED / 50,
+			"{} updates for {} bytes",
+			received,
+			TYPED
+		);
+	}
+
+	#[test]
+	fn a_panic_holding_the_writer_leaves_it_usable() {
+		let (client, server) = MemoryStream::pair();
+		let out = ThreadOut::new(server, Arc::new(ConnectionMetrics::default())).unwrap();
+		let panicked = catch_unwind(AssertUnwindSafe(|| {
+			let _writer = out.writer.lock();
+			panic!("Panicked holding the writer");
+		}));
+		assert!(panicked.is_err());
+
+		out.write(b"still here\n").unwrap();
+		let mut line = String::new();
+		BufReader::new(client).read_line(&mut line).unwrap();
+		assert_eq!(line, "still here\n");
+	}
+
+	#[test]
+	fn frames_written_in_pieces_arrive_whole() {
+		let (client, server) = MemoryStream::pair();
+		let out = ThreadOut::new(
+			Trickle {
+				stream: server,
+				most: 7,
+			},
+			Arc::new(ConnectionMetrics::default()),
+		)
+		.unwrap();
+		for len in 0..200 {
+			let mut msg = Message::Echo(vec![b'x'; len]).to_vec().unwrap();
+			msg.push(b'\n');
+			out.write(&msg).unwrap();
+		}
+
+		let mut input = ThreadIn::new(client, usize::MAX).unwrap();
+		for len in 0..200 {
+			match input.get_message().unwrap() {
+				Message::Echo(data) => assert_eq!(data.len(), len),
+				msg => panic!("Unexpected message {:?}", msg),
+			}
+		}
+	}
+
+	#[test]
+	fn peers_taking_nothing_are_let_go() {
+		let (mut client, server) = MemoryStream::pair();
+		let out = ThreadOut::new(
+			Trickle {
+				stream: server,
+				most: 0,
+			},
+			Arc::new(ConnectionMetrics::default()),
+		)
+		.unwrap();
+		out.write(b"never sent\n").unwrap();
+
+		// The connection is closed rather than written to forever
+		let mut received = Vec::new();
+		client.read_to_end(&mut received).unwrap();
+		assert!(received.is_empty());
 	}
 }
@@ -0,0 +1,273 @@
+use std::collections::VecDeque;
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex};
+
+#[cfg(feature = "async-net")]
+use super::TaskStream;
+
+// A stream a client is served over. Handles made by try_clone read from and
+// write to the same connection, and shutdown affects all of them
+pub trait Transport: Read + Write + Send + Sized + 'static {
+	fn try_clone(&self) -> io::Result<Self>;
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+	// Longest a single write may block for, where the transport can block
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+	// Where the client is connected from, if it is anywhere
+	fn peer_addr(&self) -> Option<SocketAddr>;
+
+	// The stream as tokio serves it, for those read and written by tasks
+	// rather than threads
+	#[cfg(feature = "async-net")]
+	fn task(&self) -> Option<&TaskStream> { None }
+}
+
+impl Transport for TcpStream {
+	fn try_clone(&self) -> io::Result<Self> { TcpStream::try_clone(self) }
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> { TcpStream::shutdown(self, how) }
+
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		TcpStream::set_write_timeout(self, timeout)
+	}
+
+	fn peer_addr(&self) -> Option<SocketAddr> { TcpStream::peer_addr(self).ok() }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+	fn try_clone(&self) -> io::Result<Self> { UnixStream::try_clone(self) }
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> { UnixStream::shutdown(self, how) }
+
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		UnixStream::set_write_timeout(self, timeout)
+	}
+
+	// Clients on the same machine have no address to speak of
+	fn peer_addr(&self) -> Option<SocketAddr> { None }
+}
+
+// Bytes travelling one way between the two ends of a MemoryStream
+#[derive(Default)]
+struct Pipe {
+	state: Mutex<PipeState>,
+	readable: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+	buffer: VecDeque<u8>,
+	closed: bool,
+}
+
+impl Pipe {
+	fn close(&self) {
+		self.state.lock().closed = true;
+		self.readable.notify_all();
+	}
+}
+
+// One end of a MemoryStream, closing both directions once every handle to
+// it is dropped, as a socket would
+struct End {
+	incoming: Arc<Pipe>,
+	outgoing: Arc<Pipe>,
+}
+
+impl Drop for End {
+	fn drop(&mut self) {
+		self.incoming.close();
+		self.outgoing.close();
+	}
+}
+
+// An in-process connection, so clients can be served without a network
+#[derive(Clone)]
+pub struct MemoryStream {
+	end: Arc<End>,
+}
+
+impl MemoryStream {
+	// Makes a connected pair of streams, what is written to either one being
+	// read from the other
+	pub fn pair() -> (MemoryStream, MemoryStream) {
+		let there = Arc::new(Pipe::default());
+		let back = Arc::new(Pipe::default());
+		let client = MemoryStream {
+			end: Arc::new(End {
+				incoming: back.clone(),
+				outgoing: there.clone(),
+			}),
+		};
+		let server = MemoryStream {
+			end: Arc::new(End {
+				incoming: there,
+				outgoing: back,
+			}),
+		};
+		(client, server)
+	}
+}
+
+impl Read for MemoryStream {
+	// Waits for something to read, returning 0 once the other end is gone
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let pipe = &self.end.incoming;
+		let mut state = pipe.state.lock();
+		while state.buffer.is_empty() && !state.closed {
+			pipe.readable.wait(&mut state);
+		}
+		let read = buf.len().min(state.buffer.len());
+		for (byte, read) in buf.iter_mut().zip(state.buffer.drain(..read)) {
+			*byte = read;
+		}
+		Ok(read)
+	}
+}
+
+impl Write for MemoryStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let pipe = &self.end.outgoing;
+		let mut state = pipe.state.lock();
+		if state.closed {
+			return Err(io::ErrorKind::BrokenPipe.into());
+		}
+		state.buffer.extend(buf);
+		pipe.readable.notify_all();
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl Transport for MemoryStream {
+	fn try_clone(&self) -> io::Result<Self> { Ok(self.clone()) }
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+		if how != Shutdown::Write {
+			self.end.incoming.close();
+		}
+		if how != Shutdown::Read {
+			self.end.outgoing.close();
+		}
+		Ok(())
+	}
+
+	// Writes never block, as pipes are unbounded
+	fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> { Ok(()) }
+
+	fn peer_addr(&self) -> Option<SocketAddr> { None }
+}
+
+// The process's own stdin and stdout, serving the one client which started
+// it. Shutting down stops further reads and writes, though a read already
+// waiting only ends once the client closes stdin
+#[cfg(unix)]
+#[derive(Clone)]
+pub struct StdioStream {
+	input: Arc<File>,
+	output: Arc<File>,
+	read_shut: Arc<AtomicBool>,
+	write_shut: Arc<AtomicBool>,
+}
+
+#[cfg(unix)]
+impl StdioStream {
+	// Takes over stdin and stdout, sending anything printed from now on to
+	// log, or stderr if not given, so it can't be mistaken for messages.
+	// Must only be called once
+	pub fn take(log: Option<&Path>) -> io::Result<StdioStream> {
+		let log = match log {
+			Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+			None => None,
+		};
+		let printed_to = log
+			.as_ref()
+			.map_or(libc::STDERR_FILENO, |log| log.as_raw_fd());
+		io::stdout().flush()?;
+
+		// Whatever is at stdout now keeps its own descriptor for messages
+		// before stdout is pointed elsewhere
+		let (input, output) = unsafe {
+			let input = libc::dup(libc::STDIN_FILENO);
+			if input == -1 {
+				return Err(io::Error::last_os_error());
+			}
+			let input = File::from_raw_fd(input);
+			let output = libc::dup(libc::STDOUT_FILENO);
+			if output == -1 {
+				return Err(io::Error::last_os_error());
+			}
+			let output = File::from_raw_fd(output);
+			if libc::dup2(printed_to, libc::STDOUT_FILENO) == -1 {
+				return Err(io::Error::last_os_error());
+			}
+			(input, output)
+		};
+		Ok(StdioStream {
+			input: Arc::new(input),
+			output: Arc::new(output),
+			read_shut: Arc::new(AtomicBool::new(false)),
+			write_shut: Arc::new(AtomicBool::new(false)),
+		})
+	}
+}
+
+#[cfg(unix)]
+impl Read for StdioStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.read_shut.load(Ordering::SeqCst) {
+			return Ok(0);
+		}
+		(&*self.input).read(buf)
+	}
+}
+
+#[cfg(unix)]
+impl Write for StdioStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.write_shut.load(Ordering::SeqCst) {
+			return Err(io::ErrorKind::BrokenPipe.into());
+		}
+		(&*self.output).write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { (&*self.output).flush() }
+}
+
+#[cfg(unix)]
+impl Transport for StdioStream {
+	fn try_clone(&self) -> io::Result<Self> { Ok(self.clone()) }
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+		if how != Shutdown::Write {
+			self.read_shut.store(true, Ordering::SeqCst);
+		}
+		if how != Shutdown::Read {
+			self.write_shut.store(true, Ordering::SeqCst);
+		}
+		Ok(())
+	}
+
+	// Writes block until the client reads, as there is no timeout on pipes
+	fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> { Ok(()) }
+
+	fn peer_addr(&self) -> Option<SocketAddr> { None }
+}
@@ -0,0 +1,522 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{spawn, JoinHandle};
+
+use parking_lot::Mutex;
+
+use crate::config::{Durability, Permissions};
+use crate::error::{EditrError, EditrResult};
+use crate::message::*;
+use crate::state::{ThreadIn, Transport};
+
+// The server is trusted, so its messages are read however large they are
+const RESPONSE_LIMIT: usize = usize::MAX;
+
+// Requests sent and not yet answered, oldest first. The server answers a
+// connection's requests in the order sent, so each response is for the front
+#[derive(Default)]
+struct Pending {
+	waiting: VecDeque<Sender<Message>>,
+	// Set once the reader has stopped, so nothing more will be answered
+	closed: bool,
+}
+
+// What the reader thread shares with the client
+struct Shared<T> {
+	output: Mutex<T>,
+	pending: Mutex<Pending>,
+}
+
+impl<T: Transport> Shared<T> {
+	// Writes msg, ending it with a newline as the server asks
+	fn send(&self, msg: &Message) -> EditrResult<()> {
+		let mut data = msg.to_vec()?;
+		data.push(b'\n');
+		self.output.lock().write_all(&data)?;
+		Ok(())
+	}
+}
+
+// A connection to a server. Requests may be made from any number of
+// threads, each waiting for its own response. A thread of the client's own
+// reads everything the server sends, handing responses to whoever is
+// waiting and broadcasts to the handler, or to next_broadcast if there is
+// none. ServerPings are answered there too
+pub struct Client<T: Transport = TcpStream> {
+	shared: Arc<Shared<T>>,
+	broadcasts: Mutex<Receiver<Message>>,
+	reader: Option<JoinHandle<()>>,
+}
+
+impl Client {
+	pub fn connect<A: ToSocketAddrs>(address: A) -> EditrResult<Client> {
+		let stream = TcpStream::connect(address)?;
+		stream.set_nodelay(true)?;
+		Client::new(stream)
+	}
+}
+
+impl<T: Transport> Client<T> {
+	// Talks to the server over stream, which must be freshly connected
+	pub fn new(stream: T) -> EditrResult<Client<T>> {
+		let (sender, receiver) = channel();
+		Client::start(stream, receiver, move |msg| {
+			sender.send(msg).ok();
+		})
+	}
+
+	// As new, but handing every broadcast to handler as it arrives, on the
+	// client's reader thread. Requests made from handler never complete
+	pub fn with_handler<F: FnMut(Message) + Send + 'static>(
+		stream: T,
+		handler: F,
+	) -> EditrResult<Client<T>> {
+		// Nothing is ever sent, so next_broadcast always fails
+		let (_, receiver) = channel();
+		Client::start(stream, receiver, handler)
+	}
+
+	fn start<F: FnMut(Message) + Send + 'static>(
+		stream: T,
+		broadcasts: Receiver<Message>,
+		handler: F,
+	) -> EditrResult<Client<T>> {
+		let input = ThreadIn::new(stream.try_clone()?, RESPONSE_LIMIT)?;
+		let shared = Arc::new(Shared {
+			output: Mutex::new(stream),
+			pending: Mutex::new(Pending::default()),
+		});
+		let reader = {
+			let shared = shared.clone();
+			spawn(move || read_messages(input, &shared, handler))
+		};
+		Ok(Client {
+			shared,
+			broadcasts: Mutex::new(broadcasts),
+			reader: Some(reader),
+		})
+	}
+
+	// Sends request, returning the response to it
+	pub fn request(&self, request: Message) -> EditrResult<Message> {
+		let (sender, receiver) = channel();
+		{
+			// Queued and sent under the one lock, so the queue is in the
+			// order the server sees
+			let mut pending = self.shared.pending.lock();
+			if pending.closed {
+				return Err(EditrError::Disconnected);
+			}
+			self.shared.send(&request)?;
+			pending.waiting.push_back(sender);
+		}
+		// The sender is dropped unanswered if the connection goes first
+		receiver.recv().map_err(|_| EditrError::Disconnected)
+	}
+
+	// Returns the oldest broadcast not yet taken, waiting for one if need be
+	pub fn next_broadcast(&self) -> EditrResult<Message> {
+		self.broadcasts
+			.lock()
+			.recv()
+			.map_err(|_| EditrError::Disconnected)
+	}
+
+	// Takes every broadcast which has arrived, without waiting
+	pub fn take_broadcasts(&self) -> Vec<Message> { self.broadcasts.lock().try_iter().collect() }
+
+	// Closes the connection, waiting for the reader thread to finish.
+	// Requests still waiting fail with Disconnected
+	pub fn disconnect(mut self) -> EditrResult<()> { self.stop() }
+
+	fn stop(&mut self) -> EditrResult<()> {
+		let result = self.shared.output.lock().shutdown(Shutdown::Both);
+		if let Some(reader) = self.reader.take() {
+			reader
+				.join()
+				.map_err(|_| EditrError::Internal("Client reader thread panicked".to_string()))?;
+		}
+		// The server may have closed the connection already
+		match result {
+			Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(e.into()),
+			_ => Ok(()),
+		}
+	}
+
+	pub fn ping(&self) -> EditrResult<()> {
+		match self.request(Message::Ping)? {
+			Message::Pong => Ok(()),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	pub fn hello(&self) -> EditrResult<HelloData> {
+		match self.request(Message::HelloReq)? {
+			Message::HelloResp(HelloResult::Ok(hello)) => Ok(hello),
+			Message::HelloResp(HelloResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	pub fn login(&self, user: &str, token: &str) -> EditrResult<Permissions> {
+		match self.request(Message::LoginReq(LoginReqData {
+			user: user.to_string(),
+			token: token.to_string(),
+		}))? {
+			Message::LoginResp(LoginResult::Ok(permissions)) => Ok(permissions),
+			Message::LoginResp(LoginResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Creates the file at path, returning where it was opened if asked to
+	pub fn create(
+		&self,
+		path: &str,
+		parents: bool,
+		contents: Option<Vec<u8>>,
+		open: bool,
+	) -> EditrResult<Option<PathBuf>> {
+		let request = Message::CreateReq(CreateReqData {
+			path: path.to_string(),
+			parents,
+			contents,
+			open,
+		});
+		match self.request(request)? {
+			Message::CreateResp(CreateResult::Ok(opened)) => Ok(opened),
+			Message::CreateResp(CreateResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	pub fn delete(&self, path: &str) -> EditrResult<()> {
+		match self.request(Message::DeleteReq(path.to_string()))? {
+			Message::DeleteResp(DeleteResult::Ok) => Ok(()),
+			Message::DeleteResp(DeleteResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	pub fn mkdir(&self, path: &str, recursive: bool) -> EditrResult<()> {
+		let request = Message::MkdirReq(MkdirReqData {
+			path: path.to_string(),
+			recursive,
+		});
+		match self.request(request)? {
+			Message::MkdirResp(MkdirResult::Ok) => Ok(()),
+			Message::MkdirResp(MkdirResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	pub fn rename(&self, from: &str, to: &str) -> EditrResult<()> {
+		let request = Message::RenameReq(RenameReqData {
+			from: from.to_string(),
+			to: to.to_string(),
+		});
+		match self.request(request)? {
+			Message::RenameResp(RenameResult::Ok) => Ok(()),
+			Message::RenameResp(RenameResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Lists the files which may be opened
+	pub fn list_files(&self) -> EditrResult<Vec<String>> {
+		match self.request(Message::FilesListReq)? {
+			Message::FilesListResp(FilesListResult::Ok(files)) => Ok(files),
+			Message::FilesListResp(FilesListResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Opens file, shown to others in it as name, returning its path
+	pub fn open(&self, file: &str, name: Option<&str>) -> EditrResult<PathBuf> {
+		let request = Message::OpenReq(OpenReqData {
+			file: file.to_string(),
+			name: name.map(str::to_string),
+		});
+		match self.request(request)? {
+			Message::OpenResp(OpenResult::Ok(path)) => Ok(path),
+			Message::OpenResp(OpenResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Opens a new scratch buffer, returning the path others can open it by
+	pub fn open_scratch(&self, name: &str) -> EditrResult<PathBuf> {
+		let request = Message::OpenScratchReq(OpenScratchReqData {
+			name: name.to_string(),
+		});
+		match self.request(request)? {
+			Message::OpenScratchResp(OpenResult::Ok(path)) => Ok(path),
+			Message::OpenScratchResp(OpenResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	pub fn close(&self) -> EditrResult<()> {
+		match self.request(Message::CloseReq)? {
+			Message::CloseResp(CloseResult::Ok) => Ok(()),
+			Message::CloseResp(CloseResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Reads len bytes of the open file from offset, or fewer where the file
+	// ends first
+	pub fn read(&self, offset: usize, len: usize) -> EditrResult<Vec<u8>> {
+		self.read_with_len(offset, len).map(|read| read.data)
+	}
+
+	// Reads as read does, reporting where the bytes start and how long the
+	// file was, so a short read can be told apart from one that got all it
+	// asked for
+	pub fn read_with_len(&self, offset: usize, len: usize) -> EditrResult<ReadData> {
+		match self.request(Message::ReadReq(ReadReqData { offset, len }))? {
+			Message::ReadResp(ReadResult::Ok(read)) => Ok(read),
+			Message::ReadResp(ReadResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Inserts data into the open file at offset
+	pub fn write_at(&self, offset: usize, data: &[u8]) -> EditrResult<()> {
+		let request = Message::WriteReq(WriteReqData {
+			offset,
+			data: data.to_vec(),
+		});
+		match self.request(request)? {
+			Message::WriteResp(WriteResult::Ok) => Ok(()),
+			Message::WriteResp(WriteResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Removes len bytes of the open file from offset
+	pub fn remove(&self, offset: usize, len: usize) -> EditrResult<()> {
+		match self.request(Message::RemoveReq(RemoveReqData { offset, len }))? {
+			Message::RemoveResp(RemoveResult::Ok) => Ok(()),
+			Message::RemoveResp(RemoveResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Replaces len bytes of the open file from offset with data, as one edit
+	pub fn replace(&self, offset: usize, len: usize, data: &[u8]) -> EditrResult<()> {
+		let request = Message::ReplaceReq(ReplaceReqData {
+			offset,
+			len,
+			data: data.to_vec(),
+		});
+		match self.request(request)? {
+			Message::ReplaceResp(ReplaceResult::Ok) => Ok(()),
+			Message::ReplaceResp(ReplaceResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	pub fn save(&self) -> EditrResult<()> { self.save_with(None).map(|_| ()) }
+
+	// Saves at least as durably as asked, reporting how durably it was
+	// saved and how long syncing took
+	pub fn save_with(&self, durability: Option<Durability>) -> EditrResult<SaveData> {
+		match self.request(Message::SaveReq(SaveReqData { durability }))? {
+			Message::SaveResp(SaveResult::Ok(saved)) => Ok(saved),
+			Message::SaveResp(SaveResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Writes the open file out to path, returning where it went
+	pub fn save_as(&self, path: &str, overwrite: bool) -> EditrResult<PathBuf> {
+		let request = Message::SaveAsReq(SaveAsReqData {
+			path: path.to_string(),
+			overwrite,
+		});
+		match self.request(request)? {
+			Message::SaveAsResp(SaveAsResult::Ok(path)) => Ok(path),
+			Message::SaveAsResp(SaveAsResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Moves the cursor by offset, backwards if negative
+	pub fn move_cursor(&self, offset: isize) -> EditrResult<()> {
+		match self.request(Message::MoveCursor(offset))? {
+			Message::MoveCursorResp(MoveCursorResult::Ok) => Ok(()),
+			Message::MoveCursorResp(MoveCursorResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	pub fn write_at_cursor(&self, data: &[u8]) -> EditrResult<()> {
+		let request = Message::WriteAtCursorReq(WriteAtCursorReqData {
+			data: data.to_vec(),
+		});
+		match self.request(request)? {
+			Message::WriteAtCursorResp(WriteAtCursorResult::Ok) => Ok(()),
+			Message::WriteAtCursorResp(WriteAtCursorResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+
+	pub fn remove_at_cursor(&self, len: usize) -> EditrResult<()> {
+		let request = Message::RemoveAtCursorReq(RemoveAtCursorReqData { len });
+		match self.request(request)? {
+			Message::RemoveAtCursorResp(RemoveAtCursorResult::Ok) => Ok(()),
+			Message::RemoveAtCursorResp(RemoveAtCursorResult::Err(e)) => {
+				Err(EditrError::Rejected(e))
+			}
+			other => Err(unexpected(other)),
+		}
+	}
+
+	// Returns this client's cursor, and every client's in the open file
+	pub fn cursors(&self) -> EditrResult<Cursors> {
+		match self.request(Message::GetCursorsReq)? {
+			Message::GetCursorsResp(GetCursorsResult::Ok(cursors)) => Ok(cursors),
+			Message::GetCursorsResp(GetCursorsResult::Err(e)) => Err(EditrError::Rejected(e)),
+			other => Err(unexpected(other)),
+		}
+	}
+}
+
+impl<T: Transport> Drop for Client<T> {
+	fn drop(&mut self) { self.stop().ok(); }
+}
+
+// Reads everything the server sends until the connection ends, then fails
+// whatever is still waiting
+fn read_messages<T: Transport, F: FnMut(Message)>(
+	mut input: ThreadIn<T>,
+	shared: &Shared<T>,
+	mut handler: F,
+) {
+	while let Ok(msg) = input.get_message() {
+		if let Message::ServerPing = msg {
+			// Keeps the server from timing the connection out
+			if shared.send(&Message::ServerPong).is_err() {
+				break;
+			}
+		}
+		else if msg.is_broadcast() {
+			handler(msg);
+		}
+		// Responses nobody is waiting for have nowhere to go
+		else if let Some(waiting) = shared.pending.lock().waiting.pop_front() {
+			waiting.send(msg).ok();
+		}
+	}
+	let mut pending = shared.pending.lock();
+	pending.closed = true;
+	pending.waiting.clear();
+}
+
+// The error for a message which isn't the response expected
+fn unexpected(msg: Message) -> EditrError {
+	match msg {
+		Message::InvalidResp(inner) => EditrError::InvalidMessage(inner.reason),
+		msg => EditrError::Protocol(format!("Unexpected message: {:?}", msg)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{BufRead, BufReader, Write};
+	use std::net::Shutdown;
+	use std::sync::mpsc::channel;
+	use std::thread;
+	use std::time::Duration;
+
+	use super::Client;
+	use crate::error::EditrError;
+	use crate::message::{Message, UpdateData};
+	use crate::state::{MemoryStream, Transport};
+
+	// Answers each request with itself, or Pong for a Ping, pushing an
+	// update out ahead of every answer
+	fn interleaving_server(stream: MemoryStream) {
+		let mut output = stream.clone();
+		for line in BufReader::new(stream).lines() {
+			let request: Message = serde_json::from_str(&line.unwrap()).unwrap();
+			let response = match request {
+				Message::Ping => Message::Pong,
+				request => request,
+			};
+			for msg in [Message::make_add_broadcast(0, b"x"), response] {
+				let mut buf = msg.to_vec().unwrap();
+				buf.push(b'\n');
+				output.write_all(&buf).unwrap();
+			}
+		}
+	}
+
+	#[test]
+	fn broadcasts_between_requests_and_responses_are_set_aside() {
+		let (client, server) = MemoryStream::pair();
+		thread::spawn(move || interleaving_server(server));
+		let client = Client::new(client).unwrap();
+
+		client.ping().unwrap();
+		assert!(matches!(
+			client.request(Message::Echo(b"hello".to_vec())).unwrap(),
+			Message::Echo(data) if data == b"hello"
+		));
+		for _ in 0..2 {
+			assert!(matches!(
+				client.next_broadcast().unwrap(),
+				Message::UpdateMessage(UpdateData::Add(_))
+			));
+		}
+		assert!(client.take_broadcasts().is_empty());
+		client.disconnect().unwrap();
+	}
+
+	#[test]
+	fn concurrent_requests_each_get_their_own_response() {
+		let (client, server) = MemoryStream::pair();
+		thread::spawn(move || interleaving_server(server));
+		let client = Client::new(client).unwrap();
+
+		thread::scope(|scope| {
+			for index in 0..8u8 {
+				let client = &client;
+				scope.spawn(move || {
+					for _ in 0..50 {
+						let data = vec![index; index as usize + 1];
+						match client.request(Message::Echo(data.clone())).unwrap() {
+							Message::Echo(echoed) => assert_eq!(echoed, data),
+							other => panic!("Unexpected response {:?}", other),
+						}
+					}
+				});
+			}
+		});
+		assert_eq!(client.take_broadcasts().len(), 400);
+	}
+
+	#[test]
+	fn requests_outstanding_when_the_connection_ends_fail() {
+		let (client, server) = MemoryStream::pair();
+		let client = Client::new(client).unwrap();
+
+		// The server never answers, then goes away
+		let (sent, done) = channel();
+		thread::scope(|scope| {
+			let client = &client;
+			scope.spawn(move || sent.send(client.ping()).unwrap());
+			assert!(done.recv_timeout(Duration::from_millis(200)).is_err());
+			server.shutdown(Shutdown::Both).unwrap();
+			let result = done.recv_timeout(Duration::from_secs(5)).unwrap();
+			assert!(matches!(result, Err(EditrError::Disconnected)));
+		});
+		assert!(matches!(client.ping(), Err(EditrError::Disconnected)));
+		client.disconnect().unwrap();
+	}
+}
@@ -1,81 +1,1459 @@
-use std::error::Error;
-use std::net::{TcpListener, ToSocketAddrs};
-use std::path::Path;
-use std::thread::spawn;
+use std::cell::Cell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::path::{Component, Path};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
 
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, SockRef, TcpKeepalive, Type};
+
+use crate::config::ServerConfig;
+use crate::error::{EditrError, EditrResult};
+use crate::message::{CompressResult, Message};
+#[cfg(feature = "client")]
+pub use crate::self_test::{self_test, SelfTestConfig, SelfTestReport};
 use crate::state::*;
 
+// How often sessions and connections are checked for having expired
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+// How often the listener is polled for new connections
+pub(crate) const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+// Time given to in-flight requests once the server starts closing
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+// Invalid messages a client may send before it is disconnected
+const MAX_INVALID_MESSAGES: usize = 8;
+// Pause in accepting after running out of file descriptors or memory, so
+// that some can be freed up
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(500);
+// How often the stats reporter checks whether the server is shutting down
+const STATS_POLL: Duration = Duration::from_millis(50);
+// Time given to connection threads to wind down once they are disconnected
+pub(crate) const WIND_DOWN: Duration = Duration::from_secs(5);
+
+// Set once the process has been signalled to shut down
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+// True once the server has been stopped, or the process signalled
+pub(crate) fn shutting_down<T: Transport>(shared: &SharedState<T>) -> bool {
+	shared.is_stopping() || SIGNALLED.load(Ordering::SeqCst)
+}
+
+extern "C" fn on_signal(_: libc::c_int) { SIGNALLED.store(true, Ordering::SeqCst); }
+
+// Shuts every server in the process down on SIGINT and SIGTERM
+pub fn install_signal_handlers() {
+	let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+	unsafe {
+		libc::signal(libc::SIGINT, handler);
+		libc::signal(libc::SIGTERM, handler);
+	}
+}
+
 // The main function run by the client thread
-fn client_thread(thread_local: &mut LocalState) -> Result<(), Box<dyn Error>> {
+fn client_thread<T: Transport>(thread_local: &mut LocalState<T>) -> EditrResult<()> {
+	let mut invalid_messages = 0;
 	loop {
-		let msg = thread_local.get_message()?;
+		let msg = thread_local.get_message();
+		if !handle_message(thread_local, msg, &mut invalid_messages)? {
+			return Ok(());
+		}
+	}
+}
+
+// Handles what was read from the connection, answering it. Returns whether
+// the connection carries on, or fails if it must end
+pub(crate) fn handle_message<T: Transport>(
+	thread_local: &mut LocalState<T>,
+	msg: EditrResult<Message>,
+	invalid_messages: &mut usize,
+) -> EditrResult<bool> {
+	let msg = match msg {
+		Ok(msg) => msg,
+		Err(e) => match e {
+			// Messages which can't be understood are skipped, unless
+			// the client keeps sending them
+			EditrError::InvalidMessage(_) | EditrError::FieldTooLong { .. } => {
+				*invalid_messages += 1;
+				thread_local.metrics().record("Invalid", false, true);
+				thread_local.metrics().trace().record(
+					Direction::Received,
+					"Invalid",
+					thread_local.message_size(),
+				);
+				let response = Message::make_invalid(e.to_string()).to_vec()?;
+				thread_local.socket_write(&response)?;
+				if *invalid_messages >= MAX_INVALID_MESSAGES {
+					thread_local.end(DisconnectReason::TooManyInvalidMessages, None);
+					return Err(EditrError::Protocol(
+						"Too many invalid messages".to_string(),
+					));
+				}
+				return Ok(true);
+			}
+			// Oversized messages are a protocol violation, which the client
+			// is told about before being disconnected
+			EditrError::MessageTooLarge(limit) => {
+				warn!("Client sent a message over {} bytes", limit);
+				let response = Message::make_invalid(e.to_string()).to_vec()?;
+				thread_local.socket_write(&response).ok();
+				thread_local.end(DisconnectReason::MessageTooLarge, Some(e.to_string()));
+				return Err(e);
+			}
+			_ => return Err(e),
+		},
+	};
+
+	thread_local.touch();
+
+	// Answers to heartbeats only need to have arrived
+	if let Message::ServerPong = msg {
+		return Ok(true);
+	}
+
+	debug!("<=: {}", msg.redacted());
+
+	let (response, exit) = msg.process(thread_local);
+
+	debug!("=>: {}", response.redacted());
+
+	let response_raw = response.to_vec()?;
+	thread_local.metrics().trace().record(
+		Direction::Sent,
+		message_kind(&response_raw),
+		response_raw.len(),
+	);
+
+	// Fails with Disconnected if the client has stopped keeping up, the
+	// end of its stream being seen when reading otherwise
+	thread_local.socket_write(&response_raw)?;
+
+	if let Message::CompressResp(CompressResult::Ok) = response {
+		thread_local.start_compression()?;
+	}
+
+	if let Some(reason) = exit {
+		thread_local.end(reason, None);
+		return Ok(false);
+	}
+	Ok(true)
+}
+
+// A server running in the background
+pub struct ServerHandle<T: Transport = TcpStream> {
+	pub(crate) local_addrs: Vec<SocketAddr>,
+	pub(crate) shared: SharedState<T>,
+	pub(crate) thread: JoinHandle<Result<(), String>>,
+}
+
+impl ServerHandle {
+	// The first address actually bound, which is useful when binding port 0
+	pub fn local_addr(&self) -> SocketAddr { self.local_addrs[0] }
+
+	// Every address bound, in the order given
+	pub fn local_addrs(&self) -> &[SocketAddr] { &self.local_addrs }
+}
+
+#[cfg(feature = "async-net")]
+impl ServerHandle<TaskStream> {
+	pub fn local_addr(&self) -> SocketAddr { self.local_addrs[0] }
+
+	pub fn local_addrs(&self) -> &[SocketAddr] { &self.local_addrs }
+}
+
+impl<T: Transport> ServerHandle<T> {
+	// The files being served, with their unsaved edits
+	pub fn files(&self) -> &FileStates { &self.shared.files }
+
+	// Shuts the server down as if it had been signalled, and waits for it
+	pub fn stop(self) -> EditrResult<()> {
+		self.shared.stop();
+		self.join()
+	}
+
+	// Waits for the server to shut down
+	pub fn join(self) -> EditrResult<()> {
+		self.thread
+			.join()
+			.map_err(|_| EditrError::Internal("Server thread panicked".to_string()))?
+			.map_err(EditrError::Internal)
+	}
+}
+
+pub fn start<A: ToSocketAddrs>(path: &Path, address: A) -> EditrResult<()> {
+	start_with_config(path, address, ServerConfig::default())
+}
+
+// Runs the server until it is signalled to shut down
+pub fn start_with_config<A: ToSocketAddrs>(
+	path: &Path,
+	address: A,
+	config: ServerConfig,
+) -> EditrResult<()> {
+	install_signal_handlers();
+	spawn(path, address, config)?.join()
+}
+
+// Starts the server on a thread of its own, returning once it is listening.
+// Every address given is listened on, all serving the same files
+pub fn spawn<A: ToSocketAddrs>(
+	path: &Path,
+	address: A,
+	config: ServerConfig,
+) -> EditrResult<ServerHandle> {
+	let listeners = address
+		.to_socket_addrs()?
+		.map(|address| bind(address, &config))
+		.collect::<Result<Vec<_>, _>>()?;
+	serve_listeners(path, listeners, config)
+}
+
+// Starts the server on a thread of its own, accepting from a listener which
+// has already been bound, such as one handed over by a supervisor
+pub fn serve(
+	path: &Path,
+	listener: TcpListener,
+	config: ServerConfig,
+) -> EditrResult<ServerHandle> {
+	serve_listeners(path, vec![listener], config)
+}
+
+// As serve, but accepting from every listener given
+pub fn serve_listeners(
+	path: &Path,
+	listeners: Vec<TcpListener>,
+	config: ServerConfig,
+) -> EditrResult<ServerHandle> {
+	serve_files(path, listeners, config, FileStates::new())
+}
+
+// As serve_listeners, but serving files, which may hold virtual documents
+// from FileStates::with_documents as well as those under path
+pub fn serve_files(
+	path: &Path,
+	listeners: Vec<TcpListener>,
+	config: ServerConfig,
+	files: FileStates,
+) -> EditrResult<ServerHandle> {
+	if listeners.is_empty() {
+		return Err(EditrError::Config("No address to listen on".to_string()));
+	}
+	let mut local_addrs = Vec::new();
+	for listener in &listeners {
+		// Accepting mustn't block, so that a shutdown can be noticed
+		listener.set_nonblocking(true)?;
+		local_addrs.push(listener.local_addr()?);
+	}
+	launch(path, listeners, local_addrs, config, files)
+}
 
-		println!("<=: {:?}", msg);
+// Starts the server on a thread of its own, accepting clients on the same
+// machine through a Unix socket made at socket. A socket left behind by a
+// server which has gone away is replaced. The socket is removed again once
+// the server shuts down
+#[cfg(unix)]
+pub fn serve_unix(
+	path: &Path,
+	socket: &Path,
+	config: ServerConfig,
+) -> EditrResult<ServerHandle<UnixStream>> {
+	let listener = UnixSocket::bind(socket)?;
+	launch(path, vec![listener], Vec::new(), config, FileStates::new())
+}
+
+// Sets up the server and accepts from listeners on a thread of its own
+fn launch<L: Listener>(
+	path: &Path,
+	listeners: Vec<L>,
+	local_addrs: Vec<SocketAddr>,
+	config: ServerConfig,
+	files: FileStates,
+) -> EditrResult<ServerHandle<L::Stream>> {
+	let shared = prepare(path, config, files)?;
 
-		let (response, exit) = msg.process(thread_local);
+	let thread = {
+		let shared = shared.clone();
+		thread::spawn(move || run(listeners, shared).map_err(|e| e.to_string()))
+	};
 
-		println!("=>: {:?}", response);
+	Ok(ServerHandle {
+		local_addrs,
+		shared,
+		thread,
+	})
+}
 
-		let response_raw = response.to_vec()?;
+// Something connections are accepted from, without blocking so that a
+// shutdown can be noticed
+trait Listener: Send + 'static {
+	type Stream: Transport;
+
+	// Takes a waiting connection, failing with WouldBlock if there are
+	// none. The stream is handed back blocking and set up as configured,
+	// or None if it was turned away
+	fn accept_checked(
+		&self,
+		shared: &SharedState<Self::Stream>,
+	) -> io::Result<Option<Self::Stream>>;
+}
 
-		let num_written = thread_local.socket_write(&response_raw)?;
+impl Listener for TcpListener {
+	type Stream = TcpStream;
 
-		// Check for a EOF
-		if num_written == 0 {
-			break;
+	fn accept_checked(&self, shared: &SharedState) -> io::Result<Option<TcpStream>> {
+		let (stream, peer) = self.accept()?;
+		if let Err(e) = stream.set_nonblocking(false) {
+			error!("Failed to set up connection from {}: {}", peer, e);
+			return Ok(None);
 		}
+		if !permitted(shared, &stream, peer) {
+			return Ok(None);
+		}
+		configure_stream(shared, SockRef::from(&stream), peer);
+		Ok(Some(stream))
+	}
+}
+
+// A listening Unix socket, whose file is removed once it is dropped
+#[cfg(unix)]
+struct UnixSocket {
+	listener: UnixListener,
+	path: PathBuf,
+}
 
-		// thread_local
-		//	.thread_io
-		//	.get(&thread_local.thread_id)
-		//	.ok_or("Thread local storage does not exist")?
-		//	.lock()
-		//	.or(Err("Unable to lock thread shared data"))?
-		//	.writer
-		//	.flush()?;
+#[cfg(unix)]
+impl UnixSocket {
+	fn bind(path: &Path) -> EditrResult<UnixSocket> {
+		let listener = match UnixListener::bind(path) {
+			// Nothing answering on an old socket means its server has gone.
+			// Anything else in the way is left alone
+			Err(e)
+				if e.kind() == io::ErrorKind::AddrInUse
+					&& fs::symlink_metadata(path)
+						.is_ok_and(|meta| meta.file_type().is_socket())
+					&& UnixStream::connect(path).is_err() =>
+			{
+				fs::remove_file(path)?;
+				UnixListener::bind(path)
+			}
+			result => result,
+		};
+		let listener = listener.map_err(|e| {
+			let message = format!("Failed to bind {}: {}", path.display(), e);
+			io::Error::new(e.kind(), message)
+		})?;
+		let socket = UnixSocket {
+			listener,
+			path: path.to_path_buf(),
+		};
+		socket.listener.set_nonblocking(true)?;
+		Ok(socket)
+	}
+}
 
-		if exit {
-			// Client has finished connection
-			break;
+#[cfg(unix)]
+impl Listener for UnixSocket {
+	type Stream = UnixStream;
+
+	fn accept_checked(&self, _: &SharedState<UnixStream>) -> io::Result<Option<UnixStream>> {
+		let (stream, _) = self.listener.accept()?;
+		if let Err(e) = stream.set_nonblocking(false) {
+			error!(
+				"Failed to set up connection on {}: {}",
+				self.path.display(),
+				e
+			);
+			return Ok(None);
 		}
+		info!("Accepted a connection on {}", self.path.display());
+		Ok(Some(stream))
 	}
-	Ok(())
 }
 
-pub fn start<A: ToSocketAddrs>(path: &Path, address: A) -> Result<(), Box<dyn Error>> {
-	let canonical_home = path.canonicalize()?;
+#[cfg(unix)]
+impl Drop for UnixSocket {
+	fn drop(&mut self) { fs::remove_file(&self.path).ok(); }
+}
+
+// Accepts connections from every listener until shut down
+fn run<L: Listener>(listeners: Vec<L>, shared: SharedState<L::Stream>) -> EditrResult<()> {
+	let workers = shared
+		.config
+		.workers
+		.map(|workers| Workers::start(&shared, workers));
+
+	while !shutting_down(&shared) {
+		let mut idle = true;
+		for listener in &listeners {
+			let accepted = match listener.accept_checked(&shared) {
+				Ok(accepted) => accepted,
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+				Err(e) => {
+					accept_failed(&e);
+					continue;
+				}
+			};
+			idle = false;
+			let mut stream = match accepted {
+				Some(stream) => stream,
+				None => continue,
+			};
+
+			let slot = match admit(&shared, &mut stream) {
+				Some(slot) => slot,
+				None => continue,
+			};
+
+			match &workers {
+				Some(workers) => workers.serve(stream, slot)?,
+				None => {
+					let shared = shared.clone();
+					thread::spawn(move || serve_connection(shared, stream, slot));
+				}
+			}
+		}
+
+		if idle {
+			sleep(ACCEPT_INTERVAL);
+		}
+	}
+
+	// Stops taking connections before the current ones are wound down
+	drop(listeners);
+	drop(workers);
+	close(&shared)
+}
+
+// Takes the listeners a supervisor such as systemd has passed down, following
+// the LISTEN_FDS convention. They are only taken once, and not passed on
+// to children
+#[cfg(unix)]
+pub fn activated_listeners() -> EditrResult<Vec<TcpListener>> {
+	use std::env;
+	use std::os::unix::io::FromRawFd;
+
+	// Passed descriptors start straight after stderr
+	const FIRST_FD: libc::c_int = 3;
+
+	let pid = env::var("LISTEN_PID")
+		.map_err(|_| EditrError::Config("No sockets were passed down".to_string()))?;
+	if pid.parse::<u32>().ok() != Some(std::process::id()) {
+		return Err(EditrError::Config(
+			"Sockets were passed down to another process".to_string(),
+		));
+	}
+	let count = env::var("LISTEN_FDS")
+		.ok()
+		.and_then(|count| count.parse::<libc::c_int>().ok())
+		.ok_or_else(|| EditrError::Config("Invalid LISTEN_FDS".to_string()))?;
+	for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+		env::remove_var(var);
+	}
+	if count <= 0 {
+		return Err(EditrError::Config(
+			"No sockets were passed down".to_string(),
+		));
+	}
+
+	(FIRST_FD..FIRST_FD + count)
+		.map(|fd| {
+			// Taking ownership of a descriptor which isn't open would close it
+			// again when the listener is dropped
+			if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+				return Err(EditrError::Config(format!(
+					"Socket {} was not passed down: {}",
+					fd,
+					io::Error::last_os_error()
+				)));
+			}
+			let listener = unsafe { TcpListener::from_raw_fd(fd) };
+			// Make sure it is a listening TCP socket before it is accepted from
+			listener.local_addr()?;
+			Ok(listener)
+		})
+		.collect()
+}
+
+// Sets up a server which clients reach over in-process streams rather than
+// the network, so the protocol can be exercised without sockets
+pub fn start_in_memory(
+	path: &Path,
+	config: ServerConfig,
+) -> EditrResult<SharedState<MemoryStream>> {
+	start_in_memory_with(path, config, FileStates::new())
+}
+
+// As start_in_memory, but serving files, which may hold virtual documents
+// from FileStates::with_documents as well as those under path
+pub fn start_in_memory_with(
+	path: &Path,
+	config: ServerConfig,
+	files: FileStates,
+) -> EditrResult<SharedState<MemoryStream>> {
+	prepare(path, config, files)
+}
+
+// Serves a new client of an in-memory server, returning the client's end
+pub fn connect_in_memory(shared: &SharedState<MemoryStream>) -> EditrResult<MemoryStream> {
+	let slot = shared
+		.acquire_slot()
+		.ok_or_else(|| EditrError::Protocol("Server is full".to_string()))?;
+	let (client, server) = MemoryStream::pair();
+	let shared = shared.clone();
+	thread::spawn(move || serve_connection(shared, server, slot));
+	Ok(client)
+}
+
+// Listens on address, retrying as configured. Accepting doesn't block, so
+// that a shutdown can be noticed
+pub(crate) fn bind(address: SocketAddr, config: &ServerConfig) -> EditrResult<TcpListener> {
+	let (mut attempts, mut delay) = match config.bind_retry {
+		Some(retry) => (retry.attempts, retry.delay),
+		None => (0, Duration::default()),
+	};
+	loop {
+		match try_bind(address, config.reuse_address) {
+			Ok(listener) => return Ok(listener),
+			Err(e) if attempts > 0 => {
+				warn!("Failed to bind {}, retrying in {:?}: {}", address, delay, e);
+				sleep(delay);
+				attempts -= 1;
+				delay *= 2;
+			}
+			Err(e) => {
+				let message = format!("Failed to bind {}: {}", address, e);
+				return Err(io::Error::new(e.kind(), message).into());
+			}
+		}
+	}
+}
+
+fn try_bind(address: SocketAddr, reuse_address: bool) -> io::Result<TcpListener> {
+	let socket = socket2::Socket::new(
+		Domain::for_address(address),
+		Type::STREAM,
+		Some(Protocol::TCP),
+	)?;
+	socket.set_reuse_address(reuse_address)?;
+	socket.bind(&address.into())?;
+	socket.listen(128)?;
+	socket.set_nonblocking(true)?;
+	Ok(socket.into())
+}
+
+// Validates config and sets up the state shared by every connection,
+// along with the thread maintaining it
+pub(crate) fn prepare<T: Transport>(
+	path: &Path,
+	config: ServerConfig,
+	files: FileStates,
+) -> EditrResult<SharedState<T>> {
+	let canonical_home = canonical_path(path)?;
+
+	// The shared area must be a plain directory name directly under home
+	if let Some(shared) = &config.shared_dir {
+		let mut components = Path::new(shared).components();
+		match (components.next(), components.next()) {
+			(Some(Component::Normal(_)), None) => fs::create_dir_all(canonical_home.join(shared))?,
+			_ => {
+				return Err(EditrError::Config(
+					"Invalid shared directory name".to_string(),
+				))
+			}
+		}
+	}
+
+	if config.workers == Some(0) {
+		return Err(EditrError::Config(
+			"At least one worker is required".to_string(),
+		));
+	}
+
+	if let Some(limit) = config.rate_limit {
+		if limit.messages_per_sec == 0 || limit.bytes_per_sec == 0 {
+			return Err(EditrError::Config(
+				"Rate limits must be non-zero".to_string(),
+			));
+		}
+	}
+
+	if let Some(heartbeat) = config.heartbeat {
+		if heartbeat.interval.as_secs() == 0 || heartbeat.timeout < heartbeat.interval {
+			return Err(EditrError::Config(
+				"Heartbeats must be at least a second apart, within the timeout".to_string(),
+			));
+		}
+	}
+
+	if config.autosave.is_some_and(|interval| interval.is_zero()) {
+		return Err(EditrError::Config(
+			"Autosaves must be some time apart".to_string(),
+		));
+	}
+
+	let shared = SharedState::new(config, canonical_home, files)?;
+
+	// Clean up after sessions which were not resumed in time, and
+	// connections which have gone quiet, and tidy up idle files
+	{
+		let shared = shared.clone();
+		thread::spawn(move || {
+			let mut last_pinged = Instant::now();
+			let mut last_saved = Instant::now();
+			while !shutting_down(&shared) {
+				sleep(REAP_INTERVAL);
+				reap(&shared);
+
+				if let Some(idle) = shared.config.compact_after {
+					match shared.files.compact_idle(idle) {
+						Ok(compacted) => {
+							for path in compacted {
+								debug!("Compacted {}", path.display());
+							}
+						}
+						Err(e) => error!("Failed to compact files: {}", e),
+					}
+				}
+
+				if let Some(heartbeat) = shared.config.heartbeat {
+					if last_pinged.elapsed() >= heartbeat.interval {
+						last_pinged = Instant::now();
+						ping(&shared, heartbeat.timeout);
+					}
+				}
+
+				if let Some(interval) = shared.config.autosave {
+					if last_saved.elapsed() >= interval {
+						last_saved = Instant::now();
+						autosave(&shared);
+					}
+				}
+			}
+		});
+	}
+
+	if !shared.config.metrics_log.is_zero() {
+		*shared.stats_reporter.lock() = Some(start_stats_reporter(&shared)?);
+	}
+
+	Ok(shared)
+}
+
+// Starts a thread reporting on the metrics every metrics_log, which stops
+// along with the server
+fn start_stats_reporter<T: Transport>(shared: &SharedState<T>) -> EditrResult<JoinHandle<()>> {
+	let mut file = match &shared.config.metrics_file {
+		Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+		None => None,
+	};
+	let shared = shared.clone();
+	let interval = shared.config.metrics_log;
+	Ok(thread::spawn(move || {
+		let mut previous = None;
+		let mut last_reported = Instant::now();
+		while !shutting_down(&shared) {
+			sleep(STATS_POLL.min(interval));
+			if last_reported.elapsed() >= interval {
+				last_reported = Instant::now();
+				previous = report_stats(&shared, previous.as_ref(), file.as_mut()).or(previous);
+			}
+		}
+	}))
+}
+
+// Logs a summary of the metrics, and writes it out to file if there is one.
+// Returns the snapshot taken, for the next report to count from
+fn report_stats<T: Transport>(
+	shared: &SharedState<T>,
+	previous: Option<&ServerSnapshot>,
+	file: Option<&mut File>,
+) -> Option<ServerSnapshot> {
+	let snapshot = match shared.snapshot() {
+		Ok(snapshot) => snapshot,
+		Err(e) => {
+			error!("Failed to collect metrics: {}", e);
+			return None;
+		}
+	};
+	let report = snapshot.report(previous);
+	info!(
+		"Stats: {} connections, {} open files, {} bytes, {} operations, {} errors",
+		report.connections, report.open_files, report.rope_bytes, report.operations, report.errors
+	);
+	if let Some(file) = file {
+		let written = serde_json::to_vec(&report)
+			.map_err(io::Error::from)
+			.and_then(|mut line| {
+				line.push(b'\n');
+				file.write_all(&line)
+			});
+		if let Err(e) = written {
+			error!("Failed to write stats: {}", e);
+		}
+	}
+	Some(snapshot)
+}
+
+// Counts the threads free to serve a connection
+#[derive(Clone)]
+pub(crate) struct Threads(Arc<AtomicUsize>);
+
+// A thread taken from Threads, given back when dropped
+pub(crate) struct Thread(Arc<AtomicUsize>);
+
+impl Threads {
+	pub(crate) fn new(count: usize) -> Threads { Threads(Arc::new(AtomicUsize::new(count))) }
+
+	// Takes a thread for a newly accepted connection. With none free the
+	// connection would never be answered, so the client is sent ServerBusy
+	// instead, and the connection is dropped
+	pub(crate) fn take(&self, mut stream: impl Write) -> Option<Thread> {
+		let taken = self
+			.0
+			.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |free| {
+				free.checked_sub(1)
+			})
+			.is_ok();
+		if !taken {
+			warn!("Every thread is busy, turning a connection away");
+			if let Ok(data) = Message::ServerBusy.to_vec() {
+				stream.write_all(&data).ok();
+			}
+			return None;
+		}
+		Some(Thread(self.0.clone()))
+	}
+}
+
+impl Drop for Thread {
+	fn drop(&mut self) { self.0.fetch_add(1, Ordering::SeqCst); }
+}
+
+// A fixed number of threads, each serving one connection at a time
+struct Workers<T: Transport> {
+	queue: Sender<(T, ConnectionSlot, Thread)>,
+	idle: Threads,
+}
+
+impl<T: Transport> Workers<T> {
+	fn start(shared: &SharedState<T>, workers: usize) -> Workers<T> {
+		let (queue, receiver) = channel::<(T, ConnectionSlot, Thread)>();
+		let receiver = Arc::new(Mutex::new(receiver));
+		for _ in 0..workers {
+			let shared = shared.clone();
+			let receiver = receiver.clone();
+			thread::spawn(move || loop {
+				// Workers stop once the queue has been dropped
+				let (stream, slot, _thread) = match receiver.lock().recv() {
+					Ok(connection) => connection,
+					Err(_) => break,
+				};
+				// Connections still waiting at shutdown are dropped
+				if !shutting_down(&shared) {
+					serve_connection(shared.clone(), stream, slot);
+				}
+			});
+		}
+		Workers {
+			queue,
+			idle: Threads::new(workers),
+		}
+	}
+
+	// Hands the connection to an idle worker, unless there are none
+	fn serve(&self, mut stream: T, slot: ConnectionSlot) -> EditrResult<()> {
+		let thread = match self.idle.take(&mut stream) {
+			Some(thread) => thread,
+			None => return Ok(()),
+		};
+		self.queue
+			.send((stream, slot, thread))
+			.map_err(|_| EditrError::Internal("Workers have stopped".to_string()))
+	}
+}
+
+// Reports a failure to accept a connection, which the server carries on
+// from. Running out of resources is waited out for a while before the
+// next attempt, rather than spinning
+pub(crate) fn accept_failed(e: &io::Error) {
+	error!("Failed to accept connection: {}", e);
+	let exhausted = [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM];
+	if e.raw_os_error()
+		.is_some_and(|code| exhausted.contains(&code))
+	{
+		sleep(ACCEPT_BACKOFF);
+	}
+}
+
+// Applies the configured socket options to a newly accepted stream.
+// Clones of the stream, such as the one in SharedOut, share its options
+pub(crate) fn configure_stream<T: Transport>(
+	shared: &SharedState<T>,
+	socket: SockRef,
+	peer: SocketAddr,
+) {
+	let result =
+		socket
+			.set_nodelay(shared.config.nodelay)
+			.and_then(|_| match shared.config.keepalive {
+				Some(interval) => socket.set_tcp_keepalive(
+					&TcpKeepalive::new()
+						.with_time(interval)
+						.with_interval(interval),
+				),
+				None => socket.set_keepalive(false),
+			});
+	if let Err(e) = result {
+		error!("Failed to set socket options for {}: {}", peer, e);
+	}
+	info!(
+		"Accepted {} (nodelay: {}, keepalive: {:?})",
+		peer,
+		socket.nodelay().unwrap_or(false),
+		shared
+			.config
+			.keepalive
+			.filter(|_| socket.keepalive().unwrap_or(false))
+	);
+}
+
+// Checks a newly accepted connection against the allow and deny lists.
+// Refused clients may be told so, and the connection is dropped
+pub(crate) fn permitted<T: Transport>(
+	shared: &SharedState<T>,
+	mut stream: impl Write,
+	peer: SocketAddr,
+) -> bool {
+	let config = &shared.config;
+	let address = peer.ip();
+	let allowed = config.allow.is_empty() || config.allow.iter().any(|cidr| cidr.contains(address));
+	let denied = config.deny.iter().any(|cidr| cidr.contains(address));
+	if allowed && !denied {
+		return true;
+	}
+
+	info!("Refused {}", peer);
+	if config.refusal_notice {
+		if let Ok(data) = Message::Refused.to_vec() {
+			stream.write_all(&data).ok();
+		}
+	}
+	false
+}
+
+// Takes a slot for a newly accepted connection. If the server is full the
+// client is told so, and the connection is dropped
+pub(crate) fn admit<T: Transport>(
+	shared: &SharedState<T>,
+	mut stream: impl Write,
+) -> Option<ConnectionSlot> {
+	let slot = shared.acquire_slot();
+	if slot.is_none() {
+		if let Ok(data) = Message::ServerBusy.to_vec() {
+			stream.write_all(&data).ok();
+		}
+	}
+	slot
+}
+
+// Runs a connection from start to finish.
+// The slot is held until the connection is done with
+pub(crate) fn serve_connection<T: Transport>(
+	shared: SharedState<T>,
+	stream: T,
+	_slot: ConnectionSlot,
+) {
+	let mut thread_local = match LocalState::new(shared, stream) {
+		Ok(thread_local) => thread_local,
+		Err(e) => {
+			error!("Failed to set up connection: {}", e);
+			return;
+		}
+	};
+
+	// Close file and remove io, or keep a resumable session around.
+	// Whatever the session held may be broken after a panic, so it is dropped
+	let panicked = run_connection(&mut thread_local);
+	if let Err(e) = thread_local.disconnect(!panicked) {
+		error!("Failed to clean up after connection: {}", e);
+	}
+}
+
+// Serves the one client on the other end of stdin and stdout, as when the
+// server is started by an editor plugin. Output is moved to log, or to
+// stderr, before anything is served. Returns once the client has gone,
+// after saving every file it left unsaved
+#[cfg(unix)]
+pub fn serve_stdio(path: &Path, log: Option<&Path>, config: ServerConfig) -> EditrResult<()> {
+	let stream = StdioStream::take(log)?;
+	let shared = prepare(path, config, FileStates::new())?;
+	let slot = shared
+		.acquire_slot()
+		.ok_or_else(|| EditrError::Config("No connections are allowed".to_string()))?;
+	let mut thread_local = LocalState::new(shared.clone(), stream)?;
+	let panicked = run_connection(&mut thread_local);
+
+	// Edits are dropped along with the last client in a file, so are saved
+	// while the client is still in them
+	for (path, result) in shared.files.flush_all(shared.config.durability)? {
+		if let Err(e) = result {
+			error!("Failed to save {}: {}", path.display(), e);
+		}
+	}
+	thread_local.disconnect(!panicked)?;
+	drop(slot);
+	shared.stop();
+	close(&shared)
+}
+
+// Handles a connection's messages until it ends, catching panics so they
+// don't break the server state. Returns whether it panicked
+fn run_connection<T: Transport>(thread_local: &mut LocalState<T>) -> bool {
+	let result = catch_unwind(AssertUnwindSafe(|| client_thread(thread_local)));
+	connection_ended(thread_local, result)
+}
 
-	let listener = TcpListener::bind(address)?;
+// Logs how a connection's handling ended, returning whether it panicked
+pub(crate) fn connection_ended<T: Transport>(
+	thread_local: &LocalState<T>,
+	result: thread::Result<EditrResult<()>>,
+) -> bool {
+	match result {
+		Ok(Ok(())) => false,
+		// Clients simply going away is routine, anything else is shown
+		// along with what led up to it
+		Ok(Err(e)) if matches!(e.root(), EditrError::Disconnected) => {
+			info!(
+				"Thread for connection {:?} exited with error: {}",
+				thread_local.connection_id(),
+				e
+			);
+			false
+		}
+		Ok(Err(e)) => {
+			error!(
+				"Thread for connection {:?} exited with error: {}",
+				thread_local.connection_id(),
+				e
+			);
+			for entry in thread_local.metrics().trace().entries() {
+				error!("\t{}", entry);
+			}
+			false
+		}
+		Err(payload) => {
+			let reason = payload
+				.downcast_ref::<&str>()
+				.copied()
+				.or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+				.unwrap_or("unknown cause");
+			error!(
+				"Thread for connection {:?} panicked: {}",
+				thread_local.connection_id(),
+				reason
+			);
+			true
+		}
+	}
+}
+
+// Warns clients the server is going away, then saves every open file
+// before dropping the connections
+pub(crate) fn close<T: Transport>(shared: &SharedState<T>) -> EditrResult<()> {
+	info!("Shutting down");
+	shared
+		.shared_out
+		.broadcast(&Message::ServerClosing.to_vec()?)?;
+
+	sleep(SHUTDOWN_GRACE);
+
+	for (path, result) in shared.files.flush_all(shared.config.durability)? {
+		if let Err(e) = result {
+			error!("{}", e);
+			let clients = shared.files.clients(&path)?;
+			let notice = Message::make_save_failed(path, &e).to_vec()?;
+			shared.shared_out.write_many(&clients, &notice).ok();
+		}
+	}
 
-	let files: FileStates = FileStates::new();
+	for id in shared.connections.ids() {
+		shared.connections.end(
+			id,
+			DisconnectReason::ServerShutdown,
+			None,
+			&shared.shared_out,
+		);
+	}
 
-	let shared_out: shared_out::SharedOut = shared_out::SharedOut::new();
+	// Give the connection threads a chance to clean up after themselves
+	let started = Instant::now();
+	while shared.metrics.connections() > 0 && started.elapsed() < WIND_DOWN {
+		sleep(ACCEPT_INTERVAL);
+	}
 
-	for stream_result in listener.incoming() {
-		let canonical_home = canonical_home.clone();
-		let files = files.clone();
-		let shared_out = shared_out.clone();
+	if let Some(reporter) = shared.stats_reporter.lock().take() {
+		reporter.join().ok();
+	}
+	if let Some(journal) = &shared.journal {
+		journal.close();
+	}
+	if let Some(access_log) = &shared.access_log {
+		access_log.close();
+	}
+	Ok(())
+}
 
-		spawn(move || {
-			let stream = stream_result.unwrap();
+// Checks every connection is still there, disconnecting those which have
+// been silent for longer than timeout. Their sessions end, rather than
+// waiting to be resumed, so neighbours are told they left straight away
+fn ping<T: Transport>(shared: &SharedState<T>, timeout: Duration) {
+	for id in shared.connections.idle(timeout) {
+		shared.connections.end(
+			id,
+			DisconnectReason::HeartbeatTimeout,
+			None,
+			&shared.shared_out,
+		);
+	}
+	let result = Message::ServerPing
+		.to_vec()
+		.and_then(|data| shared.shared_out.broadcast_attached(&data));
+	if let Err(e) = result {
+		error!("Failed to send heartbeat: {}", e);
+	}
+}
 
-			let mut thread_local =
-				LocalState::new(shared_out, files, canonical_home, stream).unwrap();
+// Saves every file holding unsaved edits, within the quota as a client's
+// save would be. Those in a file are told if it starts failing to save,
+// though not again each time it is retried
+fn autosave<T: Transport>(shared: &SharedState<T>) {
+	let files = match shared.files.unsaved() {
+		Ok(files) => files,
+		Err(e) => {
+			error!("Failed to autosave: {}", e);
+			return;
+		}
+	};
+	for file in files {
+		let path = file.path();
+		let home = shared.quota_home(path);
+		let failing = file.persist_failing();
+		// Whatever was reserved is given back if the write then fails
+		let reserved = Cell::new(0);
+		let result = shared
+			.files
+			.flush(path, shared.config.durability, |new_size| {
+				let old_size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+				let delta = new_size as i64 - old_size as i64;
+				shared.quotas.reserve(&home, delta)?;
+				reserved.set(delta);
+				Ok(())
+			});
+		match result {
+			Ok(_) => debug!("Autosaved {}", path.display()),
+			Err(e) => {
+				shared.quotas.release(&home, reserved.get()).ok();
+				error!("Failed to autosave {}: {}", path.display(), e);
+				if !failing {
+					let notice = Message::make_save_failed(path.clone(), &e).to_vec();
+					if let (Ok(clients), Ok(notice)) = (file.clients(), notice) {
+						shared.shared_out.write_many(&clients, &notice).ok();
+					}
+				}
+			}
+		}
+	}
+}
 
-			// Handle errors safely without breaking the server state
-			client_thread(&mut thread_local)
-				.map_err(|e| {
-					println!("Thread exited with error: {}", e);
+fn reap<T: Transport>(shared: &SharedState<T>) {
+	for (id, saved) in shared.sessions.reap(shared.config.session_grace) {
+		if let Some(path) = saved.opened_file {
+			shared
+				.files
+				.close(&path, id, |name, neighbours| {
+					let data = Message::make_peer_left(id, name).to_vec()?;
+					shared.metrics.broadcast_sent();
+					shared.shared_out.write_many(&neighbours, &data)
 				})
 				.ok();
+		}
+		shared.shared_out.remove(id).ok();
+	}
+
+	if let Some(timeout) = shared.config.handshake_timeout {
+		for (id, peer) in shared.connections.silent(timeout) {
+			let detail = peer.map(|peer| format!("Nothing received from {}", peer));
+			shared.connections.end(
+				id,
+				DisconnectReason::HandshakeTimeout,
+				detail,
+				&shared.shared_out,
+			);
+		}
+	}
 
-			// Close file
-			thread_local.file_close().unwrap();
+	if let Some(timeout) = shared.config.idle_timeout {
+		for id in shared.connections.idle(timeout) {
+			shared
+				.connections
+				.end(id, DisconnectReason::IdleTimeout, None, &shared.shared_out);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+	use std::net::Shutdown;
+
+	use super::*;
+
+	// A connected pair of streams, the second as accepted by a server
+	fn connected() -> (TcpStream, TcpStream, SocketAddr) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (accepted, peer) = listener.accept().unwrap();
+		(client, accepted, peer)
+	}
 
-			// Remove io
-			thread_local.remove_thread_io().unwrap();
+	fn shared(config: ServerConfig) -> SharedState {
+		SharedState::new(config, std::env::temp_dir(), FileStates::new()).unwrap()
+	}
+
+	#[test]
+	fn accepted_streams_get_the_configured_options() {
+		let shared = shared(ServerConfig {
+			nodelay: true,
+			keepalive: Some(Duration::from_secs(30)),
+			..ServerConfig::default()
 		});
+		let (_client, accepted, peer) = connected();
+		configure_stream(&shared, SockRef::from(&accepted), peer);
+
+		// Clones share the options, so SharedOut's copy has them too
+		let clone = accepted.try_clone().unwrap();
+		let socket = SockRef::from(&clone);
+		assert!(socket.nodelay().unwrap());
+		assert!(socket.keepalive().unwrap());
 	}
 
-	Ok(())
+	#[test]
+	fn options_can_be_turned_off() {
+		let shared = shared(ServerConfig {
+			nodelay: false,
+			keepalive: None,
+			..ServerConfig::default()
+		});
+		let (_client, accepted, peer) = connected();
+		configure_stream(&shared, SockRef::from(&accepted), peer);
+
+		let socket = SockRef::from(&accepted);
+		assert!(!socket.nodelay().unwrap());
+		assert!(!socket.keepalive().unwrap());
+	}
+
+	// A stream which panics once armed, the next time anything is read from
+	// it, as a bug in handling a message would
+	struct Panicking {
+		stream: MemoryStream,
+		armed: Arc<AtomicBool>,
+	}
+
+	impl Read for Panicking {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			let read = self.stream.read(buf)?;
+			if self.armed.load(Ordering::SeqCst) {
+				panic!("Armed stream was read from");
+			}
+			Ok(read)
+		}
+	}
+
+	impl Write for Panicking {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.stream.write(buf) }
+
+		fn flush(&mut self) -> io::Result<()> { self.stream.flush() }
+	}
+
+	impl Transport for Panicking {
+		fn try_clone(&self) -> io::Result<Self> {
+			Ok(Panicking {
+				stream: self.stream.clone(),
+				armed: self.armed.clone(),
+			})
+		}
+
+		fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.stream.shutdown(how) }
+
+		fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> { Ok(()) }
+
+		fn peer_addr(&self) -> Option<SocketAddr> { None }
+	}
+
+	// A server over a home holding file.txt, whose connections can be made
+	// to panic
+	#[cfg(feature = "client")]
+	fn panicking_server(name: &str) -> (std::path::PathBuf, SharedState<Panicking>) {
+		let home = std::env::temp_dir().join(format!("editr-{}-{}", name, std::process::id()));
+		fs::create_dir_all(&home).unwrap();
+		fs::write(home.join("file.txt"), "").unwrap();
+		let shared = SharedState::new(
+			ServerConfig::default(),
+			home.canonicalize().unwrap(),
+			FileStates::new(),
+		)
+		.unwrap();
+		(home, shared)
+	}
+
+	// Connects a client whose connection panics once armed
+	#[cfg(feature = "client")]
+	fn connect_panicking(
+		shared: &SharedState<Panicking>,
+	) -> (crate::text_client::Client<MemoryStream>, Arc<AtomicBool>) {
+		let (client, server) = MemoryStream::pair();
+		let armed = Arc::new(AtomicBool::new(false));
+		let stream = Panicking {
+			stream: server,
+			armed: armed.clone(),
+		};
+		let (shared, slot) = (shared.clone(), shared.acquire_slot().unwrap());
+		thread::spawn(move || serve_connection(shared, stream, slot));
+		(crate::text_client::Client::new(client).unwrap(), armed)
+	}
+
+	#[cfg(feature = "client")]
+	#[test]
+	fn panicking_connections_are_cleaned_up() {
+		let (home, shared) = panicking_server("panic");
+		let (doomed, armed) = connect_panicking(&shared);
+		let (peer, _) = connect_panicking(&shared);
+		doomed.open("file.txt", None).unwrap();
+		peer.open("file.txt", None).unwrap();
+		peer.take_broadcasts();
+
+		// The request is never answered, so it is left waiting
+		armed.store(true, Ordering::SeqCst);
+		thread::spawn(move || doomed.ping());
+
+		// Peers are told, and the connection's slot is given back
+		let started = Instant::now();
+		while !peer
+			.take_broadcasts()
+			.iter()
+			.any(|message| matches!(message, Message::PeerLeft(_)))
+		{
+			assert!(started.elapsed() < Duration::from_secs(5), "No PeerLeft");
+			sleep(Duration::from_millis(10));
+		}
+		while shared.metrics.connections() > 1 {
+			assert!(started.elapsed() < Duration::from_secs(5), "Slot kept");
+			sleep(Duration::from_millis(10));
+		}
+
+		// No ghost is left holding the file open
+		peer.close().unwrap();
+		peer.delete("file.txt").unwrap();
+		fs::remove_dir_all(&home).ok();
+	}
+
+	#[cfg(feature = "client")]
+	#[test]
+	fn panicked_sessions_are_not_kept_for_resuming() {
+		use crate::message::{ResumeResult, StatusResult};
+
+		let (home, shared) = panicking_server("panic-session");
+		let (doomed, armed) = connect_panicking(&shared);
+		let (peer, _) = connect_panicking(&shared);
+		let token = doomed.hello().unwrap().session;
+		doomed.open("file.txt", None).unwrap();
+		doomed.write_at(0, b"before").unwrap();
+		doomed.move_cursor(3).unwrap();
+		peer.open("file.txt", None).unwrap();
+
+		armed.store(true, Ordering::SeqCst);
+		thread::spawn(move || doomed.ping());
+		let started = Instant::now();
+		while !matches!(peer.next_broadcast().unwrap(), Message::PeerLeft(_)) {}
+		loop {
+			match peer.request(Message::StatusReq).unwrap() {
+				Message::StatusResp(StatusResult::Ok(status)) if status.connections == 1 => break,
+				Message::StatusResp(StatusResult::Ok(_)) => {
+					assert!(
+						started.elapsed() < Duration::from_secs(5),
+						"Connection counted"
+					)
+				}
+				other => panic!("Unexpected response {:?}", other),
+			}
+			sleep(Duration::from_millis(10));
+		}
+
+		// Whatever the session held may be broken, so it is dropped rather
+		// than parked, along with its cursor
+		let (returning, _) = connect_panicking(&shared);
+		assert!(matches!(
+			returning.request(Message::ResumeReq(token)).unwrap(),
+			Message::ResumeResp(ResumeResult::Err(_))
+		));
+		assert_eq!(peer.cursors().unwrap().1.len(), 1);
+		assert_eq!(peer.read(0, usize::MAX).unwrap(), b"before");
+		peer.close().unwrap();
+		peer.delete("file.txt").unwrap();
+		fs::remove_dir_all(&home).ok();
+	}
+
+	// One end of a connection which has agreed to deflate both ways
+	#[cfg(feature = "client")]
+	struct Deflated {
+		input: ThreadIn<MemoryStream>,
+		output: flate2::write::DeflateEncoder<MemoryStream>,
+		broadcasts: Vec<Message>,
+	}
+
+	#[cfg(feature = "client")]
+	impl Deflated {
+		fn connect(shared: &SharedState<MemoryStream>) -> Deflated {
+			let mut stream = connect_in_memory(shared).unwrap();
+			let mut input = ThreadIn::new(stream.clone(), 1 << 20).unwrap();
+			stream
+				.write_all(&Message::CompressReq.to_vec().unwrap())
+				.unwrap();
+			assert!(matches!(
+				input.get_message().unwrap(),
+				Message::CompressResp(CompressResult::Ok)
+			));
+			input.decompress();
+			Deflated {
+				input,
+				output: flate2::write::DeflateEncoder::new(stream, flate2::Compression::fast()),
+				broadcasts: Vec::new(),
+			}
+		}
+
+		fn request(&mut self, request: Message) -> Message {
+			self.output.write_all(&request.to_vec().unwrap()).unwrap();
+			self.output.flush().unwrap();
+			loop {
+				let msg = self.input.get_message().unwrap();
+				if !msg.is_broadcast() {
+					return msg;
+				}
+				self.broadcasts.push(msg);
+			}
+		}
+	}
+
+	#[cfg(feature = "client")]
+	#[test]
+	fn compressed_and_plain_clients_share_a_file() {
+		use crate::message::{
+			OpenReqData, OpenResult, ReadReqData, ReadResult, UpdateData, WriteReqData, WriteResult,
+		};
+		use crate::text_client::Client;
+
+		let home = std::env::temp_dir().join(format!("editr-deflate-{}", std::process::id()));
+		fs::create_dir_all(&home).unwrap();
+		fs::write(home.join("file.txt"), "").unwrap();
+		let shared = start_in_memory(&home, ServerConfig::default()).unwrap();
+
+		let mut deflated = Deflated::connect(&shared);
+		let plain = Client::new(connect_in_memory(&shared).unwrap()).unwrap();
+		let open = Message::OpenReq(OpenReqData {
+			file: "file.txt".to_string(),
+			name: None,
+		});
+		assert!(matches!(
+			deflated.request(open),
+			Message::OpenResp(OpenResult::Ok(_))
+		));
+		plain.open("file.txt", None).unwrap();
+
+		// Enough to be worth deflating, sent as many small messages
+		for i in 0..100 {
+			let write = Message::WriteReq(WriteReqData {
+				offset: i * 6,
+				data: b"hello ".to_vec(),
+			});
+			assert!(matches!(
+				deflated.request(write),
+				Message::WriteResp(WriteResult::Ok)
+			));
+		}
+		plain.write_at(600, b"world").unwrap();
+		let expected = format!("{}world", "hello ".repeat(100)).into_bytes();
+		assert_eq!(plain.read(0, usize::MAX).unwrap(), expected);
+
+		// The plain client's edit reaches the deflated one as a broadcast
+		let read = Message::ReadReq(ReadReqData {
+			offset: 0,
+			len: usize::MAX,
+		});
+		match deflated.request(read) {
+			Message::ReadResp(ReadResult::Ok(read)) => assert_eq!(read.data, expected),
+			other => panic!("Unexpected response {:?}", other),
+		}
+		while !deflated.broadcasts.iter().any(
+			|msg| matches!(msg, Message::UpdateMessage(UpdateData::Add(add)) if add.data == b"world"),
+		) {
+			let msg = deflated.input.get_message().unwrap();
+			deflated.broadcasts.push(msg);
+		}
+
+		fs::remove_dir_all(&home).ok();
+	}
+
+	// A stream which can't be cloned, as a socket can't once descriptors
+	// run out
+	struct Unclonable(MemoryStream);
+
+	impl Read for Unclonable {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+	}
+
+	impl Write for Unclonable {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
+
+		fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+	}
+
+	impl Transport for Unclonable {
+		fn try_clone(&self) -> io::Result<Self> { Err(io::Error::from_raw_os_error(libc::EMFILE)) }
+
+		fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.0.shutdown(how) }
+
+		fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> { Ok(()) }
+
+		fn peer_addr(&self) -> Option<SocketAddr> { None }
+	}
+
+	#[test]
+	fn connections_which_fail_to_set_up_are_let_go() {
+		let shared: SharedState<Unclonable> = SharedState::new(
+			ServerConfig::default(),
+			std::env::temp_dir(),
+			FileStates::new(),
+		)
+		.unwrap();
+		let (mut client, server) = MemoryStream::pair();
+		let slot = shared.acquire_slot().unwrap();
+		assert_eq!(shared.metrics.connections(), 1);
+
+		// Returns rather than panicking, giving back the slot and stream
+		serve_connection(shared.clone(), Unclonable(server), slot);
+		assert_eq!(shared.metrics.connections(), 0);
+		assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+		assert!(shared.acquire_slot().is_some());
+	}
 }
@@ -0,0 +1,59 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+
+use common::TestServer;
+
+#[test]
+fn opened_paths_can_be_sent_back() {
+	let server = TestServer::start();
+	let first = server.open("file.txt", b"hello");
+	let path = first.open("file.txt", None).unwrap();
+	assert!(path.is_absolute());
+
+	let second = server.connect();
+	assert_eq!(second.open(path.to_str().unwrap(), None).unwrap(), path);
+	assert_eq!(second.read(0, 5).unwrap(), b"hello");
+}
+
+#[test]
+fn absolute_paths_within_the_home_are_accepted_everywhere() {
+	let server = TestServer::start();
+	let home = server.home().canonicalize().unwrap();
+	let client = server.connect();
+	let path = |name: &str| home.join(name).to_str().unwrap().to_string();
+
+	client.mkdir(&path("dir"), false).unwrap();
+	client
+		.create(&path("dir/new.txt"), false, None, false)
+		.unwrap();
+	assert!(home.join("dir/new.txt").exists());
+	client
+		.rename(&path("dir/new.txt"), &path("dir/renamed.txt"))
+		.unwrap();
+	assert!(home.join("dir/renamed.txt").exists());
+	client.delete(&path("dir/renamed.txt")).unwrap();
+	assert!(!home.join("dir/renamed.txt").exists());
+}
+
+#[test]
+fn absolute_paths_outside_the_home_are_refused() {
+	let server = TestServer::start();
+	let outside = server
+		.home()
+		.parent()
+		.unwrap()
+		.join("editr-absolute-outside");
+	fs::write(&outside, "secret").unwrap();
+	let client = server.connect();
+
+	let result = client.open(outside.to_str().unwrap(), None);
+	fs::remove_file(&outside).ok();
+	assert!(result.is_err());
+	assert!(client
+		.create(outside.to_str().unwrap(), false, None, false)
+		.is_err());
+	assert!(!outside.exists());
+}
@@ -0,0 +1,54 @@
+#![cfg(all(feature = "client", unix))]
+
+mod common;
+
+use std::fs::File;
+use std::net::TcpStream;
+use std::thread::sleep;
+use std::time::Duration;
+
+use common::{Peer, TestServer};
+
+// Lowers the limit on open files for the whole test process, which has
+// this one test to itself
+fn limit_open_files(limit: libc::rlim_t) -> libc::rlimit {
+	let mut old = libc::rlimit {
+		rlim_cur: 0,
+		rlim_max: 0,
+	};
+	unsafe {
+		assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut old), 0);
+		let new = libc::rlimit {
+			rlim_cur: limit.min(old.rlim_max),
+			rlim_max: old.rlim_max,
+		};
+		assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &new), 0);
+	}
+	old
+}
+
+#[test]
+fn running_out_of_files_is_waited_out() {
+	let server = TestServer::start();
+	let talker = server.connect();
+	talker.ping().unwrap();
+
+	// Uses up every descriptor but one, which the new connection takes, so
+	// accepting it fails with EMFILE
+	let old = limit_open_files(256);
+	let mut hogs = Vec::new();
+	while let Ok(file) = File::open("/dev/null") {
+		hogs.push(file);
+	}
+	hogs.pop();
+	let stream = TcpStream::connect(server.local_addr()).unwrap();
+	sleep(Duration::from_millis(200));
+	drop(hogs);
+	unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &old) };
+
+	// The server backs off, then accepts the connection it failed to
+	let late = Peer::new(stream);
+	late.ping().unwrap();
+	talker.ping().unwrap();
+	server.connect().ping().unwrap();
+}
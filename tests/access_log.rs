@@ -0,0 +1,55 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+
+use editr::config::{AccessLogConfig, ServerConfig};
+
+use common::{temp_home, TestServer};
+
+#[test]
+fn operations_are_logged_without_their_contents() {
+	let log_dir = temp_home();
+	let log_path = log_dir.join("access.log");
+	let server = TestServer::with_config(ServerConfig {
+		access_log: Some(AccessLogConfig {
+			path: log_path.clone(),
+			max_size: u64::MAX,
+		}),
+		..ServerConfig::default()
+	});
+	let client = server.open("file.txt", b"");
+	client.write_at(0, b"secret").unwrap();
+	client.read(2, 3).unwrap();
+	assert!(client.remove(100, 1).is_err());
+	// Stopping writes out whatever is still queued
+	fs::remove_dir_all(server.stop()).ok();
+
+	let log = fs::read_to_string(&log_path).unwrap();
+	fs::remove_dir_all(&log_dir).ok();
+	assert!(!log.contains("secret"));
+	let lines: Vec<Vec<&str>> = log.lines().map(|line| line.split(' ').collect()).collect();
+	let find = |kind: &str| {
+		lines
+			.iter()
+			.find(|line| line[3] == kind)
+			.unwrap_or_else(|| panic!("No {} in {}", kind, log))
+	};
+
+	let open = find("OpenReq");
+	assert!(open.contains(&"path=file.txt"));
+	assert_eq!(open.last(), Some(&"ok"));
+	let write = find("WriteReq");
+	assert!(write[4].ends_with("file.txt"));
+	assert!(write.contains(&"offset=0") && write.contains(&"len=6"));
+	assert_eq!(write.last(), Some(&"ok"));
+	let read = find("ReadReq");
+	assert!(read.contains(&"offset=2") && read.contains(&"len=3"));
+	let remove = find("RemoveReq");
+	assert_eq!(remove.last(), Some(&"error"));
+	// Every line starts with when it happened
+	for line in &lines {
+		assert!(line[0].parse::<f64>().is_ok(), "{:?}", line);
+	}
+}
@@ -0,0 +1,46 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use editr::config::{Cidr, ServerConfig};
+use editr::message::Message;
+
+use common::TestServer;
+
+fn filtered(allow: &[&str], deny: &[&str], refusal_notice: bool) -> TestServer {
+	let ranges = |ranges: &[&str]| -> Vec<Cidr> {
+		ranges.iter().map(|range| range.parse().unwrap()).collect()
+	};
+	TestServer::with_config(ServerConfig {
+		allow: ranges(allow),
+		deny: ranges(deny),
+		refusal_notice,
+		..ServerConfig::default()
+	})
+}
+
+// Everything the server sends before closing the connection
+fn replies(server: &TestServer) -> Vec<Message> { server.connect().broadcasts_until_closed() }
+
+#[test]
+fn allowed_clients_are_served() {
+	let server = filtered(&["127.0.0.0/8"], &[], false);
+	server.connect().ping().unwrap();
+	// Without an allow list, everyone not denied is allowed
+	let server = filtered(&[], &["10.0.0.0/8"], false);
+	server.connect().ping().unwrap();
+}
+
+#[test]
+fn denied_clients_are_turned_away() {
+	// Denying wins over allowing
+	let server = filtered(&["127.0.0.0/8"], &["127.0.0.1"], false);
+	assert!(replies(&server).is_empty());
+
+	// As are those missing from the allow list
+	let server = filtered(&["10.0.0.0/8"], &[], false);
+	assert!(replies(&server).is_empty());
+
+	let server = filtered(&[], &["127.0.0.0/8"], true);
+	assert!(matches!(replies(&server)[..], [Message::Refused]));
+}
@@ -0,0 +1,128 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use editr::config::ServerConfig;
+use editr::message::{
+	AdminKickReqData, AdminKickResult, AdminListResult, AdminTraceReqData, AdminTraceResult,
+	DisconnectingData, Message,
+};
+use editr::state::{ClientId, ConnectionInfo, Direction, DisconnectReason, TRACE_LEN};
+use editr::text_client::Client;
+
+use common::TestServer;
+
+const ADMIN_TOKEN: &str = "admin";
+
+fn admin_server() -> TestServer {
+	TestServer::with_config(ServerConfig {
+		admin_token: Some(ADMIN_TOKEN.to_string()),
+		..ServerConfig::default()
+	})
+}
+
+fn connections(admin: &Client) -> Vec<ConnectionInfo> {
+	match admin
+		.request(Message::AdminListReq(ADMIN_TOKEN.to_string()))
+		.unwrap()
+	{
+		Message::AdminListResp(AdminListResult::Ok(connections)) => connections,
+		other => panic!("Unexpected response {:?}", other),
+	}
+}
+
+fn kick(admin: &Client, token: &str, client_id: ClientId) -> AdminKickResult {
+	let request = Message::AdminKickReq(AdminKickReqData {
+		token: token.to_string(),
+		client_id,
+		reason: "Misbehaving".to_string(),
+	});
+	match admin.request(request).unwrap() {
+		Message::AdminKickResp(result) => result,
+		other => panic!("Unexpected response {:?}", other),
+	}
+}
+
+#[test]
+fn kicked_clients_are_told_and_their_peers_see_them_leave() {
+	let server = admin_server();
+	let survivor = server.open("file.txt", b"");
+	let victim = server.open("file.txt", b"");
+	victim.write_at(0, b"x").unwrap();
+	survivor.next_update();
+	let admin = server.connect();
+
+	let listed = connections(&admin);
+	assert_eq!(listed.len(), 3);
+	let target = listed
+		.iter()
+		.find(|connection| connection.metrics.edits == 1)
+		.unwrap()
+		.id;
+	assert!(matches!(
+		kick(&admin, ADMIN_TOKEN, target),
+		AdminKickResult::Ok
+	));
+
+	assert!(matches!(
+		victim.next_broadcast(),
+		Message::Disconnecting(DisconnectingData {
+			reason: DisconnectReason::Kicked,
+			..
+		})
+	));
+	assert!(matches!(survivor.next_broadcast(), Message::PeerLeft(_)));
+	assert!(victim.ping().is_err());
+	survivor.ping().unwrap();
+}
+
+#[test]
+fn kicking_needs_the_admin_token() {
+	let server = admin_server();
+	let victim = server.open("file.txt", b"");
+	let admin = server.connect();
+	let target = connections(&admin)
+		.iter()
+		.find(|connection| connection.opened_file.is_some())
+		.unwrap()
+		.id;
+
+	assert!(matches!(
+		kick(&admin, "guess", target),
+		AdminKickResult::Err(_)
+	));
+	victim.ping().unwrap();
+}
+
+#[test]
+fn traces_keep_only_the_latest_messages() {
+	let server = admin_server();
+	let traced = server.open("file.txt", b"");
+	for _ in 0..TRACE_LEN {
+		traced.ping().unwrap();
+	}
+	traced.write_at(0, b"x").unwrap();
+	let admin = server.connect();
+	let target = connections(&admin)
+		.iter()
+		.find(|connection| connection.opened_file.is_some())
+		.unwrap()
+		.id;
+
+	let request = Message::AdminTraceReq(AdminTraceReqData {
+		token: ADMIN_TOKEN.to_string(),
+		client_id: target,
+	});
+	let trace = match admin.request(request).unwrap() {
+		Message::AdminTraceResp(AdminTraceResult::Ok(trace)) => trace,
+		other => panic!("Unexpected response {:?}", other),
+	};
+	assert_eq!(trace.len(), TRACE_LEN);
+	// Opening the file has rotated out behind the pings
+	assert!(trace.iter().all(|entry| !entry.kind.contains("Open")));
+	let last = &trace[TRACE_LEN - 2..];
+	assert_eq!(last[0].direction, Direction::Received);
+	assert_eq!(last[0].kind, "WriteReq");
+	assert_eq!(last[1].direction, Direction::Sent);
+	assert_eq!(last[1].kind, "WriteResp");
+}
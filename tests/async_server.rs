@@ -0,0 +1,57 @@
+#![cfg(feature = "async-net")]
+
+mod common;
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use editr::async_server;
+use editr::config::ServerConfig;
+use editr::message::Message;
+
+use common::{temp_home, Peer};
+
+// Connects to address, waiting for the server to start listening there
+fn connect(address: SocketAddr) -> Peer {
+	let deadline = Instant::now() + Duration::from_secs(5);
+	loop {
+		match TcpStream::connect(address) {
+			Ok(stream) => return Peer::new(stream),
+			Err(e) => assert!(Instant::now() < deadline, "Failed to connect: {}", e),
+		}
+		sleep(Duration::from_millis(20));
+	}
+}
+
+// An address on loopback which nothing is listening on
+fn free_address() -> SocketAddr {
+	TcpListener::bind("127.0.0.1:0")
+		.unwrap()
+		.local_addr()
+		.unwrap()
+}
+
+#[test]
+fn serves_every_address_up_to_the_workers() {
+	let home = temp_home();
+	let addresses = [free_address(), free_address()];
+	let config = ServerConfig {
+		workers: Some(1),
+		..ServerConfig::default()
+	};
+	// Runs until the process exits, as nothing signals it
+	thread::spawn(move || async_server::start_with_config(&home, &addresses[..], config));
+
+	let first = connect(addresses[0]);
+	first.ping().unwrap();
+	let second = connect(addresses[1]);
+	assert!(matches!(second.next_broadcast(), Message::ServerBusy));
+
+	drop(first);
+	let deadline = Instant::now() + Duration::from_secs(5);
+	while connect(addresses[1]).ping().is_err() {
+		assert!(Instant::now() < deadline, "The worker never freed up");
+		sleep(Duration::from_millis(20));
+	}
+}
@@ -0,0 +1,57 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use common::TestServer;
+use editr::config::ServerConfig;
+use editr::message::Message;
+
+fn config(quota: Option<u64>) -> ServerConfig {
+	ServerConfig {
+		autosave: Some(Duration::from_millis(100)),
+		quota,
+		..Default::default()
+	}
+}
+
+// Waits for the file at path to hold contents, returning whether it did
+fn saved(path: &std::path::Path, contents: &[u8]) -> bool {
+	let started = Instant::now();
+	while started.elapsed() < Duration::from_secs(5) {
+		if fs::read(path).unwrap() == contents {
+			return true;
+		}
+		sleep(Duration::from_millis(50));
+	}
+	false
+}
+
+#[test]
+fn unsaved_edits_are_saved_for_the_client() {
+	let server = TestServer::with_config(config(None));
+	let client = server.open("file.txt", b"draft");
+
+	client.write_at(5, b" two").unwrap();
+	assert!(saved(&server.home().join("file.txt"), b"draft two"));
+	client.write_at(0, b"final ").unwrap();
+	assert!(saved(&server.home().join("file.txt"), b"final draft two"));
+	assert!(client.take_broadcasts().is_empty());
+}
+
+#[test]
+fn autosaves_stay_within_the_quota() {
+	let server = TestServer::with_config(config(Some(20)));
+	let client = server.open("file.txt", b"small");
+
+	client.write_at(5, &[b'!'; 100]).unwrap();
+	// Those in the file are told once, not on every attempt
+	let told = client.next_broadcast();
+	assert!(matches!(told, Message::SaveFailed(_)), "{:?}", told);
+	sleep(Duration::from_millis(1500));
+	assert!(client.take_broadcasts().is_empty());
+	assert_eq!(fs::read(server.home().join("file.txt")).unwrap(), b"small");
+}
@@ -0,0 +1,79 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+use std::process::{Command, Output};
+
+use common::{Replica, TestServer};
+
+fn cli(server: &TestServer, args: &[&str]) -> Output {
+	Command::new(env!("CARGO_BIN_EXE_editr-cli"))
+		.arg(server.local_addr().to_string())
+		.args(args)
+		.output()
+		.expect("Failed to run editr-cli")
+}
+
+#[test]
+fn binary_contents_are_read_out_as_they_are() {
+	let server = TestServer::start();
+	let contents = [0, 159, 146, 150, b'\n', 255, b'x'];
+	fs::write(server.home().join("file.bin"), contents).unwrap();
+
+	let output = cli(&server, &["read", "file.bin"]);
+	assert!(output.status.success());
+	assert_eq!(output.stdout, contents);
+	let output = cli(
+		&server,
+		&["read", "file.bin", "--offset", "5", "--len", "1"],
+	);
+	assert_eq!(output.stdout, [255]);
+}
+
+#[test]
+fn writes_reach_those_with_the_file_open() {
+	let server = TestServer::start();
+	let peer = server.open("file.txt", b"hello");
+	let data = server.home().join("data");
+	fs::write(&data, b" world").unwrap();
+
+	let output = cli(
+		&server,
+		&[
+			"--json",
+			"write",
+			"file.txt",
+			"--offset",
+			"5",
+			"--data-file",
+			data.to_str().unwrap(),
+		],
+	);
+	assert!(output.status.success(), "{:?}", output);
+	let reply: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+	assert_eq!(reply["saved"], true);
+
+	let mut replica = Replica::new(b"hello");
+	while replica.data != b"hello world" {
+		replica.apply(&peer.next_update());
+	}
+	assert_eq!(peer.read(0, 100).unwrap(), b"hello world");
+	assert_eq!(
+		fs::read(server.home().join("file.txt")).unwrap(),
+		b"hello world"
+	);
+}
+
+#[test]
+fn failures_exit_with_their_own_status() {
+	let server = TestServer::start();
+	let _peer = server.open("open.txt", b"");
+
+	let status = |args: &[&str]| cli(&server, args).status.code().unwrap();
+	assert_eq!(status(&["read", "missing.txt"]), 3);
+	assert_eq!(status(&["delete", "open.txt"]), 6);
+	assert_eq!(status(&["list"]), 0);
+	// Usage errors exit as clap has them
+	assert_eq!(status(&["read"]), 2);
+}
@@ -0,0 +1,385 @@
+// Helpers shared by the integration tests, which drive the server only
+// through text_client. Not every test uses every helper
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[cfg(feature = "async-net")]
+use editr::async_server;
+use editr::config::ServerConfig;
+use editr::message::{Message, UpdateData};
+#[cfg(feature = "async-net")]
+use editr::state::TaskStream;
+use editr::state::{FileStates, Transport};
+use editr::text_client::Client;
+use editr::text_server::{self, ServerHandle};
+
+// How long to wait for a broadcast before deciding it is never coming
+pub const BROADCAST_WAIT: Duration = Duration::from_secs(5);
+
+// A server on an ephemeral port over a temporary home, both of which go
+// away when it is dropped. With EDITR_TEST_SERVER=async, and async-net
+// built, it is async_server's rather than text_server's
+pub struct TestServer {
+	home: PathBuf,
+	handle: Option<Handle>,
+}
+
+// Whichever server is running
+enum Handle {
+	Threads(ServerHandle),
+	#[cfg(feature = "async-net")]
+	Tasks(ServerHandle<TaskStream>),
+}
+
+impl Handle {
+	// Whether the tests were asked to run against async_server
+	fn asynchronous() -> bool {
+		cfg!(feature = "async-net")
+			&& std::env::var("EDITR_TEST_SERVER").is_ok_and(|server| server == "async")
+	}
+
+	fn spawn(home: &Path, config: ServerConfig) -> Handle {
+		#[cfg(feature = "async-net")]
+		if Handle::asynchronous() {
+			return Handle::Tasks(
+				async_server::spawn(home, "127.0.0.1:0", config).expect("Failed to start server"),
+			);
+		}
+		Handle::Threads(
+			text_server::spawn(home, "127.0.0.1:0", config).expect("Failed to start server"),
+		)
+	}
+
+	fn serve_files(home: &Path, config: ServerConfig, files: FileStates) -> Handle {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		#[cfg(feature = "async-net")]
+		if Handle::asynchronous() {
+			return Handle::Tasks(
+				async_server::serve_files(home, vec![listener], config, files)
+					.expect("Failed to start server"),
+			);
+		}
+		Handle::Threads(
+			text_server::serve_files(home, vec![listener], config, files)
+				.expect("Failed to start server"),
+		)
+	}
+
+	fn local_addr(&self) -> SocketAddr {
+		match self {
+			Handle::Threads(handle) => handle.local_addr(),
+			#[cfg(feature = "async-net")]
+			Handle::Tasks(handle) => handle.local_addr(),
+		}
+	}
+
+	fn files(&self) -> &FileStates {
+		match self {
+			Handle::Threads(handle) => handle.files(),
+			#[cfg(feature = "async-net")]
+			Handle::Tasks(handle) => handle.files(),
+		}
+	}
+
+	fn stop(self) -> editr::error::EditrResult<()> {
+		match self {
+			Handle::Threads(handle) => handle.stop(),
+			#[cfg(feature = "async-net")]
+			Handle::Tasks(handle) => handle.stop(),
+		}
+	}
+}
+
+impl TestServer {
+	pub fn start() -> TestServer { TestServer::with_config(ServerConfig::default()) }
+
+	pub fn with_config(config: ServerConfig) -> TestServer {
+		let home = temp_home();
+		TestServer::in_home(home, config)
+	}
+
+	// Serves a home which has already been filled in
+	pub fn in_home(home: PathBuf, config: ServerConfig) -> TestServer {
+		let handle = Handle::spawn(&home, config);
+		TestServer {
+			home,
+			handle: Some(handle),
+		}
+	}
+
+	// Serves files, such as ones saved through a SyncHook, over a new home
+	pub fn with_files(config: ServerConfig, files: FileStates) -> TestServer {
+		let home = temp_home();
+		let handle = Handle::serve_files(&home, config, files);
+		TestServer {
+			home,
+			handle: Some(handle),
+		}
+	}
+
+	pub fn home(&self) -> &Path { &self.home }
+
+	fn handle(&self) -> &Handle { self.handle.as_ref().unwrap() }
+
+	// The address the server is listening on
+	pub fn local_addr(&self) -> SocketAddr { self.handle().local_addr() }
+
+	// The files being served, with their unsaved edits
+	pub fn files(&self) -> &FileStates { self.handle().files() }
+
+	pub fn connect(&self) -> Peer {
+		let stream = TcpStream::connect(self.local_addr()).expect("Failed to connect");
+		Peer::new(stream)
+	}
+
+	// Connects over a Wire, returning it too so the test can make the
+	// client misbehave
+	pub fn connect_wire(&self) -> (Peer<Wire>, Wire) {
+		let stream = TcpStream::connect(self.local_addr()).expect("Failed to connect");
+		let wire = Wire::new(stream);
+		let peer = Peer::new(wire.try_clone().unwrap());
+		(peer, wire)
+	}
+
+	// Connects and opens file, creating it with contents first if need be
+	pub fn open(&self, file: &str, contents: &[u8]) -> Peer {
+		let client = self.connect();
+		if !self.home.join(file).exists() {
+			fs::write(self.home.join(file), contents).unwrap();
+		}
+		client.open(file, None).expect("Failed to open");
+		client
+	}
+
+	// Shuts the server down but keeps its home, returning it
+	pub fn stop(mut self) -> PathBuf {
+		self.handle.take().unwrap().stop().expect("Server failed");
+		std::mem::take(&mut self.home)
+	}
+}
+
+impl Drop for TestServer {
+	fn drop(&mut self) {
+		if let Some(handle) = self.handle.take() {
+			handle.stop().ok();
+		}
+		if !self.home.as_os_str().is_empty() {
+			fs::remove_dir_all(&self.home).ok();
+		}
+	}
+}
+
+// An empty directory no other test is using
+pub fn temp_home() -> PathBuf {
+	static COUNT: AtomicUsize = AtomicUsize::new(0);
+	let home = std::env::temp_dir().join(format!(
+		"editr-test-{}-{}",
+		std::process::id(),
+		COUNT.fetch_add(1, Ordering::Relaxed)
+	));
+	fs::remove_dir_all(&home).ok();
+	fs::create_dir_all(&home).unwrap();
+	home
+}
+
+// A copy of a file kept up to date from the broadcasts about it
+pub struct Replica {
+	pub data: Vec<u8>,
+}
+
+impl Replica {
+	pub fn new(data: &[u8]) -> Replica {
+		Replica {
+			data: data.to_vec(),
+		}
+	}
+
+	pub fn apply(&mut self, update: &UpdateData) {
+		match update {
+			UpdateData::Add(add) => {
+				self.data
+					.splice(add.offset..add.offset, add.data.iter().copied());
+			}
+			UpdateData::Remove(remove) => {
+				self.data.drain(remove.offset..remove.offset + remove.len);
+			}
+			UpdateData::Replace(replace) => {
+				self.data.splice(
+					replace.offset..replace.offset + replace.len,
+					replace.data.iter().copied(),
+				);
+			}
+		}
+	}
+
+	// Applies the next count updates broadcast to peer
+	pub fn follow<T: Transport>(&mut self, peer: &Peer<T>, count: usize) {
+		for _ in 0..count {
+			self.apply(&peer.next_update());
+		}
+	}
+}
+
+// A client whose broadcasts can be waited for with a time limit, so a
+// missing broadcast fails the test rather than hanging it
+pub struct Peer<T: Transport = TcpStream> {
+	client: Client<T>,
+	broadcasts: Receiver<Message>,
+}
+
+impl<T: Transport> Peer<T> {
+	pub fn new(stream: T) -> Peer<T> {
+		let (sender, broadcasts) = channel();
+		let client = Client::with_handler(stream, move |msg| {
+			sender.send(msg).ok();
+		})
+		.expect("Failed to start client");
+		Peer { client, broadcasts }
+	}
+
+	// Waits for the next broadcast, failing the test if none comes in time
+	pub fn next_broadcast(&self) -> Message {
+		self.broadcasts
+			.recv_timeout(BROADCAST_WAIT)
+			.expect("No broadcast arrived")
+	}
+
+	// The next broadcast if one has already arrived, or is on its way
+	pub fn try_next_broadcast(&self) -> Result<Message, RecvTimeoutError> {
+		self.broadcasts.recv_timeout(Duration::from_millis(500))
+	}
+
+	// Waits for the next update, skipping other broadcasts
+	pub fn next_update(&self) -> UpdateData {
+		loop {
+			if let Message::UpdateMessage(update) = self.next_broadcast() {
+				return update;
+			}
+		}
+	}
+
+	// Takes every broadcast which has arrived, without waiting
+	pub fn take_broadcasts(&self) -> Vec<Message> { self.broadcasts.try_iter().collect() }
+
+	// Waits for the server to close the connection, returning every
+	// broadcast sent before it did
+	pub fn broadcasts_until_closed(&self) -> Vec<Message> {
+		let mut broadcasts = Vec::new();
+		loop {
+			match self.broadcasts.recv_timeout(BROADCAST_WAIT) {
+				Ok(msg) => broadcasts.push(msg),
+				Err(RecvTimeoutError::Disconnected) => return broadcasts,
+				Err(RecvTimeoutError::Timeout) => panic!("Connection was never closed"),
+			}
+		}
+	}
+}
+
+impl<T: Transport> Deref for Peer<T> {
+	type Target = Client<T>;
+
+	fn deref(&self) -> &Client<T> { &self.client }
+}
+
+// What a Wire has been told to do, shared by its clones
+#[derive(Default)]
+struct Faults {
+	silent: AtomicBool,
+	stalled: AtomicBool,
+	garbled: AtomicUsize,
+}
+
+// A connection which can be made to misbehave as text_client never would,
+// so the server's handling of broken clients is tested through the client
+pub struct Wire {
+	stream: TcpStream,
+	faults: Arc<Faults>,
+}
+
+impl Wire {
+	pub fn new(stream: TcpStream) -> Wire {
+		Wire {
+			stream,
+			faults: Arc::default(),
+		}
+	}
+
+	// Drops everything written from now on, as a client which vanished would
+	pub fn silence(&self) { self.faults.silent.store(true, Ordering::SeqCst); }
+
+	// Stops reading, leaving what the server sends to pile up
+	pub fn stall(&self) { self.faults.stalled.store(true, Ordering::SeqCst); }
+
+	pub fn resume(&self) { self.faults.stalled.store(false, Ordering::SeqCst); }
+
+	// Sends a line which isn't json in place of each of the next count
+	// messages
+	pub fn garble(&self, count: usize) { self.faults.garbled.store(count, Ordering::SeqCst); }
+}
+
+impl Read for Wire {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while self.faults.stalled.load(Ordering::SeqCst) {
+			sleep(Duration::from_millis(10));
+		}
+		self.stream.read(buf)
+	}
+}
+
+impl Write for Wire {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.faults.silent.load(Ordering::SeqCst) {
+			return Ok(buf.len());
+		}
+		self.stream.write(buf)
+	}
+
+	// The client writes each message whole, so this is where one is swapped
+	fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+		let garbled = self
+			.faults
+			.garbled
+			.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+				count.checked_sub(1)
+			})
+			.is_ok();
+		if garbled {
+			self.stream.write_all(b"this is not json\n")
+		}
+		else if self.faults.silent.load(Ordering::SeqCst) {
+			Ok(())
+		}
+		else {
+			self.stream.write_all(buf)
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> { self.stream.flush() }
+}
+
+impl Transport for Wire {
+	fn try_clone(&self) -> io::Result<Self> {
+		Ok(Wire {
+			stream: self.stream.try_clone()?,
+			faults: self.faults.clone(),
+		})
+	}
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.stream.shutdown(how) }
+
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		self.stream.set_write_timeout(timeout)
+	}
+
+	fn peer_addr(&self) -> Option<SocketAddr> { self.stream.peer_addr().ok() }
+}
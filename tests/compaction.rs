@@ -0,0 +1,66 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use editr::config::ServerConfig;
+use editr::state::FileStats;
+
+use common::TestServer;
+
+const INSERTS: usize = 2000;
+const INSERT_LEN: usize = 64;
+
+fn stats(server: &TestServer) -> FileStats {
+	let mut stats = server.files().stats().unwrap();
+	assert_eq!(stats.len(), 1);
+	stats.pop().unwrap()
+}
+
+#[test]
+fn idle_files_are_compacted_without_changing() {
+	let server = TestServer::with_config(ServerConfig {
+		compact_after: Some(Duration::from_secs(1)),
+		..ServerConfig::default()
+	});
+	let client = server.open("file.txt", b"");
+
+	// Typing at the front again and again leaves the rope lopsided
+	for i in 0..INSERTS {
+		client
+			.write_at(0, &[b'a' + (i % 26) as u8; INSERT_LEN])
+			.unwrap();
+	}
+	let before = stats(&server);
+	let contents = client.read(0, usize::MAX).unwrap();
+	assert_eq!(before.last_compacted, None);
+
+	let started = Instant::now();
+	let after = loop {
+		let stats = stats(&server);
+		if stats.last_compacted.is_some() {
+			break stats;
+		}
+		assert!(
+			started.elapsed() < Duration::from_secs(5),
+			"Never compacted"
+		);
+		sleep(Duration::from_millis(100));
+	};
+	// Compacting waits for the file to have gone idle
+	assert!(started.elapsed() >= Duration::from_millis(500));
+	assert!(
+		after.depth < before.depth,
+		"{} -> {}",
+		before.depth,
+		after.depth
+	);
+	assert_eq!(after.bytes, (INSERTS * INSERT_LEN) as u64);
+	assert_eq!(client.read(0, usize::MAX).unwrap(), contents);
+
+	// Edits carry on as normal afterwards
+	client.write_at(0, b"!").unwrap();
+	assert_eq!(client.read(0, 1).unwrap(), b"!");
+}
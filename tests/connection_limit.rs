@@ -0,0 +1,58 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use editr::config::ServerConfig;
+use editr::message::Message;
+
+use common::{Peer, TestServer};
+
+fn config(max_connections: usize) -> ServerConfig {
+	ServerConfig {
+		max_connections: Some(max_connections),
+		..ServerConfig::default()
+	}
+}
+
+fn turned_away(peer: &Peer) -> bool {
+	peer.ping().is_err() && matches!(peer.try_next_broadcast(), Ok(Message::ServerBusy))
+}
+
+#[test]
+fn clients_past_the_limit_are_told_busy() {
+	let server = TestServer::with_config(config(2));
+	let first = server.open("file.txt", b"");
+	let second = server.connect();
+	second.ping().unwrap();
+
+	let third = server.connect();
+	assert!(turned_away(&third));
+
+	// Those already connected carry on as before
+	first.write_at(0, b"still here").unwrap();
+	second.open("file.txt", None).unwrap();
+	assert_eq!(second.read(0, 100).unwrap(), b"still here");
+}
+
+#[test]
+fn leaving_makes_room() {
+	let server = TestServer::with_config(config(1));
+	let first = server.connect();
+	first.ping().unwrap();
+	assert!(turned_away(&server.connect()));
+	drop(first);
+
+	// The slot comes back once the server has finished with the connection
+	let started = Instant::now();
+	loop {
+		let next = server.connect();
+		if next.ping().is_ok() {
+			break;
+		}
+		assert!(started.elapsed() < Duration::from_secs(5));
+		sleep(Duration::from_millis(20));
+	}
+}
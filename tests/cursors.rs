@@ -0,0 +1,76 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use editr::message::UpdateData;
+
+use common::{Replica, TestServer};
+
+#[test]
+fn typing_at_cursor_converges() {
+	let server = TestServer::start();
+	let typist = server.open("shared.txt", b"hello world");
+	let watcher = server.open("shared.txt", b"");
+	let mut replica = Replica::new(&watcher.read(0, usize::MAX).unwrap());
+
+	typist.move_cursor(5).unwrap();
+	for &c in b", there" {
+		typist.write_at_cursor(&[c]).unwrap();
+	}
+	typist.remove_at_cursor(1).unwrap();
+	// Updates may be merged on the way, so follow until the contents match
+	while replica.data != b"hello, thereworld" {
+		replica.apply(&watcher.next_update());
+	}
+
+	let contents = watcher.read(0, usize::MAX).unwrap();
+	assert_eq!(contents, b"hello, thereworld");
+	assert_eq!(replica.data, contents);
+	assert_eq!(typist.read(0, usize::MAX).unwrap(), contents);
+}
+
+#[test]
+fn cursor_updates_carry_effective_offset() {
+	let server = TestServer::start();
+	let typist = server.open("shared.txt", b"abc");
+	let watcher = server.open("shared.txt", b"");
+
+	// A cursor past the end writes at the end
+	typist.move_cursor(10).unwrap();
+	typist.write_at_cursor(b"d").unwrap();
+	match watcher.next_update() {
+		UpdateData::Add(add) => {
+			assert_eq!(add.offset, 3);
+			assert_eq!(add.data, b"d");
+		}
+		update => panic!("Unexpected update {:?}", update),
+	}
+}
+
+#[test]
+fn clamped_removal_moves_cursors_by_what_was_removed() {
+	let server = TestServer::start();
+	let remover = server.open("shared.txt", b"abcdef");
+	let other = server.open("shared.txt", b"");
+
+	remover.move_cursor(4).unwrap();
+	// Cursors may be left past the end
+	other.move_cursor(10).unwrap();
+	// Only two bytes are there to remove
+	remover.remove_at_cursor(10).unwrap();
+	match other.next_update() {
+		UpdateData::Remove(remove) => {
+			assert_eq!(remove.offset, 4);
+			assert_eq!(remove.len, 2);
+		}
+		update => panic!("Unexpected update {:?}", update),
+	}
+
+	let (own, mut all) = other.cursors().unwrap();
+	assert_eq!(own, 8);
+	all.sort();
+	assert_eq!(
+		all.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(),
+		[4, 8]
+	);
+}
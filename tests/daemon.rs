@@ -0,0 +1,78 @@
+#![cfg(all(feature = "client", feature = "daemon", unix))]
+
+mod common;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use editr::text_client::Client;
+
+use common::temp_home;
+
+// Polls until found returns something, failing the test after a while
+fn wait_for<T>(what: &str, mut found: impl FnMut() -> Option<T>) -> T {
+	let started = Instant::now();
+	loop {
+		if let Some(found) = found() {
+			return found;
+		}
+		assert!(started.elapsed() < Duration::from_secs(10), "No {}", what);
+		sleep(Duration::from_millis(50));
+	}
+}
+
+fn run_server(home: &Path, pidfile: &Path, log: &Path) -> std::process::Output {
+	Command::new(env!("CARGO_BIN_EXE_server"))
+		.arg(home)
+		.arg("127.0.0.1:0")
+		.arg("--daemon")
+		.arg("--pidfile")
+		.arg(pidfile)
+		.arg("--log")
+		.arg(log)
+		.output()
+		.expect("Failed to run server")
+}
+
+#[test]
+fn daemons_save_and_tidy_up_when_terminated() {
+	let home = temp_home();
+	let scratch = temp_home();
+	let (pidfile, log) = (scratch.join("editr.pid"), scratch.join("editr.log"));
+	fs::write(home.join("file.txt"), b"hello").unwrap();
+
+	// Returns once the server has gone into the background
+	let output = run_server(&home, &pidfile, &log);
+	assert!(output.status.success());
+	let pid: libc::pid_t = wait_for("pid", || {
+		fs::read_to_string(&pidfile).ok()?.trim().parse().ok()
+	});
+	assert_ne!(pid as u32, std::process::id());
+	let address: SocketAddr = wait_for("address", || {
+		let log = fs::read_to_string(&log).ok()?;
+		log.lines()
+			.find_map(|line| line.strip_prefix("LISTENING ")?.parse().ok())
+	});
+
+	// A second server is refused while the first holds the pidfile
+	let refused = run_server(&home, &pidfile, &scratch.join("second.log"));
+	assert!(!refused.status.success());
+	assert_eq!(
+		fs::read_to_string(&pidfile).unwrap().trim(),
+		pid.to_string()
+	);
+
+	let client = Client::connect(address).unwrap();
+	client.open("file.txt", None).unwrap();
+	client.write_at(5, b" world").unwrap();
+
+	assert_eq!(unsafe { libc::kill(pid, libc::SIGTERM) }, 0);
+	wait_for("pidfile removal", || (!pidfile.exists()).then_some(()));
+	assert_eq!(fs::read(home.join("file.txt")).unwrap(), b"hello world");
+	fs::remove_dir_all(&home).ok();
+	fs::remove_dir_all(&scratch).ok();
+}
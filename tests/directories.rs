@@ -0,0 +1,54 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use editr::error::EditrError;
+
+use common::TestServer;
+
+fn rejected_with<T>(result: Result<T, EditrError>, reason: &str) -> bool {
+	matches!(result, Err(EditrError::Rejected(e)) if e.contains(reason))
+}
+
+#[test]
+fn nested_directories_need_recursive() {
+	let server = TestServer::start();
+	let client = server.connect();
+
+	assert!(client.mkdir("a/b/c", false).is_err());
+	assert!(!server.home().join("a").exists());
+
+	client.mkdir("a/b/c", true).unwrap();
+	assert!(server.home().join("a/b/c").is_dir());
+	client.mkdir("a/d", false).unwrap();
+	assert!(server.home().join("a/d").is_dir());
+}
+
+#[test]
+fn new_directories_are_listed() {
+	let server = TestServer::start();
+	let client = server.connect();
+	client.mkdir("docs", false).unwrap();
+	assert!(client.list_files().unwrap().contains(&"docs/".to_string()));
+}
+
+#[test]
+fn existing_directories_are_not_created_again() {
+	let server = TestServer::start();
+	let client = server.connect();
+	client.mkdir("docs", false).unwrap();
+	assert!(rejected_with(client.mkdir("docs", false), "Already exists"));
+	// Asking for the whole path to be there is satisfied as it is
+	client.mkdir("docs", true).unwrap();
+}
+
+#[test]
+fn directories_outside_the_home_are_refused() {
+	let server = TestServer::start();
+	let client = server.connect();
+	let outside = server.home().parent().unwrap().join("editr-escaped");
+
+	assert!(client.mkdir("../editr-escaped", false).is_err());
+	assert!(client.mkdir("a/../../editr-escaped", true).is_err());
+	assert!(!outside.exists());
+}
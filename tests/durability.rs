@@ -0,0 +1,125 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use editr::config::{Durability, ServerConfig};
+use editr::state::{FileStates, SyncHook};
+
+use common::TestServer;
+
+// Records every sync asked for, along with what the watched file held
+// when new contents were synced
+#[derive(Default)]
+struct RecordingSync {
+	watched: Mutex<Option<PathBuf>>,
+	// Length of what was synced, and what the watched file held then
+	files: Mutex<Vec<(u64, Vec<u8>)>>,
+	dirs: Mutex<Vec<PathBuf>>,
+	failing_dirs: AtomicBool,
+}
+
+impl SyncHook for RecordingSync {
+	fn sync_file(&self, file: &File) -> io::Result<()> {
+		let synced = file.metadata()?.len();
+		let watched = self.watched.lock().clone().unwrap();
+		let on_disk = fs::read(watched).unwrap_or_default();
+		self.files.lock().push((synced, on_disk));
+		Ok(())
+	}
+
+	fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+		self.dirs.lock().push(dir.to_path_buf());
+		if self.failing_dirs.load(Ordering::SeqCst) {
+			return Err(io::Error::other("Directories can't be synced here"));
+		}
+		Ok(())
+	}
+}
+
+fn server(durability: Durability) -> (TestServer, Arc<RecordingSync>) {
+	let sync = Arc::new(RecordingSync::default());
+	let files = FileStates::new().synced_with(sync.clone());
+	let config = ServerConfig {
+		durability,
+		..ServerConfig::default()
+	};
+	let server = TestServer::with_files(config, files);
+	*sync.watched.lock() = Some(server.home().join("file.txt"));
+	(server, sync)
+}
+
+#[test]
+fn fast_saves_sync_nothing() {
+	let (server, sync) = server(Durability::Fast);
+	let client = server.open("file.txt", b"old");
+	client.write_at(3, b" and new").unwrap();
+
+	let saved = client.save_with(None).unwrap();
+	assert_eq!(saved.durability, Durability::Fast);
+	assert_eq!(saved.sync_us, 0);
+	assert!(sync.files.lock().is_empty());
+	assert!(sync.dirs.lock().is_empty());
+	assert_eq!(
+		fs::read(server.home().join("file.txt")).unwrap(),
+		b"old and new"
+	);
+}
+
+#[test]
+fn synced_saves_sync_the_file_before_renaming_then_the_directory() {
+	let (server, sync) = server(Durability::Fast);
+	let client = server.open("file.txt", b"old");
+	client.write_at(3, b" and new").unwrap();
+
+	let saved = client.save_with(Some(Durability::Fsync)).unwrap();
+	assert_eq!(saved.durability, Durability::Fsync);
+	// The new contents were synced while the old were still in place
+	assert_eq!(*sync.files.lock(), [(11, b"old".to_vec())]);
+	let home = fs::canonicalize(server.home()).unwrap();
+	assert_eq!(*sync.dirs.lock(), [home]);
+
+	// Only the save asked for more
+	client.save_as("copy.txt", false).unwrap();
+	client.save_with(None).unwrap();
+	assert_eq!(sync.files.lock().len(), 1);
+	assert_eq!(sync.dirs.lock().len(), 1);
+}
+
+#[test]
+fn clients_cannot_ask_for_less_than_the_server_saves_with() {
+	let (server, sync) = server(Durability::Fsync);
+	let client = server.open("file.txt", b"old");
+	client.write_at(0, b"x").unwrap();
+
+	let saved = client.save_with(Some(Durability::Fast)).unwrap();
+	assert_eq!(saved.durability, Durability::Fsync);
+	assert_eq!(sync.files.lock().len(), 1);
+
+	client.save_as("copy.txt", false).unwrap();
+	assert_eq!(sync.files.lock().len(), 2);
+	assert_eq!(sync.dirs.lock().len(), 2);
+}
+
+#[test]
+fn directories_which_cannot_be_synced_still_save() {
+	let (server, sync) = server(Durability::Fsync);
+	sync.failing_dirs.store(true, Ordering::SeqCst);
+	let client = server.open("file.txt", b"old");
+	client.write_at(3, b" and new").unwrap();
+
+	let saved = client.save_with(None).unwrap();
+	assert_eq!(saved.durability, Durability::Fsync);
+	assert_eq!(sync.dirs.lock().len(), 1);
+	assert_eq!(
+		fs::read(server.home().join("file.txt")).unwrap(),
+		b"old and new"
+	);
+}
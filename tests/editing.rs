@@ -0,0 +1,112 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use editr::message::{Message, UpdateData};
+
+use common::{Peer, TestServer};
+
+#[test]
+fn removing_past_the_end_is_refused_without_ending_the_session() {
+	let server = TestServer::start();
+	let client = server.open("file.txt", b"hello");
+
+	let e = client.remove(3, 5).unwrap_err();
+	assert!(
+		e.to_string()
+			.contains("Range 3..8 out of bounds for rope of length 5"),
+		"{}",
+		e
+	);
+	assert!(client.remove(usize::MAX, 1).is_err());
+	// Exactly up to the end is fine
+	client.remove(3, 2).unwrap();
+	assert_eq!(client.read(0, 100).unwrap(), b"hel");
+}
+
+#[test]
+fn writing_past_the_end_is_refused_without_ending_the_session() {
+	let server = TestServer::start();
+	let client = server.open("file.txt", b"hello");
+
+	let e = client.write_at(usize::MAX, b"x").unwrap_err();
+	assert!(e.to_string().contains("out of bounds"), "{}", e);
+	assert!(client.write_at(6, b"x").is_err());
+	client.write_at(5, b"!").unwrap();
+	assert_eq!(client.read(0, 100).unwrap(), b"hello!");
+}
+
+#[test]
+fn short_reads_say_where_the_file_ended() {
+	let server = TestServer::start();
+	let client = server.open("file.txt", b"hello world");
+
+	let whole = client.read_with_len(0, 11).unwrap();
+	assert_eq!(
+		(whole.offset, whole.data.as_slice(), whole.file_len),
+		(0, &b"hello world"[..], 11)
+	);
+
+	// Running past the end
+	let tail = client.read_with_len(6, 100).unwrap();
+	assert_eq!(
+		(tail.offset, tail.data.as_slice(), tail.file_len),
+		(6, &b"world"[..], 11)
+	);
+
+	// Starting past the end reads nothing from the end
+	let past = client.read_with_len(50, 10).unwrap();
+	assert_eq!((past.offset, past.data.len(), past.file_len), (11, 0, 11));
+	let far = client.read_with_len(usize::MAX, usize::MAX).unwrap();
+	assert_eq!((far.offset, far.data.len(), far.file_len), (11, 0, 11));
+	assert!(client.read(usize::MAX, 1).unwrap().is_empty());
+}
+
+// Broadcasts the peer is sent up to and including the next add, which the
+// test makes to mark the end of what it is checking
+fn updates_until_add(peer: &Peer) -> Vec<UpdateData> {
+	let mut updates = Vec::new();
+	loop {
+		if let Message::UpdateMessage(update) = peer.next_broadcast() {
+			let done = matches!(update, UpdateData::Add(_));
+			updates.push(update);
+			if done {
+				return updates;
+			}
+		}
+	}
+}
+
+#[test]
+fn replacing_reaches_neighbours_as_one_update() {
+	let server = TestServer::start();
+	let editor = server.open("file.txt", b"hello world");
+	let neighbour = server.open("file.txt", b"");
+
+	editor.replace(6, 5, b"there").unwrap();
+	editor.write_at(0, b">").unwrap();
+	let updates = updates_until_add(&neighbour);
+	assert_eq!(updates.len(), 2, "{:?}", updates);
+	match &updates[0] {
+		UpdateData::Replace(replace) => {
+			assert_eq!((replace.offset, replace.len), (6, 5));
+			assert_eq!(replace.data, b"there");
+		}
+		update => panic!("Unexpected update {:?}", update),
+	}
+
+	// Pure inserts and removes are replacements too
+	editor.replace(1, 0, b"oh, ").unwrap();
+	editor.replace(5, 6, b"").unwrap();
+	editor.write_at(0, b">").unwrap();
+	assert_eq!(updates_until_add(&neighbour).len(), 3);
+
+	let mut reference = b"hello world".to_vec();
+	reference.splice(6..11, *b"there");
+	reference.splice(0..0, *b">");
+	reference.splice(1..1, *b"oh, ");
+	reference.splice(5..11, []);
+	reference.splice(0..0, *b">");
+	assert_eq!(neighbour.read(0, 100).unwrap(), reference);
+	assert!(editor.replace(0, 100, b"x").is_err());
+}
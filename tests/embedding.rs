@@ -0,0 +1,72 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use editr::config::ServerConfig;
+use editr::rope::Rope;
+use editr::state::FileStates;
+
+use common::{Replica, TestServer};
+
+// Each document the host was handed, by name
+type Saved = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+// Serves one virtual document, report.txt, recording what saving it hands
+// back to the host
+fn embedded() -> (TestServer, Saved) {
+	let saved = Saved::default();
+	let host = {
+		let saved = saved.clone();
+		move |name: &str, contents: &[u8]| {
+			saved.lock().push((name.to_string(), contents.to_vec()));
+			Ok(())
+		}
+	};
+	let report = Rope::from_reader(&b"Total: 1"[..]).unwrap();
+	let files =
+		FileStates::with_documents(Arc::new(host), [("report.txt".to_string(), report)]).unwrap();
+	(
+		TestServer::with_files(ServerConfig::default(), files),
+		saved,
+	)
+}
+
+#[test]
+fn saving_a_document_hands_it_to_the_host() {
+	let (server, saved) = embedded();
+	let editor = server.connect();
+	editor.open("virtual://report.txt", None).unwrap();
+	let watcher = server.connect();
+	watcher.open("virtual://report.txt", None).unwrap();
+
+	editor.replace(7, 1, b"42").unwrap();
+	let mut replica = Replica::new(b"Total: 1");
+	while replica.data != b"Total: 42" {
+		replica.apply(&watcher.next_update());
+	}
+	editor.save().unwrap();
+
+	assert_eq!(
+		*saved.lock(),
+		[("report.txt".to_string(), b"Total: 42".to_vec())]
+	);
+	// Nothing was written to the home
+	assert_eq!(std::fs::read_dir(server.home()).unwrap().count(), 0);
+}
+
+#[test]
+fn documents_are_listed_with_the_home() {
+	let (server, _) = embedded();
+	std::fs::write(server.home().join("real.txt"), "").unwrap();
+	let client = server.connect();
+
+	let mut files = client.list_files().unwrap();
+	files.sort();
+	assert_eq!(files, ["real.txt", "virtual://report.txt"]);
+	// Unknown documents are not made up
+	assert!(client.open("virtual://missing.txt", None).is_err());
+}
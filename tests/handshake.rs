@@ -0,0 +1,51 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use editr::config::ServerConfig;
+use editr::message::{DisconnectingData, Message};
+use editr::state::DisconnectReason;
+
+use common::TestServer;
+
+#[test]
+fn connections_which_never_speak_are_closed() {
+	let server = TestServer::with_config(ServerConfig {
+		handshake_timeout: Some(Duration::from_secs(1)),
+		max_connections: Some(2),
+		..ServerConfig::default()
+	});
+	let talker = server.connect();
+	talker.ping().unwrap();
+
+	// Connects and says nothing, as a port scanner would
+	let mute = server.connect();
+	let connected = Instant::now();
+	assert!(matches!(
+		mute.next_broadcast(),
+		Message::Disconnecting(DisconnectingData {
+			reason: DisconnectReason::HandshakeTimeout,
+			..
+		})
+	));
+	assert!(connected.elapsed() >= Duration::from_secs(1));
+	assert!(mute.ping().is_err());
+
+	// Its slot is given back, so another client fits under the limit
+	let started = Instant::now();
+	loop {
+		let next = server.connect();
+		if next.ping().is_ok() {
+			break;
+		}
+		assert!(started.elapsed() < Duration::from_secs(5), "Slot was kept");
+		sleep(Duration::from_millis(50));
+	}
+
+	// Clients which have spoken are left alone, however quiet since
+	sleep(Duration::from_millis(1500));
+	talker.ping().unwrap();
+}
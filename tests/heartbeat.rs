@@ -0,0 +1,45 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use editr::config::{Heartbeat, ServerConfig};
+use editr::message::Message;
+
+use common::TestServer;
+
+#[test]
+fn silent_peers_are_evicted_and_their_neighbours_told() {
+	let server = TestServer::with_config(ServerConfig {
+		heartbeat: Some(Heartbeat {
+			interval: Duration::from_secs(1),
+			timeout: Duration::from_secs(2),
+		}),
+		..ServerConfig::default()
+	});
+	let watcher = server.open("file.txt", b"");
+
+	// Opens the file, then says nothing more, not even answering the
+	// server's pings, as a peer which vanished without closing would
+	let (silent, wire) = server.connect_wire();
+	silent.open("file.txt", None).unwrap();
+	wire.silence();
+	let opened = Instant::now();
+
+	let deadline = opened + Duration::from_secs(10);
+	loop {
+		match watcher.try_next_broadcast() {
+			Ok(Message::PeerLeft(_)) => break,
+			_ => assert!(Instant::now() < deadline, "Silent peer was never evicted"),
+		}
+	}
+	assert!(opened.elapsed() >= Duration::from_secs(2));
+
+	// The silent connection was closed, and the watcher, which answers
+	// every ServerPing, was kept
+	silent.broadcasts_until_closed();
+	sleep(Duration::from_secs(2));
+	watcher.ping().unwrap();
+}
@@ -0,0 +1,54 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use editr::config::ServerConfig;
+use editr::message::{DisconnectingData, Message};
+use editr::state::DisconnectReason;
+
+use common::TestServer;
+
+fn config() -> ServerConfig {
+	ServerConfig {
+		idle_timeout: Some(Duration::from_secs(1)),
+		// Dropped connections are cleaned up straight away
+		session_grace: Duration::ZERO,
+		..ServerConfig::default()
+	}
+}
+
+#[test]
+fn idle_clients_are_disconnected_while_active_ones_stay() {
+	let server = TestServer::with_config(config());
+	let idle = server.open("file.txt", b"");
+	let active = server.open("file.txt", b"");
+	idle.take_broadcasts();
+
+	// Pinging keeps the active client's timeout from running out
+	let started = Instant::now();
+	while started.elapsed() < Duration::from_secs(4) {
+		active.ping().unwrap();
+		sleep(Duration::from_millis(200));
+	}
+
+	assert!(matches!(
+		idle.next_broadcast(),
+		Message::Disconnecting(DisconnectingData {
+			reason: DisconnectReason::IdleTimeout,
+			..
+		})
+	));
+	assert!(idle.ping().is_err());
+
+	// The idle client was closed out of the file like any other leaving
+	let left = active
+		.take_broadcasts()
+		.into_iter()
+		.any(|message| matches!(message, Message::PeerLeft(_)));
+	assert!(left);
+	active.ping().unwrap();
+	assert_eq!(active.cursors().unwrap().1.len(), 1);
+}
@@ -0,0 +1,108 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+
+use editr::config::ServerConfig;
+use editr::message::{Message, UpdateData};
+use editr::state::{MemoryStream, SharedState};
+use editr::text_client::Client;
+use editr::text_server::{connect_in_memory, start_in_memory};
+
+use common::{temp_home, Replica};
+
+// A server reached without the network, over a temporary home
+struct MemoryServer {
+	home: std::path::PathBuf,
+	shared: SharedState<MemoryStream>,
+}
+
+impl MemoryServer {
+	fn start() -> MemoryServer {
+		let home = temp_home();
+		let shared = start_in_memory(&home, ServerConfig::default()).unwrap();
+		MemoryServer { home, shared }
+	}
+
+	fn open(&self, file: &str, contents: &[u8]) -> Client<MemoryStream> {
+		if !self.home.join(file).exists() {
+			fs::write(self.home.join(file), contents).unwrap();
+		}
+		let client = Client::new(connect_in_memory(&self.shared).unwrap()).unwrap();
+		client.open(file, None).unwrap();
+		client
+	}
+}
+
+impl Drop for MemoryServer {
+	fn drop(&mut self) { fs::remove_dir_all(&self.home).ok(); }
+}
+
+fn next_update(client: &Client<MemoryStream>) -> UpdateData {
+	loop {
+		match client.next_broadcast().unwrap() {
+			Message::UpdateMessage(update) => return update,
+			_ => continue,
+		}
+	}
+}
+
+#[test]
+fn edits_reach_other_clients() {
+	let server = MemoryServer::start();
+	let typist = server.open("file.txt", b"hello");
+	let watcher = server.open("file.txt", b"");
+	let mut replica = Replica::new(&watcher.read(0, usize::MAX).unwrap());
+
+	typist.write_at(5, b" world").unwrap();
+	typist.remove(0, 1).unwrap();
+	typist.replace(0, 4, b"J").unwrap();
+	while replica.data != b"J world" {
+		replica.apply(&next_update(&watcher));
+	}
+	assert_eq!(typist.read(0, usize::MAX).unwrap(), b"J world");
+}
+
+#[test]
+fn cursors_follow_edits() {
+	let server = MemoryServer::start();
+	let typist = server.open("file.txt", b"hello world");
+	let other = server.open("file.txt", b"");
+
+	other.move_cursor(6).unwrap();
+	typist.move_cursor(5).unwrap();
+	typist.write_at_cursor(b",").unwrap();
+	other.write_at_cursor(b"big ").unwrap();
+	assert_eq!(other.read(0, usize::MAX).unwrap(), b"hello, big world");
+}
+
+#[test]
+fn saves_reach_the_disk() {
+	let server = MemoryServer::start();
+	let client = server.open("file.txt", b"hello");
+	client.write_at(5, b"!").unwrap();
+	client.save().unwrap();
+	assert_eq!(fs::read(server.home.join("file.txt")).unwrap(), b"hello!");
+
+	client
+		.create("new.txt", false, Some(b"new".to_vec()), false)
+		.unwrap();
+	client.delete("new.txt").unwrap();
+	assert!(!server.home.join("new.txt").exists());
+}
+
+#[test]
+fn closing_tells_other_clients() {
+	let server = MemoryServer::start();
+	let leaver = server.open("file.txt", b"");
+	let watcher = server.open("file.txt", b"");
+
+	leaver.close().unwrap();
+	assert!(matches!(
+		watcher.next_broadcast().unwrap(),
+		Message::PeerLeft(_)
+	));
+	leaver.disconnect().unwrap();
+	watcher.ping().unwrap();
+}
@@ -0,0 +1,66 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use editr::message::{DisconnectingData, Message};
+use editr::state::DisconnectReason;
+
+use common::TestServer;
+
+fn echo(data: &[u8]) -> Message { Message::Echo(data.to_vec()) }
+
+#[test]
+fn garbage_between_messages_keeps_the_session() {
+	let server = TestServer::start();
+	let (client, wire) = server.connect_wire();
+	let first = client.request(echo(b"first")).unwrap();
+	assert!(matches!(first, Message::Echo(data) if data == b"first"));
+	wire.garble(1);
+	assert!(matches!(
+		client.request(echo(b"lost")).unwrap(),
+		Message::InvalidResp(_)
+	));
+	let second = client.request(echo(b"second")).unwrap();
+	assert!(matches!(second, Message::Echo(data) if data == b"second"));
+}
+
+#[test]
+fn repeated_garbage_disconnects() {
+	let server = TestServer::start();
+	let (client, wire) = server.connect_wire();
+	wire.garble(8);
+	for _ in 0..8 {
+		assert!(matches!(
+			client.request(echo(b"lost")).unwrap(),
+			Message::InvalidResp(_)
+		));
+	}
+
+	assert!(matches!(
+		client.next_broadcast(),
+		Message::Disconnecting(DisconnectingData {
+			reason: DisconnectReason::TooManyInvalidMessages,
+			..
+		})
+	));
+	assert!(client.ping().is_err());
+}
+
+#[test]
+fn messages_only_the_server_sends_are_a_violation() {
+	let server = TestServer::start();
+	let client = server.connect();
+	assert!(matches!(
+		client.request(Message::ServerClosing).unwrap(),
+		Message::Invalid
+	));
+
+	assert!(matches!(
+		client.next_broadcast(),
+		Message::Disconnecting(DisconnectingData {
+			reason: DisconnectReason::ProtocolViolation,
+			..
+		})
+	));
+	assert!(client.ping().is_err());
+}
@@ -0,0 +1,31 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{Replica, TestServer};
+
+const EDITS: usize = 500;
+
+#[test]
+fn edit_bursts_are_answered_promptly() {
+	let server = TestServer::start();
+	let typist = server.open("file.txt", b"");
+	let watcher = server.open("file.txt", b"");
+
+	// Each keystroke waits on its answer, so batching small writes up
+	// would cost a delayed acknowledgement every time
+	let started = Instant::now();
+	for _ in 0..EDITS {
+		typist.write_at_cursor(b"x").unwrap();
+	}
+	// Updates may be merged on the way, so follow the contents
+	let mut replica = Replica::new(b"");
+	while replica.data.len() < EDITS {
+		replica.apply(&watcher.next_update());
+	}
+	let elapsed = started.elapsed();
+	println!("{} edits delivered in {:?}", EDITS, elapsed);
+	assert!(elapsed < Duration::from_secs(5), "Took {:?}", elapsed);
+}
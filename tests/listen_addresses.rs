@@ -0,0 +1,55 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::net::{SocketAddr, TcpStream};
+
+use editr::config::ServerConfig;
+use editr::error::EditrError;
+use editr::text_server::{serve_listeners, spawn};
+
+use common::{temp_home, Peer, Replica};
+
+#[test]
+fn clients_on_different_addresses_share_files() {
+	let home = temp_home();
+	std::fs::write(home.join("shared.txt"), b"").unwrap();
+	let addresses: Vec<SocketAddr> = vec![
+		"127.0.0.1:0".parse().unwrap(),
+		"127.0.0.1:0".parse().unwrap(),
+	];
+	let handle = spawn(&home, &addresses[..], ServerConfig::default()).unwrap();
+	let bound = handle.local_addrs().to_vec();
+	assert_eq!(bound.len(), 2);
+	assert_ne!(bound[0], bound[1]);
+
+	let first = Peer::new(TcpStream::connect(bound[0]).unwrap());
+	let second = Peer::new(TcpStream::connect(bound[1]).unwrap());
+	first.open("shared.txt", None).unwrap();
+	second.open("shared.txt", None).unwrap();
+
+	first.write_at(0, b"world").unwrap();
+	second.write_at(0, b"hello ").unwrap();
+	// Clients are only told about each other's edits
+	let mut replica = Replica::new(b"world");
+	while replica.data != b"hello world" {
+		replica.apply(&first.next_update());
+	}
+	assert_eq!(first.read(0, usize::MAX).unwrap(), b"hello world");
+	assert_eq!(second.read(0, usize::MAX).unwrap(), b"hello world");
+
+	// Stopping closes every listener
+	handle.stop().unwrap();
+	for address in bound {
+		assert!(TcpStream::connect(address).is_err());
+	}
+	std::fs::remove_dir_all(home).ok();
+}
+
+#[test]
+fn nothing_to_listen_on_is_refused() {
+	let home = temp_home();
+	let result = serve_listeners(&home, Vec::new(), ServerConfig::default());
+	std::fs::remove_dir_all(home).ok();
+	assert!(matches!(result, Err(EditrError::Config(_))));
+}
@@ -0,0 +1,52 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+
+use editr::text_client::Client;
+
+use common::temp_home;
+
+// Kills the server once the test is done with it, however it ends
+struct Server(Child);
+
+impl Drop for Server {
+	fn drop(&mut self) {
+		self.0.kill().ok();
+		self.0.wait().ok();
+	}
+}
+
+#[test]
+fn the_server_reports_the_ports_it_was_given() {
+	let home = temp_home();
+	let mut server = Server(
+		Command::new(env!("CARGO_BIN_EXE_server"))
+			.arg(&home)
+			.arg("127.0.0.1:0")
+			.args(["--listen", "127.0.0.1:0"])
+			.stdout(Stdio::piped())
+			.spawn()
+			.expect("Failed to run server"),
+	);
+
+	// Kept open, as the server goes on to log there
+	let mut stdout = BufReader::new(server.0.stdout.take().unwrap()).lines();
+	let addresses: Vec<SocketAddr> = stdout
+		.by_ref()
+		.map(Result::unwrap)
+		.filter_map(|line| line.strip_prefix("LISTENING ").map(|a| a.parse().unwrap()))
+		.take(2)
+		.collect();
+	assert_eq!(addresses.len(), 2);
+	for address in addresses {
+		assert_ne!(address.port(), 0);
+		Client::connect(address).unwrap().ping().unwrap();
+	}
+
+	drop(server);
+	std::fs::remove_dir_all(home).ok();
+}
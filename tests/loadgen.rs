@@ -0,0 +1,41 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::process::{Command, Output};
+
+use editr::config::ServerConfig;
+
+use common::TestServer;
+
+fn loadgen(server: &TestServer, connections: usize) -> Output {
+	Command::new(env!("CARGO_BIN_EXE_loadgen"))
+		.arg(server.local_addr().to_string())
+		.args(["--connections", &connections.to_string()])
+		.args(["--duration", "300ms"])
+		.output()
+		.expect("Failed to run loadgen")
+}
+
+#[test]
+fn converging_run_succeeds() {
+	let server = TestServer::start();
+	let output = loadgen(&server, 4);
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert!(output.status.success(), "{}", stdout);
+	assert!(stdout.contains("Converged: yes"), "{}", stdout);
+	assert!(stdout.contains("0 failed"), "{}", stdout);
+}
+
+#[test]
+fn editors_which_fail_fail_the_run() {
+	// Editors past the workers are turned away, so never read the file back
+	let server = TestServer::with_config(ServerConfig {
+		workers: Some(2),
+		..ServerConfig::default()
+	});
+	let output = loadgen(&server, 6);
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert!(!output.status.success(), "{}", stdout);
+	assert!(stdout.contains("was not read back by"), "{}", stdout);
+}
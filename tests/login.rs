@@ -0,0 +1,91 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+
+use editr::config::ServerConfig;
+
+use common::TestServer;
+
+fn config() -> ServerConfig {
+	let mut user_tokens = HashMap::new();
+	user_tokens.insert("alice".to_string(), "alice-secret".to_string());
+	user_tokens.insert("bob".to_string(), "bob-secret".to_string());
+	ServerConfig {
+		user_homes: true,
+		shared_dir: Some("shared".to_string()),
+		user_tokens,
+		..ServerConfig::default()
+	}
+}
+
+#[test]
+fn login_confines_to_own_home() {
+	let server = TestServer::with_config(config());
+	fs::create_dir(server.home().join("bob")).unwrap();
+	fs::write(server.home().join("bob/diary.txt"), b"secret").unwrap();
+
+	let alice = server.connect();
+	alice.login("alice", "alice-secret").unwrap();
+	alice
+		.create("notes.txt", false, Some(b"hi".to_vec()), false)
+		.unwrap();
+	assert!(server.home().join("alice/notes.txt").exists());
+	assert!(alice.open("../bob/diary.txt", None).is_err());
+	// Only alice's own files and the shared area are listed
+	let mut files = alice.list_files().unwrap();
+	files.sort();
+	assert_eq!(files, ["notes.txt", "shared/"]);
+}
+
+#[test]
+fn login_needs_the_users_token() {
+	let server = TestServer::with_config(config());
+	fs::create_dir(server.home().join("bob")).unwrap();
+	fs::write(server.home().join("bob/diary.txt"), b"secret").unwrap();
+
+	let mallory = server.connect();
+	assert!(mallory.login("bob", "guess").is_err());
+	assert!(mallory.login("bob", "").is_err());
+	assert!(mallory.login("bob", "alice-secret").is_err());
+	// Unlisted users can't log in at all
+	assert!(mallory.login("carol", "").is_err());
+	// Refused logins leave the client where it was, outside bob's home
+	assert!(mallory.open("diary.txt", None).is_err());
+	assert!(!server.home().join("carol").exists());
+
+	mallory.login("bob", "bob-secret").unwrap();
+	assert_eq!(
+		mallory
+			.open("diary.txt", None)
+			.unwrap()
+			.file_name()
+			.unwrap(),
+		"diary.txt"
+	);
+}
+
+#[test]
+fn shared_area_is_shared() {
+	let server = TestServer::with_config(config());
+	fs::create_dir_all(server.home().join("shared")).unwrap();
+	fs::write(server.home().join("shared/board.txt"), b"").unwrap();
+
+	let alice = server.connect();
+	alice.login("alice", "alice-secret").unwrap();
+	let bob = server.connect();
+	bob.login("bob", "bob-secret").unwrap();
+
+	let alice_path = alice.open("shared/board.txt", None).unwrap();
+	let bob_path = bob.open("shared/board.txt", None).unwrap();
+	assert_eq!(alice_path, bob_path);
+	alice.write_at(0, b"hello bob").unwrap();
+	bob.next_update();
+	assert_eq!(bob.read(0, usize::MAX).unwrap(), b"hello bob");
+
+	// The file is busy for either of them while the other has it open
+	bob.close().unwrap();
+	assert!(bob.delete("shared/board.txt").is_err());
+}
@@ -0,0 +1,57 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use editr::config::ServerConfig;
+use editr::message::{DisconnectingData, Message};
+use editr::state::DisconnectReason;
+
+use common::TestServer;
+
+const LIMIT: usize = 1024;
+
+fn limited() -> TestServer {
+	TestServer::with_config(ServerConfig {
+		max_message_size: LIMIT,
+		..ServerConfig::default()
+	})
+}
+
+#[test]
+fn the_limit_is_advertised() {
+	let server = limited();
+	let client = server.connect();
+	assert_eq!(client.hello().unwrap().max_message_size, LIMIT);
+	// Messages under the limit are answered as usual
+	assert!(matches!(
+		client.request(Message::Echo(vec![0; 100])).unwrap(),
+		Message::Echo(data) if data.len() == 100
+	));
+}
+
+#[test]
+fn oversized_messages_close_only_their_connection() {
+	let server = limited();
+	let other = server.open("file.txt", b"hello");
+
+	let hostile = server.connect();
+	// The server may hang up before all of it has been sent
+	let response = hostile.request(Message::Echo(vec![0; 4 * LIMIT]));
+	assert!(!matches!(response, Ok(Message::Echo(_))));
+
+	// The hostile client is told why, then let go
+	assert!(matches!(
+		hostile.next_broadcast(),
+		Message::Disconnecting(DisconnectingData {
+			reason: DisconnectReason::MessageTooLarge,
+			..
+		})
+	));
+	assert!(hostile.ping().is_err());
+
+	// Everyone else carries on
+	other.ping().unwrap();
+	other.write_at(5, b" world").unwrap();
+	assert_eq!(other.read(0, usize::MAX).unwrap(), b"hello world");
+	server.connect().ping().unwrap();
+}
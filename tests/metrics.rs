@@ -0,0 +1,118 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use editr::config::ServerConfig;
+use editr::message::{AdminListResult, Message, MetricsResult, StatusResult};
+use editr::state::{ConnectionInfo, MetricsSnapshot, ServerSnapshot};
+use editr::text_client::Client;
+
+use common::TestServer;
+
+const ADMIN_TOKEN: &str = "admin";
+
+fn metrics(client: &Client) -> MetricsSnapshot {
+	match client.request(Message::MetricsReq).unwrap() {
+		Message::MetricsResp(MetricsResult::Ok(metrics)) => metrics,
+		other => panic!("Unexpected response {:?}", other),
+	}
+}
+
+fn status(client: &Client) -> ServerSnapshot {
+	match client.request(Message::StatusReq).unwrap() {
+		Message::StatusResp(StatusResult::Ok(status)) => status,
+		other => panic!("Unexpected response {:?}", other),
+	}
+}
+
+fn connections(client: &Client) -> Vec<ConnectionInfo> {
+	match client
+		.request(Message::AdminListReq(ADMIN_TOKEN.to_string()))
+		.unwrap()
+	{
+		Message::AdminListResp(AdminListResult::Ok(connections)) => connections,
+		other => panic!("Unexpected response {:?}", other),
+	}
+}
+
+// Opens a file and runs through a few edits, a read and a failed request
+fn script(client: &Client) {
+	for offset in 0..3 {
+		client.write_at(offset, b"x").unwrap();
+	}
+	client.read(0, 3).unwrap();
+	assert!(client.remove(100, 1).is_err());
+}
+
+#[test]
+fn connections_count_what_they_do() {
+	let server = TestServer::start();
+	let client = server.open("file.txt", b"");
+	let before = metrics(&client);
+	script(&client);
+	let after = metrics(&client);
+
+	assert_eq!(after.messages.get("WriteReq"), Some(&3));
+	assert_eq!(after.messages.get("ReadReq"), Some(&1));
+	assert_eq!(after.messages.get("RemoveReq"), Some(&1));
+	assert_eq!(after.edits - before.edits, 3);
+	assert_eq!(after.errors - before.errors, 1);
+	assert!(after.bytes_read > before.bytes_read);
+	assert!(after.bytes_written > before.bytes_written);
+}
+
+#[test]
+fn administrators_see_every_connection_until_it_ends() {
+	let server = TestServer::with_config(ServerConfig {
+		admin_token: Some(ADMIN_TOKEN.to_string()),
+		..ServerConfig::default()
+	});
+	let editor = server.open("file.txt", b"");
+	script(&editor);
+	let admin = server.connect();
+
+	let listed = connections(&admin);
+	assert_eq!(listed.len(), 2);
+	let edited = listed
+		.iter()
+		.find(|connection| connection.opened_file.is_some())
+		.unwrap();
+	assert_eq!(edited.metrics.edits, 3);
+	assert_eq!(edited.metrics.errors, 1);
+
+	// Ending the connection takes it out of the registry
+	drop(editor);
+	let started = Instant::now();
+	while connections(&admin).len() > 1 {
+		assert!(started.elapsed() < Duration::from_secs(5));
+		sleep(Duration::from_millis(20));
+	}
+}
+
+#[test]
+fn messages_are_timed_by_kind_and_phase() {
+	let server = TestServer::with_config(ServerConfig {
+		// Every message is slow enough to be logged
+		slow_op: Some(Duration::ZERO),
+		..ServerConfig::default()
+	});
+	let editor = server.open("file.txt", b"");
+	let _peers = (0..40)
+		.map(|_| server.open("file.txt", b""))
+		.collect::<Vec<_>>();
+	for _ in 0..10 {
+		editor.write_at(0, &[b'x'; 4096]).unwrap();
+	}
+
+	let timings = status(&editor).timings;
+	let writes = &timings["WriteReq"];
+	assert_eq!(writes.count, 10);
+	assert!(writes.max_us <= writes.total_us);
+	assert!(writes.acquire_us + writes.apply_us + writes.broadcast_us <= writes.total_us);
+	// Handing 4 KiB to 40 peers takes some time
+	assert!(writes.broadcast_us > 0);
+	assert_eq!(timings["OpenReq"].count, 41);
+}
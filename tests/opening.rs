@@ -0,0 +1,71 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+
+use editr::error::EditrError;
+
+use common::TestServer;
+
+fn rejection<T: std::fmt::Debug>(result: Result<T, EditrError>) -> String {
+	match result {
+		Err(EditrError::Rejected(e)) => e,
+		other => panic!("Expected a rejection, got {:?}", other),
+	}
+}
+
+#[test]
+fn directories_list_some_of_their_entries() {
+	let server = TestServer::start();
+	let dir = server.home().join("dir");
+	fs::create_dir(&dir).unwrap();
+	fs::write(dir.join("a.txt"), "").unwrap();
+	fs::create_dir(dir.join("sub")).unwrap();
+	let client = server.connect();
+
+	let e = rejection(client.open("dir", None));
+	assert!(e.contains("Is a directory"), "{}", e);
+	assert!(e.contains("a.txt"), "{}", e);
+	assert!(e.contains("sub"), "{}", e);
+}
+
+#[test]
+fn listed_entries_are_capped() {
+	let server = TestServer::start();
+	let dir = server.home().join("dir");
+	fs::create_dir(&dir).unwrap();
+	for index in 0..100 {
+		fs::write(dir.join(format!("file{}", index)), "").unwrap();
+	}
+	let client = server.connect();
+
+	let e = rejection(client.open("dir", None));
+	let (_, entries) = e.split_once("entries: ").unwrap();
+	assert_eq!(entries.split(", ").count(), 32);
+}
+
+#[cfg(unix)]
+#[test]
+fn other_special_files_are_not_opened() {
+	use std::os::unix::net::UnixListener;
+
+	let server = TestServer::start();
+	let _socket = UnixListener::bind(server.home().join("socket")).unwrap();
+	let client = server.connect();
+
+	let e = rejection(client.open("socket", None));
+	assert!(e.contains("Not a regular file"), "{}", e);
+	// Nothing was left open
+	assert!(client.read(0, 1).is_err());
+}
+
+#[test]
+fn failures_name_the_operation_and_path() {
+	let server = TestServer::start();
+	let client = server.connect();
+
+	let e = rejection(client.open("nested/dir/missing.txt", None));
+	assert!(e.starts_with("Failed to open"), "{}", e);
+	assert!(e.contains("nested/dir/missing.txt"), "{}", e);
+}
@@ -0,0 +1,145 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use editr::config::{Permissions, ServerConfig};
+use editr::error::EditrError;
+use editr::message::{Message, PasteAtCursorResult, YankReqData, YankResult};
+use editr::text_client::Client;
+
+use common::TestServer;
+
+fn viewer_config() -> ServerConfig {
+	ServerConfig {
+		default_permissions: Permissions::Viewer,
+		..ServerConfig::default()
+	}
+}
+
+fn is_denied<T>(result: Result<T, EditrError>) -> bool {
+	match result {
+		Err(EditrError::Rejected(e)) => e == EditrError::PermissionDenied(None).to_string(),
+		_ => false,
+	}
+}
+
+#[test]
+fn viewer_may_only_look() {
+	let server = TestServer::with_config(viewer_config());
+	let viewer = server.open("file.txt", b"contents");
+	assert_eq!(viewer.hello().unwrap().permissions, Permissions::Viewer);
+
+	// Requests which look are answered
+	assert!(viewer.list_files().is_ok());
+	assert_eq!(viewer.read(0, usize::MAX).unwrap(), b"contents");
+	assert!(viewer.move_cursor(2).is_ok());
+	assert!(viewer.cursors().is_ok());
+
+	// Every request which changes something is refused
+	let client: &Client = &viewer;
+	assert!(is_denied(client.write_at(0, b"x")));
+	assert!(is_denied(client.remove(0, 1)));
+	assert!(is_denied(client.replace(0, 1, b"x")));
+	assert!(is_denied(client.write_at_cursor(b"x")));
+	assert!(is_denied(client.remove_at_cursor(1)));
+	assert!(is_denied(client.save()));
+	assert!(is_denied(client.save_as("copy.txt", false)));
+	match client.request(Message::PasteAtCursorReq).unwrap() {
+		Message::PasteAtCursorResp(PasteAtCursorResult::Err(e)) => {
+			assert_eq!(e, EditrError::PermissionDenied(None).to_string())
+		}
+		other => panic!("Unexpected response {:?}", other),
+	}
+	assert!(is_denied(client.create("new.txt", false, None, false)));
+	assert!(is_denied(client.mkdir("dir", false)));
+	assert!(is_denied(client.rename("file.txt", "moved.txt")));
+	assert!(is_denied(client.delete("file.txt")));
+
+	// Nothing was touched
+	assert_eq!(viewer.read(0, usize::MAX).unwrap(), b"contents");
+	assert!(viewer.close().is_ok());
+	let mut files = viewer.list_files().unwrap();
+	files.sort();
+	assert_eq!(files, ["file.txt"]);
+}
+
+#[test]
+fn viewer_cannot_claim_an_editors_permissions() {
+	let mut config = viewer_config();
+	config
+		.user_permissions
+		.insert("editor".to_string(), Permissions::Editor);
+	config
+		.user_tokens
+		.insert("editor".to_string(), "editor-secret".to_string());
+	let server = TestServer::with_config(config);
+	let viewer = server.open("file.txt", b"contents");
+
+	assert!(viewer.login("editor", "wrong").is_err());
+	assert!(is_denied(viewer.write_at(0, b"x")));
+
+	assert_eq!(
+		viewer.login("editor", "editor-secret").unwrap(),
+		Permissions::Editor
+	);
+	assert!(viewer.write_at(0, b"x").is_ok());
+}
+
+#[test]
+fn read_only_servers_refuse_every_change() {
+	let server = TestServer::with_config(ServerConfig {
+		read_only: true,
+		..ServerConfig::default()
+	});
+	// Editors are held back along with everyone else
+	let editor = server.open("file.txt", b"contents");
+	let watcher = server.open("file.txt", b"");
+	let hello = editor.hello().unwrap();
+	assert_eq!(hello.permissions, Permissions::Editor);
+	assert!(hello.read_only);
+
+	// Looking, moving about and presence all work
+	assert!(editor.list_files().is_ok());
+	assert_eq!(editor.read(0, usize::MAX).unwrap(), b"contents");
+	assert!(editor.move_cursor(2).is_ok());
+	assert!(editor.cursors().is_ok());
+	assert!(editor.request(Message::StatusReq).is_ok());
+	let yank = Message::YankReq(YankReqData { offset: 0, len: 4 });
+	assert!(matches!(
+		editor.request(yank).unwrap(),
+		Message::YankResp(YankResult::Ok)
+	));
+
+	let client: &Client = &editor;
+	assert!(is_denied(client.write_at(0, b"x")));
+	assert!(is_denied(client.remove(0, 1)));
+	assert!(is_denied(client.replace(0, 1, b"x")));
+	assert!(is_denied(client.write_at_cursor(b"x")));
+	assert!(is_denied(client.remove_at_cursor(1)));
+	assert!(is_denied(client.save()));
+	assert!(is_denied(client.save_as("copy.txt", false)));
+	match client.request(Message::PasteAtCursorReq).unwrap() {
+		Message::PasteAtCursorResp(PasteAtCursorResult::Err(e)) => {
+			assert_eq!(e, EditrError::PermissionDenied(None).to_string())
+		}
+		other => panic!("Unexpected response {:?}", other),
+	}
+	assert!(is_denied(client.create("new.txt", false, None, false)));
+	assert!(is_denied(client.mkdir("dir", false)));
+	assert!(is_denied(client.rename("file.txt", "moved.txt")));
+	assert!(is_denied(client.delete("file.txt")));
+
+	// Nothing was touched, and the watcher only saw the editor leave
+	assert!(editor.close().is_ok());
+	assert!(matches!(watcher.next_broadcast(), Message::PeerLeft(_)));
+	assert_eq!(watcher.read(0, usize::MAX).unwrap(), b"contents");
+	let home = server.stop();
+	let mut files: Vec<_> = std::fs::read_dir(&home)
+		.unwrap()
+		.map(|entry| entry.unwrap().file_name())
+		.collect();
+	files.sort();
+	assert_eq!(std::fs::read(home.join("file.txt")).unwrap(), b"contents");
+	std::fs::remove_dir_all(&home).ok();
+	assert_eq!(files, ["file.txt"]);
+}
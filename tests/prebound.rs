@@ -0,0 +1,80 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::net::{TcpListener, TcpStream};
+
+use editr::config::ServerConfig;
+use editr::text_server::serve;
+
+use common::{temp_home, Peer};
+
+#[test]
+fn servers_accept_from_listeners_handed_to_them() {
+	let home = temp_home();
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let handle = serve(&home, listener, ServerConfig::default()).unwrap();
+	assert_eq!(handle.local_addr(), address);
+
+	let peer = Peer::new(TcpStream::connect(address).unwrap());
+	peer.ping().unwrap();
+	handle.stop().unwrap();
+	std::fs::remove_dir_all(home).ok();
+}
+
+// The binary takes its listener from a supervisor, as systemd passes it down
+#[cfg(unix)]
+#[test]
+fn the_server_accepts_from_an_activated_socket() {
+	use std::io::{BufRead, BufReader};
+	use std::os::unix::io::AsRawFd;
+	use std::os::unix::process::CommandExt;
+	use std::process::{Command, Stdio};
+
+	let home = temp_home();
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let fd = listener.as_raw_fd();
+
+	// The shell execs the server, so the pid it names is the server's
+	let mut command = Command::new("sh");
+	command
+		.arg("-c")
+		.arg("export LISTEN_PID=$$ LISTEN_FDS=1; exec \"$@\"")
+		.arg("sh")
+		.arg(env!("CARGO_BIN_EXE_server"))
+		.arg(&home)
+		.arg("--socket-activation")
+		.stdout(Stdio::piped());
+	// Passed descriptors start at 3, and must stay open across the exec
+	unsafe {
+		command.pre_exec(move || {
+			let passed = match fd {
+				3 => libc::fcntl(3, libc::F_SETFD, 0),
+				_ => libc::dup2(fd, 3),
+			};
+			match passed {
+				-1 => Err(std::io::Error::last_os_error()),
+				_ => Ok(()),
+			}
+		});
+	}
+	let mut server = command.spawn().expect("Failed to run server");
+	drop(listener);
+
+	let mut stdout = BufReader::new(server.stdout.take().unwrap()).lines();
+	let listening = stdout
+		.by_ref()
+		.map(Result::unwrap)
+		.find(|line| line.starts_with("LISTENING "));
+	let served = listening.as_deref() == Some(&*format!("LISTENING {}", address))
+		&& Peer::new(TcpStream::connect(address).unwrap())
+			.ping()
+			.is_ok();
+
+	server.kill().ok();
+	server.wait().ok();
+	std::fs::remove_dir_all(home).ok();
+	assert!(served, "Server listened on {:?}", listening);
+}
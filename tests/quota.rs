@@ -0,0 +1,122 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use editr::config::{Durability, ServerConfig};
+use editr::error::EditrError;
+use editr::message::{Message, UsageData, UsageResult};
+use editr::state::{FileStates, SyncHook};
+use editr::text_client::Client;
+
+use common::TestServer;
+
+fn config(quota: u64) -> ServerConfig {
+	ServerConfig {
+		quota: Some(quota),
+		..ServerConfig::default()
+	}
+}
+
+fn is_quota_exceeded<T>(result: Result<T, EditrError>) -> bool {
+	matches!(result, Err(EditrError::Rejected(e)) if e.contains("Quota exceeded"))
+}
+
+fn usage(client: &Client) -> UsageData {
+	match client.request(Message::UsageReq).unwrap() {
+		Message::UsageResp(UsageResult::Ok(usage)) => usage,
+		other => panic!("Unexpected response {:?}", other),
+	}
+}
+
+#[test]
+fn saves_past_the_quota_are_refused() {
+	let server = TestServer::with_config(config(100));
+	let client = server.open("file.txt", b"0123456789");
+
+	client.write_at(10, &[b'a'; 90]).unwrap();
+	client.save().unwrap();
+	assert_eq!(usage(&client).used, 100);
+	assert_eq!(usage(&client).limit, Some(100));
+
+	client.write_at(0, b"one too many").unwrap();
+	assert!(is_quota_exceeded(client.save()));
+	// What was on disk is left as it was
+	let on_disk = fs::read(server.home().join("file.txt")).unwrap();
+	assert_eq!(on_disk.len(), 100);
+	assert!(on_disk.starts_with(b"0123456789aaa"));
+
+	// Shrinking back within the quota can be saved
+	client.remove(0, 20).unwrap();
+	client.save().unwrap();
+	assert_eq!(usage(&client).used, 92);
+}
+
+#[test]
+fn refused_create_leaves_nothing_behind() {
+	let server = TestServer::with_config(config(10));
+	let client = server.connect();
+
+	let contents = Some(vec![b'a'; 20]);
+	assert!(is_quota_exceeded(client.create(
+		"a/b/c.txt",
+		true,
+		contents,
+		false
+	)));
+	assert!(!server.home().join("a").exists());
+
+	client
+		.create("a/b/c.txt", true, Some(b"fits".to_vec()), false)
+		.unwrap();
+	assert_eq!(fs::read(server.home().join("a/b/c.txt")).unwrap(), b"fits");
+	assert_eq!(usage(&client).used, 4);
+}
+
+// Fails every sync while failing is set
+#[derive(Default)]
+struct FailingSync {
+	failing: AtomicBool,
+}
+
+impl SyncHook for FailingSync {
+	fn sync_file(&self, _: &File) -> io::Result<()> {
+		if self.failing.load(Ordering::SeqCst) {
+			return Err(io::Error::other("Sync failed"));
+		}
+		Ok(())
+	}
+
+	fn sync_dir(&self, _: &Path) -> io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn failed_saves_give_back_their_reservation() {
+	let sync = Arc::new(FailingSync::default());
+	let files = FileStates::new().synced_with(sync.clone());
+	let config = ServerConfig {
+		durability: Durability::Fsync,
+		..config(100)
+	};
+	let server = TestServer::with_files(config, files);
+	let client = server.open("file.txt", b"");
+
+	client.write_at(0, &[b'a'; 60]).unwrap();
+	sync.failing.store(true, Ordering::SeqCst);
+	for _ in 0..5 {
+		assert!(client.save().is_err());
+		assert!(client.save_as("copy.txt", false).is_err());
+	}
+	assert_eq!(usage(&client).used, 0);
+
+	sync.failing.store(false, Ordering::SeqCst);
+	client.save().unwrap();
+	assert_eq!(usage(&client).used, 60);
+	assert!(is_quota_exceeded(client.save_as("copy.txt", false)));
+	assert_eq!(usage(&client).used, 60);
+}
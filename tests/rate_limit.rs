@@ -0,0 +1,83 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use editr::config::{RateLimit, RateLimitAction, ServerConfig};
+use editr::message::{AdminListResult, Message};
+
+use common::TestServer;
+
+const ADMIN_TOKEN: &str = "admin";
+
+fn limited(action: RateLimitAction) -> TestServer {
+	TestServer::with_config(ServerConfig {
+		rate_limit: Some(RateLimit {
+			messages_per_sec: 20,
+			bytes_per_sec: 1 << 20,
+			action,
+		}),
+		admin_token: Some(ADMIN_TOKEN.to_string()),
+		..ServerConfig::default()
+	})
+}
+
+#[test]
+fn floods_are_refused_past_the_limit() {
+	let server = limited(RateLimitAction::Reject);
+	let flooder = server.connect();
+	let refused = (0..100).filter(|_| flooder.ping().is_err()).count();
+	// A second's worth gets through, and a little more as the bucket refills
+	assert!(refused >= 60, "Only {} of 100 refused", refused);
+
+	// Other connections have limits of their own
+	let polite = server.connect();
+	for _ in 0..10 {
+		polite.ping().unwrap();
+	}
+
+	// What was refused is counted against the flooder
+	let listed = match polite
+		.request(Message::AdminListReq(ADMIN_TOKEN.to_string()))
+		.unwrap()
+	{
+		Message::AdminListResp(AdminListResult::Ok(connections)) => connections,
+		other => panic!("Unexpected response {:?}", other),
+	};
+	let throttled: Vec<u64> = listed
+		.iter()
+		.map(|connection| connection.metrics.throttled)
+		.collect();
+	assert!(throttled.contains(&(refused as u64)));
+}
+
+#[test]
+fn floods_are_slowed_to_the_limit() {
+	let server = limited(RateLimitAction::Delay);
+	let flooder = server.connect();
+	let polite = server.connect();
+
+	let flood = thread::spawn(move || {
+		let started = Instant::now();
+		for _ in 0..40 {
+			flooder.ping().unwrap();
+		}
+		started.elapsed()
+	});
+	// The flood doesn't hold up anyone else
+	let started = Instant::now();
+	for _ in 0..10 {
+		polite.ping().unwrap();
+	}
+	assert!(started.elapsed() < Duration::from_millis(400));
+
+	// Past the first second's worth, pings go at 20 a second
+	let elapsed = flood.join().unwrap();
+	assert!(
+		elapsed >= Duration::from_millis(900),
+		"Flood took {:?}",
+		elapsed
+	);
+}
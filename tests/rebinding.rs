@@ -0,0 +1,71 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use editr::config::{BindRetry, ServerConfig};
+use editr::text_server::spawn;
+
+use common::{temp_home, Peer};
+
+#[test]
+fn restarted_servers_rebind_straight_away() {
+	let home = temp_home();
+	let first = spawn(&home, "127.0.0.1:0", ServerConfig::default()).unwrap();
+	let address = first.local_addr();
+	// The server closing connections first leaves them in TIME_WAIT
+	let peer = Peer::new(TcpStream::connect(address).unwrap());
+	peer.ping().unwrap();
+	first.stop().unwrap();
+
+	let second = spawn(&home, address, ServerConfig::default()).unwrap();
+	Peer::new(TcpStream::connect(address).unwrap())
+		.ping()
+		.unwrap();
+	second.stop().unwrap();
+	std::fs::remove_dir_all(home).ok();
+}
+
+#[test]
+fn binding_is_retried_until_the_address_is_free() {
+	let home = temp_home();
+	let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = taken.local_addr().unwrap();
+	let releaser = thread::spawn(move || {
+		thread::sleep(Duration::from_millis(300));
+		drop(taken);
+	});
+
+	let config = ServerConfig {
+		bind_retry: Some(BindRetry {
+			attempts: 5,
+			delay: Duration::from_millis(100),
+		}),
+		..ServerConfig::default()
+	};
+	let handle = spawn(&home, address, config).unwrap();
+	releaser.join().unwrap();
+	assert_eq!(handle.local_addr(), address);
+	handle.stop().unwrap();
+	std::fs::remove_dir_all(home).ok();
+}
+
+#[test]
+fn giving_up_names_the_address() {
+	let home = temp_home();
+	let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = taken.local_addr().unwrap();
+	let config = ServerConfig {
+		bind_retry: Some(BindRetry {
+			attempts: 2,
+			delay: Duration::from_millis(10),
+		}),
+		..ServerConfig::default()
+	};
+	let e = spawn(&home, address, config).err().unwrap();
+	std::fs::remove_dir_all(home).ok();
+	assert!(e.to_string().contains(&address.to_string()), "{}", e);
+}
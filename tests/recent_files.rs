@@ -0,0 +1,79 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+
+use editr::message::{Message, RecentFilesResult};
+use editr::text_client::Client;
+
+use common::TestServer;
+
+// Each recent file as path and whether it still exists
+fn recent(client: &Client) -> Vec<(String, bool)> {
+	match client.request(Message::RecentFilesReq).unwrap() {
+		Message::RecentFilesResp(RecentFilesResult::Ok(files)) => files
+			.into_iter()
+			.map(|file| (file.path, file.exists))
+			.collect(),
+		other => panic!("Unexpected response {:?}", other),
+	}
+}
+
+fn entry(path: &str, exists: bool) -> (String, bool) { (path.to_string(), exists) }
+
+#[test]
+fn most_recent_comes_first_without_repeats() {
+	let server = TestServer::start();
+	for name in ["a.txt", "b.txt", "c.txt"] {
+		fs::write(server.home().join(name), "").unwrap();
+	}
+	let client = server.connect();
+	assert!(recent(&client).is_empty());
+
+	for name in ["a.txt", "b.txt", "a.txt", "c.txt"] {
+		client.open(name, None).unwrap();
+	}
+	// Closing the file leaves it in the list
+	client.close().unwrap();
+	assert_eq!(
+		recent(&client),
+		[
+			entry("c.txt", true),
+			entry("a.txt", true),
+			entry("b.txt", true)
+		]
+	);
+}
+
+#[test]
+fn the_list_is_bounded() {
+	let server = TestServer::start();
+	let client = server.connect();
+	for index in 0..30 {
+		let name = format!("file{}.txt", index);
+		fs::write(server.home().join(&name), "").unwrap();
+		client.open(&name, None).unwrap();
+	}
+	let listed = recent(&client);
+	assert_eq!(listed.len(), 20);
+	assert_eq!(listed[0], entry("file29.txt", true));
+	assert_eq!(listed[19], entry("file10.txt", true));
+}
+
+#[test]
+fn deleted_files_are_marked_missing() {
+	let server = TestServer::start();
+	let client = server.open("gone.txt", b"");
+	client.close().unwrap();
+	fs::remove_file(server.home().join("gone.txt")).unwrap();
+	assert_eq!(recent(&client), [entry("gone.txt", false)]);
+}
+
+#[test]
+fn each_connection_has_its_own_list() {
+	let server = TestServer::start();
+	let _first = server.open("file.txt", b"");
+	let second = server.connect();
+	assert!(recent(&second).is_empty());
+}
@@ -0,0 +1,76 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use editr::message::{Message, PasteAtCursorResult, UpdateData, YankReqData, YankResult};
+use editr::text_client::Client;
+
+use common::TestServer;
+
+fn yank(client: &Client, offset: usize, len: usize) -> Result<(), String> {
+	match client
+		.request(Message::YankReq(YankReqData { offset, len }))
+		.unwrap()
+	{
+		Message::YankResp(YankResult::Ok) => Ok(()),
+		Message::YankResp(YankResult::Err(e)) => Err(e),
+		other => panic!("Unexpected response {:?}", other),
+	}
+}
+
+fn paste(client: &Client) {
+	match client.request(Message::PasteAtCursorReq).unwrap() {
+		Message::PasteAtCursorResp(PasteAtCursorResult::Ok) => (),
+		other => panic!("Unexpected response {:?}", other),
+	}
+}
+
+#[test]
+fn yanked_text_pastes_into_another_file() {
+	let server = TestServer::start();
+	let client = server.open("one.txt", b"hello world");
+	yank(&client, 6, 5).unwrap();
+
+	let peer = server.open("two.txt", b"say ");
+	client.open("two.txt", None).unwrap();
+	peer.take_broadcasts();
+	client.move_cursor(4).unwrap();
+	paste(&client);
+
+	match peer.next_update() {
+		UpdateData::Add(add) => {
+			assert_eq!(add.offset, 4);
+			assert_eq!(add.data, b"world");
+		}
+		other => panic!("Unexpected update {:?}", other),
+	}
+	assert_eq!(peer.read(0, 100).unwrap(), b"say world");
+}
+
+#[test]
+fn failed_yanks_keep_the_register() {
+	let server = TestServer::start();
+	let client = server.open("file.txt", b"abc");
+	yank(&client, 0, 1).unwrap();
+	assert_eq!(
+		yank(&client, 2, 10).unwrap_err(),
+		"Range 2..12 out of bounds for rope of length 3"
+	);
+	assert!(yank(&client, usize::MAX, 2).is_err());
+
+	paste(&client);
+	assert_eq!(client.read(0, 100).unwrap(), b"aabc");
+}
+
+#[test]
+fn cut_text_can_be_pasted_back() {
+	let server = TestServer::start();
+	let client = server.open("file.txt", b"abcdef");
+	client.move_cursor(3).unwrap();
+	client.remove_at_cursor(3).unwrap();
+	assert_eq!(client.read(0, 100).unwrap(), b"abc");
+
+	client.move_cursor(-3).unwrap();
+	paste(&client);
+	assert_eq!(client.read(0, 100).unwrap(), b"defabc");
+}
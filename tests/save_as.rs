@@ -0,0 +1,79 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+
+use editr::config::ServerConfig;
+use editr::error::EditrError;
+use editr::state::FileStates;
+
+use common::TestServer;
+
+fn rejected_with<T>(result: Result<T, EditrError>, reason: &str) -> bool {
+	matches!(result, Err(EditrError::Rejected(e)) if e.contains(reason))
+}
+
+#[test]
+fn copies_go_to_the_canonical_destination() {
+	let server = TestServer::start();
+	let client = server.open("file.txt", b"hello");
+	client.write_at(5, b" world").unwrap();
+
+	let dest = client.save_as("copy.txt", false).unwrap();
+	assert_eq!(dest, server.home().canonicalize().unwrap().join("copy.txt"));
+	assert_eq!(fs::read(&dest).unwrap(), b"hello world");
+	// The file being edited is left as it was on disk
+	assert_eq!(fs::read(server.home().join("file.txt")).unwrap(), b"hello");
+}
+
+#[test]
+fn existing_files_are_only_replaced_when_asked() {
+	let server = TestServer::start();
+	fs::write(server.home().join("copy.txt"), "old").unwrap();
+	let client = server.open("file.txt", b"new");
+
+	assert!(rejected_with(
+		client.save_as("copy.txt", false),
+		"Already exists"
+	));
+	assert_eq!(fs::read(server.home().join("copy.txt")).unwrap(), b"old");
+
+	client.save_as("copy.txt", true).unwrap();
+	assert_eq!(fs::read(server.home().join("copy.txt")).unwrap(), b"new");
+}
+
+#[test]
+fn destinations_outside_the_home_are_refused() {
+	let server = TestServer::start();
+	let client = server.open("file.txt", b"data");
+	let outside = server.home().parent().unwrap().join("editr-saved-outside");
+
+	assert!(client.save_as("../editr-saved-outside", true).is_err());
+	assert!(!outside.exists());
+}
+
+#[test]
+fn open_destinations_are_refused() {
+	let server = TestServer::start();
+	let _other = server.open("other.txt", b"other");
+	let client = server.open("file.txt", b"data");
+
+	assert!(rejected_with(client.save_as("other.txt", true), "busy"));
+	assert_eq!(fs::read(server.home().join("other.txt")).unwrap(), b"other");
+}
+
+#[test]
+fn the_source_stays_unsaved() {
+	let files = FileStates::new();
+	let server = TestServer::with_files(ServerConfig::default(), files.clone());
+	let client = server.open("file.txt", b"hello");
+	let path = client.open("file.txt", None).unwrap();
+
+	client.write_at(0, b"> ").unwrap();
+	client.save_as("copy.txt", false).unwrap();
+	assert!(files.get(&path).unwrap().is_dirty());
+
+	client.save().unwrap();
+	assert!(!files.get(&path).unwrap().is_dirty());
+}
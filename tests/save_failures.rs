@@ -0,0 +1,74 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+
+use editr::message::Message;
+
+use common::{Peer, TestServer};
+
+fn persist_failing(server: &TestServer) -> bool {
+	let stats = server.files().stats().unwrap();
+	stats.iter().any(|file| file.persist_failing)
+}
+
+// Waits for a SaveFailed broadcast, skipping updates
+fn save_failed(peer: &Peer) -> Message {
+	loop {
+		match peer.next_broadcast() {
+			Message::UpdateMessage(_) => (),
+			msg => return msg,
+		}
+	}
+}
+
+#[test]
+fn every_client_is_told_of_a_failed_save_until_one_succeeds() {
+	let server = TestServer::start();
+	let saver = server.open("file.txt", b"hello");
+	let other = server.open("file.txt", b"hello");
+	saver.write_at(5, b" world").unwrap();
+
+	// Nothing can be renamed over a directory, whoever the server runs as
+	let path = server.home().join("file.txt");
+	fs::remove_file(&path).unwrap();
+	fs::create_dir(&path).unwrap();
+	assert!(saver.save().is_err());
+
+	for peer in [&saver, &other] {
+		match save_failed(peer) {
+			Message::SaveFailed(failed) => assert!(failed.path.ends_with("file.txt")),
+			msg => panic!("Unexpected broadcast {:?}", msg),
+		}
+	}
+	assert!(persist_failing(&server));
+
+	fs::remove_dir(&path).unwrap();
+	saver.save().unwrap();
+	assert_eq!(fs::read(&path).unwrap(), b"hello world");
+	assert!(!persist_failing(&server));
+}
+
+#[test]
+fn clients_are_told_when_saving_on_shutdown_fails() {
+	let server = TestServer::start();
+	let first = server.open("file.txt", b"hello");
+	let second = server.open("file.txt", b"hello");
+	first.write_at(5, b" world").unwrap();
+	second.next_update();
+
+	let path = server.home().join("file.txt");
+	fs::remove_file(&path).unwrap();
+	fs::create_dir(&path).unwrap();
+	let home = server.stop();
+
+	for peer in [&first, &second] {
+		let told = peer
+			.broadcasts_until_closed()
+			.into_iter()
+			.any(|msg| matches!(msg, Message::SaveFailed(_)));
+		assert!(told);
+	}
+	fs::remove_dir_all(home).ok();
+}
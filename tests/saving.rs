@@ -0,0 +1,74 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+use std::thread;
+
+use common::TestServer;
+
+// Names in home other than those given, such as staging files left behind
+fn others(server: &TestServer, expected: &[&str]) -> Vec<String> {
+	fs::read_dir(server.home())
+		.unwrap()
+		.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+		.filter(|name| !expected.contains(&name.as_str()))
+		.collect()
+}
+
+#[test]
+fn saving_leaves_files_named_like_staging_files_alone() {
+	let server = TestServer::start();
+	fs::write(server.home().join(".file.txt.editr-tmp"), b"mine").unwrap();
+	let client = server.open("file.txt", b"old");
+
+	client.write_at(3, b" and new").unwrap();
+	client.save().unwrap();
+	client.save_as("copy.txt", false).unwrap();
+	client
+		.create("made.txt", false, Some(b"made".to_vec()), false)
+		.unwrap();
+
+	assert_eq!(
+		fs::read(server.home().join("file.txt")).unwrap(),
+		b"old and new"
+	);
+	assert_eq!(
+		fs::read(server.home().join("copy.txt")).unwrap(),
+		b"old and new"
+	);
+	assert_eq!(
+		fs::read(server.home().join(".file.txt.editr-tmp")).unwrap(),
+		b"mine"
+	);
+	assert!(others(
+		&server,
+		&["file.txt", "copy.txt", "made.txt", ".file.txt.editr-tmp"]
+	)
+	.is_empty());
+}
+
+#[test]
+fn concurrent_saves_to_one_path_never_mix() {
+	let server = TestServer::start();
+	let contents = [vec![b'a'; 200_000], vec![b'b'; 300_000]];
+	let clients = ["a.txt", "b.txt"]
+		.iter()
+		.zip(&contents)
+		.map(|(file, contents)| server.open(file, contents))
+		.collect::<Vec<_>>();
+
+	thread::scope(|scope| {
+		for client in clients {
+			scope.spawn(move || {
+				for _ in 0..20 {
+					client.save_as("target.txt", true).unwrap();
+				}
+			});
+		}
+	});
+
+	let saved = fs::read(server.home().join("target.txt")).unwrap();
+	assert!(contents.contains(&saved));
+	assert!(others(&server, &["a.txt", "b.txt", "target.txt"]).is_empty());
+}
@@ -0,0 +1,64 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+
+use editr::error::EditrError;
+
+use common::{Replica, TestServer};
+
+#[test]
+fn clients_co_edit_a_scratch_buffer() {
+	let server = TestServer::start();
+	let first = server.connect();
+	let path = first.open_scratch("untitled").unwrap();
+	let second = server.connect();
+	second.open(path.to_str().unwrap(), None).unwrap();
+
+	first.write_at(0, b"hello").unwrap();
+	let mut replica = Replica::new(b"");
+	while replica.data != b"hello" {
+		replica.apply(&second.next_update());
+	}
+	second.write_at(5, b" world").unwrap();
+	first.next_update();
+	assert_eq!(first.read(0, 11).unwrap(), b"hello world");
+
+	// Scratch buffers live only in memory, so never show up in the home
+	assert!(first.list_files().unwrap().is_empty());
+	assert_eq!(fs::read_dir(server.home()).unwrap().count(), 0);
+}
+
+#[test]
+fn scratch_buffers_are_only_saved_as_a_new_file() {
+	let server = TestServer::start();
+	let client = server.connect();
+	client.open_scratch("untitled").unwrap();
+	client.write_at(0, b"keep me").unwrap();
+
+	match client.save() {
+		Err(EditrError::Rejected(e)) => assert!(e.contains("SaveAs"), "{}", e),
+		other => panic!("Unexpected result {:?}", other),
+	}
+	let saved = client.save_as("kept.txt", false).unwrap();
+	assert_eq!(fs::read(&saved).unwrap(), b"keep me");
+	assert_eq!(client.list_files().unwrap(), ["kept.txt"]);
+}
+
+#[test]
+fn the_last_client_leaving_evicts_a_scratch_buffer() {
+	let server = TestServer::start();
+	let first = server.connect();
+	let path = first.open_scratch("untitled").unwrap();
+	let second = server.connect();
+	second.open(path.to_str().unwrap(), None).unwrap();
+
+	first.close().unwrap();
+	assert!(server.files().contains(&path).unwrap());
+	second.close().unwrap();
+	assert!(!server.files().contains(&path).unwrap());
+
+	// Nothing is left to join
+	assert!(second.open(path.to_str().unwrap(), None).is_err());
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "client")]
+
+use std::process::Command;
+
+use editr::text_server::{self_test, SelfTestConfig};
+
+#[test]
+fn concurrent_clients_converge() {
+	let report = self_test(SelfTestConfig {
+		clients: 4,
+		operations: 200,
+		seed: Some(42),
+		..SelfTestConfig::default()
+	})
+	.unwrap();
+
+	assert_eq!(report.seed, 42);
+	assert_eq!(report.clients, 4);
+	assert!(report.edits > 0);
+	assert!(report.p50 <= report.p90);
+	assert!(report.p90 <= report.p99);
+	assert!(report.p99 <= report.max);
+}
+
+#[test]
+fn failures_name_the_seed() {
+	let e = self_test(SelfTestConfig {
+		clients: 0,
+		seed: Some(7),
+		..SelfTestConfig::default()
+	})
+	.unwrap_err();
+	assert!(e.to_string().contains("seed 7"), "{}", e);
+}
+
+#[test]
+fn the_server_runs_it_when_asked() {
+	let output = Command::new(env!("CARGO_BIN_EXE_server"))
+		.args(["--self-test", "--self-test-clients", "2"])
+		.args(["--self-test-operations", "50", "--seed", "5"])
+		.output()
+		.expect("Failed to run the server");
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert!(output.status.success(), "{}", stdout);
+	assert!(stdout.contains("Self test passed: 2 clients"), "{}", stdout);
+	assert!(stdout.contains("Seed: 5"), "{}", stdout);
+}
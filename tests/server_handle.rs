@@ -0,0 +1,53 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::net::{TcpListener, TcpStream};
+
+use editr::config::ServerConfig;
+use editr::text_server::spawn;
+
+use common::{temp_home, Peer};
+
+#[test]
+fn binding_port_zero_gets_a_port_of_its_own() {
+	let (first_home, second_home) = (temp_home(), temp_home());
+	let first = spawn(&first_home, "127.0.0.1:0", ServerConfig::default()).unwrap();
+	let second = spawn(&second_home, "127.0.0.1:0", ServerConfig::default()).unwrap();
+
+	assert_ne!(first.local_addr().port(), 0);
+	assert_ne!(first.local_addr(), second.local_addr());
+	for handle in [&first, &second] {
+		let peer = Peer::new(TcpStream::connect(handle.local_addr()).unwrap());
+		peer.ping().unwrap();
+	}
+
+	first.stop().unwrap();
+	second.stop().unwrap();
+	std::fs::remove_dir_all(first_home).ok();
+	std::fs::remove_dir_all(second_home).ok();
+}
+
+#[test]
+fn stopping_returns_once_the_server_is_down() {
+	let home = temp_home();
+	let handle = spawn(&home, "127.0.0.1:0", ServerConfig::default()).unwrap();
+	let address = handle.local_addr();
+	let peer = Peer::new(TcpStream::connect(address).unwrap());
+	peer.ping().unwrap();
+
+	handle.stop().unwrap();
+	assert!(peer.ping().is_err());
+	// The port is free to be bound again
+	TcpListener::bind(address).unwrap();
+	std::fs::remove_dir_all(home).ok();
+}
+
+#[test]
+fn failing_to_bind_is_returned() {
+	let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+	let home = temp_home();
+	let result = spawn(&home, taken.local_addr().unwrap(), ServerConfig::default());
+	std::fs::remove_dir_all(home).ok();
+	assert!(result.is_err());
+}
@@ -0,0 +1,92 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use editr::message::{Message, ResumeResult, StatusResult, UpdateAdd, UpdateData};
+
+use common::{Peer, Replica, TestServer};
+
+// Resumes the session behind token, waiting for the server to notice that
+// its last connection has gone
+fn resume(server: &TestServer, token: &str) -> Peer {
+	let deadline = Instant::now() + Duration::from_secs(5);
+	loop {
+		let peer = server.connect();
+		match peer.request(Message::ResumeReq(token.to_string())).unwrap() {
+			Message::ResumeResp(ResumeResult::Ok(_)) => return peer,
+			Message::ResumeResp(ResumeResult::Err(e)) => {
+				assert!(Instant::now() < deadline, "Failed to resume: {}", e)
+			}
+			other => panic!("Unexpected response {:?}", other),
+		}
+		sleep(Duration::from_millis(20));
+	}
+}
+
+// Waits for the server to have finished with all but count connections
+fn wait_for_connections(peer: &Peer, count: u64) {
+	let deadline = Instant::now() + Duration::from_secs(5);
+	loop {
+		match peer.request(Message::StatusReq).unwrap() {
+			Message::StatusResp(StatusResult::Ok(status)) if status.connections == count => return,
+			Message::StatusResp(StatusResult::Ok(_)) => {
+				assert!(Instant::now() < deadline, "Connections were never closed")
+			}
+			other => panic!("Unexpected response {:?}", other),
+		}
+		sleep(Duration::from_millis(20));
+	}
+}
+
+#[test]
+fn dropped_connection_resumes_where_it_was() {
+	let server = TestServer::start();
+	let typist = server.open("file.txt", b"hello");
+	let token = typist.hello().unwrap().session;
+	let watcher = server.open("file.txt", b"");
+	let mut replica = Replica::new(b"hello");
+
+	typist.move_cursor(5).unwrap();
+	typist.write_at_cursor(b"!").unwrap();
+	replica.follow(&watcher, 1);
+	drop(typist);
+	// Updates are only held for the session once it has been parked
+	wait_for_connections(&watcher, 1);
+
+	// Made while the typist was away, and delivered once it is back
+	watcher.write_at_cursor(b">").unwrap();
+	replica.apply(&UpdateData::Add(UpdateAdd {
+		offset: 0,
+		data: b">".to_vec(),
+	}));
+
+	let typist = resume(&server, &token);
+	let update = typist.next_update();
+	assert!(matches!(update, UpdateData::Add(add) if add.offset == 0));
+	// The cursor was kept, and moved along by the edit made meanwhile
+	assert_eq!(typist.cursors().unwrap().0, 7);
+
+	typist.write_at_cursor(b"?").unwrap();
+	replica.follow(&watcher, 1);
+	assert_eq!(replica.data, b">hello!?");
+	assert_eq!(typist.read(0, usize::MAX).unwrap(), replica.data);
+	assert_eq!(watcher.read(0, usize::MAX).unwrap(), replica.data);
+}
+
+#[test]
+fn unknown_tokens_do_not_resume() {
+	let server = TestServer::start();
+	let peer = server.connect();
+	let response = peer
+		.request(Message::ResumeReq(
+			"0123456789abcdef0123456789abcdef".to_string(),
+		))
+		.unwrap();
+	assert!(matches!(
+		response,
+		Message::ResumeResp(ResumeResult::Err(_))
+	));
+}
@@ -0,0 +1,28 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+use std::net::TcpStream;
+
+use editr::message::Message;
+
+use common::TestServer;
+
+#[test]
+fn stopping_saves_unsaved_edits() {
+	let server = TestServer::start();
+	let client = server.open("file.txt", b"hello");
+	client.write_at(5, b" world").unwrap();
+	let address = server.local_addr();
+
+	let home = server.stop();
+	let saved = fs::read(home.join("file.txt"));
+	fs::remove_dir_all(&home).ok();
+	assert_eq!(saved.unwrap(), b"hello world");
+
+	// Clients were warned, then let go, and nothing new is accepted
+	assert!(matches!(client.next_broadcast(), Message::ServerClosing));
+	assert!(client.ping().is_err());
+	assert!(TcpStream::connect(address).is_err());
+}
@@ -0,0 +1,45 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::TestServer;
+
+// Large writes to fill the stalled client's socket buffers, so its writer
+// blocks, then enough small ones to fill its queue
+const LARGE_WRITES: usize = 40;
+const LARGE_WRITE_LEN: usize = 1 << 16;
+const SMALL_WRITES: usize = 2000;
+
+#[test]
+fn a_stalled_reader_is_dropped_without_slowing_others() {
+	let server = TestServer::start();
+	let typist = server.open("file.txt", b"");
+	let watcher = server.open("file.txt", b"");
+
+	// Opens the file and then never reads a thing
+	let (stalled, wire) = server.connect_wire();
+	stalled.open("file.txt", None).unwrap();
+	wire.stall();
+
+	let large = vec![b'x'; LARGE_WRITE_LEN];
+	let writes = (0..LARGE_WRITES)
+		.map(|_| &large[..])
+		.chain((0..SMALL_WRITES).map(|_| &b"y"[..]));
+	let mut slowest = Duration::ZERO;
+	for data in writes {
+		let sent = Instant::now();
+		typist.write_at(0, data).unwrap();
+		slowest = slowest.max(sent.elapsed());
+		// Keeps the watcher from falling behind itself
+		watcher.take_broadcasts();
+	}
+	println!("Slowest write took {:?}", slowest);
+	assert!(slowest < Duration::from_secs(1), "Took {:?}", slowest);
+	watcher.ping().unwrap();
+
+	// The server gave up on the stalled client, so what it was sent ends
+	wire.resume();
+	stalled.broadcasts_until_closed();
+}
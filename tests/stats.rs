@@ -0,0 +1,64 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use editr::config::ServerConfig;
+use editr::state::StatsReport;
+
+use common::{temp_home, TestServer};
+
+fn reports(path: &Path) -> Vec<StatsReport> {
+	fs::read_to_string(path)
+		.unwrap_or_default()
+		.lines()
+		.map(|line| serde_json::from_str(line).expect("Malformed report"))
+		.collect()
+}
+
+#[test]
+fn sessions_are_summed_up_every_interval() {
+	let stats_dir = temp_home();
+	let stats_path = stats_dir.join("stats.jsonl");
+	let server = TestServer::with_config(ServerConfig {
+		metrics_log: Duration::from_millis(200),
+		metrics_file: Some(stats_path.clone()),
+		..ServerConfig::default()
+	});
+	let client = server.open("file.txt", b"");
+	for offset in 0..5 {
+		client.write_at(offset, b"x").unwrap();
+	}
+	assert!(client.remove(100, 1).is_err());
+
+	// Waits for a report made after the session
+	let started = Instant::now();
+	while !reports(&stats_path)
+		.iter()
+		.any(|report| report.total_errors.get("RemoveReq") == Some(&1))
+	{
+		assert!(started.elapsed() < Duration::from_secs(5), "No report");
+		sleep(Duration::from_millis(50));
+	}
+	fs::remove_dir_all(server.stop()).ok();
+
+	let reports = reports(&stats_path);
+	assert!(reports
+		.iter()
+		.any(|report| report.open_files == 1 && report.rope_bytes == 5));
+	// Operations are counted once each, in whichever report followed them
+	let operations: u64 = reports.iter().map(|report| report.operations).sum();
+	let errors: u64 = reports.iter().map(|report| report.errors).sum();
+	assert!(operations >= 7, "{} operations", operations);
+	assert_eq!(errors, 1);
+	assert!(reports.iter().any(|report| report.connections == 1));
+
+	// The reporter stopped along with the server
+	sleep(Duration::from_millis(400));
+	assert_eq!(self::reports(&stats_path).len(), reports.len());
+	fs::remove_dir_all(&stats_dir).ok();
+}
@@ -0,0 +1,181 @@
+#![cfg(all(feature = "client", unix))]
+
+mod common;
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Output, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use editr::message::Message;
+use editr::state::Transport;
+use editr::text_client::Client;
+
+use common::temp_home;
+
+// The server's stdin and stdout, as a client sees them. Shutting down
+// closes stdin, which is how the client says it has gone
+#[derive(Clone)]
+struct Pipes {
+	input: Arc<Mutex<Option<ChildStdin>>>,
+	output: Arc<Mutex<ChildStdout>>,
+}
+
+impl Read for Pipes {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.output.lock().read(buf) }
+}
+
+impl Write for Pipes {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match &mut *self.input.lock() {
+			Some(input) => input.write(buf),
+			None => Err(io::ErrorKind::BrokenPipe.into()),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match &mut *self.input.lock() {
+			Some(input) => input.flush(),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Transport for Pipes {
+	fn try_clone(&self) -> io::Result<Self> { Ok(self.clone()) }
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+		if how != Shutdown::Read {
+			self.input.lock().take();
+		}
+		Ok(())
+	}
+
+	fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> { Ok(()) }
+
+	fn peer_addr(&self) -> Option<SocketAddr> { None }
+}
+
+// Starts the server on home over its stdio, with a client on the other end
+fn spawn(home: &std::path::Path) -> (Child, Client<Pipes>) {
+	let mut child = Command::new(env!("CARGO_BIN_EXE_server"))
+		.arg("--stdio")
+		.arg(home)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.expect("Failed to run the server");
+	let pipes = Pipes {
+		input: Arc::new(Mutex::new(child.stdin.take())),
+		output: Arc::new(Mutex::new(child.stdout.take().unwrap())),
+	};
+	(child, Client::new(pipes).unwrap())
+}
+
+#[test]
+fn a_whole_session_runs_over_stdio() {
+	let home = temp_home();
+	fs::write(home.join("file.txt"), "hello").unwrap();
+	let (child, client) = spawn(&home);
+
+	client.ping().unwrap();
+	assert!(client
+		.list_files()
+		.unwrap()
+		.contains(&"file.txt".to_string()));
+	client.open("file.txt", None).unwrap();
+	client.write_at(5, b" world").unwrap();
+	assert_eq!(client.read(0, 100).unwrap(), b"hello world");
+	client.save().unwrap();
+	assert_eq!(fs::read(home.join("file.txt")).unwrap(), b"hello world");
+
+	// Left unsaved, so saved as the server goes
+	client.write_at(0, b">").unwrap();
+	client.disconnect().unwrap();
+	let output = child.wait_with_output().unwrap();
+	assert!(output.status.success(), "{:?}", output);
+	assert_eq!(fs::read(home.join("file.txt")).unwrap(), b">hello world");
+	fs::remove_dir_all(home).ok();
+}
+
+// Runs the server over stdio with its output logged and extra arguments,
+// sending it a ping and closing its input. Returns what it wrote to stdout
+// and stderr, and what it logged
+fn ping_logged(extra: &[&str]) -> (Output, String) {
+	let home = temp_home();
+	let log = home.join("editr.log");
+	let mut child = Command::new(env!("CARGO_BIN_EXE_server"))
+		.arg("--stdio")
+		.arg(&home)
+		.arg("--stdio-log")
+		.arg(&log)
+		.args(extra)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.expect("Failed to run the server");
+	let mut ping = Message::Ping.to_vec().unwrap();
+	ping.push(b'\n');
+	let mut stdin = child.stdin.take().unwrap();
+	stdin.write_all(&ping).unwrap();
+	drop(stdin);
+
+	let output = child.wait_with_output().unwrap();
+	assert!(output.status.success(), "{:?}", output);
+	let logged = fs::read_to_string(&log).unwrap();
+	fs::remove_dir_all(home).ok();
+	(output, logged)
+}
+
+#[test]
+fn output_goes_to_the_log_rather_than_the_client() {
+	let (output, logged) = ping_logged(&[]);
+	// Nothing but messages reached the client, though each was logged
+	let sent = output
+		.stdout
+		.split(|byte| *byte == b'\n')
+		.filter(|line| !line.is_empty())
+		.map(|line| Message::from_slice(line).unwrap())
+		.collect::<Vec<_>>();
+	assert!(
+		sent.iter().any(|msg| matches!(msg, Message::Pong)),
+		"{:?}",
+		sent
+	);
+	assert!(logged.contains("<=: Ping"), "{}", logged);
+	assert!(!logged.contains("WARN"), "{}", logged);
+	assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn slow_operations_are_logged_as_warnings() {
+	let (_, logged) = ping_logged(&["--slow-op", "0ms"]);
+	let warning = logged
+		.lines()
+		.find(|line| line.contains("Slow Ping"))
+		.unwrap_or_else(|| panic!("No warning in {}", logged));
+	assert!(warning.starts_with("WARN "), "{}", warning);
+}
+
+#[test]
+fn log_levels_leave_out_what_is_less_important() {
+	let (_, logged) = ping_logged(&["--slow-op", "0ms", "--log-level", "warn"]);
+	assert!(!logged.contains("<=: Ping"), "{}", logged);
+	assert!(!logged.contains("Shutting down"), "{}", logged);
+	assert!(
+		logged
+			.lines()
+			.any(|line| line.starts_with("WARN Slow Ping")),
+		"{}",
+		logged
+	);
+
+	let (_, logged) = ping_logged(&["--slow-op", "0ms", "--log-level", "error"]);
+	assert!(logged.is_empty(), "{}", logged);
+}
@@ -0,0 +1,126 @@
+#![cfg(all(feature = "client", unix))]
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+
+use editr::config::{ServerConfig, SymlinkPolicy};
+
+use common::{temp_home, TestServer};
+
+// A home holding notes.txt and docs/a.txt, with symlinks to each, one to
+// a directory outside the home, and one leading nowhere. Returns the
+// server and the outside directory
+fn fixture(symlinks: SymlinkPolicy) -> (TestServer, PathBuf) {
+	let home = fs::canonicalize(temp_home()).unwrap();
+	let outside = fs::canonicalize(temp_home()).unwrap();
+	fs::write(outside.join("secret.txt"), "secret").unwrap();
+	fs::write(home.join("notes.txt"), "notes").unwrap();
+	fs::create_dir(home.join("docs")).unwrap();
+	fs::write(home.join("docs/a.txt"), "a").unwrap();
+
+	symlink(home.join("notes.txt"), home.join("inner")).unwrap();
+	symlink(home.join("docs"), home.join("docs_link")).unwrap();
+	symlink(&outside, home.join("escape")).unwrap();
+	symlink(outside.join("secret.txt"), home.join("escape_file")).unwrap();
+	symlink(outside.join("missing.txt"), home.join("dangling")).unwrap();
+
+	let config = ServerConfig {
+		symlinks,
+		..ServerConfig::default()
+	};
+	(TestServer::in_home(home, config), outside)
+}
+
+#[test]
+fn links_leaving_the_home_are_never_followed() {
+	for policy in [SymlinkPolicy::WithinHome, SymlinkPolicy::Refuse] {
+		let (server, outside) = fixture(policy);
+		let client = server.connect();
+
+		assert!(client.open("escape_file", None).is_err());
+		assert!(client.open("escape/secret.txt", None).is_err());
+		assert!(client.delete("escape_file").is_err());
+		assert!(client.create("escape/new.txt", false, None, false).is_err());
+		assert!(client.mkdir("escape/sub", false).is_err());
+		assert!(client.rename("docs/a.txt", "escape/a.txt").is_err());
+		// Nor is one leading nowhere, as it could be created through
+		assert!(client.open("dangling", None).is_err());
+		assert!(client
+			.create("dangling", false, Some(b"x".to_vec()), false)
+			.is_err());
+
+		let mut outside_files = fs::read_dir(&outside)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name())
+			.collect::<Vec<_>>();
+		outside_files.sort();
+		assert_eq!(outside_files, ["secret.txt"]);
+		assert!(server.home().join("docs/a.txt").exists());
+
+		let listed = client.list_files().unwrap();
+		for hidden in ["escape", "escape/", "escape_file", "dangling"] {
+			assert!(!listed.iter().any(|name| name == hidden), "{:?}", listed);
+		}
+		fs::remove_dir_all(outside).ok();
+	}
+}
+
+#[test]
+fn links_within_the_home_are_followed_if_allowed() {
+	let (server, outside) = fixture(SymlinkPolicy::WithinHome);
+	let home = server.home();
+	let client = server.connect();
+
+	let opened = client.open("inner", None).unwrap();
+	assert_eq!(opened, home.join("notes.txt"));
+	assert_eq!(client.read(0, 100).unwrap(), b"notes");
+	client.save_as("docs_link/copy.txt", false).unwrap();
+	assert_eq!(fs::read(home.join("docs/copy.txt")).unwrap(), b"notes");
+	client.close().unwrap();
+
+	client
+		.create("docs_link/new.txt", false, Some(b"new".to_vec()), false)
+		.unwrap();
+	assert_eq!(fs::read(home.join("docs/new.txt")).unwrap(), b"new");
+	client.mkdir("docs_link/sub", false).unwrap();
+	assert!(home.join("docs/sub").is_dir());
+	client.rename("docs/a.txt", "docs_link/b.txt").unwrap();
+	assert!(home.join("docs/b.txt").exists());
+
+	let listed = client.list_files().unwrap();
+	assert!(listed.contains(&"inner".to_string()), "{:?}", listed);
+	assert!(listed.contains(&"docs_link/".to_string()), "{:?}", listed);
+	fs::remove_dir_all(outside).ok();
+}
+
+#[test]
+fn no_links_are_followed_if_refused() {
+	let (server, outside) = fixture(SymlinkPolicy::Refuse);
+	let home = server.home();
+	let client = server.connect();
+
+	assert!(client.open("inner", None).is_err());
+	assert!(client.open("docs_link/a.txt", None).is_err());
+	assert!(client.delete("inner").is_err());
+	assert!(client
+		.create("docs_link/new.txt", false, None, false)
+		.is_err());
+	assert!(client.mkdir("docs_link/sub", false).is_err());
+	assert!(client.rename("docs/a.txt", "docs_link/b.txt").is_err());
+	client.open("notes.txt", None).unwrap();
+	assert!(client.save_as("docs_link/copy.txt", false).is_err());
+
+	// Nothing was touched through the links
+	assert!(home.join("notes.txt").exists());
+	assert_eq!(fs::read_dir(home.join("docs")).unwrap().count(), 1);
+	let listed = client.list_files().unwrap();
+	for hidden in ["inner", "docs_link/", "docs_link"] {
+		assert!(!listed.iter().any(|name| name == hidden), "{:?}", listed);
+	}
+	// Plain paths still work
+	assert!(listed.contains(&"docs/".to_string()), "{:?}", listed);
+	fs::remove_dir_all(outside).ok();
+}
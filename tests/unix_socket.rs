@@ -0,0 +1,60 @@
+#![cfg(all(unix, feature = "client"))]
+
+mod common;
+
+use std::fs;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use common::{temp_home, Peer, Replica};
+use editr::config::ServerConfig;
+use editr::text_server::serve_unix;
+
+#[test]
+fn clients_edit_together_over_a_unix_socket() {
+	let home = temp_home();
+	let socket = home.join("editr.sock");
+	fs::write(home.join("file.txt"), b"hello").unwrap();
+	let server = serve_unix(&home, &socket, ServerConfig::default()).unwrap();
+
+	let writer = Peer::new(UnixStream::connect(&socket).unwrap());
+	let reader = Peer::new(UnixStream::connect(&socket).unwrap());
+	writer.open("file.txt", None).unwrap();
+	reader.open("file.txt", None).unwrap();
+	writer.write_at(5, b" world").unwrap();
+	let mut replica = Replica::new(b"hello");
+	replica.follow(&reader, 1);
+	assert_eq!(replica.data, b"hello world");
+	writer.save().unwrap();
+
+	server.stop().unwrap();
+	assert_eq!(fs::read(home.join("file.txt")).unwrap(), b"hello world");
+	// The socket goes along with the server
+	assert!(!socket.exists());
+	fs::remove_dir_all(&home).ok();
+}
+
+#[test]
+fn only_sockets_nothing_answers_on_are_replaced() {
+	let home = temp_home();
+	let socket = home.join("editr.sock");
+	// As left behind by a server which has gone away
+	drop(UnixListener::bind(&socket).unwrap());
+	assert!(socket.exists());
+	let server = serve_unix(&home, &socket, ServerConfig::default()).unwrap();
+	Peer::new(UnixStream::connect(&socket).unwrap())
+		.ping()
+		.unwrap();
+
+	// One still being served from is left to its server
+	assert!(serve_unix(&home, &socket, ServerConfig::default()).is_err());
+	Peer::new(UnixStream::connect(&socket).unwrap())
+		.ping()
+		.unwrap();
+	server.stop().unwrap();
+
+	let file = home.join("notes.txt");
+	fs::write(&file, b"mine").unwrap();
+	assert!(serve_unix(&home, &file, ServerConfig::default()).is_err());
+	assert_eq!(fs::read(&file).unwrap(), b"mine");
+	fs::remove_dir_all(&home).ok();
+}
@@ -0,0 +1,105 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use editr::config::ServerConfig;
+use editr::message::Message;
+
+use common::{Peer, TestServer};
+
+fn config(workers: usize) -> ServerConfig {
+	ServerConfig {
+		workers: Some(workers),
+		..ServerConfig::default()
+	}
+}
+
+#[derive(PartialEq, Debug)]
+enum Outcome {
+	Served,
+	// Told the server is busy before being disconnected
+	TurnedAway,
+	// Disconnected without being told why
+	Dropped,
+}
+
+fn outcome(peer: &Peer) -> Outcome {
+	match peer.ping() {
+		Ok(()) => Outcome::Served,
+		Err(_) if matches!(peer.try_next_broadcast(), Ok(Message::ServerBusy)) => {
+			Outcome::TurnedAway
+		}
+		Err(_) => Outcome::Dropped,
+	}
+}
+
+#[test]
+fn connections_past_the_workers_are_told_busy() {
+	let server = TestServer::with_config(config(2));
+	let served = [server.connect(), server.connect()];
+	for peer in &served {
+		peer.ping().unwrap();
+	}
+
+	let extra = server.connect();
+	assert!(matches!(extra.next_broadcast(), Message::ServerBusy));
+	assert!(extra.ping().is_err());
+
+	// A worker freed up serves the next connection
+	drop(served);
+	let deadline = Instant::now() + Duration::from_secs(5);
+	loop {
+		let peer = server.connect();
+		if peer.ping().is_ok() {
+			break;
+		}
+		assert!(Instant::now() < deadline, "No worker freed up");
+		sleep(Duration::from_millis(20));
+	}
+}
+
+#[test]
+fn server_stays_healthy_at_the_cap() {
+	const WORKERS: usize = 4;
+	let server = TestServer::with_config(config(WORKERS));
+
+	// Every connection is either served or turned away, none left hanging
+	let outcomes = thread::scope(|scope| {
+		let clients = (0..32)
+			.map(|_| {
+				scope.spawn(|| {
+					let peer = server.connect();
+					let outcome = outcome(&peer);
+					// Served connections are held on to, keeping their workers busy
+					sleep(Duration::from_millis(200));
+					outcome
+				})
+			})
+			.collect::<Vec<_>>();
+		clients
+			.into_iter()
+			.map(|client| client.join().unwrap())
+			.collect::<Vec<_>>()
+	});
+	assert!(!outcomes.contains(&Outcome::Dropped));
+	let served = outcomes
+		.iter()
+		.filter(|outcome| **outcome == Outcome::Served)
+		.count();
+	assert!((1..=WORKERS).contains(&served), "{} served", served);
+
+	// Once they have gone, the server is back to serving at full strength
+	let deadline = Instant::now() + Duration::from_secs(5);
+	loop {
+		let peers = (0..WORKERS).map(|_| server.connect()).collect::<Vec<_>>();
+		if peers.iter().all(|peer| outcome(peer) == Outcome::Served) {
+			break;
+		}
+		drop(peers);
+		assert!(Instant::now() < deadline, "Workers never freed up");
+		sleep(Duration::from_millis(50));
+	}
+}